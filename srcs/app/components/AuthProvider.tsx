@@ -22,6 +22,28 @@ type AuthProps = {
   readonly children: JSXElement;
 };
 
+type Tokens = {
+  access_token: string;
+  refresh_token: string;
+};
+
+/**
+ * Mirrors the access token in a cookie, so the browser can load assets
+ * straight from `<img src>`, which cannot send a header. Clears the cookie
+ * without a token.
+ */
+function setAccessCookie(accessToken?: string) {
+  document.cookie = accessToken
+    ? `access_token=${accessToken}; path=/api; SameSite=Strict`
+    : 'access_token=; path=/api; max-age=0';
+}
+
+function storeTokens(tokens: Tokens) {
+  localStorage.setItem('access_token', tokens.access_token);
+  localStorage.setItem('refresh_token', tokens.refresh_token);
+  setAccessCookie(tokens.access_token);
+}
+
 let AuthContext: Context<{
   register: () => void;
   login: () => void;
@@ -30,10 +52,7 @@ let AuthContext: Context<{
 }>;
 
 export function AuthProvider(props: AuthProps) {
-  const [token, setToken] = createSignal<{
-    access_token: string;
-    refresh_token: string;
-  }>({
+  const [token, setToken] = createSignal<Tokens>({
     access_token: localStorage.access_token,
     refresh_token: localStorage.refresh_token,
   });
@@ -47,8 +66,7 @@ export function AuthProvider(props: AuthProps) {
       })
       .then((payload) => {
         setIsLoggedIn(true);
-        localStorage.setItem('access_token', payload.data.access_token);
-        localStorage.setItem('refresh_token', payload.data.refresh_token);
+        storeTokens(payload.data);
         setToken(payload.data);
         socket.connect();
       });
@@ -62,8 +80,7 @@ export function AuthProvider(props: AuthProps) {
       })
       .then((payload) => {
         setIsLoggedIn(true);
-        localStorage.setItem('access_token', payload.data.access_token);
-        localStorage.setItem('refresh_token', payload.data.refresh_token);
+        storeTokens(payload.data);
         setToken(payload.data);
         socket.connect();
       });
@@ -73,6 +90,7 @@ export function AuthProvider(props: AuthProps) {
     setIsLoggedIn(false);
     delete localStorage.access_token;
     delete localStorage.refresh_token;
+    setAccessCookie();
   }
 
   function onSubmit(event: SubmitEvent) {
@@ -102,6 +120,9 @@ export function AuthProvider(props: AuthProps) {
       return;
     }
 
+    // The cookie may have expired, or been cleared, since the session was
+    // stored.
+    setAccessCookie(token().access_token);
     setIsLoggedIn(true);
     socket.connect();
 
@@ -109,15 +130,11 @@ export function AuthProvider(props: AuthProps) {
     const timeout = exp * 1e3 - 30e3 - Date.now();
     const timeoutId = setTimeout(async () => {
       try {
-        const { data } = await axios.post<{
-          access_token: string;
-          refresh_token: string;
-        }>('/api/user/refresh', {
+        const { data } = await axios.post<Tokens>('/api/user/refresh', {
           refresh_token: token().refresh_token,
         });
         setIsLoggedIn(true);
-        localStorage.setItem('access_token', data.access_token);
-        localStorage.setItem('refresh_token', data.refresh_token);
+        storeTokens(data);
       } catch {
         logout();
       }
@@ -2,7 +2,7 @@ import { invoke } from '@tauri-apps/api/core';
 import { type IDBPDatabase, openDB } from 'idb';
 import { type Socket, io } from 'socket.io-client';
 import { type JSXElement, useContext, createContext } from 'solid-js';
-import { type Item, type Asset } from 'types';
+import { type Item, type Asset, type Nearby } from 'types';
 
 import { isTauri } from '../lib/const.js';
 import {
@@ -18,8 +18,9 @@ import { type Storage } from '../lib/types.js';
 
 const socket = io(window.location.origin, {
   autoConnect: false,
-  auth: {
-    user: 'test',
+  // Read on every (re)connect, so a refreshed access token is picked up.
+  auth: (callback) => {
+    callback({ token: localStorage.getItem('access_token') });
   },
 });
 
@@ -64,6 +65,13 @@ async function connect(storage?: Storage, path?: string): Promise<boolean> {
   }
 }
 
+/**
+ * The space being viewed, defaulting to the first one.
+ */
+function currentSpace() {
+  return Number(localStorage.getItem('space') ?? 1);
+}
+
 async function getNearbyItems() {
   const storage = localStorage.getItem('storage');
   switch (storage) {
@@ -75,14 +83,19 @@ async function getNearbyItems() {
     }
     case 'local': {
       if (isTauri) {
-        return await invoke<Item[]>('get_nearby_items');
+        const { items } = await invoke<Nearby>('get_nearby_items', {
+          spaceId: currentSpace(),
+        });
+        return items;
       }
       break;
     }
     case 'cloud': {
       // if (localStorage.getItem('access_token')) {
-      const res = await socket.emitWithAck('item:get_nearby');
-      return Array.isArray(res) ? res : [res];
+      const { items } = (await socket.emitWithAck('item:get_nearby', {
+        space_id: currentSpace(),
+      })) as Nearby;
+      return items;
       // }
       break;
     }
@@ -163,13 +176,21 @@ async function createItem(item: Item, assets: number[][]) {
     }
     case 'local': {
       if (isTauri) {
-        return await invoke<Item>('create_item', { ...item, assets });
+        return await invoke<Item>('create_item', {
+          ...item,
+          spaceId: currentSpace(),
+          assets,
+        });
       }
       break;
     }
     case 'cloud': {
       // if (localStorage.getItem('access_token')) {
-      return await socket.emitWithAck('item:create', { ...item, assets });
+      return await socket.emitWithAck('item:create', {
+        ...item,
+        space_id: currentSpace(),
+        assets,
+      });
       // }
       break;
     }
@@ -199,8 +220,7 @@ async function updateItem(items: Item[]) {
     }
     case 'local': {
       if (isTauri) {
-        await invoke<Item>('patch_item', { items });
-        return items;
+        return await invoke<Item[]>('patch_item', { items });
       }
       break;
     }
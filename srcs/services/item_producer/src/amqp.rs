@@ -0,0 +1,430 @@
+//! [`EventBus`] backed by RabbitMQ. Events are published to `amq.topic` with
+//! publisher confirms and every instance consumes them from a durable queue
+//! of its own, dead-lettering the ones it fails to process twice.
+
+use std::{collections::BTreeSet, sync::Arc, time::Duration};
+
+use amqprs::{
+  callbacks::{ChannelCallback, DefaultChannelCallback, DefaultConnectionCallback},
+  channel::{
+    BasicAckArguments, BasicConsumeArguments, BasicNackArguments, BasicPublishArguments,
+    BasicQosArguments, Channel, ConfirmSelectArguments, ExchangeDeclareArguments,
+    QueueBindArguments, QueueDeclareArguments,
+  },
+  connection::{Connection, OpenConnectionArguments},
+  consumer::AsyncConsumer,
+  Ack, BasicProperties, Cancel, CloseChannel, Deliver, FieldTable, FieldValue, Nack, Return,
+};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use tokio::sync::{
+  mpsc::{self, UnboundedReceiver, UnboundedSender},
+  Mutex,
+};
+use tracing::{info, warn};
+
+use crate::{
+  bus::{Delivery, Event, EventBus, Subscriber},
+  metrics::{Metrics, Outcome},
+};
+
+const EXCHANGE: &str = "amq.topic";
+/// Every item, connector and space event, as published by the outbox.
+const ROUTING_KEYS: [&str; 3] = ["item.*", "connector.*", "space.*"];
+/// Receives messages that could not be processed, for later inspection.
+const DEAD_LETTER_EXCHANGE: &str = "item.dead_letter";
+const DEAD_LETTER_QUEUE: &str = "item.dead_letter";
+/// Queues of instances that are gone for this long, in milliseconds, are
+/// removed by the broker.
+const QUEUE_EXPIRES: i32 = 60 * 60 * 1000;
+/// Unacknowledged messages delivered at once.
+const PREFETCH_COUNT: u16 = 64;
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the queue length is sampled for the lag metric.
+const LAG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+enum Confirm {
+  Ack { delivery_tag: u64, multiple: bool },
+  Nack { delivery_tag: u64, multiple: bool },
+}
+
+/// Forwards publisher confirms to the publisher.
+struct Confirms(UnboundedSender<Confirm>);
+
+#[async_trait]
+impl ChannelCallback for Confirms {
+  async fn close(
+    &mut self,
+    _channel: &Channel,
+    close: CloseChannel,
+  ) -> Result<(), amqprs::error::Error> {
+    warn!("Publisher channel closed: {}", close);
+    Ok(())
+  }
+
+  async fn cancel(
+    &mut self,
+    _channel: &Channel,
+    _cancel: Cancel,
+  ) -> Result<(), amqprs::error::Error> {
+    Ok(())
+  }
+
+  async fn flow(&mut self, _channel: &Channel, active: bool) -> Result<bool, amqprs::error::Error> {
+    Ok(active)
+  }
+
+  async fn publish_ack(&mut self, _channel: &Channel, ack: Ack) {
+    self
+      .0
+      .send(Confirm::Ack {
+        delivery_tag: ack.delivery_tag(),
+        multiple: ack.mutiple(),
+      })
+      .ok();
+  }
+
+  async fn publish_nack(&mut self, _channel: &Channel, nack: Nack) {
+    self
+      .0
+      .send(Confirm::Nack {
+        delivery_tag: nack.delivery_tag(),
+        multiple: nack.multiple(),
+      })
+      .ok();
+  }
+
+  async fn publish_return(
+    &mut self,
+    _channel: &Channel,
+    _ret: Return,
+    _basic_properties: BasicProperties,
+    _content: Vec<u8>,
+  ) {
+  }
+}
+
+/// Settles `pending` delivery tags with a confirm, failing on a nack.
+fn settle(pending: &mut BTreeSet<u64>, confirm: Confirm) -> Result<()> {
+  let (delivery_tag, multiple, acked) = match confirm {
+    Confirm::Ack {
+      delivery_tag,
+      multiple,
+    } => (delivery_tag, multiple, true),
+    Confirm::Nack {
+      delivery_tag,
+      multiple,
+    } => (delivery_tag, multiple, false),
+  };
+  let settled = if multiple {
+    let rest = pending.split_off(&(delivery_tag + 1));
+    std::mem::replace(pending, rest)
+  } else {
+    pending.take(&delivery_tag).into_iter().collect()
+  };
+  if !acked && !settled.is_empty() {
+    return Err(anyhow!("broker rejected {} event(s)", settled.len()));
+  }
+  Ok(())
+}
+
+struct Publisher {
+  connection: Connection,
+  channel: Channel,
+  confirms: UnboundedReceiver<Confirm>,
+  /// Delivery tag of the next publish, counted by the broker per channel.
+  next_delivery_tag: u64,
+}
+
+impl Publisher {
+  async fn open(arguments: &OpenConnectionArguments) -> Result<Self> {
+    let connection = Connection::open(arguments).await?;
+    connection
+      .register_callback(DefaultConnectionCallback)
+      .await?;
+    let channel = connection.open_channel(None).await?;
+    let (sender, confirms) = mpsc::unbounded_channel();
+    channel.register_callback(Confirms(sender)).await?;
+    channel
+      .confirm_select(ConfirmSelectArguments::default())
+      .await?;
+    Ok(Self {
+      connection,
+      channel,
+      confirms,
+      next_delivery_tag: 1,
+    })
+  }
+
+  fn is_open(&self) -> bool {
+    self.connection.is_open() && self.channel.is_open()
+  }
+
+  async fn publish(&mut self, events: &[Event]) -> Result<()> {
+    let mut pending = BTreeSet::new();
+    for event in events {
+      let properties = BasicProperties::default()
+        .with_message_id(&event.id)
+        .with_persistence(true)
+        .finish();
+      self
+        .channel
+        .basic_publish(
+          properties,
+          event.payload.clone(),
+          BasicPublishArguments::new(EXCHANGE, &event.routing_key),
+        )
+        .await?;
+      pending.insert(self.next_delivery_tag);
+      self.next_delivery_tag += 1;
+    }
+    while !pending.is_empty() {
+      match tokio::time::timeout(CONFIRM_TIMEOUT, self.confirms.recv()).await {
+        Ok(Some(confirm)) => settle(&mut pending, confirm)?,
+        Ok(None) => return Err(anyhow!("publisher channel closed")),
+        Err(_) => return Err(anyhow!("timed out waiting for publisher confirms")),
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Settles the messages delivered to a [`Subscriber`].
+struct Consumer {
+  subscriber: Box<dyn Subscriber>,
+}
+
+#[async_trait]
+impl AsyncConsumer for Consumer {
+  async fn consume(
+    &mut self,
+    channel: &Channel,
+    deliver: Deliver,
+    basic_properties: BasicProperties,
+    content: Vec<u8>,
+  ) {
+    let delivery_tag = deliver.delivery_tag();
+    let outcome = self
+      .subscriber
+      .deliver(Delivery {
+        id: basic_properties.message_id().cloned(),
+        routing_key: deliver.routing_key().clone(),
+        payload: content,
+        redelivered: deliver.redelivered(),
+      })
+      .await;
+    let result = match outcome {
+      Outcome::Ack | Outcome::Duplicate => {
+        channel
+          .basic_ack(BasicAckArguments::new(delivery_tag, false))
+          .await
+      }
+      Outcome::Requeue => {
+        channel
+          .basic_nack(BasicNackArguments::new(delivery_tag, false, true))
+          .await
+      }
+      Outcome::DeadLetter => {
+        channel
+          .basic_nack(BasicNackArguments::new(delivery_tag, false, false))
+          .await
+      }
+    };
+    // Unacknowledged messages are redelivered once the consumer reconnects.
+    if let Err(e) = result {
+      warn!("Failed to settle message {}: {}", delivery_tag, e);
+    }
+  }
+}
+
+async fn declare_topology(channel: &Channel, queue: &str) -> Result<()> {
+  channel
+    .exchange_declare(
+      ExchangeDeclareArguments::new(DEAD_LETTER_EXCHANGE, "fanout")
+        .durable(true)
+        .finish(),
+    )
+    .await?;
+  channel
+    .queue_declare(QueueDeclareArguments::durable_client_named(
+      DEAD_LETTER_QUEUE,
+    ))
+    .await?;
+  channel
+    .queue_bind(QueueBindArguments::new(
+      DEAD_LETTER_QUEUE,
+      DEAD_LETTER_EXCHANGE,
+      "",
+    ))
+    .await?;
+
+  let mut arguments = FieldTable::new();
+  arguments.insert(
+    "x-dead-letter-exchange".try_into()?,
+    DEAD_LETTER_EXCHANGE.into(),
+  );
+  arguments.insert("x-expires".try_into()?, FieldValue::I(QUEUE_EXPIRES));
+  channel
+    .queue_declare(
+      QueueDeclareArguments::durable_client_named(queue)
+        .arguments(arguments)
+        .finish(),
+    )
+    .await?;
+  for routing_key in ROUTING_KEYS {
+    channel
+      .queue_bind(QueueBindArguments::new(queue, EXCHANGE, routing_key))
+      .await?;
+  }
+  Ok(())
+}
+
+pub struct AmqpBus {
+  arguments: OpenConnectionArguments,
+  /// Durable queue of this instance, so events published while it
+  /// reconnects are delivered afterwards.
+  queue: String,
+  metrics: Arc<Metrics>,
+  /// Opened on the first publish and again after a failed one.
+  publisher: Mutex<Option<Publisher>>,
+}
+
+impl AmqpBus {
+  pub fn new(arguments: OpenConnectionArguments, queue: String, metrics: Arc<Metrics>) -> Self {
+    Self {
+      arguments,
+      queue,
+      metrics,
+      publisher: Mutex::new(None),
+    }
+  }
+
+  /// Consumes until the connection is lost.
+  async fn consume(&self, subscriber: Box<dyn Subscriber>) -> Result<()> {
+    let connection = Connection::open(&self.arguments).await?;
+    connection
+      .register_callback(DefaultConnectionCallback)
+      .await?;
+    let channel = connection.open_channel(None).await?;
+    channel.register_callback(DefaultChannelCallback).await?;
+
+    declare_topology(&channel, &self.queue).await?;
+    channel
+      .basic_qos(BasicQosArguments::new(0, PREFETCH_COUNT, false))
+      .await?;
+    // An empty tag lets the broker generate a unique one.
+    channel
+      .basic_consume(
+        Consumer { subscriber },
+        BasicConsumeArguments::new(&self.queue, ""),
+      )
+      .await?;
+    info!("Consuming events from {}", self.queue);
+    self.metrics.set_consumer_up(true);
+
+    let io_failure = connection.listen_network_io_failure();
+    tokio::pin!(io_failure);
+    let mut interval = tokio::time::interval(LAG_INTERVAL);
+    loop {
+      tokio::select! {
+        _ = &mut io_failure => break,
+        _ = interval.tick() => {
+          if !connection.is_open() || !channel.is_open() {
+            break;
+          }
+          let passive = QueueDeclareArguments::new(&self.queue).passive(true).finish();
+          match channel.queue_declare(passive).await {
+            Ok(Some((_, messages, _))) => self.metrics.set_consumer_lag(messages),
+            Ok(None) => {}
+            Err(e) => {
+              warn!("Failed to sample consumer lag: {}", e);
+              break;
+            }
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+#[async_trait]
+impl EventBus for AmqpBus {
+  async fn publish(&self, events: &[Event]) -> Result<()> {
+    let mut publisher = self.publisher.lock().await;
+    let open = match publisher.take() {
+      Some(open) if open.is_open() => open,
+      _ => Publisher::open(&self.arguments).await?,
+    };
+    // The state of the channel is unknown after a failure, so it is
+    // reopened for the next publish.
+    let open = publisher.insert(open);
+    let result = open.publish(events).await;
+    if result.is_err() {
+      *publisher = None;
+    }
+    result
+  }
+
+  async fn subscribe(&self, subscriber: Box<dyn Subscriber>) -> Result<()> {
+    let result = self.consume(subscriber).await;
+    self.metrics.set_consumer_up(false);
+    result
+  }
+
+  async fn check(&self) -> Result<()> {
+    if !self.metrics.consumer_up() {
+      return Err(anyhow!("consumer is not connected"));
+    }
+    // A publish in progress holds the publisher, and drops it if the channel
+    // fails. Waiting for its confirms would outlast the health check.
+    let Ok(mut publisher) = self.publisher.try_lock() else {
+      return Ok(());
+    };
+    match publisher.take() {
+      Some(open) if open.is_open() => *publisher = Some(open),
+      // The publisher is opened on the first publish, the relay may not have
+      // published yet.
+      _ => *publisher = Some(Publisher::open(&self.arguments).await?),
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_settle() {
+    let mut pending = BTreeSet::from([1, 2, 3, 4]);
+    settle(
+      &mut pending,
+      Confirm::Ack {
+        delivery_tag: 2,
+        multiple: false,
+      },
+    )
+    .unwrap();
+    assert_eq!(pending, BTreeSet::from([1, 3, 4]));
+    settle(
+      &mut pending,
+      Confirm::Ack {
+        delivery_tag: 3,
+        multiple: true,
+      },
+    )
+    .unwrap();
+    assert_eq!(pending, BTreeSet::from([4]));
+    assert!(settle(
+      &mut pending,
+      Confirm::Nack {
+        delivery_tag: 4,
+        multiple: false,
+      },
+    )
+    .is_err());
+    assert!(pending.is_empty());
+  }
+}
@@ -0,0 +1,588 @@
+//! Export and import of whole spaces as [`Archive`]s. Archives are larger than
+//! a message, so an export is downloaded over HTTP like an asset, and an
+//! archive to import is uploaded like an asset with `asset:upload_begin` and
+//! `asset:upload_append`, then `space:import` consumes the complete upload in
+//! place of `asset:upload_commit`.
+
+use std::collections::HashSet;
+
+use axum::{
+  body::Body,
+  extract::{Path, State},
+  http::{
+    header::{CACHE_CONTROL, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE},
+    HeaderMap, StatusCode,
+  },
+  response::Response,
+};
+use bytes::Bytes;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use socketioxide::extract::{AckSender, Data, SocketRef, State as SocketState};
+use sqlx::PgPool;
+use tracing::error;
+use utils::{archive, archive::Archive, asset_store::AssetStore, axum::ApiError};
+
+use crate::{
+  assets, clients,
+  connectors::{self, ConnectorData},
+  events::{self, Kind},
+  handlers::{link_assets, respond, Error},
+  history::{self, Snapshot},
+  item::{Asset, Connector, Descendant, Item, EDITORS},
+  search,
+  spaces::{self, Space},
+  uploads, GlobalState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct SpaceImport {
+  /// Id of the complete upload of the archive.
+  pub upload: String,
+  /// Hex encoded SHA-256 of the archive.
+  pub hash: String,
+  /// Name of the new space, the one in the archive by default.
+  pub name: Option<String>,
+}
+
+async fn export_space(
+  db_pool: &PgPool,
+  asset_store: &dyn AssetStore,
+  user: &str,
+  id: i64,
+) -> Result<Bytes, Error> {
+  let mut transaction = db_pool.begin().await?;
+  spaces::authorize(&mut transaction, id, user).await?;
+  let name = sqlx::query_scalar!("SELECT name FROM space WHERE id = $1", id as i32)
+    .fetch_one(&mut *transaction)
+    .await?;
+  let items = sqlx::query_as!(
+    Item,
+    r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item WHERE space_id = $1 AND deleted_at IS NULL ORDER BY id
+    "#,
+    id as i32,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let connectors = sqlx::query_as!(
+    Connector,
+    "SELECT * FROM connector WHERE space_id = $1 ORDER BY id",
+    id as i32,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let item_ids = items.iter().map(|item| item.id as i32).collect::<Vec<_>>();
+  let assets = sqlx::query!(
+    r#"
+SELECT DISTINCT asset.id, asset.name, asset.mime FROM asset
+JOIN item_assets ON item_assets.asset_id = asset.id
+WHERE item_assets.item_id = ANY($1)
+ORDER BY asset.id
+    "#,
+    &item_ids,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  transaction.commit().await?;
+
+  let mut archive = Archive::from_rows(name, items, connectors);
+  archive.assets.reserve(assets.len());
+  for asset in assets {
+    let data = asset_store
+      .get(&asset.id)
+      .await?
+      .ok_or_else(|| Error::UnknownAsset(asset.id.clone()))?;
+    archive.assets.push(archive::Asset {
+      id: asset.id,
+      name: asset.name,
+      mime: asset.mime,
+      data,
+    });
+  }
+
+  Ok(Bytes::from(
+    tokio::task::spawn_blocking(move || archive.write())
+      .await
+      .unwrap()?,
+  ))
+}
+
+/// Reads an archive and the assets it contains, keyed by their id in the
+/// archive.
+fn read(data: &[u8]) -> Result<(Archive, Vec<(String, Asset)>), Error> {
+  let mut archive = Archive::read(data)?;
+  let assets = std::mem::take(&mut archive.assets)
+    .into_iter()
+    .map(|entry| {
+      let mut asset = Asset::new(entry.data);
+      asset.name = entry.name;
+      asset.inspect()?;
+      Ok((entry.id, asset))
+    })
+    .collect::<Result<Vec<_>, Error>>()?;
+  Ok((archive, assets))
+}
+
+/// Creates a space of `user` from an uploaded archive. Items, connectors and
+/// assets get new ids, and assets with content already stored are shared.
+async fn import_space(
+  db_pool: &PgPool,
+  asset_store: &dyn AssetStore,
+  user: &str,
+  request: SpaceImport,
+) -> Result<Space, Error> {
+  let mut transaction = db_pool.begin().await?;
+
+  let upload = uploads::take(&mut transaction, &request.upload, user).await?;
+  if request.hash.to_ascii_lowercase() != upload.hash
+    || format!("{:x}", Sha256::digest(&upload.data)) != upload.hash
+  {
+    return Err(Error::ChecksumMismatch);
+  }
+  let (archive, assets) = tokio::task::spawn_blocking(move || read(&upload.data))
+    .await
+    .unwrap()?;
+  if let Some(item) = archive
+    .items
+    .iter()
+    .find(|item| !EDITORS.contains(&item.editor.as_str()))
+  {
+    return Err(Error::UnknownEditor(item.editor.clone()));
+  }
+
+  let space = spaces::insert(
+    &mut transaction,
+    user,
+    request.name.as_deref().unwrap_or(&archive.name),
+  )
+  .await?;
+
+  let (asset_ids, archived_assets) = assets
+    .into_iter()
+    .map(|(id, asset)| ((id, asset.id.clone()), asset))
+    .unzip::<_, _, Vec<_>, Vec<_>>();
+  // Identical content resolves to the already stored asset.
+  let (existing_ids, new_assets) = assets::insert(&mut transaction, archived_assets).await?;
+  let mut ids = archive::Ids::new(asset_ids, &existing_ids);
+
+  let mut items = Vec::with_capacity(archive.items.len());
+  for item in archive.ordered_items()? {
+    let mut descendants =
+      serde_json::from_str::<Vec<Descendant>>(item.schema.as_deref().unwrap_or("[]"))?;
+    let mut refs = HashSet::new();
+    for descendant in &descendants {
+      descendant.asset_refs(&mut refs);
+    }
+    if let Some(unknown) = ids.unknown_asset(&refs) {
+      return Err(Error::UnknownAsset(unknown.clone()));
+    }
+    for descendant in &mut descendants {
+      descendant.replace_refs(ids.assets());
+    }
+
+    let imported = sqlx::query_as!(
+      Item,
+      r#"
+INSERT INTO item ( space_id, x, y, w, h, schema, editor, z_index, parent_id )
+VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9 )
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+      "#,
+      space.id as i32,
+      item.x as i32,
+      item.y as i32,
+      item.w as i32,
+      item.h as i32,
+      serde_json::to_string(&descendants)?,
+      item.editor,
+      item.z_index as i32,
+      ids.parent(item).map(|id| id as i32),
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    ids.insert_item(item.id, imported.id);
+
+    link_assets(&mut transaction, imported.id as i32, &descendants).await?;
+    search::index(&mut transaction, imported.id as i32, &descendants).await?;
+    history::record(
+      &mut transaction,
+      user,
+      "import",
+      &Snapshot::default(),
+      &imported,
+    )
+    .await?;
+    items.push(imported);
+  }
+
+  for connector in archive.connectors {
+    let connector = ids.connector(connector);
+    let data = ConnectorData {
+      source_id: connector.source_id,
+      target_id: connector.target_id,
+      source_anchor: connector.source_anchor,
+      target_anchor: connector.target_anchor,
+      label: connector.label,
+      style: connector.style,
+    };
+    connectors::check(&data)?;
+    connectors::insert(&mut transaction, space.id as i32, &data).await?;
+  }
+  events::publish(&mut transaction, Kind::Created, &items).await?;
+
+  assets::commit(transaction, asset_store, new_assets).await?;
+  Ok(space)
+}
+
+/// Serves the archive of a space of the authenticated user as a download.
+#[tracing::instrument(skip(db_pool, asset_store, keys, headers))]
+pub async fn export(
+  Path(space_id): Path<i32>,
+  headers: HeaderMap,
+  State(GlobalState {
+    db_pool,
+    asset_store,
+    keys,
+    ..
+  }): State<GlobalState>,
+) -> Result<Response, ApiError> {
+  let user = assets::authenticate(&headers, &keys)?;
+  let data = export_space(&db_pool, asset_store.as_ref(), &user, space_id as i64)
+    .await
+    .map_err(|e| match e {
+      Error::SpaceNotFound(_) => ApiError(StatusCode::NOT_FOUND, Some("Space not found".into())),
+      e => {
+        error!("{}", e);
+        ApiError(StatusCode::INTERNAL_SERVER_ERROR, None)
+      }
+    })?;
+
+  Response::builder()
+    .status(StatusCode::OK)
+    .header(CONTENT_TYPE, "application/zip")
+    .header(
+      CONTENT_DISPOSITION,
+      format!("attachment; filename=\"space-{}.zip\"", space_id),
+    )
+    .header(CACHE_CONTROL, "private, no-store")
+    .header(CONTENT_LENGTH, data.len())
+    .body(Body::from(data))
+    .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn import(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SpaceImport>,
+  SocketState(GlobalState {
+    db_pool,
+    asset_store,
+    ..
+  }): SocketState<GlobalState>,
+) {
+  let user = clients::user(&socket);
+  respond(
+    ack,
+    import_space(&db_pool, asset_store.as_ref(), &user, data).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use axum::{body::to_bytes, http::header::AUTHORIZATION};
+  use utils::jwt::{self, Claims, Keys, TokenUse};
+
+  use super::*;
+  use crate::{
+    bus::MemoryBus,
+    fixtures::{self, item, ItemRow},
+    metrics::Metrics,
+    store::DatabaseStore,
+    uploads::{
+      self,
+      tests::{hash, PNG},
+      UploadBegin, UploadChunk,
+    },
+  };
+
+  /// Uploads `data` for `user` without committing it, as a client importing
+  /// it would.
+  async fn upload(db_pool: &PgPool, user: &str, data: &[u8]) -> String {
+    let upload = uploads::begin(
+      db_pool,
+      user,
+      UploadBegin {
+        size: data.len() as i64,
+        hash: hash(data),
+      },
+    )
+    .await
+    .unwrap();
+    uploads::append(
+      db_pool,
+      user,
+      UploadChunk {
+        id: upload.id.clone(),
+        offset: 0,
+        data: Bytes::copy_from_slice(data),
+      },
+    )
+    .await
+    .unwrap();
+    upload.id
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_archive(db_pool: PgPool) {
+    let store = DatabaseStore::new(db_pool.clone());
+    fixtures::space(&db_pool, 1, "alice").await;
+    sqlx::query("UPDATE space SET name = 'Plans' WHERE id = 1")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    fixtures::items(
+      &db_pool,
+      [
+        ItemRow {
+          w: 100,
+          h: 100,
+          z_index: 1,
+          schema: Some("[]"),
+          ..item(1, 1)
+        },
+        ItemRow {
+          parent_id: Some(1),
+          x: 10,
+          y: 10,
+          w: 10,
+          h: 10,
+          z_index: 1,
+          schema: Some(r#"[{"type": "image", "mime": "image/png", "name": "", "uuid": "a", "children": [{"text": ""}]}]"#),
+          ..item(2, 1)
+        },
+        ItemRow {
+          x: 200,
+          w: 10,
+          h: 10,
+          z_index: 2,
+          schema: Some("[]"),
+          ..item(3, 1)
+        },
+        ItemRow {
+          x: 300,
+          w: 10,
+          h: 10,
+          z_index: 3,
+          schema: Some("[]"),
+          trashed: true,
+          ..item(4, 1)
+        },
+      ],
+    )
+    .await;
+    sqlx::query(
+      r#"
+INSERT INTO connector (space_id, source_id, target_id, source_anchor, target_anchor) VALUES
+  (1, 1, 3, 'right', 'left'),
+  (1, 3, 4, 'auto', 'auto')
+      "#,
+    )
+    .execute(&db_pool)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO asset (id, name, mime, hash) VALUES ('a', 'dot', 'image/png', $1)")
+      .bind(hash(&PNG))
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    store.put("a", PNG.to_vec()).await.unwrap();
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (2, 'a')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+
+    assert!(matches!(
+      export_space(&db_pool, &store, "bob", 1).await,
+      Err(Error::SpaceNotFound(1))
+    ));
+    let data = export_space(&db_pool, &store, "alice", 1).await.unwrap();
+    let exported = Archive::read(&data).unwrap();
+    assert_eq!(exported.name, "Plans");
+    assert_eq!(exported.items.len(), 3);
+    assert_eq!(exported.connectors.len(), 1);
+    assert_eq!(exported.assets[0].data, PNG.to_vec());
+
+    let id = upload(&db_pool, "bob", &data).await;
+    let space = import_space(
+      &db_pool,
+      &store,
+      "bob",
+      SpaceImport {
+        upload: id,
+        hash: hash(&data),
+        name: None,
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(space.name, "Plans");
+
+    let items = sqlx::query_as!(
+      Item,
+      r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item WHERE space_id = $1 ORDER BY id
+      "#,
+      space.id as i32,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .unwrap();
+    let layout = items
+      .iter()
+      .map(|item| (item.x, item.y, item.z_index))
+      .collect::<Vec<_>>();
+    // Top-level items are imported before the items they contain.
+    assert_eq!(layout, vec![(0, 0, 1), (200, 0, 2), (10, 10, 1)]);
+    assert!(items.iter().all(|item| item.id > 4));
+    assert_eq!(items[2].parent_id, Some(items[0].id));
+    let connector = sqlx::query_as!(
+      Connector,
+      "SELECT * FROM connector WHERE space_id = $1",
+      space.id as i32,
+    )
+    .fetch_one(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(
+      (connector.source_id, connector.target_id),
+      (items[0].id, items[1].id)
+    );
+
+    // The image has the same content, so the stored asset is shared.
+    let mut refs = HashSet::new();
+    for descendant in
+      serde_json::from_str::<Vec<Descendant>>(items[2].schema.as_ref().unwrap()).unwrap()
+    {
+      descendant.asset_refs(&mut refs);
+    }
+    assert_eq!(refs, HashSet::from(["a".to_string()]));
+    assert_eq!(
+      sqlx::query_scalar!("SELECT ref_count FROM asset WHERE id = 'a'")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap(),
+      2
+    );
+
+    // The upload is consumed by the import.
+    assert!(matches!(
+      import_space(
+        &db_pool,
+        &store,
+        "bob",
+        SpaceImport {
+          upload: "missing".into(),
+          hash: hash(&data),
+          name: None,
+        },
+      )
+      .await,
+      Err(Error::UploadNotFound(_))
+    ));
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_import_foreign_asset(db_pool: PgPool) {
+    let store = DatabaseStore::new(db_pool.clone());
+    sqlx::query("INSERT INTO asset (id, name, mime) VALUES ('a', '', 'image/png')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    // An item referencing an asset of the server rather than of the archive.
+    let data = Archive {
+      name: "Plans".into(),
+      items: vec![archive::Item {
+        id: 1,
+        x: 0,
+        y: 0,
+        w: 1,
+        h: 1,
+        editor: "rich".into(),
+        schema: Some(
+          r#"[{"type": "image", "mime": "image/png", "name": "", "uuid": "a", "children": [{"text": ""}]}]"#
+            .into(),
+        ),
+        z_index: 1,
+        parent_id: None,
+      }],
+      ..Default::default()
+    }
+    .write()
+    .unwrap();
+    let id = upload(&db_pool, "bob", &data).await;
+    assert!(matches!(
+      import_space(
+        &db_pool,
+        &store,
+        "bob",
+        SpaceImport {
+          upload: id,
+          hash: hash(&data),
+          name: Some("Mine".into()),
+        },
+      )
+      .await,
+      Err(Error::UnknownAsset(_))
+    ));
+    let spaces: i64 = sqlx::query_scalar("SELECT count(*) FROM space")
+      .fetch_one(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(spaces, 0);
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_export(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(&db_pool, [item(1, 1)]).await;
+    let state = GlobalState {
+      db_pool: db_pool.clone(),
+      asset_store: Arc::new(DatabaseStore::new(db_pool.clone())),
+      bus: Arc::new(MemoryBus::new()),
+      metrics: Arc::new(Metrics::new()),
+      keys: Arc::new(Keys::new(b"secret")),
+    };
+    let get = |user: Option<&str>| {
+      let mut headers = HeaderMap::new();
+      if let Some(user) = user {
+        let token = jwt::sign(
+          &Claims::new(user.into(), TokenUse::Access),
+          &state.keys.encoding,
+        )
+        .unwrap();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+      }
+      export(Path(1), headers, State(state.clone()))
+    };
+    let status = |result: Result<Response, ApiError>| match result {
+      Ok(response) => response.status(),
+      Err(ApiError(status, _)) => status,
+    };
+
+    assert_eq!(status(get(None).await), StatusCode::UNAUTHORIZED);
+    assert_eq!(status(get(Some("bob")).await), StatusCode::NOT_FOUND);
+    let response = get(Some("alice")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[CONTENT_TYPE], "application/zip");
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(Archive::read(&body).unwrap().items.len(), 1);
+  }
+}
@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+
+use axum::{
+  body::Body,
+  extract::{Path, State},
+  http::{
+    header::{
+      ACCEPT_RANGES, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+      COOKIE, ETAG, IF_NONE_MATCH, IF_RANGE, RANGE,
+    },
+    HeaderMap, StatusCode,
+  },
+  response::Response,
+};
+use sqlx::{PgConnection, Postgres, Transaction};
+use tracing::warn;
+use utils::{
+  asset_store::AssetStore,
+  axum::ApiError,
+  jwt::{self, Keys, TokenUse},
+};
+
+use crate::{handlers::Error, item::Asset, spaces, GlobalState};
+
+/// Assets are never modified after they are stored, so clients may cache them
+/// indefinitely. They are access checked, hence `private`.
+const CACHE_CONTROL_VALUE: &str = "private, max-age=31536000, immutable";
+
+/// Inserts the metadata of `asset` and returns its id. Identical content
+/// resolves to the already stored asset, whose id is returned instead.
+pub async fn upsert(connection: &mut PgConnection, asset: &Asset) -> sqlx::Result<String> {
+  sqlx::query_scalar!(
+    r#"
+INSERT INTO asset ( id, name, mime, hash, width, height, thumbnail, size )
+VALUES ( $1, $2, $3, $4, $5, $6, $7, $8 )
+ON CONFLICT ( hash ) DO UPDATE SET hash = EXCLUDED.hash
+RETURNING id
+    "#,
+    asset.id,
+    asset.name,
+    asset.mime,
+    asset.hash,
+    asset.width,
+    asset.height,
+    asset.thumbnail,
+    asset.size,
+  )
+  .fetch_one(connection)
+  .await
+}
+
+/// Stores the metadata of new assets, returning the ids of assets whose
+/// content was already stored along with the assets whose content still has
+/// to be put by [`commit`].
+pub async fn insert(
+  connection: &mut PgConnection,
+  assets: Vec<Asset>,
+) -> Result<(HashMap<String, String>, Vec<Asset>), Error> {
+  let mut existing_ids = HashMap::new();
+  let mut new_assets = Vec::new();
+  for asset in assets {
+    let id = upsert(connection, &asset).await?;
+    if id != asset.id {
+      existing_ids.insert(asset.id, id);
+    } else {
+      new_assets.push(asset);
+    }
+  }
+  Ok((existing_ids, new_assets))
+}
+
+/// Puts the content of the assets [`insert`] stored in the asset store, then
+/// commits the transaction. Content is put first so metadata never exists
+/// without it, and is deleted again when the commit fails so no content is
+/// left without metadata.
+pub async fn commit(
+  transaction: Transaction<'_, Postgres>,
+  asset_store: &dyn AssetStore,
+  assets: Vec<Asset>,
+) -> Result<(), Error> {
+  let mut stored = Vec::with_capacity(assets.len());
+  let mut result = Ok(());
+  for asset in assets {
+    let Some(data) = asset.data else {
+      continue;
+    };
+    result = asset_store.put(&asset.id, data).await.map_err(Error::from);
+    if result.is_err() {
+      break;
+    }
+    stored.push(asset.id);
+  }
+  if result.is_ok() {
+    result = transaction.commit().await.map_err(Error::from);
+  }
+  if result.is_err() {
+    for id in &stored {
+      if let Err(e) = asset_store.delete(id).await {
+        warn!(
+          "Failed to delete the content of uncommitted asset {}: {}",
+          id, e
+        );
+      }
+    }
+  }
+  result
+}
+
+/// An inclusive byte range within an asset.
+#[derive(Debug, PartialEq)]
+struct ByteRange {
+  start: u64,
+  end: u64,
+}
+
+#[derive(Debug, PartialEq)]
+enum RangeRequest {
+  Full,
+  Partial(ByteRange),
+  Unsatisfiable,
+}
+
+/// Resolves a `Range` header against the asset size. Only single `bytes` ranges
+/// are supported; anything else is ignored and served in full, as permitted by
+/// RFC 9110.
+fn parse_range(header: Option<&str>, size: u64) -> RangeRequest {
+  let Some(spec) = header.and_then(|header| header.trim().strip_prefix("bytes=")) else {
+    return RangeRequest::Full;
+  };
+  if spec.contains(',') {
+    return RangeRequest::Full;
+  }
+  let Some((start, end)) = spec.trim().split_once('-') else {
+    return RangeRequest::Full;
+  };
+
+  if start.is_empty() {
+    // Suffix range, e.g. `bytes=-500` for the last 500 bytes.
+    return match end.parse::<u64>() {
+      Ok(0) => RangeRequest::Unsatisfiable,
+      Ok(_) if size == 0 => RangeRequest::Unsatisfiable,
+      Ok(length) => RangeRequest::Partial(ByteRange {
+        start: size.saturating_sub(length),
+        end: size - 1,
+      }),
+      Err(_) => RangeRequest::Full,
+    };
+  }
+
+  let Ok(start) = start.parse::<u64>() else {
+    return RangeRequest::Full;
+  };
+  let end = match end {
+    "" => u64::MAX,
+    end => match end.parse::<u64>() {
+      Ok(end) if end >= start => end,
+      _ => return RangeRequest::Full,
+    },
+  };
+  if start >= size {
+    return RangeRequest::Unsatisfiable;
+  }
+  RangeRequest::Partial(ByteRange {
+    start,
+    end: end.min(size - 1),
+  })
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+  header
+    .split(',')
+    .map(|tag| tag.trim().trim_start_matches("W/"))
+    .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Cookie holding the access token, for browsers loading assets from
+/// `<img src>`, which cannot send an `Authorization` header.
+const TOKEN_COOKIE: &str = "access_token";
+
+fn token_cookie(headers: &HeaderMap) -> Option<&str> {
+  headers
+    .get_all(COOKIE)
+    .iter()
+    .filter_map(|value| value.to_str().ok())
+    .flat_map(|value| value.split(';'))
+    .filter_map(|cookie| cookie.trim().split_once('='))
+    .find(|(name, _)| *name == TOKEN_COOKIE)
+    .map(|(_, token)| token)
+}
+
+/// Identifies the user by the access token of the request, sent as a bearer
+/// token or in the [`TOKEN_COOKIE`] cookie.
+pub fn authenticate(headers: &HeaderMap, keys: &Keys) -> Result<String, ApiError> {
+  headers
+    .get(AUTHORIZATION)
+    .and_then(|value| value.to_str().ok())
+    .and_then(|value| value.strip_prefix("Bearer "))
+    .or_else(|| token_cookie(headers))
+    .and_then(|token| jwt::verify(token, &keys.decoding, TokenUse::Access).ok())
+    .map(|claims| claims.sub)
+    .ok_or(ApiError(
+      StatusCode::UNAUTHORIZED,
+      Some("Invalid token".into()),
+    ))
+}
+
+/// Serves the content of an asset owned by the given item, honouring
+/// conditional and byte-range requests. The item must be in a space of the
+/// authenticated user and not in the trash.
+#[tracing::instrument(skip(db_pool, asset_store, keys, headers))]
+pub async fn get_asset(
+  Path((item_id, asset_id)): Path<(i32, String)>,
+  headers: HeaderMap,
+  State(GlobalState {
+    db_pool,
+    asset_store,
+    keys,
+    ..
+  }): State<GlobalState>,
+) -> Result<Response, ApiError> {
+  let user = authenticate(&headers, &keys)?;
+  let mut connection = db_pool
+    .acquire()
+    .await
+    .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+  spaces::authorize_item(&mut connection, item_id as i64, &user)
+    .await
+    .map_err(|e| match e {
+      Error::ItemNotFound(_) => ApiError(StatusCode::NOT_FOUND, Some("Asset not found".into())),
+      _ => ApiError(StatusCode::INTERNAL_SERVER_ERROR, None),
+    })?;
+
+  // Only assets linked to the requested item are accessible through it.
+  let asset = sqlx::query!(
+    r#"
+SELECT asset.id, asset.mime, asset.hash, asset.size
+FROM asset
+JOIN item_assets ON item_assets.asset_id = asset.id
+WHERE item_assets.item_id = $1 AND asset.id = $2
+    "#,
+    item_id,
+    asset_id,
+  )
+  .fetch_optional(&mut *connection)
+  .await
+  .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?
+  .ok_or(ApiError(
+    StatusCode::NOT_FOUND,
+    Some("Asset not found".into()),
+  ))?;
+  drop(connection);
+
+  let etag = format!("\"{}\"", asset.hash.as_ref().unwrap_or(&asset.id));
+  let size = asset.size as u64;
+  let mime = match asset.mime.as_str() {
+    "" => "application/octet-stream",
+    mime => mime,
+  };
+
+  let response = Response::builder()
+    .header(ETAG, &etag)
+    .header(CACHE_CONTROL, CACHE_CONTROL_VALUE)
+    .header(ACCEPT_RANGES, "bytes");
+
+  if headers
+    .get(IF_NONE_MATCH)
+    .and_then(|value| value.to_str().ok())
+    .is_some_and(|value| etag_matches(value, &etag))
+  {
+    return response
+      .status(StatusCode::NOT_MODIFIED)
+      .body(Body::empty())
+      .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None));
+  }
+
+  // A stale `If-Range` validator means the client wants the whole asset.
+  let if_range_matches = headers
+    .get(IF_RANGE)
+    .and_then(|value| value.to_str().ok())
+    .map_or(true, |value| value == etag);
+  let range = if if_range_matches {
+    parse_range(
+      headers.get(RANGE).and_then(|value| value.to_str().ok()),
+      size,
+    )
+  } else {
+    RangeRequest::Full
+  };
+
+  let (response, range) = match range {
+    RangeRequest::Full => (
+      response.status(StatusCode::OK),
+      ByteRange {
+        start: 0,
+        end: size.saturating_sub(1),
+      },
+    ),
+    RangeRequest::Partial(range) => (
+      response.status(StatusCode::PARTIAL_CONTENT).header(
+        CONTENT_RANGE,
+        format!("bytes {}-{}/{}", range.start, range.end, size),
+      ),
+      range,
+    ),
+    RangeRequest::Unsatisfiable => {
+      return response
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(CONTENT_RANGE, format!("bytes */{}", size))
+        .body(Body::empty())
+        .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None));
+    }
+  };
+
+  let (body, length) = match size {
+    0 => (Body::empty(), 0),
+    _ => (
+      Body::from_stream(
+        asset_store
+          .stream_range(&asset.id, range.start..range.end + 1)
+          .await
+          .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?
+          .ok_or(ApiError(
+            StatusCode::NOT_FOUND,
+            Some("Asset content not found".into()),
+          ))?,
+      ),
+      range.end + 1 - range.start,
+    ),
+  };
+
+  response
+    .header(CONTENT_TYPE, mime)
+    .header(CONTENT_LENGTH, length)
+    .body(body)
+    .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use axum::body::to_bytes;
+  use sqlx::PgPool;
+  use utils::jwt::Claims;
+
+  use super::*;
+  use crate::{
+    bus::MemoryBus,
+    fixtures::{self, item},
+    metrics::Metrics,
+    store::DatabaseStore,
+  };
+
+  #[test]
+  fn test_parse_range() {
+    let partial = |start, end| RangeRequest::Partial(ByteRange { start, end });
+
+    assert_eq!(parse_range(None, 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("bytes=0-9"), 100), partial(0, 9));
+    assert_eq!(parse_range(Some("bytes=90-"), 100), partial(90, 99));
+    assert_eq!(parse_range(Some("bytes=90-200"), 100), partial(90, 99));
+    assert_eq!(parse_range(Some("bytes=-10"), 100), partial(90, 99));
+    assert_eq!(parse_range(Some("bytes=-200"), 100), partial(0, 99));
+    assert_eq!(
+      parse_range(Some("bytes=100-"), 100),
+      RangeRequest::Unsatisfiable
+    );
+    assert_eq!(
+      parse_range(Some("bytes=-0"), 100),
+      RangeRequest::Unsatisfiable
+    );
+    assert_eq!(
+      parse_range(Some("bytes=0-"), 0),
+      RangeRequest::Unsatisfiable
+    );
+    assert_eq!(parse_range(Some("bytes=9-0"), 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("bytes=0-1,5-6"), 100), RangeRequest::Full);
+    assert_eq!(parse_range(Some("items=0-1"), 100), RangeRequest::Full);
+  }
+
+  #[test]
+  fn test_etag_matches() {
+    assert!(etag_matches("\"abc\"", "\"abc\""));
+    assert!(etag_matches("W/\"abc\", \"def\"", "\"abc\""));
+    assert!(etag_matches("*", "\"abc\""));
+    assert!(!etag_matches("\"def\"", "\"abc\""));
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_get_asset(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(&db_pool, [item(1, 1)]).await;
+    sqlx::query("INSERT INTO asset (id, name, mime, size) VALUES ('a', '', 'text/plain', 5)")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (1, 'a')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    let asset_store = Arc::new(DatabaseStore::new(db_pool.clone()));
+    asset_store.put("a", b"hello".to_vec()).await.unwrap();
+    let state = GlobalState {
+      db_pool: db_pool.clone(),
+      asset_store,
+      bus: Arc::new(MemoryBus::new()),
+      metrics: Arc::new(Metrics::new()),
+      keys: Arc::new(Keys::new(b"secret")),
+    };
+    let sign = |claims: Claims| jwt::sign(&claims, &state.keys.encoding).unwrap();
+    let get_with = |header, value: String| {
+      let mut headers = HeaderMap::new();
+      headers.insert(header, value.parse().unwrap());
+      get_asset(Path((1, "a".to_string())), headers, State(state.clone()))
+    };
+    let get = |token: Option<Claims>| match token {
+      Some(claims) => get_with(AUTHORIZATION, format!("Bearer {}", sign(claims))),
+      None => get_asset(
+        Path((1, "a".to_string())),
+        HeaderMap::new(),
+        State(state.clone()),
+      ),
+    };
+    let status = |result: Result<Response, ApiError>| match result {
+      Ok(response) => response.status(),
+      Err(ApiError(status, _)) => status,
+    };
+    let access = |user: &str| Some(Claims::new(user.into(), TokenUse::Access));
+
+    assert_eq!(status(get(None).await), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+      status(get(Some(Claims::new("alice".into(), TokenUse::Refresh))).await),
+      StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(status(get(access("bob")).await), StatusCode::NOT_FOUND);
+    let response = get(access("alice")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"hello");
+
+    // Browsers send the token as a cookie.
+    let cookie = |user: &str| {
+      format!(
+        "theme=dark; access_token={}",
+        sign(Claims::new(user.into(), TokenUse::Access))
+      )
+    };
+    assert_eq!(
+      status(get_with(COOKIE, cookie("alice")).await),
+      StatusCode::OK
+    );
+    assert_eq!(
+      status(get_with(COOKIE, cookie("bob")).await),
+      StatusCode::NOT_FOUND
+    );
+
+    sqlx::query("UPDATE item SET deleted_at = now() WHERE id = 1")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(status(get(access("alice")).await), StatusCode::NOT_FOUND);
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_commit(db_pool: PgPool) {
+    let store = DatabaseStore::new(db_pool.clone());
+    let insert = |data: &[u8]| {
+      let db_pool = db_pool.clone();
+      let asset = Asset::new(data.to_vec());
+      async move {
+        let mut transaction = db_pool.begin().await.unwrap();
+        let (_, new_assets) = insert(&mut transaction, vec![asset]).await.unwrap();
+        (transaction, new_assets)
+      }
+    };
+
+    let (transaction, new_assets) = insert(b"hello").await;
+    let id = new_assets[0].id.clone();
+    commit(transaction, &store, new_assets).await.unwrap();
+    assert_eq!(store.get(&id).await.unwrap(), Some(b"hello".to_vec()));
+
+    // Content of a transaction that fails to commit is deleted again.
+    sqlx::raw_sql(
+      r#"
+CREATE FUNCTION reject_asset() RETURNS TRIGGER AS $$
+BEGIN
+  RAISE EXCEPTION 'rejected';
+END
+$$ LANGUAGE plpgsql;
+CREATE CONSTRAINT TRIGGER reject_asset AFTER INSERT ON asset
+DEFERRABLE INITIALLY DEFERRED
+FOR EACH ROW EXECUTE FUNCTION reject_asset();
+      "#,
+    )
+    .execute(&db_pool)
+    .await
+    .unwrap();
+    let (transaction, new_assets) = insert(b"world").await;
+    let id = new_assets[0].id.clone();
+    assert!(commit(transaction, &store, new_assets).await.is_err());
+    assert_eq!(store.get(&id).await.unwrap(), None);
+  }
+}
@@ -0,0 +1,170 @@
+//! Event bus carrying the events relayed from the outbox to the consumer of
+//! every instance. [`amqp::AmqpBus`] is backed by RabbitMQ, [`MemoryBus`]
+//! broadcasts in-process, for a single instance or tests to run without a
+//! broker.
+//!
+//! [`amqp::AmqpBus`]: crate::amqp::AmqpBus
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::metrics::Outcome;
+
+/// Events buffered for a lagging [`MemoryBus`] subscriber before it misses
+/// some.
+const MEMORY_CAPACITY: usize = 1024;
+
+/// An outbox event to publish.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+  /// Idempotency key, for consumers to drop duplicates.
+  pub id: String,
+  pub routing_key: String,
+  pub payload: Vec<u8>,
+}
+
+/// An event delivered to a [`Subscriber`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Delivery {
+  pub id: Option<String>,
+  pub routing_key: String,
+  pub payload: Vec<u8>,
+  /// Whether the event was delivered before and requeued.
+  pub redelivered: bool,
+}
+
+#[async_trait]
+pub trait Subscriber: Send + 'static {
+  /// Handles a delivered event, returning how the bus settles it.
+  async fn deliver(&mut self, delivery: Delivery) -> Outcome;
+}
+
+#[async_trait]
+pub trait EventBus: Send + Sync {
+  /// Publishes `events` in order, returning once the bus accepted all of them.
+  async fn publish(&self, events: &[Event]) -> Result<()>;
+
+  /// Delivers every published event to `subscriber` until the bus is lost.
+  /// Errors are returned only while subscribing.
+  async fn subscribe(&self, subscriber: Box<dyn Subscriber>) -> Result<()>;
+
+  /// Fails if events would not be published or delivered.
+  async fn check(&self) -> Result<()>;
+}
+
+/// Bus carrying the events between instances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+  Amqp,
+  /// Only delivers the events of this instance, for deployments running a
+  /// single one.
+  Memory,
+}
+
+/// Broadcasts events to the subscribers of this process.
+pub struct MemoryBus {
+  sender: broadcast::Sender<Event>,
+}
+
+impl MemoryBus {
+  pub fn new() -> Self {
+    let (sender, _) = broadcast::channel(MEMORY_CAPACITY);
+    Self { sender }
+  }
+}
+
+#[async_trait]
+impl EventBus for MemoryBus {
+  async fn publish(&self, events: &[Event]) -> Result<()> {
+    for event in events {
+      // Events published without subscribers are dropped, as a broker does
+      // without bound queues.
+      self.sender.send(event.clone()).ok();
+    }
+    Ok(())
+  }
+
+  async fn subscribe(&self, mut subscriber: Box<dyn Subscriber>) -> Result<()> {
+    let mut receiver = self.sender.subscribe();
+    loop {
+      let event = match receiver.recv().await {
+        Ok(event) => event,
+        Err(RecvError::Lagged(missed)) => {
+          warn!("Subscriber missed {} event(s)", missed);
+          continue;
+        }
+        Err(RecvError::Closed) => return Ok(()),
+      };
+      let mut delivery = Delivery {
+        id: Some(event.id),
+        routing_key: event.routing_key,
+        payload: event.payload,
+        redelivered: false,
+      };
+      // Requeued events are redelivered once, there is no dead letter queue.
+      if subscriber.deliver(delivery.clone()).await == Outcome::Requeue {
+        delivery.redelivered = true;
+        subscriber.deliver(delivery).await;
+      }
+    }
+  }
+
+  async fn check(&self) -> Result<()> {
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::Arc;
+
+  use tokio::sync::mpsc;
+
+  use super::*;
+
+  /// Requeues every event delivered for the first time.
+  struct Retrying(mpsc::UnboundedSender<Delivery>);
+
+  #[async_trait]
+  impl Subscriber for Retrying {
+    async fn deliver(&mut self, delivery: Delivery) -> Outcome {
+      let redelivered = delivery.redelivered;
+      self.0.send(delivery).unwrap();
+      match redelivered {
+        true => Outcome::Ack,
+        false => Outcome::Requeue,
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn test_memory_bus() {
+    let bus = Arc::new(MemoryBus::new());
+    let event = Event {
+      id: "a".into(),
+      routing_key: "item.update".into(),
+      payload: vec![1],
+    };
+    // Nobody is subscribed yet.
+    bus.publish(std::slice::from_ref(&event)).await.unwrap();
+
+    let (sender, mut deliveries) = mpsc::unbounded_channel();
+    let subscribed = bus.clone();
+    tokio::spawn(async move { subscribed.subscribe(Box::new(Retrying(sender))).await });
+    while bus.sender.receiver_count() == 0 {
+      tokio::task::yield_now().await;
+    }
+    bus.publish(&[event]).await.unwrap();
+
+    let first = deliveries.recv().await.unwrap();
+    assert_eq!(first.id.as_deref(), Some("a"));
+    assert!(!first.redelivered);
+    let second = deliveries.recv().await.unwrap();
+    assert_eq!(second.payload, vec![1]);
+    assert!(second.redelivered);
+    // The event published before subscribing is never delivered.
+    assert!(deliveries.try_recv().is_err());
+  }
+}
@@ -3,9 +3,11 @@ use std::{
   sync::{OnceLock, RwLock},
 };
 
+use anyhow::Context;
 use serde::Deserialize;
 use socketioxide::{extract::SocketRef, ParserError};
 use tracing::info;
+use utils::jwt::{self, Keys, TokenUse};
 
 static USERS: OnceLock<RwLock<HashMap<String, String>>> = OnceLock::new();
 pub fn get_users() -> &'static RwLock<HashMap<String, String>> {
@@ -14,27 +16,88 @@ pub fn get_users() -> &'static RwLock<HashMap<String, String>> {
 
 #[derive(Debug, Deserialize)]
 pub struct AuthData {
-  user: Option<String>,
+  /// Access token issued by the user service.
+  token: Option<String>,
+  /// `protobuf` to receive events as binary messages: [`ItemEvent`],
+  /// [`SpaceResponse`] and [`ConnectorResponse`].
+  ///
+  /// [`ItemEvent`]: crate::handlers::item::ItemEvent
+  /// [`SpaceResponse`]: crate::handlers::item::SpaceResponse
+  /// [`ConnectorResponse`]: crate::handlers::item::ConnectorResponse
+  format: Option<String>,
 }
 
-#[derive(Debug)]
-pub enum ConnectError {
-  InvalidUsername,
-  EncodeError(ParserError),
+/// Encoding of the events emitted to a socket. Sockets that do not ask for
+/// protobuf, or ask for an unknown format, fall back to JSON.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Format {
+  #[default]
+  Json,
+  Protobuf,
+}
+
+pub fn format(socket: &SocketRef) -> Format {
+  socket.extensions.get::<Format>().unwrap_or_default()
+}
+
+/// User of the socket, the subject of its access token. Sockets are only set
+/// up once their user is known, an empty name owns nothing.
+pub fn user(socket: &SocketRef) -> String {
+  get_users()
+    .read()
+    .unwrap()
+    .get(&socket.id.to_string())
+    .cloned()
+    .unwrap_or_default()
+}
+
+/// Identifies the user by the access token of the socket. Refresh tokens, or
+/// tokens meant for another audience, are refused.
+fn authenticate(auth: &AuthData, keys: &Keys) -> anyhow::Result<String> {
+  let token = auth.token.as_deref().context("missing token")?;
+  Ok(jwt::verify(token, &keys.decoding, TokenUse::Access)?.sub)
 }
 
 pub fn user_connect(
   socket: &SocketRef,
   auth: Result<AuthData, ParserError>,
-) -> Result<(), ConnectError> {
-  let auth = auth.map_err(ConnectError::EncodeError)?;
-  let mut users = get_users().write().unwrap();
-  if let Some(user) = auth.user {
-    info!("Connecting user: {}", user);
-    users.insert(socket.id.to_string(), user);
-  } else {
-    return Err(ConnectError::InvalidUsername);
+  keys: &Keys,
+) -> anyhow::Result<()> {
+  let auth = auth?;
+  let user = authenticate(&auth, keys)?;
+  info!("Connecting user: {}", user);
+  get_users()
+    .write()
+    .unwrap()
+    .insert(socket.id.to_string(), user);
+  if auth.format.as_deref() == Some("protobuf") {
+    socket.extensions.insert(Format::Protobuf);
   }
-  drop(users);
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use utils::jwt::Claims;
+
+  use super::*;
+
+  #[test]
+  fn test_authenticate() {
+    let keys = Keys::new(b"secret");
+    let auth = |token_use: Option<TokenUse>| AuthData {
+      token: token_use.map(|token_use| {
+        jwt::sign(&Claims::new("alice".into(), token_use), &keys.encoding).unwrap()
+      }),
+      format: None,
+    };
+
+    assert_eq!(
+      authenticate(&auth(Some(TokenUse::Access)), &keys).unwrap(),
+      "alice"
+    );
+    assert!(authenticate(&auth(Some(TokenUse::Refresh)), &keys).is_err());
+    assert!(authenticate(&auth(None), &keys).is_err());
+    assert!(authenticate(&auth(Some(TokenUse::Access)), &Keys::new(b"other")).is_err());
+  }
+}
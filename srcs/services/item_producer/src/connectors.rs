@@ -0,0 +1,326 @@
+//! Connectors link two items, e.g. the nodes of a mind map or a flow.
+
+use prost::Message as ProtoMessage;
+use serde::Deserialize;
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgExecutor, PgPool};
+
+use crate::{
+  clients,
+  handlers::{item::ConnectorResponse, respond, Error},
+  item::{Connector, ANCHORS},
+  outbox, spaces, GlobalState,
+};
+
+fn default_anchor() -> String {
+  ANCHORS[0].to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectorData {
+  pub source_id: i64,
+  pub target_id: i64,
+  #[serde(default = "default_anchor")]
+  pub source_anchor: String,
+  #[serde(default = "default_anchor")]
+  pub target_anchor: String,
+  pub label: Option<String>,
+  pub style: Option<String>,
+}
+
+/// Replaces a connector, including its endpoints.
+#[derive(Debug, Deserialize)]
+pub struct ConnectorUpdate {
+  pub id: i64,
+  #[serde(flatten)]
+  pub data: ConnectorData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectorRef {
+  pub id: i64,
+}
+
+fn encode_connector(connector: &Connector) -> Vec<u8> {
+  ConnectorResponse {
+    id: connector.id,
+    space_id: connector.space_id,
+    source_id: connector.source_id,
+    target_id: connector.target_id,
+    source_anchor: connector.source_anchor.clone(),
+    target_anchor: connector.target_anchor.clone(),
+    label: connector.label.clone(),
+    style: connector.style.clone(),
+  }
+  .encode_to_vec()
+}
+
+/// Checks that the endpoints differ, the anchors and the style.
+pub(crate) fn check(data: &ConnectorData) -> Result<(), Error> {
+  if data.source_id == data.target_id {
+    return Err(Error::InvalidConnector("source and target must differ"));
+  }
+  for anchor in [&data.source_anchor, &data.target_anchor] {
+    if !ANCHORS.contains(&anchor.as_str()) {
+      return Err(Error::InvalidConnector("unknown anchor"));
+    }
+  }
+  if let Some(style) = &data.style {
+    serde_json::from_str::<serde_json::Value>(style)?;
+  }
+  Ok(())
+}
+
+/// Checks the connector and that both endpoints are distinct items in the
+/// same space of `user`, returning the space.
+async fn validate(
+  connection: &mut PgConnection,
+  user: &str,
+  data: &ConnectorData,
+) -> Result<i32, Error> {
+  check(data)?;
+  let mut space_ids = vec![];
+  for id in [data.source_id, data.target_id] {
+    space_ids.push(spaces::authorize_item(&mut *connection, id, user).await?);
+  }
+  if space_ids[0] != space_ids[1] {
+    return Err(Error::InvalidConnector("endpoints are in different spaces"));
+  }
+  Ok(space_ids[0])
+}
+
+/// Connectors with at least one endpoint among `item_ids` and neither in the
+/// trash.
+pub async fn attached(
+  executor: impl PgExecutor<'_>,
+  item_ids: &[i32],
+) -> sqlx::Result<Vec<Connector>> {
+  sqlx::query_as!(
+    Connector,
+    r#"
+SELECT * FROM connector
+WHERE (source_id = ANY($1) OR target_id = ANY($1))
+  AND NOT EXISTS (
+    SELECT FROM item
+    WHERE item.id IN (connector.source_id, connector.target_id) AND item.deleted_at IS NOT NULL
+  )
+ORDER BY id
+    "#,
+    item_ids,
+  )
+  .fetch_all(executor)
+  .await
+}
+
+async fn create_connector(
+  db_pool: &PgPool,
+  user: &str,
+  data: ConnectorData,
+) -> Result<Connector, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let space_id = validate(&mut transaction, user, &data).await?;
+  let connector = insert(&mut transaction, space_id, &data).await?;
+  transaction.commit().await?;
+  Ok(connector)
+}
+
+/// Inserts a connector between items already checked to be in `space_id`.
+pub(crate) async fn insert(
+  connection: &mut PgConnection,
+  space_id: i32,
+  data: &ConnectorData,
+) -> Result<Connector, Error> {
+  let connector = sqlx::query_as!(
+    Connector,
+    r#"
+INSERT INTO connector ( space_id, source_id, target_id, source_anchor, target_anchor, label, style )
+VALUES ( $1, $2, $3, $4, $5, $6, $7 ) RETURNING *
+    "#,
+    space_id,
+    data.source_id as i32,
+    data.target_id as i32,
+    data.source_anchor,
+    data.target_anchor,
+    data.label,
+    data.style,
+  )
+  .fetch_one(&mut *connection)
+  .await?;
+  outbox::enqueue(connection, "connector.create", encode_connector(&connector)).await?;
+  Ok(connector)
+}
+
+async fn update_connector(
+  db_pool: &PgPool,
+  user: &str,
+  update: ConnectorUpdate,
+) -> Result<Connector, Error> {
+  let ConnectorUpdate { id, data } = update;
+  let mut transaction = db_pool.begin().await?;
+  let space_id = validate(&mut transaction, user, &data).await?;
+
+  let connector = sqlx::query_as!(
+    Connector,
+    r#"
+UPDATE connector
+SET source_id = $2, target_id = $3, source_anchor = $4, target_anchor = $5, label = $6, style = $7
+WHERE id = $1 AND space_id = $8 RETURNING *
+    "#,
+    id as i32,
+    data.source_id as i32,
+    data.target_id as i32,
+    data.source_anchor,
+    data.target_anchor,
+    data.label,
+    data.style,
+    space_id,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::ConnectorNotFound(id))?;
+  outbox::enqueue(
+    &mut transaction,
+    "connector.update",
+    encode_connector(&connector),
+  )
+  .await?;
+
+  transaction.commit().await?;
+  Ok(connector)
+}
+
+async fn delete_connector(db_pool: &PgPool, user: &str, id: i64) -> Result<Connector, Error> {
+  let mut transaction = db_pool.begin().await?;
+
+  let connector = sqlx::query_as!(
+    Connector,
+    r#"
+DELETE FROM connector
+WHERE id = $1 AND space_id IN (SELECT id FROM space WHERE owner = $2)
+RETURNING *
+    "#,
+    id as i32,
+    user,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::ConnectorNotFound(id))?;
+  outbox::enqueue(
+    &mut transaction,
+    "connector.delete",
+    encode_connector(&connector),
+  )
+  .await?;
+
+  transaction.commit().await?;
+  Ok(connector)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn create(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ConnectorData>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    create_connector(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn update(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ConnectorUpdate>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    update_connector(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn delete(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ConnectorRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    delete_connector(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item};
+
+  fn data(source_id: i64, target_id: i64) -> ConnectorData {
+    serde_json::from_value(serde_json::json!({
+      "source_id": source_id,
+      "target_id": target_id,
+      "target_anchor": "left",
+      "label": "next",
+    }))
+    .unwrap()
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_connector(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::space(&db_pool, 2, "alice").await;
+    fixtures::items(&db_pool, [item(1, 1), item(2, 1), item(3, 1), item(4, 2)]).await;
+
+    let connector = create_connector(&db_pool, "alice", data(1, 2))
+      .await
+      .unwrap();
+    assert_eq!(connector.source_anchor, "auto");
+    assert_eq!(connector.target_anchor, "left");
+    assert!(matches!(
+      create_connector(&db_pool, "alice", data(1, 1)).await,
+      Err(Error::InvalidConnector(_))
+    ));
+    assert!(matches!(
+      create_connector(&db_pool, "alice", data(1, 4)).await,
+      Err(Error::InvalidConnector(_))
+    ));
+    assert!(matches!(
+      create_connector(&db_pool, "bob", data(1, 2)).await,
+      Err(Error::ItemNotFound(1))
+    ));
+
+    let updated = update_connector(
+      &db_pool,
+      "alice",
+      ConnectorUpdate {
+        id: connector.id,
+        data: data(3, 2),
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(updated.source_id, 3);
+    assert_eq!(attached(&db_pool, &[1]).await.unwrap(), vec![]);
+    assert_eq!(attached(&db_pool, &[2]).await.unwrap(), vec![updated]);
+
+    assert!(matches!(
+      delete_connector(&db_pool, "bob", connector.id).await,
+      Err(Error::ConnectorNotFound(_))
+    ));
+    // Deleting either endpoint removes the connector.
+    sqlx::query("DELETE FROM item WHERE id = 3")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    assert!(attached(&db_pool, &[2]).await.unwrap().is_empty());
+    assert!(matches!(
+      delete_connector(&db_pool, "alice", connector.id).await,
+      Err(Error::ConnectorNotFound(_))
+    ));
+  }
+}
@@ -1,90 +1,302 @@
-use std::sync::Arc;
-
-use amqprs::{
-  channel::{BasicConsumeArguments, Channel, QueueBindArguments, QueueDeclareArguments},
-  consumer::AsyncConsumer,
-  BasicProperties, Deliver,
+use std::{
+  collections::{HashSet, VecDeque},
+  sync::{Arc, Mutex},
+  time::Duration,
 };
-use anyhow::{Ok, Result};
+
 use async_trait::async_trait;
 use bytes::Bytes;
 use prost::Message;
+use serde::Serialize;
 use socketioxide::SocketIo;
-use tokio::sync::Notify;
-use tracing::info;
+use tracing::{error, warn};
+
+use crate::{
+  bus::{Delivery, EventBus, Subscriber},
+  clients::Format,
+  events,
+  handlers::item::{ConnectorResponse, SpaceResponse},
+  metrics::{Metrics, Outcome},
+  spaces::{self, Space},
+};
 
-use crate::{handlers::item::ItemResponse, item::Item};
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Message ids remembered to drop redelivered events.
+const RECENT_IDS: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Failure {
+  /// The message can never be processed.
+  Decode,
+  /// The message may be processed on another attempt.
+  Emit,
+}
+
+impl Failure {
+  fn reason(self) -> &'static str {
+    match self {
+      Failure::Decode => "decode",
+      Failure::Emit => "emit",
+    }
+  }
+}
+
+/// Failed messages are retried once before they are dead-lettered.
+fn outcome(failure: Option<Failure>, redelivered: bool) -> Outcome {
+  match failure {
+    None => Outcome::Ack,
+    Some(Failure::Emit) if !redelivered => Outcome::Requeue,
+    Some(_) => Outcome::DeadLetter,
+  }
+}
+
+/// The most recently seen message ids, oldest first.
+#[derive(Default)]
+struct RecentIds {
+  order: VecDeque<String>,
+  ids: HashSet<String>,
+}
+
+impl RecentIds {
+  fn contains(&self, id: &str) -> bool {
+    self.ids.contains(id)
+  }
+
+  /// Remembers `id`, forgetting the oldest one when full.
+  fn insert(&mut self, id: &str) {
+    if !self.ids.insert(id.to_owned()) {
+      return;
+    }
+    if self.order.len() == RECENT_IDS {
+      if let Some(oldest) = self.order.pop_front() {
+        self.ids.remove(&oldest);
+      }
+    }
+    self.order.push_back(id.to_owned());
+  }
+}
 
 struct ItemConsumer {
   socket: SocketIo,
+  metrics: Arc<Metrics>,
+  /// The outbox delivers at least once, so the same event may arrive again.
+  /// Shared by the consumers of every subscription, since the bus redelivers
+  /// unacknowledged events after a reconnect.
+  recent_ids: Arc<Mutex<RecentIds>>,
 }
 
 impl ItemConsumer {
-  pub fn new(socket: SocketIo) -> Self {
-    Self { socket }
+  /// Emits `event` to the sockets in the room of the space, as protobuf or
+  /// JSON as negotiated by the socket. Each room is emitted to regardless of
+  /// the other, and the event is only retried when both fail, so a retry never
+  /// repeats it to a room that received it.
+  async fn emit<T: Serialize + ?Sized>(
+    &self,
+    space_id: i64,
+    event: &str,
+    protobuf: &Bytes,
+    json: &T,
+  ) -> Result<(), Failure> {
+    let protobuf = self
+      .socket
+      .to(spaces::room(space_id, Format::Protobuf))
+      .emit(event.to_owned(), protobuf)
+      .await;
+    let json = self
+      .socket
+      .to(spaces::room(space_id, Format::Json))
+      .emit(event.to_owned(), json)
+      .await;
+    for e in [&protobuf, &json]
+      .into_iter()
+      .filter_map(|sent| sent.as_ref().err())
+    {
+      warn!("Failed to emit {}: {}", event, e);
+    }
+    match (protobuf, json) {
+      (Err(_), Err(_)) => Err(Failure::Emit),
+      _ => Ok(()),
+    }
+  }
+
+  /// Emits an item event to the sockets of its space, or of each space for a
+  /// batch.
+  async fn forward_items(&self, content: Vec<u8>) -> Result<(), Failure> {
+    let emits = events::split(content).ok_or_else(|| {
+      warn!("Failed to decode item event");
+      Failure::Decode
+    })?;
+    for emit in emits {
+      self
+        .emit(emit.space_id, "item:event", &emit.protobuf, &emit.json)
+        .await?;
+    }
+    Ok(())
+  }
+
+  /// Emits the event to the sockets of its space. Items are emitted on
+  /// `item:event`, connectors and spaces on `connector:<action>` and
+  /// `space:<action>`.
+  async fn forward(&self, routing_key: &str, content: Vec<u8>) -> Result<(), Failure> {
+    let content = Bytes::from(content);
+    match routing_key.split_once('.') {
+      Some(("space", action)) => {
+        let space = SpaceResponse::decode(content.clone()).map_err(|e| {
+          warn!("Failed to decode space event: {}", e);
+          Failure::Decode
+        })?;
+        let json = Space {
+          id: space.id,
+          name: space.name,
+        };
+        let event = format!("space:{}", action);
+        self.emit(space.id, &event, &content, &json).await
+      }
+      Some(("connector", action)) => {
+        let connector = ConnectorResponse::decode(content.clone()).map_err(|e| {
+          warn!("Failed to decode connector event: {}", e);
+          Failure::Decode
+        })?;
+        let space_id = connector.space_id;
+        let json = events::decode_connector(connector);
+        let event = format!("connector:{}", action);
+        self.emit(space_id, &event, &content, &json).await
+      }
+      _ => self.forward_items(content.into()).await,
+    }
   }
 }
 
 #[async_trait]
-impl AsyncConsumer for ItemConsumer {
-  async fn consume(
-    &mut self,
-    _channel: &Channel,
-    _deliver: Deliver,
-    _basic_properties: BasicProperties,
-    content: Vec<u8>,
-  ) {
-    info!("Consuming incoming message: {:?}", content);
-    let item = ItemResponse::decode(Bytes::from(content)).unwrap();
-
-    self
-      .socket
-      .emit(
-        "/",
-        &serde_json::to_string(&Item {
-          id: item.id,
-          x: item.x,
-          y: item.y,
-          w: item.w,
-          h: item.h,
-          schema: item.schema,
-        })
-        .unwrap(),
-      )
+impl Subscriber for ItemConsumer {
+  async fn deliver(&mut self, delivery: Delivery) -> Outcome {
+    if let Some(id) = &delivery.id {
+      if self.recent_ids.lock().unwrap().contains(id) {
+        self.metrics.inc_messages_consumed(Outcome::Duplicate);
+        return Outcome::Duplicate;
+      }
+    }
+
+    let failure = self
+      .forward(&delivery.routing_key, delivery.payload)
       .await
-      .unwrap();
+      .err();
+    if let Some(failure) = failure {
+      self.metrics.inc_consumer_failures(failure.reason());
+    }
+
+    let outcome = outcome(failure, delivery.redelivered);
+    if let (Outcome::Ack, Some(id)) = (outcome, &delivery.id) {
+      self.recent_ids.lock().unwrap().insert(id);
+    }
+    self.metrics.inc_messages_consumed(outcome);
+    outcome
   }
 }
 
-pub async fn background_task(socket: SocketIo, channel: Arc<Channel>) -> Result<()> {
-  info!("Connect AMQP consumer");
-
-  let (queue_name, _, _) = channel
-    .queue_declare(QueueDeclareArguments::default())
-    .await?
-    .unwrap();
-
-  let exchange_name = "amq.topic";
-  let routing_key = "item.update";
-  channel
-    .queue_bind(QueueBindArguments::new(
-      &queue_name,
-      exchange_name,
-      routing_key,
-    ))
-    .await?;
-
-  let args = BasicConsumeArguments::new(&queue_name, "example_basic_pub_sub");
-
-  tokio::spawn(async move {
-    channel
-      .basic_consume(ItemConsumer::new(socket), args)
-      .await
-      .unwrap();
-    let guard = Notify::new();
-    guard.notified().await;
-  });
-  let guard = Notify::new();
-  guard.notified().await;
-  Ok(())
+/// Forwards item updates to the connected sockets, subscribing again with
+/// exponential backoff whenever the bus becomes unavailable.
+pub async fn background_task(socket: SocketIo, bus: Arc<dyn EventBus>, metrics: Arc<Metrics>) {
+  let mut backoff = INITIAL_BACKOFF;
+  let recent_ids = Arc::new(Mutex::new(RecentIds::default()));
+  loop {
+    let consumer = ItemConsumer {
+      socket: socket.clone(),
+      metrics: metrics.clone(),
+      recent_ids: recent_ids.clone(),
+    };
+    match bus.subscribe(Box::new(consumer)).await {
+      Ok(()) => {
+        warn!("Event consumer lost its subscription");
+        backoff = INITIAL_BACKOFF;
+      }
+      Err(e) => error!("Event consumer failed to subscribe: {}", e),
+    }
+
+    tokio::time::sleep(backoff).await;
+    backoff = (backoff * 2).min(MAX_BACKOFF);
+    metrics.inc_consumer_reconnects();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tokio::sync::mpsc;
+
+  use super::*;
+  use crate::bus::Event;
+
+  /// Delivers the same event on every subscription, as a broker redelivers
+  /// the events left unacknowledged when the connection was lost.
+  struct RedeliveringBus {
+    outcomes: mpsc::UnboundedSender<Outcome>,
+  }
+
+  #[async_trait]
+  impl EventBus for RedeliveringBus {
+    async fn publish(&self, _events: &[Event]) -> anyhow::Result<()> {
+      Ok(())
+    }
+
+    async fn subscribe(&self, mut subscriber: Box<dyn Subscriber>) -> anyhow::Result<()> {
+      let space = SpaceResponse {
+        id: 1,
+        name: "Plans".into(),
+      };
+      let outcome = subscriber
+        .deliver(Delivery {
+          id: Some("a".into()),
+          routing_key: "space.created".into(),
+          payload: space.encode_to_vec(),
+          redelivered: true,
+        })
+        .await;
+      self.outcomes.send(outcome).unwrap();
+      Ok(())
+    }
+
+    async fn check(&self) -> anyhow::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_outcome() {
+    assert_eq!(outcome(None, false), Outcome::Ack);
+    assert_eq!(outcome(None, true), Outcome::Ack);
+    assert_eq!(outcome(Some(Failure::Emit), false), Outcome::Requeue);
+    assert_eq!(outcome(Some(Failure::Emit), true), Outcome::DeadLetter);
+    assert_eq!(outcome(Some(Failure::Decode), false), Outcome::DeadLetter);
+  }
+
+  #[tokio::test]
+  async fn test_redelivery_after_reconnect() {
+    let (_, socket) = SocketIo::new_layer();
+    socket.ns("/", || {});
+    let (tx, mut outcomes) = mpsc::unbounded_channel();
+    tokio::spawn(background_task(
+      socket,
+      Arc::new(RedeliveringBus { outcomes: tx }),
+      Arc::new(Metrics::new()),
+    ));
+
+    assert_eq!(outcomes.recv().await, Some(Outcome::Ack));
+    assert_eq!(outcomes.recv().await, Some(Outcome::Duplicate));
+  }
+
+  #[test]
+  fn test_recent_ids() {
+    let mut recent_ids = RecentIds::default();
+    recent_ids.insert("a");
+    recent_ids.insert("a");
+    assert!(recent_ids.contains("a"));
+    for i in 0..RECENT_IDS {
+      recent_ids.insert(&i.to_string());
+    }
+    // The oldest ids are forgotten.
+    assert!(!recent_ids.contains("a"));
+    assert_eq!(recent_ids.order.len(), RECENT_IDS);
+    assert_eq!(recent_ids.ids.len(), RECENT_IDS);
+  }
 }
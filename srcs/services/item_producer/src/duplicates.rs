@@ -0,0 +1,280 @@
+//! Duplication of items. The copies of a selection keep their layout, moved by
+//! an offset, and the items they contain are copied along. Copies share the
+//! assets of their originals: each link counts as a reference to the asset, so
+//! it outlives whichever item is deleted first.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+
+use crate::{
+  clients,
+  events::{self, Kind},
+  handlers::{respond, Error},
+  history::{self, Snapshot},
+  item::Item,
+  search, spaces, GlobalState,
+};
+
+/// Copies `ids`, with the items they contain, moved by `dx` and `dy`.
+#[derive(Debug, Deserialize)]
+pub struct Duplicate {
+  pub ids: Vec<i64>,
+  pub dx: i64,
+  pub dy: i64,
+}
+
+/// The items `ids` and their descendants outside of the trash, bottom first.
+pub(crate) async fn subtree(connection: &mut PgConnection, ids: &[i32]) -> sqlx::Result<Vec<Item>> {
+  sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT id FROM item WHERE id = ANY($1) AND deleted_at IS NULL
+  UNION
+  SELECT item.id FROM item JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.deleted_at IS NULL
+)
+SELECT item.id, item.space_id, item.x, item.y, item.w, item.h, item.schema, item.editor,
+  item.z_index, item.parent_id AS "parent_id: i64", item.version, item.position_version,
+  item.size_version, item.schema_version, item.deleted_at, item.trash_batch
+FROM item JOIN subtree ON item.id = subtree.id
+ORDER BY item.z_index, item.id
+    "#,
+    ids,
+  )
+  .fetch_all(connection)
+  .await
+}
+
+/// Copies an item into `parent_id`. Without a `z_index` the copy is stacked on
+/// top of its new siblings.
+async fn copy(
+  connection: &mut PgConnection,
+  item: &Item,
+  parent_id: Option<i64>,
+  z_index: Option<i32>,
+  (dx, dy): (i64, i64),
+) -> sqlx::Result<Item> {
+  let copy = sqlx::query_as!(
+    Item,
+    r#"
+INSERT INTO item ( space_id, x, y, w, h, schema, editor, z_index, parent_id )
+SELECT space_id, x + $2, y + $3, w, h, schema, editor,
+  COALESCE($4, (
+    SELECT COALESCE(MAX(z_index), 0) + 1 FROM item AS sibling
+    WHERE sibling.space_id = item.space_id AND sibling.parent_id IS NOT DISTINCT FROM $5
+  )),
+  $5
+FROM item WHERE id = $1
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+    "#,
+    item.id as i32,
+    dx as i32,
+    dy as i32,
+    z_index,
+    parent_id.map(|id| id as i32),
+  )
+  .fetch_one(&mut *connection)
+  .await?;
+  sqlx::query!(
+    r#"
+INSERT INTO item_assets ( item_id, asset_id )
+SELECT $1, asset_id FROM item_assets WHERE item_id = $2
+    "#,
+    copy.id as i32,
+    item.id as i32,
+  )
+  .execute(connection)
+  .await?;
+  Ok(copy)
+}
+
+async fn duplicate_items(
+  db_pool: &PgPool,
+  user: &str,
+  data: Duplicate,
+) -> Result<Vec<Item>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  for &id in &data.ids {
+    spaces::authorize_item(&mut transaction, id, user).await?;
+  }
+  let ids = data.ids.iter().map(|&id| id as i32).collect::<Vec<_>>();
+  let mut pending = subtree(&mut transaction, &ids).await?;
+  let copied = pending.iter().map(|item| item.id).collect::<HashSet<_>>();
+
+  // Parents are copied before their children. Copies of the selected items
+  // are stacked on top in their original order, the stacks they contain are
+  // copied as they are.
+  let mut copies = HashMap::<i64, i64>::new();
+  let mut items = Vec::new();
+  while !pending.is_empty() {
+    let (ready, rest) = pending
+      .into_iter()
+      .partition::<Vec<_>, _>(|item| match item.parent_id {
+        Some(parent_id) => !copied.contains(&parent_id) || copies.contains_key(&parent_id),
+        None => true,
+      });
+    for item in ready {
+      let (parent_id, z_index) = match item.parent_id.and_then(|id| copies.get(&id)) {
+        Some(&parent_id) => (Some(parent_id), Some(item.z_index as i32)),
+        None => (item.parent_id, None),
+      };
+      let copy = copy(
+        &mut transaction,
+        &item,
+        parent_id,
+        z_index,
+        (data.dx, data.dy),
+      )
+      .await?;
+      copies.insert(item.id, copy.id);
+      let snapshot = Snapshot::of(&copy);
+      search::index(&mut transaction, copy.id as i32, &snapshot.schema).await?;
+      history::record(
+        &mut transaction,
+        user,
+        "duplicate",
+        &Snapshot::default(),
+        &copy,
+      )
+      .await?;
+      items.push(copy);
+    }
+    pending = rest;
+  }
+  events::publish(&mut transaction, Kind::Created, &items).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn duplicate(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<Duplicate>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    duplicate_items(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item, ItemRow};
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_duplicate(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::space(&db_pool, 2, "bob").await;
+    fixtures::items(
+      &db_pool,
+      [
+        ItemRow {
+          w: 100,
+          h: 100,
+          z_index: 1,
+          schema: Some("[]"),
+          ..item(1, 1)
+        },
+        ItemRow {
+          parent_id: Some(1),
+          x: 10,
+          y: 10,
+          w: 10,
+          h: 10,
+          z_index: 1,
+          schema: Some(r#"[{"type": "image", "mime": "image/png", "name": "", "uuid": "a", "children": [{"text": ""}]}]"#),
+          ..item(2, 1)
+        },
+        ItemRow {
+          parent_id: Some(1),
+          x: 20,
+          y: 20,
+          w: 10,
+          h: 10,
+          z_index: 2,
+          schema: Some("[]"),
+          ..item(3, 1)
+        },
+        ItemRow {
+          x: 200,
+          w: 10,
+          h: 10,
+          z_index: 2,
+          schema: Some("[]"),
+          ..item(4, 1)
+        },
+      ],
+    )
+    .await;
+    sqlx::query("INSERT INTO asset (id, name, mime) VALUES ('a', '', 'image/png')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (2, 'a')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+
+    let copies = duplicate_items(
+      &db_pool,
+      "alice",
+      Duplicate {
+        ids: vec![4, 1],
+        dx: 5,
+        dy: 5,
+      },
+    )
+    .await
+    .unwrap();
+    let layout = copies
+      .iter()
+      .map(|item| (item.x, item.y, item.z_index))
+      .collect::<Vec<_>>();
+    // The frame and the item stay on top of the stack in their order, the
+    // children of the frame are copied into the copy of the frame.
+    assert_eq!(
+      layout,
+      vec![(5, 5, 3), (205, 5, 4), (15, 15, 1), (25, 25, 2)]
+    );
+    assert_eq!(copies[0].parent_id, None);
+    assert_eq!(copies[2].parent_id, Some(copies[0].id));
+    assert_eq!(copies[3].parent_id, Some(copies[0].id));
+
+    // The image is shared rather than copied.
+    assert_eq!(
+      sqlx::query_scalar!("SELECT ref_count FROM asset WHERE id = 'a'")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap(),
+      2
+    );
+    let original = sqlx::query_scalar!("SELECT schema FROM item WHERE id = 2")
+      .fetch_one(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(copies[2].schema, original);
+
+    assert!(matches!(
+      duplicate_items(
+        &db_pool,
+        "bob",
+        Duplicate {
+          ids: vec![1],
+          dx: 0,
+          dy: 0,
+        },
+      )
+      .await,
+      Err(Error::ItemNotFound(1))
+    ));
+  }
+}
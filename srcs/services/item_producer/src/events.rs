@@ -0,0 +1,338 @@
+//! Item events. Every change to items is published as an [`ItemEvent`] that
+//! carries the complete items, with the assets they reference and the
+//! connectors attached to them, so consumers never need to query them.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bytes::Bytes;
+use chrono::DateTime;
+use prost::Message as ProtoMessage;
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use crate::{
+  connectors,
+  handlers::{
+    item::{
+      item_event, ConnectorResponse, ItemBatch, ItemCreated, ItemDeleted, ItemEvent, ItemResponse,
+      ItemUpdated,
+    },
+    Error,
+  },
+  item::{Connector, Item},
+  outbox,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+  Created,
+  Updated,
+  /// Moved to the trash, or purged from it.
+  Deleted,
+}
+
+impl Kind {
+  fn routing_key(self) -> &'static str {
+    match self {
+      Kind::Created => "item.create",
+      Kind::Updated => "item.update",
+      Kind::Deleted => "item.delete",
+    }
+  }
+}
+
+/// An item with the assets it references and the connectors attached to it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ItemState {
+  #[serde(flatten)]
+  pub item: Item,
+  pub asset_ids: Vec<String>,
+  pub connectors: Vec<Connector>,
+}
+
+/// [`ItemEvent`] as emitted to sockets that did not negotiate protobuf.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+  Created { item: ItemState },
+  Updated { item: ItemState },
+  Deleted { item: ItemState },
+  Batch { items: Vec<ItemState> },
+}
+
+/// Loads the assets and connectors of the items.
+async fn states(connection: &mut PgConnection, items: &[Item]) -> Result<Vec<ItemState>, Error> {
+  let ids = items.iter().map(|item| item.id as i32).collect::<Vec<_>>();
+  let mut asset_ids = HashMap::<i64, Vec<String>>::new();
+  for row in sqlx::query!(
+    r#"
+SELECT item_id AS "item_id!", asset_id AS "asset_id!" FROM item_assets
+WHERE item_id = ANY($1)
+ORDER BY asset_id
+    "#,
+    &ids,
+  )
+  .fetch_all(&mut *connection)
+  .await?
+  {
+    asset_ids
+      .entry(i64::from(row.item_id))
+      .or_default()
+      .push(row.asset_id);
+  }
+  let connectors = connectors::attached(&mut *connection, &ids).await?;
+
+  Ok(
+    items
+      .iter()
+      .map(|item| ItemState {
+        item: item.clone(),
+        asset_ids: asset_ids.remove(&item.id).unwrap_or_default(),
+        connectors: connectors
+          .iter()
+          .filter(|connector| item.id == connector.source_id || item.id == connector.target_id)
+          .cloned()
+          .collect(),
+      })
+      .collect(),
+  )
+}
+
+/// Enqueues an event for every item.
+pub async fn publish(
+  connection: &mut PgConnection,
+  kind: Kind,
+  items: &[Item],
+) -> Result<(), Error> {
+  for state in states(&mut *connection, items).await? {
+    let item = Some(encode(&state));
+    let event = match kind {
+      Kind::Created => item_event::Event::Created(ItemCreated { item }),
+      Kind::Updated => item_event::Event::Updated(ItemUpdated { item }),
+      Kind::Deleted => item_event::Event::Deleted(ItemDeleted { item }),
+    };
+    let payload = ItemEvent { event: Some(event) }.encode_to_vec();
+    outbox::enqueue(&mut *connection, kind.routing_key(), payload).await?;
+  }
+  Ok(())
+}
+
+/// Enqueues a single event for items changed together.
+pub async fn publish_batch(connection: &mut PgConnection, items: &[Item]) -> Result<(), Error> {
+  let items = states(&mut *connection, items)
+    .await?
+    .iter()
+    .map(encode)
+    .collect();
+  let event = item_event::Event::Batch(ItemBatch { items });
+  let payload = ItemEvent { event: Some(event) }.encode_to_vec();
+  outbox::enqueue(connection, "item.batch", payload).await?;
+  Ok(())
+}
+
+fn encode_connector(connector: &Connector) -> ConnectorResponse {
+  ConnectorResponse {
+    id: connector.id,
+    space_id: connector.space_id,
+    source_id: connector.source_id,
+    target_id: connector.target_id,
+    source_anchor: connector.source_anchor.clone(),
+    target_anchor: connector.target_anchor.clone(),
+    label: connector.label.clone(),
+    style: connector.style.clone(),
+  }
+}
+
+pub fn decode_connector(connector: ConnectorResponse) -> Connector {
+  Connector {
+    id: connector.id,
+    space_id: connector.space_id,
+    source_id: connector.source_id,
+    target_id: connector.target_id,
+    source_anchor: connector.source_anchor,
+    target_anchor: connector.target_anchor,
+    label: connector.label,
+    style: connector.style,
+  }
+}
+
+fn encode(state: &ItemState) -> ItemResponse {
+  let item = &state.item;
+  ItemResponse {
+    id: item.id,
+    x: item.x,
+    y: item.y,
+    w: item.w,
+    h: item.h,
+    schema: item.schema.clone(),
+    space_id: item.space_id,
+    z_index: item.z_index,
+    parent_id: item.parent_id,
+    version: item.version,
+    position_version: item.position_version,
+    size_version: item.size_version,
+    schema_version: item.schema_version,
+    deleted_at: item.deleted_at.map(|at| at.timestamp()),
+    editor: item.editor.clone(),
+    asset_ids: state.asset_ids.clone(),
+    connectors: state.connectors.iter().map(encode_connector).collect(),
+  }
+}
+
+fn decode(item: ItemResponse) -> ItemState {
+  ItemState {
+    item: Item {
+      id: item.id,
+      space_id: item.space_id,
+      x: item.x,
+      y: item.y,
+      w: item.w,
+      h: item.h,
+      schema: item.schema,
+      editor: item.editor,
+      z_index: item.z_index,
+      parent_id: item.parent_id,
+      version: item.version,
+      position_version: item.position_version,
+      size_version: item.size_version,
+      schema_version: item.schema_version,
+      deleted_at: item
+        .deleted_at
+        .and_then(|at| DateTime::from_timestamp(at, 0)),
+      trash_batch: None,
+    },
+    asset_ids: item.asset_ids,
+    connectors: item.connectors.into_iter().map(decode_connector).collect(),
+  }
+}
+
+/// An event for the sockets of one space, in both encodings.
+#[derive(Debug)]
+pub struct Emit {
+  pub space_id: i64,
+  pub protobuf: Bytes,
+  pub json: Event,
+}
+
+fn emit(space_id: i64, event: item_event::Event, json: Event) -> Emit {
+  Emit {
+    space_id,
+    protobuf: Bytes::from(ItemEvent { event: Some(event) }.encode_to_vec()),
+    json,
+  }
+}
+
+/// Splits an encoded event by the space of its items. Returns `None` if the
+/// event cannot be decoded.
+pub fn split(content: Vec<u8>) -> Option<Vec<Emit>> {
+  let event = ItemEvent::decode(Bytes::from(content)).ok()?.event?;
+  let (space_id, json) = match &event {
+    item_event::Event::Created(ItemCreated { item }) => {
+      let item = decode(item.clone()?);
+      (item.item.space_id, Event::Created { item })
+    }
+    item_event::Event::Updated(ItemUpdated { item }) => {
+      let item = decode(item.clone()?);
+      (item.item.space_id, Event::Updated { item })
+    }
+    item_event::Event::Deleted(ItemDeleted { item }) => {
+      let item = decode(item.clone()?);
+      (item.item.space_id, Event::Deleted { item })
+    }
+    item_event::Event::Batch(ItemBatch { items }) => {
+      let mut spaces = BTreeMap::<i64, Vec<ItemResponse>>::new();
+      for item in items {
+        spaces.entry(item.space_id).or_default().push(item.clone());
+      }
+      return Some(
+        spaces
+          .into_iter()
+          .map(|(space_id, items)| {
+            let json = Event::Batch {
+              items: items.iter().cloned().map(decode).collect(),
+            };
+            emit(
+              space_id,
+              item_event::Event::Batch(ItemBatch { items }),
+              json,
+            )
+          })
+          .collect(),
+      );
+    }
+  };
+  Some(vec![emit(space_id, event, json)])
+}
+
+#[cfg(test)]
+mod tests {
+  use sqlx::PgPool;
+
+  use super::*;
+  use crate::fixtures::{self, item};
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_publish(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::space(&db_pool, 2, "alice").await;
+    fixtures::items(&db_pool, [item(1, 1), item(2, 1), item(3, 2)]).await;
+    sqlx::query("INSERT INTO asset (id, name, mime) VALUES ('a', '', 'image/png')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (1, 'a')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO connector (space_id, source_id, target_id) VALUES (1, 1, 2)")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    let items = sqlx::query_as!(
+      Item,
+      r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item ORDER BY id
+      "#,
+    )
+    .fetch_all(&db_pool)
+    .await
+    .unwrap();
+
+    let mut connection = db_pool.acquire().await.unwrap();
+    publish(&mut connection, Kind::Updated, &items[..1])
+      .await
+      .unwrap();
+    publish_batch(&mut connection, &items).await.unwrap();
+    let payloads = sqlx::query_scalar!("SELECT payload FROM outbox ORDER BY id")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(payloads.len(), 2);
+
+    let emits = split(payloads[0].clone()).unwrap();
+    assert_eq!(emits.len(), 1);
+    let Event::Updated { item } = &emits[0].json else {
+      panic!("expected an update, got {:?}", emits[0].json);
+    };
+    assert_eq!(item.item, items[0]);
+    assert_eq!(item.asset_ids, vec!["a"]);
+    assert_eq!(item.connectors.len(), 1);
+    assert_eq!(emits[0].protobuf, Bytes::from(payloads[0].clone()));
+
+    // Batches are emitted once per space.
+    let emits = split(payloads[1].clone()).unwrap();
+    assert_eq!(
+      emits
+        .iter()
+        .map(|emit| match &emit.json {
+          Event::Batch { items } => (emit.space_id, items.len()),
+          event => panic!("expected a batch, got {:?}", event),
+        })
+        .collect::<Vec<_>>(),
+      vec![(1, 2), (2, 1)]
+    );
+    assert!(split(vec![0xff]).is_none());
+  }
+}
@@ -0,0 +1,88 @@
+//! Rows the database tests start from. Ids are given explicitly and the
+//! sequences are moved past them, so rows created by the code under test do
+//! not collide.
+
+use sqlx::PgPool;
+
+use crate::item::Item;
+
+/// Inserts an unnamed space of `owner`.
+pub async fn space(db_pool: &PgPool, id: i32, owner: &str) {
+  sqlx::query("INSERT INTO space (id, owner, name) VALUES ($1, $2, '')")
+    .bind(id)
+    .bind(owner)
+    .execute(db_pool)
+    .await
+    .unwrap();
+  sqlx::query("SELECT setval('space_id_seq', max(id)) FROM space")
+    .execute(db_pool)
+    .await
+    .unwrap();
+}
+
+/// An item to insert with [`items`].
+pub struct ItemRow {
+  pub id: i32,
+  pub space_id: i32,
+  pub parent_id: Option<i32>,
+  pub x: i32,
+  pub y: i32,
+  pub w: i32,
+  pub h: i32,
+  pub z_index: i32,
+  pub schema: Option<&'static str>,
+  /// Puts the item in the trash.
+  pub trashed: bool,
+}
+
+/// A unit square at the origin, on top of nothing, to adjust with struct
+/// update syntax.
+pub fn item(id: i32, space_id: i32) -> ItemRow {
+  ItemRow {
+    id,
+    space_id,
+    parent_id: None,
+    x: 0,
+    y: 0,
+    w: 1,
+    h: 1,
+    z_index: 0,
+    schema: None,
+    trashed: false,
+  }
+}
+
+/// Inserts the items in order, so parents have to come before their children.
+pub async fn items(db_pool: &PgPool, rows: impl IntoIterator<Item = ItemRow>) -> Vec<Item> {
+  let mut items = vec![];
+  for row in rows {
+    let item = sqlx::query_as!(
+      Item,
+      r#"
+INSERT INTO item ( id, space_id, parent_id, x, y, w, h, z_index, schema, deleted_at, trash_batch )
+VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, CASE WHEN $10 THEN now() END, CASE WHEN $10 THEN $1::INTEGER END )
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+      "#,
+      row.id,
+      row.space_id,
+      row.parent_id,
+      row.x,
+      row.y,
+      row.w,
+      row.h,
+      row.z_index,
+      row.schema,
+      row.trashed,
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap();
+    items.push(item);
+  }
+  sqlx::query("SELECT setval('item_id_seq', max(id)) FROM item")
+    .execute(db_pool)
+    .await
+    .unwrap();
+  items
+}
@@ -0,0 +1,282 @@
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+  time::Duration,
+};
+
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::PgPool;
+use tracing::{error, info, warn};
+use utils::asset_store::AssetStore;
+
+use crate::{handlers::Error, item::Descendant};
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+  /// `item_assets` links whose item schema no longer references the asset.
+  pub stale_links: Vec<(i32, String)>,
+  /// Assets no item or template references, through `item_assets`,
+  /// `template_assets` or a schema.
+  pub unreferenced: Vec<String>,
+  /// Unreferenced assets whose grace period has passed.
+  pub expired: Vec<String>,
+  /// Uploads that have not received a chunk within the grace period.
+  pub expired_uploads: Vec<String>,
+}
+
+/// Finds assets that are no longer referenced by any item or template and
+/// deletes those that have been orphaned for longer than `grace_period`, along
+/// with their content. With `dry_run` the report is produced without changing
+/// anything.
+///
+/// Every run reads and parses the schema of every item, so its cost grows with
+/// the number of items rather than the number of assets; `asset_gc_interval`
+/// should leave room for that on large databases.
+///
+/// References are read without locking, so items may change while the run is
+/// in progress. Before a stale link is removed, the schema of its item is
+/// reread under `FOR UPDATE` and the link is kept if the asset is referenced
+/// again. Assets are only deleted while their `ref_count` is still zero, which
+/// the delete rechecks on the locked row.
+pub async fn collect(
+  db_pool: &PgPool,
+  asset_store: &dyn AssetStore,
+  grace_period: Duration,
+  dry_run: bool,
+) -> Result<Report, Error> {
+  let mut transaction = db_pool.begin().await?;
+
+  // Items whose schema cannot be parsed keep all of their linked assets.
+  let mut schema_refs = HashMap::new();
+  let mut items = sqlx::query!("SELECT id, schema FROM item").fetch(&mut *transaction);
+  while let Some(item) = items.try_next().await? {
+    match schema_asset_refs(item.schema.as_deref()) {
+      Ok(refs) => {
+        schema_refs.insert(item.id, refs);
+      }
+      Err(e) => warn!("Skipping unparsable schema of item {}: {}", item.id, e),
+    }
+  }
+  drop(items);
+
+  let mut report = Report::default();
+  let mut referenced = schema_refs
+    .values()
+    .flatten()
+    .cloned()
+    .collect::<HashSet<_>>();
+  let links = sqlx::query!("SELECT item_id, asset_id FROM item_assets")
+    .fetch_all(&mut *transaction)
+    .await?;
+  for link in links {
+    match schema_refs.get(&link.item_id) {
+      Some(refs) if !refs.contains(&link.asset_id) => {
+        report.stale_links.push((link.item_id, link.asset_id))
+      }
+      _ => {
+        referenced.insert(link.asset_id);
+      }
+    }
+  }
+  // Templates keep the assets of the items they were saved from.
+  referenced.extend(
+    sqlx::query_scalar!("SELECT asset_id FROM template_assets")
+      .fetch_all(&mut *transaction)
+      .await?,
+  );
+  let mut referenced = referenced.into_iter().collect::<Vec<_>>();
+
+  report.unreferenced =
+    sqlx::query_scalar!("SELECT id FROM asset WHERE NOT (id = ANY($1))", &referenced)
+      .fetch_all(&mut *transaction)
+      .await?;
+  report.expired = sqlx::query_scalar!(
+    r#"
+SELECT id FROM asset
+WHERE NOT (id = ANY($1)) AND ref_count <= 0
+  AND orphaned_at < now() - make_interval(secs => $2)
+    "#,
+    &referenced,
+    grace_period.as_secs_f64(),
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  report.expired_uploads = sqlx::query_scalar!(
+    "SELECT id FROM asset_upload WHERE updated_at < now() - make_interval(secs => $1)",
+    grace_period.as_secs_f64(),
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+
+  if dry_run {
+    transaction.rollback().await?;
+    return Ok(report);
+  }
+
+  let mut stale_links = Vec::with_capacity(report.stale_links.len());
+  for (item_id, asset_id) in report.stale_links {
+    // The schema may have changed since it was read; a missing item has no
+    // references and an unparsable schema keeps its links.
+    let schema = sqlx::query_scalar!("SELECT schema FROM item WHERE id = $1 FOR UPDATE", item_id)
+      .fetch_optional(&mut *transaction)
+      .await?;
+    let stale = match schema.map(|schema| schema_asset_refs(schema.as_deref())) {
+      Some(Ok(refs)) => !refs.contains(&asset_id),
+      Some(Err(_)) => false,
+      None => true,
+    };
+    if !stale {
+      report.unreferenced.retain(|id| *id != asset_id);
+      report.expired.retain(|id| *id != asset_id);
+      referenced.push(asset_id);
+      continue;
+    }
+    sqlx::query!(
+      "DELETE FROM item_assets WHERE item_id = $1 AND asset_id = $2",
+      item_id,
+      asset_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+    stale_links.push((item_id, asset_id));
+  }
+  report.stale_links = stale_links;
+  sqlx::query!(
+    "UPDATE asset SET orphaned_at = NULL WHERE id = ANY($1) AND orphaned_at IS NOT NULL",
+    &referenced,
+  )
+  .execute(&mut *transaction)
+  .await?;
+  sqlx::query!(
+    r#"
+UPDATE asset SET orphaned_at = now()
+WHERE NOT (id = ANY($1)) AND ref_count <= 0 AND orphaned_at IS NULL
+    "#,
+    &referenced,
+  )
+  .execute(&mut *transaction)
+  .await?;
+  // Assets linked since they were found expired are kept.
+  report.expired = sqlx::query_scalar!(
+    "DELETE FROM asset WHERE id = ANY($1) AND ref_count <= 0 RETURNING id",
+    &report.expired,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  sqlx::query!(
+    "DELETE FROM asset_upload WHERE id = ANY($1)",
+    &report.expired_uploads,
+  )
+  .execute(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+
+  for id in &report.expired {
+    asset_store.delete(id).await?;
+  }
+  Ok(report)
+}
+
+fn schema_asset_refs(schema: Option<&str>) -> Result<HashSet<String>, serde_json::Error> {
+  let mut refs = HashSet::new();
+  serde_json::from_str::<Vec<Descendant>>(schema.unwrap_or("[]"))?
+    .iter()
+    .for_each(|descendant| descendant.asset_refs(&mut refs));
+  Ok(refs)
+}
+
+pub async fn background_task(
+  db_pool: PgPool,
+  asset_store: Arc<dyn AssetStore>,
+  interval: Duration,
+  grace_period: Duration,
+  dry_run: bool,
+) {
+  let mut interval = tokio::time::interval(interval);
+  loop {
+    interval.tick().await;
+    match collect(&db_pool, asset_store.as_ref(), grace_period, dry_run).await {
+      Ok(report) if dry_run => info!("Asset GC dry run: {:?}", report),
+      Ok(report) => info!(
+        "Asset GC removed {} stale link(s), {} asset(s) and {} upload(s), {} unreferenced",
+        report.stale_links.len(),
+        report.expired.len(),
+        report.expired_uploads.len(),
+        report.unreferenced.len(),
+      ),
+      Err(e) => error!("Asset GC failed: {}", e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixtures::{self, item, ItemRow},
+    store::DatabaseStore,
+  };
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_collect(db_pool: PgPool) {
+    let store = DatabaseStore::new(db_pool.clone());
+    sqlx::query("INSERT INTO asset (id, name, mime, hash) VALUES ('a', '', '', 'a'), ('b', '', '', 'b'), ('c', '', '', 'c')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    for id in ["a", "b", "c"] {
+      store.put(id, id.into()).await.unwrap();
+    }
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(
+      &db_pool,
+      [ItemRow {
+        schema: Some(r#"[{"type":"image","mime":"","name":"","uuid":"a"}]"#),
+        ..item(1, 1)
+      }],
+    )
+    .await;
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (1, 'a'), (1, 'b')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO asset_upload (id, owner, hash, size, updated_at) VALUES ('u', 'alice', 'u', 1, now() - interval '1 hour')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+
+    let mut report = collect(&db_pool, &store, Duration::ZERO, true)
+      .await
+      .unwrap();
+    report.unreferenced.sort();
+    assert_eq!(report.stale_links, vec![(1, "b".to_string())]);
+    assert_eq!(report.unreferenced, vec!["b", "c"]);
+    assert!(report.expired.is_empty());
+    assert_eq!(report.expired_uploads, vec!["u"]);
+
+    // The dry run leaves the link in place, the first real run only marks.
+    let report = collect(&db_pool, &store, Duration::ZERO, false)
+      .await
+      .unwrap();
+    assert_eq!(report.stale_links.len(), 1);
+    assert!(report.expired.is_empty());
+    assert_eq!(report.expired_uploads.len(), 1);
+
+    let mut report = collect(&db_pool, &store, Duration::ZERO, false)
+      .await
+      .unwrap();
+    report.expired.sort();
+    assert!(report.stale_links.is_empty());
+    assert_eq!(report.expired, vec!["b", "c"]);
+
+    let remaining: Vec<String> = sqlx::query_scalar("SELECT id FROM asset")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(remaining, vec!["a"]);
+    assert_eq!(store.get("b").await.unwrap(), None);
+    assert!(store.get("a").await.unwrap().is_some());
+  }
+}
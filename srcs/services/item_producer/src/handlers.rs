@@ -1,21 +1,84 @@
-// use amqprs::{channel::BasicPublishArguments, BasicProperties};
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
-use socketioxide::extract::{AckSender, Data, State};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+use tracing::error;
+use utils::{
+  archive, asset,
+  asset_store::{self, AssetStore},
+};
 
+#[allow(dead_code)]
 pub mod item {
   include!(concat!(env!("OUT_DIR"), "/item.rs"));
 }
-// use item::ItemResponse;
-// use prost::Message as ProtoMessage;
-use uuid::Uuid;
 
 use crate::{
-  item::{Asset, Descendant, Item},
-  GlobalState,
+  assets, clients, connectors,
+  events::{self, Kind},
+  history::{self, Snapshot},
+  item::{Asset, Connector, Descendant, Item, EDITORS},
+  search, spaces, GlobalState,
 };
 
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Sqlx(#[from] sqlx::Error),
+  #[error(transparent)]
+  Serde(#[from] serde_json::Error),
+  #[error(transparent)]
+  Asset(#[from] asset::Error),
+  #[error(transparent)]
+  AssetStore(#[from] asset_store::Error),
+  #[error(transparent)]
+  Archive(#[from] archive::Error),
+  #[error("item {0} not found")]
+  ItemNotFound(i64),
+  #[error("unknown asset {0}")]
+  UnknownAsset(String),
+  #[error("upload {0} not found")]
+  UploadNotFound(String),
+  #[error("invalid upload: {0}")]
+  InvalidUpload(&'static str),
+  #[error("upload incomplete, {offset} of {size} bytes received")]
+  UploadIncomplete { offset: i64, size: i64 },
+  #[error("upload checksum mismatch")]
+  ChecksumMismatch,
+  #[error("invalid group: {0}")]
+  InvalidGroup(&'static str),
+  #[error("connector {0} not found")]
+  ConnectorNotFound(i64),
+  #[error("invalid connector: {0}")]
+  InvalidConnector(&'static str),
+  #[error("space {0} not found")]
+  SpaceNotFound(i64),
+  #[error("invalid space: {0}")]
+  InvalidSpace(&'static str),
+  #[error("item {} was changed concurrently", .0.id)]
+  Conflict(Box<Item>),
+  #[error("revision {0} not found")]
+  RevisionNotFound(i64),
+  #[error("unknown editor {0}")]
+  UnknownEditor(String),
+  #[error("template {0} not found")]
+  TemplateNotFound(i64),
+  #[error("invalid template: {0}")]
+  InvalidTemplate(&'static str),
+  #[error("too many patches, at most {0} per batch")]
+  TooManyPatches(usize),
+}
+
+fn default_editor() -> String {
+  EDITORS[0].to_string()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ItemWithAssets {
+  pub space_id: i64,
+  #[serde(default = "default_editor")]
+  pub editor: String,
   pub x: i64,
   pub y: i64,
   pub w: i64,
@@ -24,7 +87,78 @@ pub struct ItemWithAssets {
   pub assets: Vec<Vec<u8>>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Replaces an item. Images may only reference stored assets, e.g. ones
+/// created through the upload protocol.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ItemUpdate {
+  pub id: i64,
+  /// Version of the item the update is based on.
+  pub version: i64,
+  pub x: i64,
+  pub y: i64,
+  pub w: i64,
+  pub h: i64,
+  pub schema: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+  pub error: String,
+  /// The item as stored, when a write was rejected as stale.
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub current: Option<Item>,
+}
+
+impl ErrorResponse {
+  pub fn new(error: impl ToString) -> Self {
+    Self {
+      error: error.to_string(),
+      current: None,
+    }
+  }
+}
+
+impl From<Error> for ErrorResponse {
+  fn from(error: Error) -> Self {
+    let message = error.to_string();
+    match error {
+      Error::Conflict(item) => Self {
+        error: message,
+        current: Some(*item),
+      },
+      _ => Self::new(message),
+    }
+  }
+}
+
+/// Acknowledges with `result`, or with an [`ErrorResponse`] on failure.
+pub fn respond<T: Serialize>(ack: AckSender, result: Result<T, Error>) {
+  match result {
+    Ok(value) => ack.send(&value).ok(),
+    Err(e) => {
+      error!("{}", e);
+      ack.send(&ErrorResponse::from(e)).ok()
+    }
+  };
+}
+
+/// Builds the error rejecting a stale write from the item as stored.
+pub(crate) async fn conflict(connection: &mut PgConnection, id: i64) -> Result<Error, Error> {
+  let item = sqlx::query_as!(
+    Item,
+    r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item WHERE id = $1
+    "#,
+    id as i32,
+  )
+  .fetch_one(connection)
+  .await?;
+  Ok(Error::Conflict(Box::new(item)))
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct BoundingBox {
   xmin: i32,
   ymin: i32,
@@ -32,169 +166,511 @@ pub struct BoundingBox {
   ymax: i32,
 }
 
+/// Items returned by `item:get_nearby` unless the query asks for fewer.
+pub const NEARBY_PAGE_SIZE: i64 = 500;
+
+/// Most items returned by `item:get_nearby` at once.
+pub const MAX_NEARBY_PAGE_SIZE: i64 = 2000;
+
+/// Where a page of nearby items ended, to continue after with the same
+/// viewport.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct NearbyCursor {
+  distance: f64,
+  id: i64,
+}
+
+/// A page of the items of a space, nearest to the centre of the viewport
+/// first.
+#[derive(Debug, Deserialize)]
+pub struct NearbyQuery {
+  pub space_id: i64,
+  /// Visible area, the origin is the centre when missing.
+  #[serde(default)]
+  pub viewport: Option<BoundingBox>,
+  /// Cursor of the previous page.
+  #[serde(default)]
+  pub after: Option<NearbyCursor>,
+  #[serde(default)]
+  pub limit: Option<i64>,
+  /// Leaves out the schema of the items, e.g. to lay out a huge board before
+  /// loading the content.
+  #[serde(default)]
+  pub geometry_only: bool,
+}
+
+/// Items in view and the connectors attached to any of them.
+#[derive(Debug, Serialize)]
+pub struct Nearby {
+  pub items: Vec<Item>,
+  pub connectors: Vec<Connector>,
+  /// Cursor of the next page, unless this is the last one.
+  pub next: Option<NearbyCursor>,
+}
+
+impl BoundingBox {
+  /// Twice the centre, which keeps it integral.
+  fn doubled_centre(&self) -> (f64, f64) {
+    (
+      self.xmin as f64 + self.xmax as f64,
+      self.ymin as f64 + self.ymax as f64,
+    )
+  }
+}
+
+/// Squared distance between the centres of the item and of the viewport,
+/// quadrupled, computed as the nearby query does so a cursor matches exactly.
+fn distance(item: &Item, (cx, cy): (f64, f64)) -> f64 {
+  let dx = 2.0 * item.x as f64 + item.w as f64 - cx;
+  let dy = 2.0 * item.y as f64 + item.h as f64 - cy;
+  dx * dx + dy * dy
+}
+
 #[tracing::instrument(skip_all)]
 pub async fn get_nearby(
+  socket: SocketRef,
   ack: AckSender,
+  Data(query): Data<NearbyQuery>,
   State(GlobalState {
-    db_pool,
-    shared_amqp_channel: _,
+    db_pool, metrics, ..
   }): State<GlobalState>,
 ) {
-  let records: Vec<Item> = sqlx::query_as!(Item, "SELECT * FROM item;")
-    .fetch_all(&db_pool)
-    .await
-    .unwrap();
+  let user = clients::user(&socket);
+  respond(
+    ack,
+    metrics
+      .time_query("nearby", nearby(&db_pool, &user, query))
+      .await,
+  );
+}
+
+async fn nearby(db_pool: &PgPool, user: &str, query: NearbyQuery) -> Result<Nearby, Error> {
+  let mut connection = db_pool.acquire().await?;
+  spaces::authorize(&mut connection, query.space_id, user).await?;
+  drop(connection);
 
-  ack.send(&records).ok();
+  let centre = query.viewport.unwrap_or_default().doubled_centre();
+  let limit = query
+    .limit
+    .unwrap_or(NEARBY_PAGE_SIZE)
+    .clamp(1, MAX_NEARBY_PAGE_SIZE);
+  // One more item than the page tells whether another page follows.
+  let mut items = sqlx::query_as!(
+    Item,
+    r#"
+SELECT id, space_id, x, y, w, h, CASE WHEN $7 THEN NULL ELSE schema END AS schema, editor,
+  z_index, parent_id AS "parent_id: i64", version, position_version, size_version,
+  schema_version, deleted_at, trash_batch
+FROM item,
+  LATERAL (SELECT 2 * x::FLOAT8 + w - $2 AS dx, 2 * y::FLOAT8 + h - $3 AS dy) AS offsets
+WHERE space_id = $1 AND deleted_at IS NULL
+  AND ($4::FLOAT8 IS NULL OR (dx * dx + dy * dy, id) > ($4, $5))
+ORDER BY dx * dx + dy * dy, id
+LIMIT $6
+    "#,
+    query.space_id as i32,
+    centre.0,
+    centre.1,
+    query.after.map(|cursor| cursor.distance),
+    query.after.map_or(0, |cursor| cursor.id as i32),
+    limit + 1,
+    query.geometry_only,
+  )
+  .fetch_all(db_pool)
+  .await?;
+  let next = match items.len() as i64 > limit {
+    true => {
+      items.truncate(limit as usize);
+      items.last().map(|item| NearbyCursor {
+        distance: distance(item, centre),
+        id: item.id,
+      })
+    }
+    false => None,
+  };
+
+  let ids = items.iter().map(|item| item.id as i32).collect::<Vec<_>>();
+  let connectors = connectors::attached(db_pool, &ids).await?;
+  Ok(Nearby {
+    items,
+    connectors,
+    next,
+  })
+}
+
+/// Inspects uploaded assets off the async runtime and checks them against the
+/// image elements referencing them.
+async fn prepare_assets(
+  data: Vec<Vec<u8>>,
+  descendants: &[Descendant],
+) -> Result<Vec<Asset>, asset::Error> {
+  let assets = tokio::task::spawn_blocking(move || {
+    data
+      .into_iter()
+      .map(|data| {
+        let mut asset = Asset::new(data);
+        asset.inspect()?;
+        Ok(asset)
+      })
+      .collect::<Result<Vec<Asset>, asset::Error>>()
+  })
+  .await
+  .unwrap()?;
+  for descendant in descendants {
+    descendant.check_refs(&assets)?;
+  }
+  Ok(assets)
+}
+
+/// Links the item to every asset its schema references and drops links to
+/// assets it no longer references. References must name stored assets.
+pub(crate) async fn link_assets(
+  connection: &mut PgConnection,
+  item_id: i32,
+  descendants: &[Descendant],
+) -> Result<(), Error> {
+  let mut refs = HashSet::new();
+  for descendant in descendants {
+    descendant.asset_refs(&mut refs);
+  }
+  let refs = refs.into_iter().collect::<Vec<_>>();
+
+  let stored = sqlx::query_scalar!("SELECT id FROM asset WHERE id = ANY($1)", &refs)
+    .fetch_all(&mut *connection)
+    .await?;
+  if let Some(unknown) = refs.iter().find(|id| !stored.contains(id)) {
+    return Err(Error::UnknownAsset(unknown.clone()));
+  }
+
+  sqlx::query!(
+    "DELETE FROM item_assets WHERE item_id = $1 AND NOT (asset_id = ANY($2))",
+    item_id,
+    &refs,
+  )
+  .execute(&mut *connection)
+  .await?;
+  sqlx::query!(
+    r#"
+INSERT INTO item_assets ( item_id, asset_id )
+SELECT $1, unnest($2::VARCHAR[])
+ON CONFLICT DO NOTHING
+    "#,
+    item_id,
+    &refs,
+  )
+  .execute(&mut *connection)
+  .await?;
+  Ok(())
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn create(
+  socket: SocketRef,
   ack: AckSender,
   Data(data): Data<ItemWithAssets>,
   State(GlobalState {
     db_pool,
-    shared_amqp_channel: _,
+    asset_store,
+    ..
   }): State<GlobalState>,
 ) {
-  let mut assets = data.assets;
-
-  let mut transaction = db_pool.begin().await.unwrap();
-
-  let decendants = serde_json::from_str::<Vec<Descendant>>(&data.schema).unwrap();
-  let mut assets = assets
-    .iter_mut()
-    .map(|asset| Asset {
-      id: Uuid::new_v4().to_string(),
-      name: "".to_string(),
-      mime: "".to_string(),
-      data: Some(asset.to_vec()),
-    })
-    .collect::<Vec<Asset>>();
-  let mut output_descendants: Vec<Descendant> = vec![];
-  let mut prepared_assets: Vec<Asset> = vec![];
-
-  for descendant in decendants {
-    let (d, a) = descendant.process_refs(&mut assets);
-    output_descendants.push(d);
-    for a in a {
-      prepared_assets.push(a.clone());
-    }
+  let user = clients::user(&socket);
+  respond(
+    ack,
+    create_item(&db_pool, asset_store.as_ref(), &user, data).await,
+  );
+}
+
+async fn create_item(
+  db_pool: &PgPool,
+  asset_store: &dyn AssetStore,
+  user: &str,
+  data: ItemWithAssets,
+) -> Result<Item, Error> {
+  if !EDITORS.contains(&data.editor.as_str()) {
+    return Err(Error::UnknownEditor(data.editor));
   }
+  let decendants = serde_json::from_str::<Vec<Descendant>>(&data.schema)?;
+  let mut assets = prepare_assets(data.assets, &decendants).await?;
+  let mut output_descendants = decendants
+    .into_iter()
+    .map(|descendant| descendant.process_refs(&mut assets).0)
+    .collect::<Vec<Descendant>>();
 
-  for asset in &prepared_assets {
-    sqlx::query!(
-      r#"
-INSERT INTO asset ( id, name, mime, data )
-VALUES ( $1, $2, $3, $4 )
-      "#,
-      asset.id,
-      asset.name,
-      asset.mime,
-      asset.data,
-    )
-    .execute(&mut *transaction)
-    .await
-    .unwrap();
+  let mut transaction = db_pool.begin().await?;
+  spaces::authorize(&mut transaction, data.space_id, user).await?;
+
+  // Identical content resolves to the already stored asset.
+  let (existing_ids, new_assets) = assets::insert(&mut transaction, assets).await?;
+  for descendant in &mut output_descendants {
+    descendant.replace_refs(&existing_ids);
   }
 
-  let schema = serde_json::to_string::<Vec<Descendant>>(&output_descendants).unwrap();
+  let schema = serde_json::to_string::<Vec<Descendant>>(&output_descendants)?;
 
+  // New items are stacked on top of the other top-level items.
   let item = sqlx::query_as!(
     Item,
     r#"
-INSERT INTO item ( x, y, w, h, schema )
-VALUES ( $1, $2, $3, $4, $5 ) RETURNING *
+INSERT INTO item ( space_id, x, y, w, h, schema, editor, z_index )
+SELECT $1, $2, $3, $4, $5, $6, $7, COALESCE(MAX(z_index), 0) + 1
+FROM item WHERE space_id = $1 AND parent_id IS NULL
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
       "#,
+    data.space_id as i32,
     data.x as i32,
     data.y as i32,
     data.w as i32,
     data.h as i32,
     schema,
+    data.editor,
   )
   .fetch_one(&mut *transaction)
-  .await
-  .unwrap();
-
-  for asset in prepared_assets {
-    sqlx::query!(
-      r#"
-  INSERT INTO item_assets ( item_id, asset_id )
-  VALUES ( $1, $2 )
-        "#,
-      item.id as i32,
-      asset.id,
+  .await?;
+
+  link_assets(&mut transaction, item.id as i32, &output_descendants).await?;
+  search::index(&mut transaction, item.id as i32, &output_descendants).await?;
+  history::record(
+    &mut transaction,
+    user,
+    "create",
+    &Snapshot::default(),
+    &item,
+  )
+  .await?;
+  events::publish(&mut transaction, Kind::Created, std::slice::from_ref(&item)).await?;
+
+  assets::commit(transaction, asset_store, new_assets).await?;
+  Ok(item)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn update(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemUpdate>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    update_item(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+async fn update_item(db_pool: &PgPool, user: &str, data: ItemUpdate) -> Result<Item, Error> {
+  let descendants = serde_json::from_str::<Vec<Descendant>>(&data.schema)?;
+  // Content cannot be sent inline, so index references are always missing.
+  for descendant in &descendants {
+    descendant.check_refs(&[])?;
+  }
+
+  let mut transaction = db_pool.begin().await?;
+  spaces::authorize_item(&mut transaction, data.id, user).await?;
+  let before = history::snapshot(&mut transaction, data.id).await?;
+
+  // Every field is replaced, so any change since the base version conflicts.
+  let item = sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item
+SET x = $2, y = $3, w = $4, h = $5, schema = $6, version = version + 1,
+  position_version = version + 1, size_version = version + 1, schema_version = version + 1
+WHERE id = $1 AND GREATEST(position_version, size_version, schema_version) <= $7
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+    "#,
+    data.id as i32,
+    data.x as i32,
+    data.y as i32,
+    data.w as i32,
+    data.h as i32,
+    data.schema,
+    data.version as i32,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?;
+  let Some(item) = item else {
+    return Err(conflict(&mut transaction, data.id).await?);
+  };
+
+  link_assets(&mut transaction, item.id as i32, &descendants).await?;
+  search::index(&mut transaction, item.id as i32, &descendants).await?;
+  history::record(&mut transaction, user, "update", &before, &item).await?;
+  events::publish(&mut transaction, Kind::Updated, std::slice::from_ref(&item)).await?;
+
+  transaction.commit().await?;
+  Ok(item)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    fixtures::{self, item, ItemRow},
+    store::DatabaseStore,
+  };
+
+  fn schema(uuid: &str) -> String {
+    format!(r#"[{{"type":"image","mime":"image/png","name":"","uuid":"{uuid}"}}]"#)
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_update_links_assets(db_pool: PgPool) {
+    sqlx::query("INSERT INTO asset (id, name, mime, hash) VALUES ('a', '', 'image/png', 'a'), ('b', '', 'image/png', 'b')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    fixtures::space(&db_pool, 1, "alice").await;
+    let item = create_item(
+      &db_pool,
+      &DatabaseStore::new(db_pool.clone()),
+      "alice",
+      ItemWithAssets {
+        space_id: 1,
+        x: 0,
+        y: 0,
+        w: 1,
+        h: 1,
+        editor: "rich".into(),
+        schema: schema("a"),
+        assets: vec![],
+      },
     )
-    .execute(&mut *transaction)
     .await
     .unwrap();
+    let update = |uuid: &str| ItemUpdate {
+      id: item.id,
+      version: item.version,
+      x: 1,
+      y: 1,
+      w: 1,
+      h: 1,
+      schema: schema(uuid),
+    };
+
+    assert!(matches!(
+      update_item(&db_pool, "bob", update("b")).await,
+      Err(Error::ItemNotFound(_))
+    ));
+    assert!(matches!(
+      update_item(&db_pool, "alice", update("missing")).await,
+      Err(Error::UnknownAsset(id)) if id == "missing"
+    ));
+    assert!(matches!(
+      update_item(&db_pool, "alice", update("0")).await,
+      Err(Error::Asset(asset::Error::Missing(0)))
+    ));
+
+    let updated = update_item(&db_pool, "alice", update("b")).await.unwrap();
+    assert_eq!((updated.x, updated.y), (1, 1));
+    assert_eq!(updated.version, item.version + 1);
+    // Replaying the update based on the old version is stale.
+    assert!(matches!(
+      update_item(&db_pool, "alice", update("b")).await,
+      Err(Error::Conflict(current)) if *current == updated
+    ));
+    let links: Vec<String> =
+      sqlx::query_scalar("SELECT asset_id FROM item_assets WHERE item_id = $1")
+        .bind(item.id as i32)
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(links, vec!["b"]);
+
+    // Failed updates roll back their events along with the change.
+    let events: Vec<String> = sqlx::query_scalar("SELECT routing_key FROM outbox ORDER BY id")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(events, vec!["item.create", "item.update"]);
   }
 
-  transaction.commit().await.unwrap();
-
-  ack.send(&item).ok();
-}
-
-// pub async fn update_outer(
-//   Data(data): Data<ItemWithAssets>,
-//   State(GlobalState {
-//     db_pool: _,
-//     shared_amqp_channel,
-//   }): State<GlobalState>,
-// ) {
-//   let exchange_name = "amq.topic";
-//   let routing_key = "item.update";
-//   let args = BasicPublishArguments::new(exchange_name, routing_key);
-//   shared_amqp_channel
-//     .basic_publish(
-//       BasicProperties::default(),
-//       ItemResponse::encode_to_vec(&ItemResponse {
-//         id: data.id,
-//         x: data.x,
-//         y: data.y,
-//         w: data.w,
-//         h: data.h,
-//         name: data.name,
-//         schema: data.schema,
-//       }),
-//       args,
-//     )
-//     .await
-//     .unwrap();
-// }
-
-// pub async fn update_inner(
-//   Data(data): Data<ItemWithAssets>,
-//   State(GlobalState {
-//     db_pool: _,
-//     shared_amqp_channel,
-//   }): State<GlobalState>,
-// ) {
-//   let exchange_name = "amq.topic";
-//   let routing_key = "item.update";
-//   let args = BasicPublishArguments::new(exchange_name, routing_key);
-//   // broadcast_item_updates(data)
-//   shared_amqp_channel
-//     .basic_publish(
-//       BasicProperties::default(),
-//       ItemResponse::encode_to_vec(&ItemResponse {
-//         id: data.id,
-//         x: data.x,
-//         y: data.y,
-//         w: data.w,
-//         h: data.h,
-//         name: data.name,
-//         schema: data.schema,
-//       }),
-//       args,
-//     )
-//     .await
-//     .unwrap();
-// }
-
-// async fn broadcast_item_updates(item, ) {
-//   channel
-//     .basic_publish(BasicProperties::default(), data.into_bytes(), args)
-//     .await
-//     .unwrap();
-// }
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_nearby_pages(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    // Items at increasing distances from (100, 100), two at the same one.
+    let at = |id, x, y| ItemRow {
+      x,
+      y,
+      w: 10,
+      h: 10,
+      schema: Some("[]"),
+      ..item(id, 1)
+    };
+    fixtures::items(
+      &db_pool,
+      [
+        at(1, 500, 100),
+        at(2, 95, 95),
+        at(3, 195, 95),
+        at(4, 95, 195),
+        ItemRow {
+          trashed: true,
+          ..at(5, 100, 100)
+        },
+        at(6, -1000, -1000),
+      ],
+    )
+    .await;
+    sqlx::query(
+      "INSERT INTO connector (space_id, source_id, target_id, source_anchor, target_anchor) VALUES (1, 2, 6, 'auto', 'auto')",
+    )
+    .execute(&db_pool)
+    .await
+    .unwrap();
+
+    let page = |after, geometry_only| {
+      nearby(
+        &db_pool,
+        "alice",
+        NearbyQuery {
+          space_id: 1,
+          viewport: Some(BoundingBox {
+            xmin: 0,
+            ymin: 0,
+            xmax: 200,
+            ymax: 200,
+          }),
+          after,
+          limit: Some(2),
+          geometry_only,
+        },
+      )
+    };
+    let mut ids = vec![];
+    let mut after = None;
+    loop {
+      let nearby = page(after, false).await.unwrap();
+      assert!(nearby.items.iter().all(|item| item.schema.is_some()));
+      ids.push(nearby.items.iter().map(|item| item.id).collect::<Vec<_>>());
+      if ids.len() == 1 {
+        assert_eq!(nearby.connectors.len(), 1);
+      }
+      match nearby.next {
+        Some(next) => after = Some(next),
+        None => break,
+      }
+    }
+    // The trashed item is left out, ties are broken by id.
+    assert_eq!(ids, vec![vec![2, 3], vec![4, 1], vec![6]]);
+
+    let geometry = page(None, true).await.unwrap();
+    assert!(geometry.items.iter().all(|item| item.schema.is_none()));
+    assert!(matches!(
+      nearby(
+        &db_pool,
+        "bob",
+        NearbyQuery {
+          space_id: 1,
+          viewport: None,
+          after: None,
+          limit: None,
+          geometry_only: false,
+        },
+      )
+      .await,
+      Err(Error::SpaceNotFound(1))
+    ));
+  }
+}
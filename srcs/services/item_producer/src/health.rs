@@ -0,0 +1,37 @@
+//! Liveness and readiness of the service, checking the database, the asset
+//! store and the event bus. Over AMQP, both the consumer of item events and
+//! the publisher the outbox relay sends them with must be connected.
+
+use axum::extract::State;
+use utils::health::{self, Check, Report};
+
+use crate::GlobalState;
+
+/// Asset looked up to check that the asset store answers. It does not exist.
+const PROBE_ASSET: &str = "healthz";
+
+async fn checks(state: &GlobalState) -> [(&'static str, Check); 3] {
+  let (postgres, asset_store, event_bus) = tokio::join!(
+    health::check(async {
+      sqlx::query("SELECT 1")
+        .execute(&state.db_pool)
+        .await
+        .map(|_| ())
+    }),
+    health::check(async { state.asset_store.get(PROBE_ASSET).await.map(|_| ()) }),
+    health::check(state.bus.check()),
+  );
+  [
+    ("postgres", postgres),
+    ("asset_store", asset_store),
+    ("event_bus", event_bus),
+  ]
+}
+
+pub async fn healthz(State(state): State<GlobalState>) -> Report {
+  Report::liveness(checks(&state).await)
+}
+
+pub async fn readyz(State(state): State<GlobalState>) -> Report {
+  Report::readiness(checks(&state).await)
+}
@@ -0,0 +1,463 @@
+//! Append-only audit trail of item changes. Every write records who made it
+//! and a field-level diff, which is enough to rebuild any earlier revision of
+//! an item and revert to it.
+
+use serde::{Deserialize, Serialize};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+
+use crate::{
+  clients,
+  events::{self, Kind},
+  handlers::{conflict, link_assets, respond, Error},
+  item::{Descendant, Item},
+  layers::ItemRef,
+  search, spaces, GlobalState,
+};
+
+/// Fields of an item tracked by the history.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+  pub x: i64,
+  pub y: i64,
+  pub w: i64,
+  pub h: i64,
+  pub schema: Vec<Descendant>,
+  pub deleted_at: Option<i64>,
+}
+
+impl Snapshot {
+  /// Schemas that cannot be parsed count as empty.
+  pub fn of(item: &Item) -> Self {
+    Self {
+      x: item.x,
+      y: item.y,
+      w: item.w,
+      h: item.h,
+      schema: item
+        .schema
+        .as_deref()
+        .and_then(|schema| serde_json::from_str(schema).ok())
+        .unwrap_or_default(),
+      deleted_at: item.deleted_at.map(|at| at.timestamp()),
+    }
+  }
+
+  pub fn apply(&mut self, diff: &Diff) {
+    for (field, change) in [
+      (&mut self.x, diff.x),
+      (&mut self.y, diff.y),
+      (&mut self.w, diff.w),
+      (&mut self.h, diff.h),
+    ] {
+      if let Some((_, to)) = change {
+        *field = to;
+      }
+    }
+    for change in &diff.schema {
+      match &change.to {
+        Some(block) if change.index < self.schema.len() => {
+          self.schema[change.index] = block.clone()
+        }
+        Some(block) => self.schema.push(block.clone()),
+        None => self.schema.truncate(change.index),
+      }
+    }
+    if let Some((_, to)) = diff.deleted_at {
+      self.deleted_at = to;
+    }
+  }
+}
+
+/// Replacement of the top-level block at `index`. Blocks are only added or
+/// removed at the end.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockChange {
+  pub index: usize,
+  pub from: Option<Descendant>,
+  pub to: Option<Descendant>,
+}
+
+/// Changed fields as `[from, to]`, and the changed blocks of the schema.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diff {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub x: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub y: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub w: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub h: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub schema: Vec<BlockChange>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub deleted_at: Option<(Option<i64>, Option<i64>)>,
+}
+
+fn change<T: PartialEq + Clone>(from: &T, to: &T) -> Option<(T, T)> {
+  (from != to).then(|| (from.clone(), to.clone()))
+}
+
+impl Diff {
+  pub fn between(from: &Snapshot, to: &Snapshot) -> Self {
+    let blocks = from.schema.len().max(to.schema.len());
+    Self {
+      x: change(&from.x, &to.x),
+      y: change(&from.y, &to.y),
+      w: change(&from.w, &to.w),
+      h: change(&from.h, &to.h),
+      schema: (0..blocks)
+        .filter_map(|index| {
+          let (from, to) = (from.schema.get(index), to.schema.get(index));
+          (from != to).then(|| BlockChange {
+            index,
+            from: from.cloned(),
+            to: to.cloned(),
+          })
+        })
+        .collect(),
+      deleted_at: change(&from.deleted_at, &to.deleted_at),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revision {
+  pub id: i64,
+  pub item_id: i64,
+  pub actor: String,
+  /// `create`, `update`, `patch`, `move`, `delete`, `restore`, `purge`,
+  /// `revert`, `duplicate`, `import` or `template`.
+  pub action: String,
+  pub changes: Diff,
+  /// Unix time of the change.
+  pub created_at: i64,
+}
+
+/// Reverts the item to the state after revision `id`.
+#[derive(Debug, Deserialize)]
+pub struct Revert {
+  pub item_id: i64,
+  pub id: i64,
+  /// Version of the item the revert is based on. Any change since rejects it.
+  pub version: i64,
+}
+
+/// Tracked fields of a stored item, e.g. before it is changed.
+pub async fn snapshot(connection: &mut PgConnection, id: i64) -> Result<Snapshot, Error> {
+  let item = sqlx::query_as!(
+    Item,
+    r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item WHERE id = $1
+    "#,
+    id as i32,
+  )
+  .fetch_optional(connection)
+  .await?
+  .ok_or(Error::ItemNotFound(id))?;
+  Ok(Snapshot::of(&item))
+}
+
+/// Appends the change from `before` to the stored `after` to the history.
+pub async fn record(
+  connection: &mut PgConnection,
+  actor: &str,
+  action: &str,
+  before: &Snapshot,
+  after: &Item,
+) -> Result<(), Error> {
+  let changes = serde_json::to_string(&Diff::between(before, &Snapshot::of(after)))?;
+  sqlx::query!(
+    "INSERT INTO item_history ( item_id, actor, action, changes ) VALUES ( $1, $2, $3, $4 )",
+    after.id as i32,
+    actor,
+    action,
+    changes,
+  )
+  .execute(connection)
+  .await?;
+  Ok(())
+}
+
+/// Revisions of an item owned by `user`, oldest first, including items in the
+/// trash.
+async fn list_history(db_pool: &PgPool, user: &str, item_id: i64) -> Result<Vec<Revision>, Error> {
+  let mut connection = db_pool.acquire().await?;
+  sqlx::query_scalar!(
+    r#"
+SELECT item.id FROM item
+JOIN space ON space.id = item.space_id
+WHERE item.id = $1 AND space.owner = $2
+    "#,
+    item_id as i32,
+    user,
+  )
+  .fetch_optional(&mut *connection)
+  .await?
+  .ok_or(Error::ItemNotFound(item_id))?;
+
+  let rows = sqlx::query!(
+    "SELECT * FROM item_history WHERE item_id = $1 ORDER BY id",
+    item_id as i32,
+  )
+  .fetch_all(&mut *connection)
+  .await?;
+  rows
+    .into_iter()
+    .map(|row| {
+      Ok(Revision {
+        id: row.id,
+        item_id: row.item_id.into(),
+        actor: row.actor,
+        action: row.action,
+        changes: serde_json::from_str(&row.changes)?,
+        created_at: row.created_at.timestamp(),
+      })
+    })
+    .collect()
+}
+
+/// Replaces the position, size and content of an item by their state after an
+/// earlier revision, as a new revision. The revert touches every field, so it
+/// conflicts with any change made after its base version.
+async fn revert_item(db_pool: &PgPool, user: &str, revert: Revert) -> Result<Item, Error> {
+  let mut transaction = db_pool.begin().await?;
+  spaces::authorize_item(&mut transaction, revert.item_id, user).await?;
+
+  let changes = sqlx::query_scalar!(
+    "SELECT changes FROM item_history WHERE item_id = $1 AND id <= $2 ORDER BY id",
+    revert.item_id as i32,
+    revert.id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  if changes.is_empty() {
+    return Err(Error::RevisionNotFound(revert.id));
+  }
+  let mut target = Snapshot::default();
+  for changes in changes {
+    target.apply(&serde_json::from_str(&changes)?);
+  }
+
+  let before = snapshot(&mut transaction, revert.item_id).await?;
+  let schema = serde_json::to_string(&target.schema)?;
+  let Some(item) = sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item
+SET x = $2, y = $3, w = $4, h = $5, schema = $6, version = version + 1,
+  position_version = version + 1, size_version = version + 1, schema_version = version + 1
+WHERE id = $1 AND GREATEST(position_version, size_version, schema_version) <= $7
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+    "#,
+    revert.item_id as i32,
+    target.x as i32,
+    target.y as i32,
+    target.w as i32,
+    target.h as i32,
+    schema,
+    revert.version as i32,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  else {
+    return Err(conflict(&mut transaction, revert.item_id).await?);
+  };
+
+  link_assets(&mut transaction, item.id as i32, &target.schema).await?;
+  search::index(&mut transaction, item.id as i32, &target.schema).await?;
+  record(&mut transaction, user, "revert", &before, &item).await?;
+  events::publish(&mut transaction, Kind::Updated, std::slice::from_ref(&item)).await?;
+
+  transaction.commit().await?;
+  Ok(item)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    list_history(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn revert(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<Revert>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    revert_item(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item, ItemRow};
+
+  fn blocks(texts: &[&str]) -> Vec<Descendant> {
+    texts
+      .iter()
+      .map(|text| {
+        serde_json::from_value(serde_json::json!({
+          "type": "paragraph",
+          "children": [{ "text": text }],
+        }))
+        .unwrap()
+      })
+      .collect()
+  }
+
+  #[test]
+  fn test_diff() {
+    let a = Snapshot {
+      x: 1,
+      schema: blocks(&["a", "b", "c"]),
+      ..Default::default()
+    };
+    let b = Snapshot {
+      x: 2,
+      w: 3,
+      schema: blocks(&["a", "B"]),
+      ..Default::default()
+    };
+
+    let diff = Diff::between(&a, &b);
+    assert_eq!((diff.x, diff.y, diff.w), (Some((1, 2)), None, Some((0, 3))));
+    assert_eq!(
+      diff
+        .schema
+        .iter()
+        .map(|change| change.index)
+        .collect::<Vec<_>>(),
+      vec![1, 2]
+    );
+    let mut rebuilt = a.clone();
+    rebuilt.apply(&diff);
+    assert_eq!(rebuilt, b);
+    rebuilt.apply(&Diff::between(&b, &a));
+    assert_eq!(rebuilt, a);
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_history(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    let created = fixtures::items(
+      &db_pool,
+      [ItemRow {
+        schema: Some("[]"),
+        ..item(1, 1)
+      }],
+    )
+    .await
+    .remove(0);
+    let mut connection = db_pool.acquire().await.unwrap();
+    record(
+      &mut connection,
+      "alice",
+      "create",
+      &Snapshot::default(),
+      &created,
+    )
+    .await
+    .unwrap();
+    let schema = serde_json::to_string(&blocks(&["hello"])).unwrap();
+    let edited = sqlx::query_as!(
+      Item,
+      r#"
+UPDATE item SET x = 5, schema = $2 WHERE id = $1
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+      "#,
+      created.id as i32,
+      schema,
+    )
+    .fetch_one(&mut *connection)
+    .await
+    .unwrap();
+    record(
+      &mut connection,
+      "bob",
+      "patch",
+      &Snapshot::of(&created),
+      &edited,
+    )
+    .await
+    .unwrap();
+    drop(connection);
+
+    let history = list_history(&db_pool, "alice", created.id).await.unwrap();
+    assert_eq!(
+      history
+        .iter()
+        .map(|revision| (revision.actor.as_str(), revision.action.as_str()))
+        .collect::<Vec<_>>(),
+      vec![("alice", "create"), ("bob", "patch")]
+    );
+    assert_eq!(history[1].changes.x, Some((0, 5)));
+    assert_eq!(history[1].changes.schema.len(), 1);
+    assert!(matches!(
+      list_history(&db_pool, "bob", created.id).await,
+      Err(Error::ItemNotFound(_))
+    ));
+
+    let reverted = revert_item(
+      &db_pool,
+      "alice",
+      Revert {
+        item_id: created.id,
+        id: history[0].id,
+        version: edited.version,
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!((reverted.x, reverted.schema.as_deref()), (0, Some("[]")));
+    assert_eq!(reverted.version, edited.version + 1);
+    let history = list_history(&db_pool, "alice", created.id).await.unwrap();
+    assert_eq!(history[2].action, "revert");
+    assert_eq!(history[2].changes.x, Some((5, 0)));
+
+    // A revert based on the version before the last one would discard it.
+    match revert_item(
+      &db_pool,
+      "alice",
+      Revert {
+        item_id: created.id,
+        id: history[1].id,
+        version: edited.version,
+      },
+    )
+    .await
+    {
+      Err(Error::Conflict(current)) => assert_eq!(current.version, reverted.version),
+      result => panic!("expected a conflict, got {:?}", result),
+    }
+    assert_eq!(
+      list_history(&db_pool, "alice", created.id)
+        .await
+        .unwrap()
+        .len(),
+      3
+    );
+
+    // Revisions cannot be rewritten.
+    assert!(sqlx::query("DELETE FROM item_history")
+      .execute(&db_pool)
+      .await
+      .is_err());
+  }
+}
@@ -1,13 +1,93 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use utils::{archive, asset};
+use uuid::Uuid;
 
 #[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
 pub struct Item {
   pub id: i64,
+  pub space_id: i64,
   pub x: i64,
   pub y: i64,
   pub w: i64,
   pub h: i64,
   pub schema: Option<String>,
+  /// One of [`EDITORS`].
+  pub editor: String,
+  /// Stacking order among items with the same parent.
+  pub z_index: i64,
+  /// Frame containing the item.
+  pub parent_id: Option<i64>,
+  /// Number of changes to the item, the base of the next write.
+  pub version: i64,
+  /// Version that last changed `x` and `y`.
+  #[serde(skip)]
+  pub position_version: i64,
+  /// Version that last changed `w` and `h`.
+  #[serde(skip)]
+  pub size_version: i64,
+  /// Version that last changed the content.
+  #[serde(skip)]
+  pub schema_version: i64,
+  /// When the item was moved to the trash, as unix time for clients.
+  #[serde(default, with = "chrono::serde::ts_seconds_option")]
+  pub deleted_at: Option<DateTime<Utc>>,
+  /// Id of the item whose deletion trashed the item, shared by the items
+  /// trashed with it.
+  #[serde(skip)]
+  pub trash_batch: Option<i64>,
+}
+
+/// Editors an item can be shown in.
+pub const EDITORS: [&str; 2] = ["rich", "markdown"];
+
+/// Sides of an item a connector can be attached to. `auto` picks the side
+/// facing the other item.
+pub const ANCHORS: [&str; 5] = ["auto", "top", "right", "bottom", "left"];
+
+#[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
+pub struct Connector {
+  pub id: i64,
+  pub space_id: i64,
+  pub source_id: i64,
+  pub target_id: i64,
+  pub source_anchor: String,
+  pub target_anchor: String,
+  pub label: Option<String>,
+  /// Stroke, arrowheads and the like, as JSON interpreted by the client.
+  pub style: Option<String>,
+}
+
+impl From<Item> for archive::Item {
+  fn from(item: Item) -> Self {
+    Self {
+      id: item.id,
+      x: item.x,
+      y: item.y,
+      w: item.w,
+      h: item.h,
+      editor: item.editor,
+      schema: item.schema,
+      z_index: item.z_index,
+      parent_id: item.parent_id,
+    }
+  }
+}
+
+impl From<Connector> for archive::Connector {
+  fn from(connector: Connector) -> Self {
+    Self {
+      source_id: connector.source_id,
+      target_id: connector.target_id,
+      source_anchor: connector.source_anchor,
+      target_anchor: connector.target_anchor,
+      label: connector.label,
+      style: connector.style,
+    }
+  }
 }
 
 #[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
@@ -15,9 +95,43 @@ pub struct Asset {
   pub id: String,
   pub name: String,
   pub mime: String,
+  pub hash: Option<String>,
+  pub width: Option<i32>,
+  pub height: Option<i32>,
+  pub thumbnail: Option<Vec<u8>>,
+  pub size: i64,
+  /// Content, which lives in the asset store rather than next to the metadata.
   pub data: Option<Vec<u8>>,
 }
 
+impl Asset {
+  /// Creates an asset with a fresh id, keyed by the SHA-256 of its content so
+  /// identical uploads can be deduplicated.
+  pub fn new(data: Vec<u8>) -> Self {
+    Self {
+      id: Uuid::new_v4().to_string(),
+      name: "".to_string(),
+      mime: "".to_string(),
+      hash: Some(format!("{:x}", Sha256::digest(&data))),
+      width: None,
+      height: None,
+      thumbnail: None,
+      size: data.len() as i64,
+      data: Some(data),
+    }
+  }
+
+  /// Fills in the type, dimensions and thumbnail detected from the content.
+  pub fn inspect(&mut self) -> Result<(), asset::Error> {
+    let metadata = asset::inspect(self.data.as_deref().unwrap_or_default())?;
+    self.mime = metadata.mime;
+    self.width = Some(metadata.width as i32);
+    self.height = Some(metadata.height as i32);
+    self.thumbnail = Some(metadata.thumbnail);
+    Ok(())
+  }
+}
+
 #[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
 pub struct EmptyText {
   pub text: String,
@@ -64,9 +178,9 @@ pub struct HeadingTwoElement {
 
 #[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
 pub struct ImageElement {
-  mime: String,
-  name: String,
-  uuid: String,
+  pub mime: String,
+  pub name: String,
+  pub uuid: String,
 }
 
 #[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
@@ -120,6 +234,38 @@ pub enum Element {
   CodeLine(CodeLineElement),
 }
 
+impl Element {
+  fn children(&self) -> Option<&Vec<Descendant>> {
+    match self {
+      Element::BlockQuote(element) => Some(&element.children),
+      Element::BulletedList(element) => Some(&element.children),
+      Element::CheckListItem(element) => Some(&element.children),
+      Element::Heading(element) => Some(&element.children),
+      Element::HeadingTwo(element) => Some(&element.children),
+      Element::Link(element) => Some(&element.children),
+      Element::Button(element) => Some(&element.children),
+      Element::ListItem(element) => Some(&element.children),
+      Element::Paragraph(element) => Some(&element.children),
+      Element::Image(_) | Element::CodeBlock(_) | Element::CodeLine(_) => None,
+    }
+  }
+
+  fn children_mut(&mut self) -> Option<&mut Vec<Descendant>> {
+    match self {
+      Element::BlockQuote(element) => Some(&mut element.children),
+      Element::BulletedList(element) => Some(&mut element.children),
+      Element::CheckListItem(element) => Some(&mut element.children),
+      Element::Heading(element) => Some(&mut element.children),
+      Element::HeadingTwo(element) => Some(&mut element.children),
+      Element::Link(element) => Some(&mut element.children),
+      Element::Button(element) => Some(&mut element.children),
+      Element::ListItem(element) => Some(&mut element.children),
+      Element::Paragraph(element) => Some(&mut element.children),
+      Element::Image(_) | Element::CodeBlock(_) | Element::CodeLine(_) => None,
+    }
+  }
+}
+
 #[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
 #[serde(untagged)]
 pub enum Descendant {
@@ -128,58 +274,232 @@ pub enum Descendant {
 }
 
 impl Descendant {
-  pub fn process_refs(self, assets: &mut Vec<Asset>) -> (Self, &Vec<Asset>) {
-    (
-      match self {
-        Descendant::Element(element) => match element {
-          Element::BlockQuote(mut element) => {
-            element.children = element
-              .children
-              .iter()
-              .map(|descendant| descendant.clone().process_refs(assets).0)
-              .collect::<Vec<Descendant>>();
-            Descendant::Element(Element::BlockQuote(element))
-          }
-          Element::Button(mut element) => {
-            element.children = element
-              .children
-              .iter()
-              .map(|descendant| descendant.clone().process_refs(assets).0)
-              .collect::<Vec<Descendant>>();
-            Descendant::Element(Element::Button(element))
-          }
-          Element::Paragraph(mut element) => {
-            element.children = element
-              .children
-              .iter()
-              .map(|descendant| descendant.clone().process_refs(assets).0)
-              .collect::<Vec<Descendant>>();
-            Descendant::Element(Element::Paragraph(element))
-          }
-          Element::Image(mut image) => {
-            if let Ok(content) = image.uuid.parse::<usize>() {
-              assert!(assets.len() >= content, "Exceeded asset count");
-              assets[content].name = image.clone().name;
-              assets[content].mime = image.clone().mime;
-              image.uuid = assets[content].clone().id;
-            }
-            Descendant::Element(Element::Image(image))
+  /// Rewrites `ImageElement.uuid` references found in `ids` to their mapped
+  /// asset id.
+  pub fn replace_refs(&mut self, ids: &HashMap<String, String>) {
+    if let Descendant::Element(element) = self {
+      if let Element::Image(image) = element {
+        if let Some(id) = ids.get(&image.uuid) {
+          image.uuid = id.clone();
+        }
+      } else if let Some(children) = element.children_mut() {
+        for child in children {
+          child.replace_refs(ids);
+        }
+      }
+    }
+  }
+
+  /// Appends the plain text of this subtree to `text`, ending every block and
+  /// code line with a line break.
+  pub fn plain_text(&self, text: &mut String) {
+    let element = match self {
+      Descendant::Text(leaf) => return text.push_str(&leaf.text),
+      Descendant::Element(element) => element,
+    };
+    match element {
+      Element::CodeBlock(CodeBlockElement { children, .. })
+      | Element::CodeLine(CodeLineElement { children }) => {
+        for line in children {
+          text.push_str(&line.text);
+          text.push('\n');
+        }
+      }
+      _ => {
+        for child in element.children().into_iter().flatten() {
+          child.plain_text(text);
+        }
+        // Links and buttons are inline.
+        let inline = matches!(element, Element::Link(_) | Element::Button(_));
+        if !inline && !text.is_empty() && !text.ends_with('\n') {
+          text.push('\n');
+        }
+      }
+    }
+  }
+
+  /// Collects the asset ids referenced by `ImageElement.uuid` in this subtree.
+  pub fn asset_refs(&self, refs: &mut HashSet<String>) {
+    if let Descendant::Element(element) = self {
+      if let Element::Image(image) = element {
+        refs.insert(image.uuid.clone());
+      } else if let Some(children) = element.children() {
+        for child in children {
+          child.asset_refs(refs);
+        }
+      }
+    }
+  }
+
+  /// Checks images that reference an uploaded asset by index against the type
+  /// detected from its content.
+  pub fn check_refs(&self, assets: &[Asset]) -> Result<(), asset::Error> {
+    if let Descendant::Element(element) = self {
+      if let Element::Image(image) = element {
+        if let Ok(content) = image.uuid.parse::<usize>() {
+          let asset = assets.get(content).ok_or(asset::Error::Missing(content))?;
+          asset::check_declared(&image.mime, &asset.mime)?;
+        }
+      } else if let Some(children) = element.children() {
+        for child in children {
+          child.check_refs(assets)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  pub fn process_refs(mut self, assets: &mut Vec<Asset>) -> (Self, &Vec<Asset>) {
+    if let Descendant::Element(element) = &mut self {
+      if let Element::Image(image) = element {
+        if let Ok(content) = image.uuid.parse::<usize>() {
+          assert!(assets.len() > content, "Exceeded asset count");
+          assets[content].name = image.name.clone();
+          // A type detected from the content takes precedence.
+          if assets[content].mime.is_empty() {
+            assets[content].mime = image.mime.clone();
           }
-          other => Descendant::Element(other),
-        },
-        other => other,
-      },
-      assets,
-    )
+          image.uuid = assets[content].id.clone();
+        }
+      } else if let Some(children) = element.children_mut() {
+        for child in children.iter_mut() {
+          *child = child.clone().process_refs(assets).0;
+        }
+      }
+    }
+    (self, assets)
   }
 }
 
 #[cfg(test)]
 mod tests {
-  use uuid::Uuid;
-
   use super::*;
 
+  #[test]
+  fn test_asset_hash() {
+    let a = Asset::new(vec![1, 2, 3]);
+    let b = Asset::new(vec![1, 2, 3]);
+    let c = Asset::new(vec![3, 2, 1]);
+
+    assert_ne!(a.id, b.id);
+    assert_eq!(a.hash, b.hash);
+    assert_ne!(a.hash, c.hash);
+    assert_eq!(
+      a.hash.unwrap(),
+      "039058c6f2c0cb492c533b0a4d14ef77cc0f78abccced5287d84a1a2011cfb81"
+    );
+  }
+
+  #[test]
+  fn test_plain_text() {
+    let descendants = serde_json::from_str::<Vec<Descendant>>(
+      r#"
+    [
+      {
+        "type": "heading",
+        "children": [{ "text": "Plans" }]
+      },
+      {
+        "type": "paragraph",
+        "children": [
+          { "text": "See " },
+          { "type": "link", "url": "https://example.com", "children": [{ "text": "the docs", "bold": true }] },
+          { "text": " first." }
+        ]
+      },
+      { "type": "image", "mime": "image/png", "name": "diagram", "uuid": "a" },
+      {
+        "type": "code_block",
+        "language": "rust",
+        "children": [{ "text": "fn main() {}" }, { "text": "" }]
+      }
+    ]
+    "#,
+    )
+    .unwrap();
+
+    let mut text = String::new();
+    for descendant in &descendants {
+      descendant.plain_text(&mut text);
+    }
+    assert_eq!(text, "Plans\nSee the docs first.\nfn main() {}\n\n");
+  }
+
+  #[test]
+  fn test_replace_refs() {
+    let mut descendant = serde_json::from_str::<Descendant>(
+      r#"
+    {
+      "type": "bulleted_list",
+      "children": [
+        {
+          "type": "list_item",
+          "children": [
+            {
+              "type": "image",
+              "mime": "image/png",
+              "name": "image",
+              "uuid": "a"
+            }
+          ]
+        },
+        {
+          "type": "image",
+          "mime": "image/png",
+          "name": "image",
+          "uuid": "b"
+        }
+      ]
+    }
+    "#,
+    )
+    .unwrap();
+    let ids = HashMap::from([("a".to_string(), "c".to_string())]);
+
+    descendant.replace_refs(&ids);
+    let mut refs = HashSet::new();
+    descendant.asset_refs(&mut refs);
+    assert_eq!(refs, HashSet::from(["c".to_string(), "b".to_string()]));
+
+    let output = serde_json::to_string(&descendant).unwrap();
+    assert!(output.contains(r#""uuid":"c""#));
+    assert!(output.contains(r#""uuid":"b""#));
+    assert!(!output.contains(r#""uuid":"a""#));
+  }
+
+  #[test]
+  fn test_check_refs() {
+    let descendant = serde_json::from_str::<Descendant>(
+      r#"
+    {
+      "type": "paragraph",
+      "children": [
+        {
+          "type": "image",
+          "mime": "image/png",
+          "name": "image",
+          "uuid": "0"
+        }
+      ]
+    }
+    "#,
+    )
+    .unwrap();
+    let mut asset = Asset::new(vec![]);
+
+    asset.mime = "image/png".to_string();
+    assert!(descendant.check_refs(&[asset.clone()]).is_ok());
+    assert!(matches!(
+      descendant.check_refs(&[]),
+      Err(asset::Error::Missing(0))
+    ));
+    asset.mime = "image/gif".to_string();
+    assert!(matches!(
+      descendant.check_refs(&[asset]),
+      Err(asset::Error::Mismatch { .. })
+    ));
+  }
+
   #[test]
   fn test() {
     let schema = r#"
@@ -232,13 +552,18 @@ mod tests {
       ]
     }
     "#;
-    let item_schema = serde_json::from_str::<Descendant>(&schema).unwrap();
-    let mut assets = vec![None, None, None, None]
+    let item_schema = serde_json::from_str::<Descendant>(schema).unwrap();
+    let mut assets = [None, None, None, None]
       .iter_mut()
       .map(|asset| Asset {
         id: Uuid::new_v4().to_string(),
         name: String::from("image"),
         mime: "".to_string(),
+        hash: None,
+        width: None,
+        height: None,
+        thumbnail: None,
+        size: 0,
         data: asset.take(),
       })
       .collect::<Vec<Asset>>();
@@ -307,7 +632,7 @@ mod tests {
         prepared_assets.get(3).unwrap().id,
         prepared_assets.get(1).unwrap().id,
         prepared_assets.get(2).unwrap().id,
-        prepared_assets.get(0).unwrap().id,
+        prepared_assets.first().unwrap().id,
       )
       .as_str(),
     )
@@ -0,0 +1,474 @@
+//! Stacking order and grouping of items. Items with the same parent are
+//! siblings, stacked by `z_index` with ties broken by id. A frame renders below
+//! its children and moving it moves them along.
+
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+
+use crate::{
+  clients,
+  events::{self, Kind},
+  handlers::{respond, Error},
+  history::{self, Snapshot},
+  item::Item,
+  spaces, GlobalState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ItemRef {
+  pub id: i64,
+}
+
+/// Moves `ids` into the frame `parent_id`, on top of its other children.
+#[derive(Debug, Deserialize)]
+pub struct Group {
+  pub parent_id: i64,
+  pub ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+  Forward,
+  Back,
+}
+
+/// Ids of the children of `parent_id` in the space outside the trash, bottom
+/// first. The rows stay locked so concurrent changes to the same stack are
+/// serialized.
+async fn children(
+  connection: &mut PgConnection,
+  space_id: i32,
+  parent_id: Option<i32>,
+) -> sqlx::Result<Vec<i32>> {
+  sqlx::query_scalar!(
+    r#"
+SELECT id FROM item
+WHERE space_id = $1 AND parent_id IS NOT DISTINCT FROM $2 AND deleted_at IS NULL
+ORDER BY z_index, id
+FOR UPDATE
+    "#,
+    space_id,
+    parent_id,
+  )
+  .fetch_all(connection)
+  .await
+}
+
+/// Numbers the stack `ids` bottom to top, returning the items that changed.
+async fn restack(connection: &mut PgConnection, ids: &[i32]) -> sqlx::Result<Vec<Item>> {
+  sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item SET z_index = stack.z_index, version = item.version + 1
+FROM unnest($1::INTEGER[]) WITH ORDINALITY AS stack ( id, z_index )
+WHERE item.id = stack.id AND item.z_index <> stack.z_index
+RETURNING item.id, item.space_id, item.x, item.y, item.w, item.h, item.schema, item.editor,
+  item.z_index, item.parent_id AS "parent_id: i64", item.version, item.position_version,
+  item.size_version, item.schema_version, item.deleted_at, item.trash_batch
+    "#,
+    ids,
+  )
+  .fetch_all(connection)
+  .await
+}
+
+/// Returns the space and parent of an item owned by `user`.
+async fn locate(
+  connection: &mut PgConnection,
+  id: i64,
+  user: &str,
+) -> Result<(i32, Option<i32>), Error> {
+  let space_id = spaces::authorize_item(&mut *connection, id, user).await?;
+  let parent_id = sqlx::query_scalar!("SELECT parent_id FROM item WHERE id = $1", id as i32)
+    .fetch_one(connection)
+    .await?;
+  Ok((space_id, parent_id))
+}
+
+/// Enqueues an update for every changed item, keeping the last version of each.
+async fn publish(
+  connection: &mut PgConnection,
+  changed: impl IntoIterator<Item = Item>,
+) -> Result<Vec<Item>, Error> {
+  let items = changed
+    .into_iter()
+    .map(|item| (item.id, item))
+    .collect::<BTreeMap<_, _>>();
+  let items = items.into_values().collect::<Vec<_>>();
+  events::publish(connection, Kind::Updated, &items).await?;
+  Ok(items)
+}
+
+async fn step_item(db_pool: &PgPool, user: &str, id: i64, step: Step) -> Result<Vec<Item>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let (space_id, parent_id) = locate(&mut transaction, id, user).await?;
+  let mut ids = children(&mut transaction, space_id, parent_id).await?;
+
+  let index = ids
+    .iter()
+    .position(|&sibling| sibling as i64 == id)
+    .unwrap();
+  match step {
+    Step::Forward if index + 1 < ids.len() => ids.swap(index, index + 1),
+    Step::Back if index > 0 => ids.swap(index - 1, index),
+    _ => {}
+  }
+  let changed = restack(&mut transaction, &ids).await?;
+  let items = publish(&mut transaction, changed).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+async fn group_items(db_pool: &PgPool, user: &str, group: Group) -> Result<Vec<Item>, Error> {
+  let ids = group.ids.iter().map(|&id| id as i32).collect::<Vec<_>>();
+  let mut transaction = db_pool.begin().await?;
+  let (space_id, _) = locate(&mut transaction, group.parent_id, user).await?;
+
+  // The frame may not end up inside one of the grouped items.
+  let ancestors = sqlx::query_scalar!(
+    r#"
+WITH RECURSIVE ancestors ( id, parent_id ) AS (
+  SELECT id, parent_id FROM item WHERE id = $1
+  UNION
+  SELECT item.id, item.parent_id FROM item JOIN ancestors ON item.id = ancestors.parent_id
+)
+SELECT id AS "id!" FROM ancestors
+    "#,
+    group.parent_id as i32,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  if ids.iter().any(|id| ancestors.contains(id)) {
+    return Err(Error::InvalidGroup("an item cannot contain itself"));
+  }
+
+  let mut changed = sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item SET parent_id = $1, version = version + 1
+WHERE id = ANY($2) AND space_id = $3 AND deleted_at IS NULL
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+    "#,
+    group.parent_id as i32,
+    &ids,
+    space_id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  if let Some(&missing) = group
+    .ids
+    .iter()
+    .find(|&&id| !changed.iter().any(|item| item.id == id))
+  {
+    return Err(Error::ItemNotFound(missing));
+  }
+
+  // Grouped items keep their relative order, above the existing children.
+  let stack = sqlx::query_scalar!(
+    r#"
+SELECT id FROM item
+WHERE parent_id = $1 AND deleted_at IS NULL
+ORDER BY id = ANY($2), z_index, id
+FOR UPDATE
+    "#,
+    group.parent_id as i32,
+    &ids,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  changed.extend(restack(&mut transaction, &stack).await?);
+  let items = publish(&mut transaction, changed).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+async fn ungroup_items(db_pool: &PgPool, user: &str, id: i64) -> Result<Vec<Item>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let (space_id, parent_id) = locate(&mut transaction, id, user).await?;
+
+  // Children take the place of the frame, directly above it.
+  let contained = children(&mut transaction, space_id, Some(id as i32)).await?;
+  let mut stack = children(&mut transaction, space_id, parent_id).await?;
+  let index = stack
+    .iter()
+    .position(|&sibling| sibling as i64 == id)
+    .unwrap();
+  stack.splice(index + 1..index + 1, contained);
+
+  let mut changed = sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item SET parent_id = $2, version = version + 1
+WHERE parent_id = $1 AND deleted_at IS NULL
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+    "#,
+    id as i32,
+    parent_id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  changed.extend(restack(&mut transaction, &stack).await?);
+  let items = publish(&mut transaction, changed).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+/// Moves every item `id` contains by `dx`, `dy` and records the moves for
+/// `user`, returning the moved items.
+pub(crate) async fn move_contents(
+  connection: &mut PgConnection,
+  user: &str,
+  id: i32,
+  (dx, dy): (i64, i64),
+) -> Result<Vec<Item>, Error> {
+  let moved = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT id FROM item WHERE parent_id = $1 AND deleted_at IS NULL
+  UNION
+  SELECT item.id FROM item JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.deleted_at IS NULL
+)
+UPDATE item
+SET x = item.x + $2, y = item.y + $3,
+  version = item.version + 1, position_version = item.version + 1
+FROM subtree
+WHERE item.id = subtree.id
+RETURNING item.id, item.space_id, item.x, item.y, item.w, item.h, item.schema, item.editor,
+  item.z_index, item.parent_id AS "parent_id: i64", item.version, item.position_version,
+  item.size_version, item.schema_version, item.deleted_at, item.trash_batch
+    "#,
+    id,
+    dx as i32,
+    dy as i32,
+  )
+  .fetch_all(&mut *connection)
+  .await?;
+  for item in &moved {
+    let before = Snapshot {
+      x: item.x - dx,
+      y: item.y - dy,
+      ..Snapshot::of(item)
+    };
+    history::record(&mut *connection, user, "move", &before, item).await?;
+  }
+  Ok(moved)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn bring_forward(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    step_item(&db_pool, &clients::user(&socket), data.id, Step::Forward).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn send_back(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    step_item(&db_pool, &clients::user(&socket), data.id, Step::Back).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn group(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<Group>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    group_items(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn ungroup(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    ungroup_items(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item};
+
+  const SPACE: i32 = 1;
+
+  async fn create_spaces(db_pool: &PgPool) {
+    fixtures::space(db_pool, 1, "alice").await;
+    fixtures::space(db_pool, 2, "bob").await;
+  }
+
+  async fn insert(db_pool: &PgPool, x: i32, parent_id: Option<i32>) -> i64 {
+    sqlx::query_scalar!(
+      r#"
+INSERT INTO item ( space_id, x, y, w, h, schema, z_index, parent_id )
+SELECT $1, $2, 0, 1, 1, '[]', COALESCE(MAX(z_index), 0) + 1, $3
+FROM item WHERE space_id = $1 AND parent_id IS NOT DISTINCT FROM $3
+RETURNING id
+      "#,
+      SPACE,
+      x,
+      parent_id,
+    )
+    .fetch_one(db_pool)
+    .await
+    .unwrap() as i64
+  }
+
+  async fn stack(db_pool: &PgPool, parent_id: Option<i32>) -> Vec<i64> {
+    let mut connection = db_pool.acquire().await.unwrap();
+    children(&mut connection, SPACE, parent_id)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(i64::from)
+      .collect()
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_step(db_pool: PgPool) {
+    create_spaces(&db_pool).await;
+    let a = insert(&db_pool, 0, None).await;
+    let b = insert(&db_pool, 0, None).await;
+    let c = insert(&db_pool, 0, None).await;
+
+    let changed = step_item(&db_pool, "alice", a, Step::Forward)
+      .await
+      .unwrap();
+    assert_eq!(changed.len(), 2);
+    assert_eq!(stack(&db_pool, None).await, vec![b, a, c]);
+    step_item(&db_pool, "alice", c, Step::Back).await.unwrap();
+    assert_eq!(stack(&db_pool, None).await, vec![b, c, a]);
+    assert!(matches!(
+      step_item(&db_pool, "bob", a, Step::Back).await,
+      Err(Error::ItemNotFound(_))
+    ));
+    // Stepping past either end changes nothing.
+    assert!(step_item(&db_pool, "alice", a, Step::Forward)
+      .await
+      .unwrap()
+      .is_empty());
+    assert!(matches!(
+      step_item(&db_pool, "alice", 100, Step::Back).await,
+      Err(Error::ItemNotFound(100))
+    ));
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_group(db_pool: PgPool) {
+    create_spaces(&db_pool).await;
+    let frame = insert(&db_pool, 0, None).await;
+    let a = insert(&db_pool, 10, None).await;
+    let b = insert(&db_pool, 20, None).await;
+    let nested = insert(&db_pool, 30, Some(b as i32)).await;
+
+    group_items(
+      &db_pool,
+      "alice",
+      Group {
+        parent_id: frame,
+        ids: vec![b, a],
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(stack(&db_pool, Some(frame as i32)).await, vec![a, b]);
+    assert_eq!(stack(&db_pool, None).await, vec![frame]);
+    assert!(matches!(
+      group_items(
+        &db_pool,
+        "alice",
+        Group {
+          parent_id: nested,
+          ids: vec![frame],
+        },
+      )
+      .await,
+      Err(Error::InvalidGroup(_))
+    ));
+    let other = fixtures::items(&db_pool, [item(100, 2)]).await[0].id;
+    assert!(matches!(
+      group_items(
+        &db_pool,
+        "alice",
+        Group {
+          parent_id: frame,
+          ids: vec![other],
+        },
+      )
+      .await,
+      Err(Error::ItemNotFound(_))
+    ));
+
+    let top = insert(&db_pool, 0, None).await;
+    ungroup_items(&db_pool, "alice", frame).await.unwrap();
+    assert_eq!(stack(&db_pool, None).await, vec![frame, a, b, top]);
+    assert_eq!(stack(&db_pool, Some(b as i32)).await, vec![nested]);
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_trashed(db_pool: PgPool) {
+    create_spaces(&db_pool).await;
+    let frame = insert(&db_pool, 0, None).await;
+    let a = insert(&db_pool, 10, Some(frame as i32)).await;
+    let trashed = insert(&db_pool, 20, Some(frame as i32)).await;
+    sqlx::query!(
+      "UPDATE item SET deleted_at = now() WHERE id = $1",
+      trashed as i32
+    )
+    .execute(&db_pool)
+    .await
+    .unwrap();
+
+    // Trashed items are neither restacked, moved nor ungrouped.
+    assert!(step_item(&db_pool, "alice", a, Step::Forward)
+      .await
+      .unwrap()
+      .is_empty());
+    let mut connection = db_pool.acquire().await.unwrap();
+    let moved = move_contents(&mut connection, "alice", frame as i32, (5, 5))
+      .await
+      .unwrap();
+    assert_eq!(
+      moved.iter().map(|item| item.id).collect::<Vec<_>>(),
+      vec![a]
+    );
+    let ungrouped = ungroup_items(&db_pool, "alice", frame).await.unwrap();
+    assert!(ungrouped.iter().all(|item| item.id != trashed));
+    let parent_id = sqlx::query_scalar!("SELECT parent_id FROM item WHERE id = $1", trashed as i32)
+      .fetch_one(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(parent_id, Some(frame as i32));
+  }
+}
@@ -0,0 +1,310 @@
+use std::{future::Future, time::Duration};
+
+use axum::{
+  body::Body,
+  extract::State,
+  http::{header::CONTENT_TYPE, StatusCode},
+  response::{IntoResponse, Response},
+};
+use prometheus_client::{
+  encoding::{text::encode, EncodeLabelSet, EncodeLabelValue},
+  metrics::{counter::Counter, family::Family, gauge::Gauge, histogram::Histogram},
+  registry::Registry,
+};
+use serde::de::DeserializeOwned;
+use socketioxide::extract::{AckSender, Data, SocketRef, State as SocketState};
+use tokio::time::Instant;
+
+use crate::GlobalState;
+
+/// Upper bounds, in seconds, of the latency histograms.
+const LATENCY_BUCKETS: [f64; 11] = [
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+fn latency_histogram() -> Histogram {
+  Histogram::new(LATENCY_BUCKETS)
+}
+
+/// What happened to a consumed message.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum Outcome {
+  Ack,
+  Requeue,
+  DeadLetter,
+  /// Acknowledged without processing, as it was already delivered.
+  Duplicate,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OutcomeLabels {
+  outcome: Outcome,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct ReasonLabels {
+  reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct EventLabels {
+  event: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct QueryLabels {
+  query: &'static str,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+  registry: Registry,
+  consumer_up: Gauge,
+  consumer_lag: Gauge,
+  consumer_reconnects: Counter,
+  consumer_failures: Family<ReasonLabels, Counter>,
+  messages_consumed: Family<OutcomeLabels, Counter>,
+  messages_published: Counter,
+  publish_failures: Counter,
+  active_connections: Gauge,
+  connections: Counter,
+  events_received: Family<EventLabels, Counter>,
+  event_latency: Family<EventLabels, Histogram>,
+  query_latency: Family<QueryLabels, Histogram>,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    let mut registry = Registry::default();
+
+    let consumer_up = Gauge::default();
+    registry.register(
+      "amqp_consumer_up",
+      "Whether the AMQP consumer is connected and consuming",
+      consumer_up.clone(),
+    );
+
+    let consumer_lag = Gauge::default();
+    registry.register(
+      "amqp_consumer_lag",
+      "Number of messages waiting in the consumer queue",
+      consumer_lag.clone(),
+    );
+
+    let consumer_reconnects = Counter::default();
+    registry.register(
+      "amqp_consumer_reconnects",
+      "Number of times the AMQP consumer reconnected",
+      consumer_reconnects.clone(),
+    );
+
+    let consumer_failures = Family::<ReasonLabels, Counter>::default();
+    registry.register(
+      "amqp_consumer_failures",
+      "Number of messages the AMQP consumer failed to process",
+      consumer_failures.clone(),
+    );
+
+    let messages_consumed = Family::<OutcomeLabels, Counter>::default();
+    registry.register(
+      "amqp_messages_consumed",
+      "Number of AMQP messages consumed",
+      messages_consumed.clone(),
+    );
+
+    let messages_published = Counter::default();
+    registry.register(
+      "amqp_messages_published",
+      "Number of outbox events published and confirmed by the broker",
+      messages_published.clone(),
+    );
+
+    let publish_failures = Counter::default();
+    registry.register(
+      "amqp_publish_failures",
+      "Number of times the outbox relay failed to publish",
+      publish_failures.clone(),
+    );
+
+    let active_connections = Gauge::default();
+    registry.register(
+      "active_connections",
+      "Number of connected sockets",
+      active_connections.clone(),
+    );
+
+    let connections = Counter::default();
+    registry.register(
+      "connections",
+      "Number of sockets that connected",
+      connections.clone(),
+    );
+
+    let events_received = Family::<EventLabels, Counter>::default();
+    registry.register(
+      "events_received",
+      "Number of socket events received",
+      events_received.clone(),
+    );
+
+    let event_latency = Family::<EventLabels, Histogram>::new_with_constructor(
+      latency_histogram as fn() -> Histogram,
+    );
+    registry.register(
+      "event_latency_seconds",
+      "Time to handle socket events in seconds",
+      event_latency.clone(),
+    );
+
+    let query_latency = Family::<QueryLabels, Histogram>::new_with_constructor(
+      latency_histogram as fn() -> Histogram,
+    );
+    registry.register(
+      "db_query_latency_seconds",
+      "Latency of database queries in seconds",
+      query_latency.clone(),
+    );
+
+    Metrics {
+      registry,
+      consumer_up,
+      consumer_lag,
+      consumer_reconnects,
+      consumer_failures,
+      messages_consumed,
+      messages_published,
+      publish_failures,
+      active_connections,
+      connections,
+      events_received,
+      event_latency,
+      query_latency,
+    }
+  }
+
+  pub fn set_consumer_up(&self, up: bool) {
+    self.consumer_up.set(up as i64);
+  }
+
+  pub fn consumer_up(&self) -> bool {
+    self.consumer_up.get() == 1
+  }
+
+  pub fn set_consumer_lag(&self, messages: u32) {
+    self.consumer_lag.set(messages as i64);
+  }
+
+  pub fn inc_consumer_reconnects(&self) {
+    self.consumer_reconnects.inc();
+  }
+
+  pub fn inc_consumer_failures(&self, reason: &'static str) {
+    self
+      .consumer_failures
+      .get_or_create(&ReasonLabels { reason })
+      .inc();
+  }
+
+  pub fn inc_messages_consumed(&self, outcome: Outcome) {
+    self
+      .messages_consumed
+      .get_or_create(&OutcomeLabels { outcome })
+      .inc();
+  }
+
+  pub fn inc_messages_published(&self, messages: usize) {
+    self.messages_published.inc_by(messages as u64);
+  }
+
+  pub fn inc_publish_failures(&self) {
+    self.publish_failures.inc();
+  }
+
+  pub fn inc_active_connections(&self) {
+    self.connections.inc();
+    self.active_connections.inc();
+  }
+
+  pub fn dec_active_connections(&self) {
+    self.active_connections.dec();
+  }
+
+  fn observe_event(&self, event: &'static str, latency: Duration) {
+    let labels = EventLabels { event };
+    self.events_received.get_or_create(&labels).inc();
+    self
+      .event_latency
+      .get_or_create(&labels)
+      .observe(latency.as_secs_f64());
+  }
+
+  /// Runs `future`, recording how long it took as the latency of `query`.
+  pub async fn time_query<T>(&self, query: &'static str, future: impl Future<Output = T>) -> T {
+    let start = Instant::now();
+    let output = future.await;
+    self
+      .query_latency
+      .get_or_create(&QueryLabels { query })
+      .observe(start.elapsed().as_secs_f64());
+    output
+  }
+}
+
+/// Registers `handler` for `event`, counting the events it handles and
+/// timing them.
+pub fn on<T, F, Fut>(socket: &SocketRef, event: &'static str, handler: F)
+where
+  T: DeserializeOwned + Send + Sync + 'static,
+  F: Fn(SocketRef, AckSender, Data<T>, SocketState<GlobalState>) -> Fut
+    + Clone
+    + Send
+    + Sync
+    + 'static,
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  socket.on(event, metered(event, handler));
+}
+
+fn metered<T, F, Fut>(
+  event: &'static str,
+  handler: F,
+) -> impl Fn(SocketRef, AckSender, Data<T>, SocketState<GlobalState>) -> BoxFuture
+     + Clone
+     + Send
+     + Sync
+     + 'static
+where
+  T: DeserializeOwned + Send + Sync + 'static,
+  F: Fn(SocketRef, AckSender, Data<T>, SocketState<GlobalState>) -> Fut
+    + Clone
+    + Send
+    + Sync
+    + 'static,
+  Fut: Future<Output = ()> + Send + 'static,
+{
+  move |socket, ack, data, state| {
+    let handler = handler.clone();
+    Box::pin(async move {
+      let metrics = state.metrics.clone();
+      let start = Instant::now();
+      handler(socket, ack, data, state).await;
+      metrics.observe_event(event, start.elapsed());
+    })
+  }
+}
+
+type BoxFuture = std::pin::Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub async fn metrics_handler(State(state): State<GlobalState>) -> impl IntoResponse {
+  let mut buffer = String::new();
+  encode(&mut buffer, &state.metrics.registry).unwrap();
+
+  Response::builder()
+    .status(StatusCode::OK)
+    .header(
+      CONTENT_TYPE,
+      "application/openmetrics-text; version=1.0.0; charset=utf-8",
+    )
+    .body(Body::from(buffer))
+    .unwrap()
+}
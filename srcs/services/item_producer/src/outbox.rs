@@ -0,0 +1,179 @@
+//! Transactional outbox for item events. Handlers enqueue an event in the same
+//! transaction as the change it describes and the relay publishes it to the
+//! [`EventBus`] once committed. Events are marked as sent only after the bus
+//! accepted them, so they are delivered at least once even if the service
+//! stops in between. Each event carries an idempotency key as its message id
+//! for consumers to drop duplicates.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use sqlx::{postgres::PgListener, PgConnection, PgPool};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+  bus::{Event, EventBus},
+  metrics::Metrics,
+};
+
+/// Notified whenever events are enqueued.
+const CHANNEL: &str = "outbox";
+/// Events published per transaction.
+const BATCH_SIZE: i64 = 100;
+/// Pending events are also picked up without a notification, e.g. ones
+/// enqueued while the relay was disconnected.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Sent events are kept this long, in seconds, before they are purged.
+const RETENTION: f64 = 60.0 * 60.0 * 24.0;
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Enqueues an event, to be published once the transaction commits.
+pub async fn enqueue(
+  connection: &mut PgConnection,
+  routing_key: &str,
+  payload: Vec<u8>,
+) -> sqlx::Result<()> {
+  sqlx::query!(
+    r#"
+INSERT INTO outbox ( idempotency_key, routing_key, payload )
+VALUES ( $1, $2, $3 )
+    "#,
+    Uuid::new_v4().to_string(),
+    routing_key,
+    payload,
+  )
+  .execute(connection)
+  .await?;
+  Ok(())
+}
+
+/// Publishes a batch of pending events, returning how many were sent.
+async fn publish_pending(db_pool: &PgPool, bus: &dyn EventBus, metrics: &Metrics) -> Result<usize> {
+  let mut transaction = db_pool.begin().await?;
+  // Locked rows are being published by another instance.
+  let events = sqlx::query!(
+    r#"
+SELECT id, idempotency_key, routing_key, payload FROM outbox
+WHERE sent_at IS NULL
+ORDER BY id
+LIMIT $1
+FOR UPDATE SKIP LOCKED
+    "#,
+    BATCH_SIZE,
+  )
+  .fetch_all(&mut *transaction);
+  let events = metrics.time_query("outbox_pending", events).await?;
+  if events.is_empty() {
+    return Ok(0);
+  }
+
+  let ids = events.iter().map(|event| event.id).collect::<Vec<_>>();
+  let events = events
+    .into_iter()
+    .map(|event| Event {
+      id: event.idempotency_key,
+      routing_key: event.routing_key,
+      payload: event.payload,
+    })
+    .collect::<Vec<_>>();
+  bus.publish(&events).await?;
+
+  sqlx::query!("UPDATE outbox SET sent_at = now() WHERE id = ANY($1)", &ids)
+    .execute(&mut *transaction)
+    .await?;
+  transaction.commit().await?;
+  Ok(events.len())
+}
+
+async fn purge(db_pool: &PgPool) -> sqlx::Result<()> {
+  let purged = sqlx::query!(
+    "DELETE FROM outbox WHERE sent_at < now() - make_interval(secs => $1)",
+    RETENTION,
+  )
+  .execute(db_pool)
+  .await?;
+  if purged.rows_affected() > 0 {
+    info!("Purged {} sent event(s)", purged.rows_affected());
+  }
+  Ok(())
+}
+
+/// Relays events until publishing fails or the database connection is lost.
+/// The reconnection `backoff` starts over once events are published again.
+async fn relay(
+  db_pool: &PgPool,
+  bus: &dyn EventBus,
+  metrics: &Metrics,
+  backoff: &mut Duration,
+) -> Result<()> {
+  let mut listener = PgListener::connect_with(db_pool).await?;
+  listener.listen(CHANNEL).await?;
+  info!("Relaying outbox events");
+
+  let mut purge_interval = tokio::time::interval(PURGE_INTERVAL);
+  loop {
+    let published = publish_pending(db_pool, bus, metrics).await?;
+    metrics.inc_messages_published(published);
+    *backoff = INITIAL_BACKOFF;
+    if published as i64 == BATCH_SIZE {
+      continue;
+    }
+    tokio::select! {
+      notification = listener.recv() => {
+        notification?;
+      }
+      _ = purge_interval.tick() => {
+        if let Err(e) = purge(db_pool).await {
+          warn!("Failed to purge sent events: {}", e);
+        }
+      }
+      _ = tokio::time::sleep(POLL_INTERVAL) => {}
+    }
+  }
+}
+
+/// Publishes enqueued events, reconnecting with exponential backoff.
+pub async fn background_task(db_pool: PgPool, bus: Arc<dyn EventBus>, metrics: Arc<Metrics>) {
+  let mut backoff = INITIAL_BACKOFF;
+  loop {
+    if let Err(e) = relay(&db_pool, bus.as_ref(), &metrics, &mut backoff).await {
+      error!("Outbox relay failed: {}", e);
+      metrics.inc_publish_failures();
+    }
+    tokio::time::sleep(backoff).await;
+    backoff = (backoff * 2).min(MAX_BACKOFF);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_enqueue(db_pool: PgPool) {
+    let mut transaction = db_pool.begin().await.unwrap();
+    enqueue(&mut transaction, "item.update", vec![1])
+      .await
+      .unwrap();
+    enqueue(&mut transaction, "item.update", vec![2])
+      .await
+      .unwrap();
+    transaction.rollback().await.unwrap();
+
+    let mut connection = db_pool.acquire().await.unwrap();
+    enqueue(&mut connection, "item.update", vec![3])
+      .await
+      .unwrap();
+
+    let events = sqlx::query!("SELECT idempotency_key, payload FROM outbox WHERE sent_at IS NULL")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].payload, vec![3]);
+  }
+}
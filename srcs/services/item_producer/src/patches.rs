@@ -0,0 +1,428 @@
+//! Fine-grained writes to items. A patch changes one group of fields and only
+//! conflicts with changes to that group made after the version it was based
+//! on, so concurrent moves, resizes and edits of the same item merge.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{Acquire, PgConnection, PgPool};
+
+use crate::{
+  clients,
+  events::{self, Kind},
+  handlers::{conflict, link_assets, respond, Error, ErrorResponse},
+  history,
+  item::{Descendant, Item},
+  layers, search, spaces, GlobalState,
+};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+  /// Moves the item and everything it contains by the same offset, so a
+  /// selection is dragged by patching only its topmost items.
+  Move {
+    x: i64,
+    y: i64,
+  },
+  Resize {
+    w: i64,
+    h: i64,
+  },
+  /// Replaces the content. Images may only reference stored assets.
+  Schema {
+    schema: String,
+  },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ItemPatch {
+  pub id: i64,
+  /// Version of the item the patch is based on.
+  pub version: i64,
+  #[serde(flatten)]
+  pub change: Change,
+}
+
+/// Patches applied at once, e.g. to every item of a selection being dragged.
+#[derive(Debug, Deserialize)]
+pub struct PatchBatch {
+  pub patches: Vec<ItemPatch>,
+  /// Applies the patches that succeed and reports the others, instead of
+  /// applying all or none of them.
+  #[serde(default)]
+  pub partial: bool,
+}
+
+/// A patch of a batch that was not applied.
+#[derive(Debug, Serialize)]
+pub struct PatchFailure {
+  pub id: i64,
+  #[serde(flatten)]
+  pub error: ErrorResponse,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+  /// The patched items, in the order of their patches.
+  pub items: Vec<Item>,
+  pub failures: Vec<PatchFailure>,
+}
+
+/// Patches accepted per batch.
+const MAX_PATCHES: usize = 1000;
+
+/// Applies the patch, returning `None` if its fields changed since its base.
+async fn apply(connection: &mut PgConnection, patch: &ItemPatch) -> Result<Option<Item>, Error> {
+  let (id, version) = (patch.id as i32, patch.version as i32);
+  let item = match &patch.change {
+    Change::Move { x, y } => {
+      sqlx::query_as!(
+        Item,
+        r#"
+UPDATE item SET x = $3, y = $4, version = version + 1, position_version = version + 1
+WHERE id = $1 AND position_version <= $2
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+        "#,
+        id,
+        version,
+        *x as i32,
+        *y as i32,
+      )
+      .fetch_optional(&mut *connection)
+      .await?
+    }
+    Change::Resize { w, h } => {
+      sqlx::query_as!(
+        Item,
+        r#"
+UPDATE item SET w = $3, h = $4, version = version + 1, size_version = version + 1
+WHERE id = $1 AND size_version <= $2
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+        "#,
+        id,
+        version,
+        *w as i32,
+        *h as i32,
+      )
+      .fetch_optional(&mut *connection)
+      .await?
+    }
+    Change::Schema { schema } => {
+      let descendants = serde_json::from_str::<Vec<Descendant>>(schema)?;
+      for descendant in &descendants {
+        descendant.check_refs(&[])?;
+      }
+      let item = sqlx::query_as!(
+        Item,
+        r#"
+UPDATE item SET schema = $3, version = version + 1, schema_version = version + 1
+WHERE id = $1 AND schema_version <= $2
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+        "#,
+        id,
+        version,
+        schema,
+      )
+      .fetch_optional(&mut *connection)
+      .await?;
+      if item.is_some() {
+        link_assets(&mut *connection, id, &descendants).await?;
+        search::index(&mut *connection, id, &descendants).await?;
+      }
+      item
+    }
+  };
+  Ok(item)
+}
+
+/// Applies the patch on behalf of `user` and records it, without publishing.
+/// Returns the patched item and the items it carried along when moved.
+async fn apply_as(
+  connection: &mut PgConnection,
+  user: &str,
+  patch: &ItemPatch,
+) -> Result<(Item, Vec<Item>), Error> {
+  spaces::authorize_item(&mut *connection, patch.id, user).await?;
+  let before = history::snapshot(&mut *connection, patch.id).await?;
+
+  let Some(item) = apply(&mut *connection, patch).await? else {
+    return Err(conflict(&mut *connection, patch.id).await?);
+  };
+  history::record(&mut *connection, user, "patch", &before, &item).await?;
+  let contents = match patch.change {
+    Change::Move { .. } => {
+      let offset = (item.x - before.x, item.y - before.y);
+      layers::move_contents(&mut *connection, user, item.id as i32, offset).await?
+    }
+    _ => vec![],
+  };
+  Ok((item, contents))
+}
+
+async fn patch_item(db_pool: &PgPool, user: &str, patch: ItemPatch) -> Result<Item, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let (item, contents) = apply_as(&mut transaction, user, &patch).await?;
+  let mut changed = vec![item.clone()];
+  changed.extend(contents);
+  events::publish(&mut transaction, Kind::Updated, &changed).await?;
+
+  transaction.commit().await?;
+  Ok(item)
+}
+
+/// Applies the patches in order and publishes the patched items, with the
+/// contents of moved frames, as one `item.batch` event. A partial batch
+/// applies each patch in a savepoint so a failed patch leaves the others in
+/// place.
+async fn patch_items(
+  db_pool: &PgPool,
+  user: &str,
+  batch: PatchBatch,
+) -> Result<BatchResult, Error> {
+  if batch.patches.len() > MAX_PATCHES {
+    return Err(Error::TooManyPatches(MAX_PATCHES));
+  }
+  let mut transaction = db_pool.begin().await?;
+  let mut items = vec![];
+  let mut changed = vec![];
+  let mut failures = vec![];
+  for patch in &batch.patches {
+    if !batch.partial {
+      let (item, contents) = apply_as(&mut transaction, user, patch).await?;
+      changed.push(item.clone());
+      changed.extend(contents);
+      items.push(item);
+      continue;
+    }
+    let mut savepoint = transaction.begin().await?;
+    match apply_as(&mut savepoint, user, patch).await {
+      Ok((item, contents)) => {
+        savepoint.commit().await?;
+        changed.push(item.clone());
+        changed.extend(contents);
+        items.push(item);
+      }
+      Err(e) => {
+        savepoint.rollback().await?;
+        failures.push(PatchFailure {
+          id: patch.id,
+          error: e.into(),
+        });
+      }
+    }
+  }
+
+  // Items patched more than once are broadcast in their final state.
+  let latest = changed
+    .into_iter()
+    .map(|item| (item.id, item))
+    .collect::<BTreeMap<_, _>>();
+  if !latest.is_empty() {
+    let latest = latest.into_values().collect::<Vec<_>>();
+    events::publish_batch(&mut transaction, &latest).await?;
+  }
+
+  transaction.commit().await?;
+  Ok(BatchResult { items, failures })
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn patch(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemPatch>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    patch_item(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn patch_many(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<PatchBatch>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    patch_items(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item, ItemRow};
+
+  fn patch(version: i64, change: serde_json::Value) -> ItemPatch {
+    let mut value = serde_json::json!({ "id": 1, "version": version });
+    value
+      .as_object_mut()
+      .unwrap()
+      .extend(change.as_object().unwrap().clone());
+    serde_json::from_value(value).unwrap()
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_patch(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(
+      &db_pool,
+      [ItemRow {
+        schema: Some("[]"),
+        ..item(1, 1)
+      }],
+    )
+    .await;
+    let move_to = |x| serde_json::json!({ "op": "move", "x": x, "y": 0 });
+
+    // Both editors start from version 1 and touch different fields.
+    let moved = patch_item(&db_pool, "alice", patch(1, move_to(5)))
+      .await
+      .unwrap();
+    assert_eq!((moved.x, moved.version), (5, 2));
+    let resized = patch_item(
+      &db_pool,
+      "alice",
+      patch(1, serde_json::json!({ "op": "resize", "w": 3, "h": 4 })),
+    )
+    .await
+    .unwrap();
+    assert_eq!((resized.x, resized.w, resized.version), (5, 3, 3));
+
+    // A second move based on version 1 would clobber the first one.
+    match patch_item(&db_pool, "alice", patch(1, move_to(9))).await {
+      Err(Error::Conflict(current)) => assert_eq!((current.x, current.version), (5, 3)),
+      result => panic!("expected a conflict, got {:?}", result),
+    }
+    patch_item(&db_pool, "alice", patch(3, move_to(9)))
+      .await
+      .unwrap();
+
+    let edited = patch_item(
+      &db_pool,
+      "alice",
+      patch(1, serde_json::json!({ "op": "schema", "schema": "[]" })),
+    )
+    .await
+    .unwrap();
+    assert_eq!((edited.x, edited.w, edited.version), (9, 3, 5));
+    assert!(matches!(
+      patch_item(&db_pool, "bob", patch(5, move_to(0))).await,
+      Err(Error::ItemNotFound(1))
+    ));
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_patch_many(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(&db_pool, [item(1, 1), item(2, 1)]).await;
+    let batch = |partial| {
+      serde_json::from_value::<PatchBatch>(serde_json::json!({
+        "partial": partial,
+        "patches": [
+          { "id": 1, "version": 1, "op": "move", "x": 5, "y": 5 },
+          { "id": 2, "version": 1, "op": "move", "x": 5, "y": 5 },
+          { "id": 3, "version": 1, "op": "move", "x": 5, "y": 5 },
+        ],
+      }))
+      .unwrap()
+    };
+    let batches = || async {
+      sqlx::query_scalar!(
+        "SELECT count(*) AS \"count!\" FROM outbox WHERE routing_key = 'item.batch'"
+      )
+      .fetch_one(&db_pool)
+      .await
+      .unwrap()
+    };
+
+    // The missing item rejects the whole batch.
+    assert!(matches!(
+      patch_items(&db_pool, "alice", batch(false)).await,
+      Err(Error::ItemNotFound(3))
+    ));
+    assert_eq!(batches().await, 0);
+
+    let result = patch_items(&db_pool, "alice", batch(true)).await.unwrap();
+    assert_eq!(
+      result
+        .items
+        .iter()
+        .map(|item| (item.id, item.x))
+        .collect::<Vec<_>>(),
+      vec![(1, 5), (2, 5)]
+    );
+    assert_eq!(result.failures.len(), 1);
+    assert_eq!(result.failures[0].id, 3);
+    assert_eq!(batches().await, 1);
+
+    // Stale patches are reported with the item as stored.
+    let result = patch_items(&db_pool, "alice", batch(true)).await.unwrap();
+    assert!(result.items.is_empty());
+    assert_eq!(
+      result.failures[0].error.current.as_ref().unwrap().version,
+      2
+    );
+    assert_eq!(batches().await, 1);
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_patch_frame(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(
+      &db_pool,
+      [
+        ItemRow {
+          w: 9,
+          h: 9,
+          ..item(1, 1)
+        },
+        ItemRow {
+          parent_id: Some(1),
+          x: 1,
+          y: 1,
+          ..item(2, 1)
+        },
+        ItemRow {
+          parent_id: Some(2),
+          x: 2,
+          y: 2,
+          ..item(3, 1)
+        },
+      ],
+    )
+    .await;
+
+    let moved = patch_item(
+      &db_pool,
+      "alice",
+      patch(1, serde_json::json!({ "op": "move", "x": 5, "y": 0 })),
+    )
+    .await
+    .unwrap();
+    assert_eq!((moved.x, moved.y), (5, 0));
+    let positions = sqlx::query!("SELECT id, x, y FROM item ORDER BY id")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap()
+      .into_iter()
+      .map(|row| (row.id, row.x, row.y))
+      .collect::<Vec<_>>();
+    assert_eq!(positions, vec![(1, 5, 0), (2, 6, 1), (3, 7, 2)]);
+    let moves =
+      sqlx::query_scalar!("SELECT count(*) AS \"count!\" FROM item_history WHERE action = 'move'")
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(moves, 2);
+  }
+}
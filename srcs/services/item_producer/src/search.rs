@@ -0,0 +1,254 @@
+//! Full-text search over the content of items. The plain text of every item is
+//! kept in `item_text` as its schema changes and ranked by Postgres.
+
+use serde::{Deserialize, Serialize};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+
+use crate::{
+  clients,
+  handlers::{respond, Error},
+  item::Descendant,
+  spaces, GlobalState,
+};
+
+const MAX_HITS: i64 = 100;
+
+fn default_limit() -> i64 {
+  20
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+  pub space_id: i64,
+  /// Web search syntax, e.g. `"exact phrase" or -excluded`.
+  pub query: String,
+  #[serde(default = "default_limit")]
+  pub limit: i64,
+}
+
+/// An item matching the query, with the coordinates to pan to.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Hit {
+  pub id: i64,
+  pub x: i64,
+  pub y: i64,
+  pub w: i64,
+  pub h: i64,
+  pub rank: f32,
+  /// Matching fragments as HTML: the text is escaped and the terms are
+  /// wrapped in `<mark>`.
+  pub snippet: String,
+}
+
+/// Delimit the matching terms in headlines. Item text cannot contain them,
+/// they are stripped before the headline is built.
+const START_SEL: char = '\u{2}';
+const STOP_SEL: char = '\u{3}';
+
+/// Escapes a headline for HTML and turns its delimiters into `<mark>` tags.
+fn highlight(headline: &str) -> String {
+  let mut snippet = String::with_capacity(headline.len());
+  for c in headline.chars() {
+    match c {
+      START_SEL => snippet.push_str("<mark>"),
+      STOP_SEL => snippet.push_str("</mark>"),
+      '&' => snippet.push_str("&amp;"),
+      '<' => snippet.push_str("&lt;"),
+      '>' => snippet.push_str("&gt;"),
+      '"' => snippet.push_str("&quot;"),
+      '\'' => snippet.push_str("&#39;"),
+      c => snippet.push(c),
+    }
+  }
+  snippet
+}
+
+/// Replaces the indexed text of an item.
+pub async fn index(
+  connection: &mut PgConnection,
+  item_id: i32,
+  descendants: &[Descendant],
+) -> sqlx::Result<()> {
+  let mut content = String::new();
+  for descendant in descendants {
+    descendant.plain_text(&mut content);
+  }
+  sqlx::query!(
+    r#"
+INSERT INTO item_text ( item_id, content ) VALUES ( $1, $2 )
+ON CONFLICT ( item_id ) DO UPDATE SET content = excluded.content
+    "#,
+    item_id,
+    content,
+  )
+  .execute(connection)
+  .await?;
+  Ok(())
+}
+
+async fn search_items(db_pool: &PgPool, user: &str, query: SearchQuery) -> Result<Vec<Hit>, Error> {
+  let mut connection = db_pool.acquire().await?;
+  spaces::authorize(&mut connection, query.space_id, user).await?;
+  if query.query.trim().is_empty() {
+    return Ok(vec![]);
+  }
+
+  let hits = sqlx::query_as!(
+    Hit,
+    r#"
+SELECT
+  item.id AS "id!: i64",
+  item.x AS "x!: i64",
+  item.y AS "y!: i64",
+  item.w AS "w!: i64",
+  item.h AS "h!: i64",
+  ts_rank_cd(item_text.document, query) AS "rank!",
+  ts_headline(
+    'english', translate(item_text.content, chr(2) || chr(3), ''), query,
+    format('StartSel=%s, StopSel=%s, MaxFragments=2, MaxWords=20, MinWords=5', chr(2), chr(3))
+  ) AS "snippet!"
+FROM item
+JOIN item_text ON item_text.item_id = item.id,
+  websearch_to_tsquery('english', $2) AS query
+WHERE item.space_id = $1 AND item.deleted_at IS NULL AND item_text.document @@ query
+ORDER BY 6 DESC, item.id
+LIMIT $3
+      "#,
+    query.space_id as i32,
+    query.query,
+    query.limit.clamp(1, MAX_HITS),
+  )
+  .fetch_all(&mut *connection)
+  .await?;
+  Ok(
+    hits
+      .into_iter()
+      .map(|hit| Hit {
+        snippet: highlight(&hit.snippet),
+        ..hit
+      })
+      .collect(),
+  )
+}
+
+/// Ranks the items of a space by how well their content matches the query.
+#[tracing::instrument(skip_all)]
+pub async fn search(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SearchQuery>,
+  State(GlobalState {
+    db_pool, metrics, ..
+  }): State<GlobalState>,
+) {
+  let user = clients::user(&socket);
+  respond(
+    ack,
+    metrics
+      .time_query("search", search_items(&db_pool, &user, data))
+      .await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item, ItemRow};
+
+  fn paragraph(text: &str) -> Vec<Descendant> {
+    serde_json::from_value(serde_json::json!([
+      { "type": "paragraph", "children": [{ "text": text }] }
+    ]))
+    .unwrap()
+  }
+
+  fn query(query: &str) -> SearchQuery {
+    SearchQuery {
+      space_id: 1,
+      query: query.into(),
+      limit: default_limit(),
+    }
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_search(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::space(&db_pool, 2, "alice").await;
+    fixtures::items(
+      &db_pool,
+      [
+        ItemRow {
+          x: 10,
+          y: 20,
+          ..item(1, 1)
+        },
+        item(2, 1),
+        item(3, 2),
+        item(4, 2),
+      ],
+    )
+    .await;
+    let mut connection = db_pool.acquire().await.unwrap();
+    for (id, text) in [
+      (1, "Roadmap for the launch, launch checklist"),
+      (2, "Launching next week"),
+      (3, "Launch of the other board"),
+      (4, "<img src=x onerror=alert(1)> launch \u{2}party\u{3}"),
+    ] {
+      index(&mut connection, id, &paragraph(text)).await.unwrap();
+    }
+    index(&mut connection, 2, &paragraph("Groceries"))
+      .await
+      .unwrap();
+    drop(connection);
+
+    let hits = search_items(&db_pool, "alice", query("launch"))
+      .await
+      .unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!((hits[0].id, hits[0].x, hits[0].y), (1, 10, 20));
+    assert!(hits[0].snippet.contains("<mark>launch</mark>"));
+    assert_eq!(
+      search_items(&db_pool, "alice", query("groceries"))
+        .await
+        .unwrap()
+        .len(),
+      1
+    );
+    assert!(search_items(&db_pool, "alice", query(" "))
+      .await
+      .unwrap()
+      .is_empty());
+    // Item text is escaped, only the matches are marked up.
+    let hits = search_items(
+      &db_pool,
+      "alice",
+      SearchQuery {
+        space_id: 2,
+        ..query("launch party")
+      },
+    )
+    .await
+    .unwrap();
+    let snippet = &hits.iter().find(|hit| hit.id == 4).unwrap().snippet;
+    let text = snippet.replace("<mark>", "").replace("</mark>", "");
+    assert!(!text.contains('<') && !text.contains('>'));
+    assert!(text.contains("&gt;"));
+    assert!(snippet.contains("<mark>launch</mark> <mark>party</mark>"));
+    assert!(matches!(
+      search_items(&db_pool, "bob", query("launch")).await,
+      Err(Error::SpaceNotFound(1))
+    ));
+
+    // Deleting the item drops its text.
+    sqlx::query("DELETE FROM item WHERE id = 1")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    assert!(search_items(&db_pool, "alice", query("roadmap"))
+      .await
+      .unwrap()
+      .is_empty());
+  }
+}
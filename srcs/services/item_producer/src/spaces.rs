@@ -0,0 +1,364 @@
+//! Spaces are the boards of a user. Every item and connector belongs to one,
+//! operations check that the connected user owns it, and events are emitted to
+//! the sockets that joined its room.
+
+use prost::Message as ProtoMessage;
+use serde::{Deserialize, Serialize};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+use tracing::info;
+
+use crate::{
+  clients::{self, Format},
+  handlers::{item::SpaceResponse, respond, Error},
+  outbox, GlobalState,
+};
+
+#[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
+pub struct Space {
+  pub id: i64,
+  pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpaceCreate {
+  pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpaceRename {
+  pub id: i64,
+  pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SpaceRef {
+  pub id: i64,
+}
+
+/// Socket.IO room of the sockets receiving the events of a space in
+/// `format`.
+pub fn room(space_id: i64, format: Format) -> String {
+  match format {
+    Format::Json => format!("space:{}:json", space_id),
+    Format::Protobuf => format!("space:{}:protobuf", space_id),
+  }
+}
+
+fn encode_space(space: &Space) -> Vec<u8> {
+  SpaceResponse {
+    id: space.id,
+    name: space.name.clone(),
+  }
+  .encode_to_vec()
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+  match name.trim().chars().count() {
+    0 => Err(Error::InvalidSpace("name is empty")),
+    1..=100 => Ok(()),
+    _ => Err(Error::InvalidSpace("name is longer than 100 characters")),
+  }
+}
+
+/// Gives `owner` the spaces without one. The migration introducing spaces
+/// moved the items that existed before into such a space, which nobody can
+/// open until it is claimed.
+pub async fn claim_legacy(db_pool: &PgPool, owner: &str) -> Result<u64, Error> {
+  let claimed = sqlx::query!("UPDATE space SET owner = $1 WHERE owner IS NULL", owner)
+    .execute(db_pool)
+    .await?;
+  Ok(claimed.rows_affected())
+}
+
+/// Checks that `user` owns the space. The space is locked against deletion
+/// until the transaction ends.
+pub async fn authorize(
+  connection: &mut PgConnection,
+  space_id: i64,
+  user: &str,
+) -> Result<(), Error> {
+  sqlx::query_scalar!(
+    "SELECT id FROM space WHERE id = $1 AND owner = $2 FOR SHARE",
+    space_id as i32,
+    user,
+  )
+  .fetch_optional(connection)
+  .await?
+  .map(|_| ())
+  .ok_or(Error::SpaceNotFound(space_id))
+}
+
+/// Returns the space of an item owned by `user`, locking the item. Items of
+/// other users and items in the trash are reported as missing.
+pub async fn authorize_item(
+  connection: &mut PgConnection,
+  item_id: i64,
+  user: &str,
+) -> Result<i32, Error> {
+  sqlx::query_scalar!(
+    r#"
+SELECT item.space_id FROM item
+JOIN space ON space.id = item.space_id
+WHERE item.id = $1 AND space.owner = $2 AND item.deleted_at IS NULL
+FOR UPDATE OF item
+    "#,
+    item_id as i32,
+    user,
+  )
+  .fetch_optional(connection)
+  .await?
+  .ok_or(Error::ItemNotFound(item_id))
+}
+
+async fn list_spaces(db_pool: &PgPool, user: &str) -> Result<Vec<Space>, Error> {
+  Ok(
+    sqlx::query_as!(
+      Space,
+      "SELECT id, name FROM space WHERE owner = $1 ORDER BY created_at, id",
+      user,
+    )
+    .fetch_all(db_pool)
+    .await?,
+  )
+}
+
+/// Creates a space owned by `user`.
+pub(crate) async fn insert(
+  connection: &mut PgConnection,
+  user: &str,
+  name: &str,
+) -> Result<Space, Error> {
+  check_name(name)?;
+  let space = sqlx::query_as!(
+    Space,
+    "INSERT INTO space ( owner, name ) VALUES ( $1, $2 ) RETURNING id, name",
+    user,
+    name.trim(),
+  )
+  .fetch_one(&mut *connection)
+  .await?;
+  outbox::enqueue(connection, "space.create", encode_space(&space)).await?;
+  Ok(space)
+}
+
+async fn create_space(db_pool: &PgPool, user: &str, data: SpaceCreate) -> Result<Space, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let space = insert(&mut transaction, user, &data.name).await?;
+  transaction.commit().await?;
+  Ok(space)
+}
+
+async fn rename_space(db_pool: &PgPool, user: &str, data: SpaceRename) -> Result<Space, Error> {
+  check_name(&data.name)?;
+  let mut transaction = db_pool.begin().await?;
+
+  let space = sqlx::query_as!(
+    Space,
+    "UPDATE space SET name = $3 WHERE id = $1 AND owner = $2 RETURNING id, name",
+    data.id as i32,
+    user,
+    data.name.trim(),
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::SpaceNotFound(data.id))?;
+  outbox::enqueue(&mut transaction, "space.update", encode_space(&space)).await?;
+
+  transaction.commit().await?;
+  Ok(space)
+}
+
+/// Deletes the space along with its items and connectors.
+async fn delete_space(db_pool: &PgPool, user: &str, id: i64) -> Result<Space, Error> {
+  let mut transaction = db_pool.begin().await?;
+
+  let space = sqlx::query_as!(
+    Space,
+    "DELETE FROM space WHERE id = $1 AND owner = $2 RETURNING id, name",
+    id as i32,
+    user,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::SpaceNotFound(id))?;
+  outbox::enqueue(&mut transaction, "space.delete", encode_space(&space)).await?;
+
+  transaction.commit().await?;
+  Ok(space)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list(
+  socket: SocketRef,
+  ack: AckSender,
+  _: Data<serde_json::Value>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(ack, list_spaces(&db_pool, &clients::user(&socket)).await);
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn create(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SpaceCreate>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    create_space(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn rename(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SpaceRename>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    rename_space(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn delete(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SpaceRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    delete_space(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+/// Subscribes the socket to the events of a space, leaving any other space.
+#[tracing::instrument(skip_all)]
+pub async fn join(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SpaceRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  let result = async {
+    let mut connection = db_pool.acquire().await?;
+    authorize(&mut connection, data.id, &clients::user(&socket)).await?;
+    socket.leave_all();
+    socket.join(room(data.id, clients::format(&socket)));
+    info!("Socket {} joined space {}", socket.id, data.id);
+    Ok(data.id)
+  }
+  .await;
+  respond(ack, result);
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn leave(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<SpaceRef>,
+  _: State<GlobalState>,
+) {
+  socket.leave(room(data.id, clients::format(&socket)));
+  ack.send(&data.id).ok();
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item};
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_spaces(db_pool: PgPool) {
+    let space = create_space(
+      &db_pool,
+      "alice",
+      SpaceCreate {
+        name: " Plans ".into(),
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(space.name, "Plans");
+    create_space(&db_pool, "bob", SpaceCreate { name: "Bob".into() })
+      .await
+      .unwrap();
+    assert!(matches!(
+      create_space(&db_pool, "alice", SpaceCreate { name: " ".into() }).await,
+      Err(Error::InvalidSpace(_))
+    ));
+    assert_eq!(
+      list_spaces(&db_pool, "alice").await.unwrap(),
+      vec![space.clone()]
+    );
+
+    // Only the owner can rename, join or delete a space.
+    let rename = |user, name: &str| {
+      rename_space(
+        &db_pool,
+        user,
+        SpaceRename {
+          id: space.id,
+          name: name.into(),
+        },
+      )
+    };
+    assert!(matches!(
+      rename("bob", "Mine").await,
+      Err(Error::SpaceNotFound(_))
+    ));
+    assert_eq!(rename("alice", "Ideas").await.unwrap().name, "Ideas");
+    let mut connection = db_pool.acquire().await.unwrap();
+    assert!(authorize(&mut connection, space.id, "bob").await.is_err());
+    authorize(&mut connection, space.id, "alice").await.unwrap();
+    drop(connection);
+
+    fixtures::items(&db_pool, [item(1, space.id as i32)]).await;
+    assert!(delete_space(&db_pool, "bob", space.id).await.is_err());
+    delete_space(&db_pool, "alice", space.id).await.unwrap();
+    let items: i64 = sqlx::query_scalar("SELECT count(*) FROM item")
+      .fetch_one(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(items, 0);
+  }
+
+  #[sqlx::test(migrations = false)]
+  async fn test_legacy_items(db_pool: PgPool) {
+    // Stops right before spaces existed.
+    let migrator = sqlx::migrate!("../migrations");
+    let before_spaces = sqlx::migrate::Migrator {
+      migrations: migrator
+        .iter()
+        .filter(|migration| migration.version < 20261019170000)
+        .cloned()
+        .collect(),
+      ..sqlx::migrate!("../migrations")
+    };
+    before_spaces.run(&db_pool).await.unwrap();
+    sqlx::query("INSERT INTO item (x, y, w, h) VALUES (0, 0, 1, 1), (1, 1, 1, 1)")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    migrator.run(&db_pool).await.unwrap();
+
+    assert!(list_spaces(&db_pool, "alice").await.unwrap().is_empty());
+    assert_eq!(claim_legacy(&db_pool, "alice").await.unwrap(), 1);
+    assert_eq!(claim_legacy(&db_pool, "bob").await.unwrap(), 0);
+    let spaces = list_spaces(&db_pool, "alice").await.unwrap();
+    assert_eq!(spaces.len(), 1);
+    assert_eq!(spaces[0].name, "Default");
+    let mut connection = db_pool.acquire().await.unwrap();
+    for id in [1, 2] {
+      assert_eq!(
+        authorize_item(&mut connection, id, "alice").await.unwrap() as i64,
+        spaces[0].id
+      );
+    }
+  }
+}
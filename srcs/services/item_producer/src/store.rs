@@ -0,0 +1,61 @@
+use std::ops::Range;
+
+use async_trait::async_trait;
+use sqlx::PgPool;
+use utils::asset_store::{AssetStore, Error};
+
+/// Keeps asset content in the `asset_blob` table.
+pub struct DatabaseStore {
+  db_pool: PgPool,
+}
+
+impl DatabaseStore {
+  pub fn new(db_pool: PgPool) -> Self {
+    Self { db_pool }
+  }
+}
+
+#[async_trait]
+impl AssetStore for DatabaseStore {
+  async fn put(&self, id: &str, data: Vec<u8>) -> Result<(), Error> {
+    sqlx::query!(
+      r#"
+INSERT INTO asset_blob ( id, data ) VALUES ( $1, $2 )
+ON CONFLICT ( id ) DO UPDATE SET data = EXCLUDED.data
+      "#,
+      id,
+      data,
+    )
+    .execute(&self.db_pool)
+    .await
+    .map_err(|e| Error::Database(e.into()))?;
+    Ok(())
+  }
+
+  async fn get(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+    sqlx::query_scalar!("SELECT data FROM asset_blob WHERE id = $1", id)
+      .fetch_optional(&self.db_pool)
+      .await
+      .map_err(|e| Error::Database(e.into()))
+  }
+
+  async fn get_range(&self, id: &str, range: Range<u64>) -> Result<Option<Vec<u8>>, Error> {
+    sqlx::query_scalar!(
+      r#"SELECT substring(data FROM $2 FOR $3) AS "data!" FROM asset_blob WHERE id = $1"#,
+      id,
+      range.start as i32 + 1,
+      range.end.saturating_sub(range.start) as i32,
+    )
+    .fetch_optional(&self.db_pool)
+    .await
+    .map_err(|e| Error::Database(e.into()))
+  }
+
+  async fn delete(&self, id: &str) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM asset_blob WHERE id = $1", id)
+      .execute(&self.db_pool)
+      .await
+      .map_err(|e| Error::Database(e.into()))?;
+    Ok(())
+  }
+}
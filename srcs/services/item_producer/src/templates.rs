@@ -0,0 +1,498 @@
+//! Templates are items saved under a name to be laid out again, like the
+//! notes of a recurring meeting. A template keeps the items with the items
+//! they contain and the connectors between them, placed relative to their
+//! top-left corner, and shares their assets: each asset of a template counts
+//! as a reference, so it outlives the items the template was saved from.
+//! Instantiating a template creates new items, stacked on top of the space.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::PgPool;
+use utils::{archive, archive::Archive};
+
+use crate::{
+  clients,
+  connectors::{self, ConnectorData},
+  duplicates,
+  events::{self, Kind},
+  handlers::{link_assets, respond, Error},
+  history::{self, Snapshot},
+  item::{Connector, Item},
+  search, spaces, GlobalState,
+};
+
+#[derive(Serialize, PartialEq, Deserialize, Clone, Debug)]
+pub struct Template {
+  pub id: i64,
+  pub name: String,
+  /// Space the template is offered in, every space of its owner without it.
+  pub space_id: Option<i64>,
+}
+
+/// Saves `ids`, with the items they contain, as a template named `name`.
+#[derive(Debug, Deserialize)]
+pub struct TemplateSave {
+  pub ids: Vec<i64>,
+  pub name: String,
+  /// Offers the template in the space of the items only.
+  #[serde(default)]
+  pub space_only: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TemplateList {
+  /// Lists the templates offered in the space rather than all of them.
+  pub space_id: Option<i64>,
+}
+
+/// Creates the items of a template with their top-left corner at `x` and `y`.
+#[derive(Debug, Deserialize)]
+pub struct TemplateInstantiate {
+  pub id: i64,
+  pub space_id: i64,
+  pub x: i64,
+  pub y: i64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TemplateRef {
+  pub id: i64,
+}
+
+/// Stored content of a template, in the format of space archives. Item ids
+/// only relate the items to each other.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Content {
+  items: Vec<archive::Item>,
+  connectors: Vec<archive::Connector>,
+}
+
+fn check_name(name: &str) -> Result<(), Error> {
+  match name.trim().chars().count() {
+    0 => Err(Error::InvalidTemplate("name is empty")),
+    1..=100 => Ok(()),
+    _ => Err(Error::InvalidTemplate("name is longer than 100 characters")),
+  }
+}
+
+async fn save_template(
+  db_pool: &PgPool,
+  user: &str,
+  data: TemplateSave,
+) -> Result<Template, Error> {
+  check_name(&data.name)?;
+  if data.ids.is_empty() {
+    return Err(Error::InvalidTemplate("no items"));
+  }
+  let mut transaction = db_pool.begin().await?;
+  let mut space_id = None;
+  for &id in &data.ids {
+    let item_space_id = spaces::authorize_item(&mut transaction, id, user).await?;
+    if space_id
+      .replace(item_space_id)
+      .is_some_and(|id| id != item_space_id)
+    {
+      return Err(Error::InvalidTemplate("items are in different spaces"));
+    }
+  }
+
+  let ids = data.ids.iter().map(|&id| id as i32).collect::<Vec<_>>();
+  let items = duplicates::subtree(&mut transaction, &ids).await?;
+  let saved = items.iter().map(|item| item.id as i32).collect::<Vec<_>>();
+  let connectors = sqlx::query_as!(
+    Connector,
+    "SELECT * FROM connector WHERE source_id = ANY($1) AND target_id = ANY($1) ORDER BY id",
+    &saved,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+
+  // Items whose frame is left out become top-level items of the template.
+  let saved_ids = items.iter().map(|item| item.id).collect::<HashSet<_>>();
+  let x = items.iter().map(|item| item.x).min().unwrap_or_default();
+  let y = items.iter().map(|item| item.y).min().unwrap_or_default();
+  let content = Content {
+    items: items
+      .into_iter()
+      .map(|item| archive::Item {
+        id: item.id,
+        x: item.x - x,
+        y: item.y - y,
+        w: item.w,
+        h: item.h,
+        editor: item.editor,
+        schema: item.schema,
+        z_index: item.z_index,
+        parent_id: item.parent_id.filter(|id| saved_ids.contains(id)),
+      })
+      .collect(),
+    connectors: connectors
+      .into_iter()
+      .map(|connector| archive::Connector {
+        source_id: connector.source_id,
+        target_id: connector.target_id,
+        source_anchor: connector.source_anchor,
+        target_anchor: connector.target_anchor,
+        label: connector.label,
+        style: connector.style,
+      })
+      .collect(),
+  };
+
+  let template = sqlx::query_as!(
+    Template,
+    r#"
+INSERT INTO template ( owner, space_id, name, content ) VALUES ( $1, $2, $3, $4 )
+RETURNING id, name, space_id AS "space_id: i64"
+    "#,
+    user,
+    space_id.filter(|_| data.space_only),
+    data.name.trim(),
+    serde_json::to_string(&content)?,
+  )
+  .fetch_one(&mut *transaction)
+  .await?;
+  sqlx::query!(
+    r#"
+INSERT INTO template_assets ( template_id, asset_id )
+SELECT DISTINCT $1::INTEGER, asset_id FROM item_assets WHERE item_id = ANY($2)
+    "#,
+    template.id as i32,
+    &saved,
+  )
+  .execute(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+  Ok(template)
+}
+
+async fn list_templates(
+  db_pool: &PgPool,
+  user: &str,
+  data: TemplateList,
+) -> Result<Vec<Template>, Error> {
+  Ok(
+    sqlx::query_as!(
+      Template,
+      r#"
+SELECT id, name, space_id AS "space_id: i64" FROM template
+WHERE owner = $1 AND ($2::INTEGER IS NULL OR space_id IS NULL OR space_id = $2)
+ORDER BY name, id
+      "#,
+      user,
+      data.space_id.map(|id| id as i32),
+    )
+    .fetch_all(db_pool)
+    .await?,
+  )
+}
+
+async fn instantiate_template(
+  db_pool: &PgPool,
+  user: &str,
+  data: TemplateInstantiate,
+) -> Result<Vec<Item>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  spaces::authorize(&mut transaction, data.space_id, user).await?;
+  let content = sqlx::query_scalar!(
+    r#"
+SELECT content FROM template
+WHERE id = $1 AND owner = $2 AND (space_id IS NULL OR space_id = $3)
+    "#,
+    data.id as i32,
+    user,
+    data.space_id as i32,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::TemplateNotFound(data.id))?;
+  let content = serde_json::from_str::<Content>(&content)?;
+  let layout = Archive {
+    items: content.items,
+    connectors: content.connectors,
+    ..Default::default()
+  };
+
+  // Top-level items are stacked on top of the space in their order, the
+  // stacks they contain are created as they are.
+  let mut item_ids = HashMap::<i64, i64>::new();
+  let mut items = Vec::with_capacity(layout.items.len());
+  for item in layout.ordered_items()? {
+    let parent_id = item.parent_id.map(|id| item_ids[&id]);
+    let instance = sqlx::query_as!(
+      Item,
+      r#"
+INSERT INTO item ( space_id, x, y, w, h, schema, editor, z_index, parent_id )
+VALUES ( $1, $2, $3, $4, $5, $6, $7, COALESCE($8, (
+  SELECT COALESCE(MAX(z_index), 0) + 1 FROM item WHERE space_id = $1 AND parent_id IS NULL
+)), $9 )
+RETURNING id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+      "#,
+      data.space_id as i32,
+      (item.x + data.x) as i32,
+      (item.y + data.y) as i32,
+      item.w as i32,
+      item.h as i32,
+      item.schema,
+      item.editor,
+      parent_id.map(|_| item.z_index as i32),
+      parent_id.map(|id| id as i32),
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    item_ids.insert(item.id, instance.id);
+
+    let snapshot = Snapshot::of(&instance);
+    link_assets(&mut transaction, instance.id as i32, &snapshot.schema).await?;
+    search::index(&mut transaction, instance.id as i32, &snapshot.schema).await?;
+    history::record(
+      &mut transaction,
+      user,
+      "template",
+      &Snapshot::default(),
+      &instance,
+    )
+    .await?;
+    items.push(instance);
+  }
+
+  for connector in &layout.connectors {
+    let connector = ConnectorData {
+      source_id: item_ids[&connector.source_id],
+      target_id: item_ids[&connector.target_id],
+      source_anchor: connector.source_anchor.clone(),
+      target_anchor: connector.target_anchor.clone(),
+      label: connector.label.clone(),
+      style: connector.style.clone(),
+    };
+    connectors::insert(&mut transaction, data.space_id as i32, &connector).await?;
+  }
+  events::publish(&mut transaction, Kind::Created, &items).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+async fn delete_template(db_pool: &PgPool, user: &str, id: i64) -> Result<Template, Error> {
+  sqlx::query_as!(
+    Template,
+    r#"
+DELETE FROM template WHERE id = $1 AND owner = $2
+RETURNING id, name, space_id AS "space_id: i64"
+    "#,
+    id as i32,
+    user,
+  )
+  .fetch_optional(db_pool)
+  .await?
+  .ok_or(Error::TemplateNotFound(id))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn save(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<TemplateSave>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    save_template(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<TemplateList>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    list_templates(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn instantiate(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<TemplateInstantiate>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    instantiate_template(&db_pool, &clients::user(&socket), data).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn delete(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<TemplateRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    delete_template(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item, ItemRow};
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_templates(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::space(&db_pool, 2, "alice").await;
+    fixtures::space(&db_pool, 3, "bob").await;
+    fixtures::items(
+      &db_pool,
+      [
+        ItemRow {
+          x: 100,
+          y: 100,
+          w: 100,
+          h: 100,
+          z_index: 1,
+          schema: Some("[]"),
+          ..item(1, 1)
+        },
+        ItemRow {
+          parent_id: Some(1),
+          x: 110,
+          y: 110,
+          w: 10,
+          h: 10,
+          z_index: 1,
+          schema: Some(r#"[{"type": "image", "mime": "image/png", "name": "", "uuid": "a", "children": [{"text": ""}]}]"#),
+          ..item(2, 1)
+        },
+        ItemRow {
+          x: 300,
+          y: 100,
+          w: 10,
+          h: 10,
+          z_index: 2,
+          schema: Some("[]"),
+          ..item(3, 1)
+        },
+        ItemRow {
+          w: 10,
+          h: 10,
+          z_index: 1,
+          schema: Some("[]"),
+          ..item(4, 2)
+        },
+      ],
+    )
+    .await;
+    sqlx::query("INSERT INTO asset (id, name, mime) VALUES ('a', '', 'image/png')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (2, 'a')")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    sqlx::query("INSERT INTO connector (space_id, source_id, target_id) VALUES (1, 2, 3)")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+
+    let template = save_template(
+      &db_pool,
+      "alice",
+      TemplateSave {
+        ids: vec![1, 3],
+        name: " Meeting notes ".into(),
+        space_only: true,
+      },
+    )
+    .await
+    .unwrap();
+    assert_eq!(template.name, "Meeting notes");
+    assert_eq!(template.space_id, Some(1));
+    assert!(matches!(
+      save_template(
+        &db_pool,
+        "alice",
+        TemplateSave {
+          ids: vec![1, 4],
+          name: "Mixed".into(),
+          space_only: false,
+        },
+      )
+      .await,
+      Err(Error::InvalidTemplate(_))
+    ));
+    let list = |space_id| list_templates(&db_pool, "alice", TemplateList { space_id });
+    assert_eq!(list(Some(1)).await.unwrap(), vec![template.clone()]);
+    assert!(list(Some(2)).await.unwrap().is_empty());
+
+    // The template keeps the image once its items are gone.
+    sqlx::query("DELETE FROM item WHERE space_id = 1")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    let ref_count =
+      || sqlx::query_scalar!("SELECT ref_count FROM asset WHERE id = 'a'").fetch_one(&db_pool);
+    assert_eq!(ref_count().await.unwrap(), 1);
+
+    let instantiate = |space_id| {
+      instantiate_template(
+        &db_pool,
+        "alice",
+        TemplateInstantiate {
+          id: template.id,
+          space_id,
+          x: 10,
+          y: 20,
+        },
+      )
+    };
+    let items = instantiate(1).await.unwrap();
+    let layout = items
+      .iter()
+      .map(|item| (item.x, item.y, item.z_index, item.parent_id))
+      .collect::<Vec<_>>();
+    assert_eq!(
+      layout,
+      vec![
+        (10, 20, 1, None),
+        (210, 20, 2, None),
+        (20, 30, 1, Some(items[0].id)),
+      ]
+    );
+    assert_eq!(ref_count().await.unwrap(), 2);
+    let connector = sqlx::query!("SELECT source_id, target_id FROM connector")
+      .fetch_one(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(
+      (connector.source_id as i64, connector.target_id as i64),
+      (items[2].id, items[1].id)
+    );
+    assert!(matches!(
+      instantiate(2).await,
+      Err(Error::TemplateNotFound(_))
+    ));
+
+    assert!(matches!(
+      delete_template(&db_pool, "bob", template.id).await,
+      Err(Error::TemplateNotFound(_))
+    ));
+    delete_template(&db_pool, "alice", template.id)
+      .await
+      .unwrap();
+    assert_eq!(ref_count().await.unwrap(), 1);
+  }
+}
@@ -0,0 +1,444 @@
+//! Deleted items stay in the trash until they are restored, purged or expire.
+//! Deleting a frame trashes the items it contains along with it, and they are
+//! restored or purged together.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+use tracing::{error, info};
+
+use crate::{
+  clients,
+  events::{self, Kind},
+  handlers::{respond, Error},
+  history::{self, Snapshot},
+  item::Item,
+  layers::ItemRef,
+  spaces, GlobalState,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct TrashQuery {
+  pub space_id: i64,
+}
+
+/// Locks a trashed item owned by `user`, returning when it was deleted and
+/// the batch it was trashed in.
+async fn find_trashed(
+  connection: &mut PgConnection,
+  id: i64,
+  user: &str,
+) -> Result<(DateTime<Utc>, i64), Error> {
+  sqlx::query!(
+    r#"
+SELECT item.deleted_at AS "deleted_at!", item.trash_batch AS "trash_batch!" FROM item
+JOIN space ON space.id = item.space_id
+WHERE item.id = $1 AND space.owner = $2 AND item.deleted_at IS NOT NULL
+FOR UPDATE OF item
+    "#,
+    id as i32,
+    user,
+  )
+  .map(|row| (row.deleted_at, row.trash_batch))
+  .fetch_optional(connection)
+  .await?
+  .ok_or(Error::ItemNotFound(id))
+}
+
+/// Enqueues a `kind` event for every item, keeping the last version of each.
+async fn publish(
+  connection: &mut PgConnection,
+  kind: Kind,
+  changed: impl IntoIterator<Item = Item>,
+) -> Result<Vec<Item>, Error> {
+  let items = changed
+    .into_iter()
+    .map(|item| (item.id, item))
+    .collect::<BTreeMap<_, _>>()
+    .into_values()
+    .collect::<Vec<_>>();
+  events::publish(connection, kind, &items).await?;
+  Ok(items)
+}
+
+async fn trash_item(db_pool: &PgPool, user: &str, id: i64) -> Result<Vec<Item>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  spaces::authorize_item(&mut transaction, id, user).await?;
+
+  let trashed = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT $1::INTEGER
+  UNION
+  SELECT item.id FROM item
+  JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.deleted_at IS NULL
+)
+UPDATE item SET deleted_at = now(), trash_batch = $1, version = item.version + 1
+FROM subtree
+WHERE item.id = subtree.id
+RETURNING item.id, item.space_id, item.x, item.y, item.w, item.h, item.schema, item.editor,
+  item.z_index, item.parent_id AS "parent_id: i64", item.version, item.position_version,
+  item.size_version, item.schema_version, item.deleted_at, item.trash_batch
+    "#,
+    id as i32,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  for item in &trashed {
+    let before = Snapshot {
+      deleted_at: None,
+      ..Snapshot::of(item)
+    };
+    history::record(&mut transaction, user, "delete", &before, item).await?;
+  }
+  let items = publish(&mut transaction, Kind::Deleted, trashed).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+async fn list_trash(db_pool: &PgPool, user: &str, space_id: i64) -> Result<Vec<Item>, Error> {
+  let mut connection = db_pool.acquire().await?;
+  spaces::authorize(&mut connection, space_id, user).await?;
+  Ok(
+    sqlx::query_as!(
+      Item,
+      r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item
+WHERE space_id = $1 AND deleted_at IS NOT NULL
+ORDER BY deleted_at DESC, id
+      "#,
+      space_id as i32,
+    )
+    .fetch_all(&mut *connection)
+    .await?,
+  )
+}
+
+/// Restores the item along with the items trashed with it. An item whose frame
+/// is still in the trash is restored to the top level.
+async fn restore_item(db_pool: &PgPool, user: &str, id: i64) -> Result<Vec<Item>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let (deleted_at, batch) = find_trashed(&mut transaction, id, user).await?;
+
+  let restored = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT $1::INTEGER
+  UNION
+  SELECT item.id FROM item
+  JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.trash_batch = $2
+), frame AS (
+  SELECT parent.id FROM item
+  JOIN item AS parent ON parent.id = item.parent_id
+  WHERE item.id = $1 AND parent.deleted_at IS NOT NULL
+)
+UPDATE item
+SET deleted_at = NULL,
+  trash_batch = NULL,
+  version = item.version + 1,
+  parent_id = CASE WHEN item.parent_id IN (SELECT id FROM frame) THEN NULL ELSE item.parent_id END
+FROM subtree
+WHERE item.id = subtree.id
+RETURNING item.id, item.space_id, item.x, item.y, item.w, item.h, item.schema, item.editor,
+  item.z_index, item.parent_id AS "parent_id: i64", item.version, item.position_version,
+  item.size_version, item.schema_version, item.deleted_at, item.trash_batch
+    "#,
+    id as i32,
+    batch,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  for item in &restored {
+    let before = Snapshot {
+      deleted_at: Some(deleted_at.timestamp()),
+      ..Snapshot::of(item)
+    };
+    history::record(&mut transaction, user, "restore", &before, item).await?;
+  }
+  let items = publish(&mut transaction, Kind::Updated, restored).await?;
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+/// Records a `purge` for every item and enqueues their final state, then
+/// permanently deletes them, returning their ids.
+async fn purge_items(
+  connection: &mut PgConnection,
+  actor: &str,
+  purged: Vec<Item>,
+) -> Result<Vec<i64>, Error> {
+  for item in &purged {
+    history::record(&mut *connection, actor, "purge", &Snapshot::of(item), item).await?;
+  }
+  let items = publish(&mut *connection, Kind::Deleted, purged).await?;
+  let ids = items.iter().map(|item| item.id as i32).collect::<Vec<_>>();
+  sqlx::query!("DELETE FROM item WHERE id = ANY($1)", &ids)
+    .execute(&mut *connection)
+    .await?;
+  Ok(items.into_iter().map(|item| item.id).collect())
+}
+
+/// Permanently deletes the item along with the items trashed with it,
+/// returning their ids.
+async fn purge_item(db_pool: &PgPool, user: &str, id: i64) -> Result<Vec<i64>, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let (_, batch) = find_trashed(&mut transaction, id, user).await?;
+
+  let purged = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT $1::INTEGER
+  UNION
+  SELECT item.id FROM item
+  JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.trash_batch = $2
+)
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item WHERE id IN (SELECT id FROM subtree)
+FOR UPDATE
+    "#,
+    id as i32,
+    batch,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let ids = purge_items(&mut transaction, user, purged).await?;
+
+  transaction.commit().await?;
+  Ok(ids)
+}
+
+/// Permanently deletes items that have been in the trash for longer than
+/// `retention`, recording the purge without an actor. Their assets are left to
+/// the garbage collector.
+pub async fn purge_expired(db_pool: &PgPool, retention: Duration) -> Result<u64, Error> {
+  let mut transaction = db_pool.begin().await?;
+  let expired = sqlx::query_as!(
+    Item,
+    r#"
+SELECT id, space_id, x, y, w, h, schema, editor, z_index, parent_id AS "parent_id: i64",
+  version, position_version, size_version, schema_version, deleted_at, trash_batch
+FROM item WHERE deleted_at < now() - make_interval(secs => $1) FOR UPDATE
+    "#,
+    retention.as_secs_f64(),
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let ids = purge_items(&mut transaction, "", expired).await?;
+
+  transaction.commit().await?;
+  Ok(ids.len() as u64)
+}
+
+pub async fn background_task(db_pool: PgPool, interval: Duration, retention: Duration) {
+  let mut interval = tokio::time::interval(interval);
+  loop {
+    interval.tick().await;
+    match purge_expired(&db_pool, retention).await {
+      Ok(0) => {}
+      Ok(purged) => info!("Purged {} expired item(s) from the trash", purged),
+      Err(e) => error!("Trash purge failed: {}", e),
+    }
+  }
+}
+
+/// Moves an item, and every item it contains, to the trash.
+#[tracing::instrument(skip_all)]
+pub async fn delete(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    trash_item(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn list(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<TrashQuery>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    list_trash(&db_pool, &clients::user(&socket), data.space_id).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn restore(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    restore_item(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn purge(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<ItemRef>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    purge_item(&db_pool, &clients::user(&socket), data.id).await,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::fixtures::{self, item, ItemRow};
+
+  fn ids(items: Vec<Item>) -> Vec<i64> {
+    items.into_iter().map(|item| item.id).collect()
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_trash(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(
+      &db_pool,
+      [
+        item(1, 1),
+        ItemRow {
+          parent_id: Some(1),
+          ..item(2, 1)
+        },
+        item(3, 1),
+      ],
+    )
+    .await;
+    sqlx::query("INSERT INTO connector (space_id, source_id, target_id) VALUES (1, 2, 3)")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+
+    // The frame takes its child along, and the connector is hidden.
+    assert_eq!(
+      ids(trash_item(&db_pool, "alice", 1).await.unwrap()),
+      vec![1, 2]
+    );
+    assert!(matches!(
+      trash_item(&db_pool, "alice", 1).await,
+      Err(Error::ItemNotFound(1))
+    ));
+    assert!(crate::connectors::attached(&db_pool, &[3])
+      .await
+      .unwrap()
+      .is_empty());
+    assert_eq!(
+      ids(list_trash(&db_pool, "alice", 1).await.unwrap()),
+      vec![1, 2]
+    );
+    assert!(list_trash(&db_pool, "bob", 1).await.is_err());
+
+    // A child restored without its frame moves to the top level.
+    let restored = restore_item(&db_pool, "alice", 2).await.unwrap();
+    assert_eq!(restored.len(), 1);
+    assert_eq!(restored[0].parent_id, None);
+    assert_eq!(restored[0].deleted_at, None);
+    assert_eq!(
+      crate::connectors::attached(&db_pool, &[3])
+        .await
+        .unwrap()
+        .len(),
+      1
+    );
+
+    assert!(matches!(
+      purge_item(&db_pool, "alice", 3).await,
+      Err(Error::ItemNotFound(3))
+    ));
+    assert_eq!(purge_item(&db_pool, "alice", 1).await.unwrap(), vec![1]);
+    let actions: Vec<String> =
+      sqlx::query_scalar("SELECT action FROM item_history WHERE item_id = 1 ORDER BY id")
+        .fetch_all(&db_pool)
+        .await
+        .unwrap();
+    assert_eq!(actions, vec!["delete", "purge"]);
+    let events: Vec<String> = sqlx::query_scalar("SELECT routing_key FROM outbox ORDER BY id")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(events.last().map(String::as_str), Some("item.delete"));
+
+    trash_item(&db_pool, "alice", 3).await.unwrap();
+    assert_eq!(
+      purge_expired(&db_pool, Duration::from_secs(60))
+        .await
+        .unwrap(),
+      0
+    );
+    sqlx::query("UPDATE item SET deleted_at = deleted_at - INTERVAL '2 minutes'")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(
+      purge_expired(&db_pool, Duration::from_secs(60))
+        .await
+        .unwrap(),
+      1
+    );
+    let remaining: Vec<i32> = sqlx::query_scalar("SELECT id FROM item")
+      .fetch_all(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(remaining, vec![2]);
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_trash_batches(db_pool: PgPool) {
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(
+      &db_pool,
+      [
+        item(1, 1),
+        ItemRow {
+          parent_id: Some(1),
+          ..item(2, 1)
+        },
+      ],
+    )
+    .await;
+
+    // The child was trashed on its own, even if at the same time as its frame.
+    trash_item(&db_pool, "alice", 2).await.unwrap();
+    trash_item(&db_pool, "alice", 1).await.unwrap();
+    sqlx::query("UPDATE item SET deleted_at = '2026-01-01T00:00:00Z'")
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(
+      ids(restore_item(&db_pool, "alice", 1).await.unwrap()),
+      vec![1]
+    );
+    assert_eq!(
+      ids(list_trash(&db_pool, "alice", 1).await.unwrap()),
+      vec![2]
+    );
+  }
+}
@@ -0,0 +1,514 @@
+//! Chunked asset uploads. A client begins an upload with the size and SHA-256
+//! of the content, appends chunks at the offset the server reports and commits
+//! once every byte has arrived. The resulting asset id can be referenced by
+//! `item:create` and `item:update`.
+//!
+//! Uploads are stored in Postgres, one row per chunk, and keyed by their
+//! uploader and content, so a client that reconnects resumes by beginning the
+//! same upload again. Only the user who began an upload can append to or
+//! commit it.
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use socketioxide::extract::{AckSender, Data, SocketRef, State};
+use sqlx::{PgConnection, PgPool};
+use utils::asset_store::AssetStore;
+use uuid::Uuid;
+
+use crate::{
+  assets, clients,
+  handlers::{respond, Error},
+  item::Asset,
+  GlobalState,
+};
+
+/// Largest asset accepted through an upload, in bytes.
+pub const MAX_ASSET_SIZE: i64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadBegin {
+  pub size: i64,
+  /// Hex encoded SHA-256 of the complete content.
+  pub hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadChunk {
+  pub id: String,
+  pub offset: i64,
+  pub data: Bytes,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UploadCommit {
+  pub id: String,
+  /// Hex encoded SHA-256 of the complete content.
+  pub hash: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct UploadStatus {
+  pub id: String,
+  pub size: i64,
+  /// Where the next chunk has to start.
+  pub offset: i64,
+  /// Set once the content is stored, either by a commit or because an
+  /// identical asset already existed.
+  pub asset_id: Option<String>,
+}
+
+/// Starts an upload, or resumes the one `user` has in progress for the same
+/// content.
+pub async fn begin(
+  db_pool: &PgPool,
+  user: &str,
+  request: UploadBegin,
+) -> Result<UploadStatus, Error> {
+  let hash = request.hash.to_ascii_lowercase();
+  if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+    return Err(Error::InvalidUpload("hash must be a hex encoded SHA-256"));
+  }
+  if !(0..=MAX_ASSET_SIZE).contains(&request.size) {
+    return Err(Error::InvalidUpload("size exceeds the asset size limit"));
+  }
+
+  // Content is only skipped if `user` can already reach the stored asset,
+  // other users' asset ids are not disclosed. Anyone else uploads it in full
+  // and the commit resolves it to the stored asset.
+  let existing = sqlx::query_scalar!(
+    r#"
+SELECT asset.id FROM asset
+WHERE asset.hash = $1 AND (
+  EXISTS (
+    SELECT 1 FROM item_assets
+    JOIN item ON item.id = item_assets.item_id
+    JOIN space ON space.id = item.space_id
+    WHERE item_assets.asset_id = asset.id AND space.owner = $2
+  )
+  OR EXISTS (
+    SELECT 1 FROM template_assets
+    JOIN template ON template.id = template_assets.template_id
+    WHERE template_assets.asset_id = asset.id AND template.owner = $2
+  )
+)
+    "#,
+    hash,
+    user,
+  )
+  .fetch_optional(db_pool)
+  .await?;
+  if let Some(asset_id) = existing {
+    return Ok(UploadStatus {
+      id: asset_id.clone(),
+      size: request.size,
+      offset: request.size,
+      asset_id: Some(asset_id),
+    });
+  }
+
+  let upload = sqlx::query!(
+    r#"
+INSERT INTO asset_upload ( id, owner, hash, size )
+VALUES ( $1, $2, $3, $4 )
+ON CONFLICT ( owner, hash, size ) DO UPDATE SET updated_at = now()
+RETURNING id, received
+    "#,
+    Uuid::new_v4().to_string(),
+    user,
+    hash,
+    request.size,
+  )
+  .fetch_one(db_pool)
+  .await?;
+
+  Ok(UploadStatus {
+    id: upload.id,
+    size: request.size,
+    offset: upload.received,
+    asset_id: None,
+  })
+}
+
+/// Appends a chunk if it starts at the current offset. A chunk at any other
+/// offset is ignored and the status tells the client where to continue.
+pub async fn append(
+  db_pool: &PgPool,
+  user: &str,
+  chunk: UploadChunk,
+) -> Result<UploadStatus, Error> {
+  // Empty chunks add nothing and would take the place of the next chunk.
+  if !chunk.data.is_empty() {
+    let mut transaction = db_pool.begin().await?;
+    let appended = sqlx::query!(
+      r#"
+UPDATE asset_upload SET received = received + $4, updated_at = now()
+WHERE id = $1 AND owner = $3 AND received = $2 AND received + $4 <= size
+RETURNING size, received
+      "#,
+      chunk.id,
+      chunk.offset,
+      user,
+      chunk.data.len() as i64,
+    )
+    .fetch_optional(&mut *transaction)
+    .await?;
+    if let Some(upload) = appended {
+      sqlx::query!(
+        r#"
+INSERT INTO asset_upload_chunk ( upload_id, "offset", data )
+VALUES ( $1, $2, $3 )
+        "#,
+        chunk.id,
+        chunk.offset,
+        &chunk.data[..],
+      )
+      .execute(&mut *transaction)
+      .await?;
+      transaction.commit().await?;
+      return Ok(UploadStatus {
+        id: chunk.id,
+        size: upload.size,
+        offset: upload.received,
+        asset_id: None,
+      });
+    }
+  }
+
+  let upload = sqlx::query!(
+    "SELECT size, received FROM asset_upload WHERE id = $1 AND owner = $2",
+    chunk.id,
+    user,
+  )
+  .fetch_optional(db_pool)
+  .await?
+  .ok_or_else(|| Error::UploadNotFound(chunk.id.clone()))?;
+  if upload.received == chunk.offset && !chunk.data.is_empty() {
+    return Err(Error::InvalidUpload("chunk exceeds the declared size"));
+  }
+  Ok(UploadStatus {
+    id: chunk.id,
+    size: upload.size,
+    offset: upload.received,
+    asset_id: None,
+  })
+}
+
+/// Content of a complete upload.
+pub struct Content {
+  /// Hex encoded SHA-256 declared when the upload began.
+  pub hash: String,
+  pub data: Vec<u8>,
+}
+
+/// Removes the upload `id` of `user` once every byte has arrived and returns
+/// its chunks joined in order.
+pub async fn take(connection: &mut PgConnection, id: &str, user: &str) -> Result<Content, Error> {
+  let upload = sqlx::query!(
+    "SELECT hash, size, received FROM asset_upload WHERE id = $1 AND owner = $2 FOR UPDATE",
+    id,
+    user,
+  )
+  .fetch_optional(&mut *connection)
+  .await?
+  .ok_or_else(|| Error::UploadNotFound(id.to_string()))?;
+  if upload.received != upload.size {
+    return Err(Error::UploadIncomplete {
+      offset: upload.received,
+      size: upload.size,
+    });
+  }
+
+  let chunks = sqlx::query_scalar!(
+    r#"SELECT data FROM asset_upload_chunk WHERE upload_id = $1 ORDER BY "offset""#,
+    id,
+  )
+  .fetch_all(&mut *connection)
+  .await?;
+  sqlx::query!("DELETE FROM asset_upload WHERE id = $1", id)
+    .execute(&mut *connection)
+    .await?;
+  Ok(Content {
+    hash: upload.hash,
+    data: chunks.concat(),
+  })
+}
+
+/// Verifies the complete content against the checksum and stores it as an
+/// asset. Content that fails verification is discarded so the upload can be
+/// started over.
+pub async fn commit(
+  db_pool: &PgPool,
+  asset_store: &dyn AssetStore,
+  user: &str,
+  request: UploadCommit,
+) -> Result<UploadStatus, Error> {
+  let mut transaction = db_pool.begin().await?;
+
+  let upload = take(&mut transaction, &request.id, user).await?;
+  let size = upload.data.len() as i64;
+  let mut asset = Asset::new(upload.data);
+  let verified = if request.hash.to_ascii_lowercase() != upload.hash
+    || asset.hash.as_ref() != Some(&upload.hash)
+  {
+    Err(Error::ChecksumMismatch)
+  } else {
+    tokio::task::spawn_blocking(move || asset.inspect().map(|_| asset))
+      .await
+      .unwrap()
+      .map_err(Error::from)
+  };
+  let asset = match verified {
+    Ok(asset) => asset,
+    Err(e) => {
+      transaction.commit().await?;
+      return Err(e);
+    }
+  };
+  let asset_id = asset.id.clone();
+  let (existing_ids, new_assets) = assets::insert(&mut transaction, vec![asset]).await?;
+  let asset_id = existing_ids.get(&asset_id).cloned().unwrap_or(asset_id);
+
+  assets::commit(transaction, asset_store, new_assets).await?;
+  Ok(UploadStatus {
+    id: request.id,
+    size,
+    offset: size,
+    asset_id: Some(asset_id),
+  })
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upload_begin(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<UploadBegin>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(ack, begin(&db_pool, &clients::user(&socket), data).await);
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upload_append(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<UploadChunk>,
+  State(GlobalState { db_pool, .. }): State<GlobalState>,
+) {
+  respond(ack, append(&db_pool, &clients::user(&socket), data).await);
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn upload_commit(
+  socket: SocketRef,
+  ack: AckSender,
+  Data(data): Data<UploadCommit>,
+  State(GlobalState {
+    db_pool,
+    asset_store,
+    ..
+  }): State<GlobalState>,
+) {
+  respond(
+    ack,
+    commit(
+      &db_pool,
+      asset_store.as_ref(),
+      &clients::user(&socket),
+      data,
+    )
+    .await,
+  );
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use sha2::{Digest, Sha256};
+
+  use super::*;
+  use crate::{
+    fixtures::{self, item},
+    store::DatabaseStore,
+  };
+
+  /// A 1x1 PNG.
+  pub(crate) const PNG: [u8; 67] = [
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0a, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+    0x42, 0x60, 0x82,
+  ];
+
+  pub(crate) fn hash(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+  }
+
+  fn chunk(id: &str, offset: usize, len: usize) -> UploadChunk {
+    UploadChunk {
+      id: id.to_string(),
+      offset: offset as i64,
+      data: Bytes::copy_from_slice(&PNG[offset..offset + len]),
+    }
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_upload(db_pool: PgPool) {
+    let store = DatabaseStore::new(db_pool.clone());
+    let begin_request = || UploadBegin {
+      size: PNG.len() as i64,
+      hash: hash(&PNG),
+    };
+    let upload = begin(&db_pool, "alice", begin_request()).await.unwrap();
+    assert_eq!(upload.offset, 0);
+    assert_eq!(upload.asset_id, None);
+
+    let status = append(&db_pool, "alice", chunk(&upload.id, 0, 40))
+      .await
+      .unwrap();
+    assert_eq!(status.offset, 40);
+    // A repeated chunk is ignored.
+    let status = append(&db_pool, "alice", chunk(&upload.id, 0, 40))
+      .await
+      .unwrap();
+    assert_eq!(status.offset, 40);
+
+    // Beginning again after a reconnect resumes the same upload.
+    let resumed = begin(&db_pool, "alice", begin_request()).await.unwrap();
+    assert_eq!(
+      (resumed.id.as_str(), resumed.offset),
+      (upload.id.as_str(), 40)
+    );
+
+    let commit_request = || UploadCommit {
+      id: upload.id.clone(),
+      hash: hash(&PNG),
+    };
+    assert!(matches!(
+      commit(&db_pool, &store, "alice", commit_request()).await,
+      Err(Error::UploadIncomplete { offset: 40, .. })
+    ));
+    let oversized = UploadChunk {
+      id: upload.id.clone(),
+      offset: 40,
+      data: Bytes::from(vec![0; PNG.len() - 40 + 1]),
+    };
+    assert!(matches!(
+      append(&db_pool, "alice", oversized).await,
+      Err(Error::InvalidUpload(_))
+    ));
+
+    // An empty chunk changes nothing.
+    let status = append(&db_pool, "alice", chunk(&upload.id, 40, 0))
+      .await
+      .unwrap();
+    assert_eq!(status.offset, 40);
+
+    append(&db_pool, "alice", chunk(&upload.id, 40, PNG.len() - 40))
+      .await
+      .unwrap();
+    let chunks: i64 = sqlx::query_scalar("SELECT count(*) FROM asset_upload_chunk")
+      .fetch_one(&db_pool)
+      .await
+      .unwrap();
+    assert_eq!(chunks, 2);
+    let status = commit(&db_pool, &store, "alice", commit_request())
+      .await
+      .unwrap();
+    let asset_id = status.asset_id.unwrap();
+    let asset = sqlx::query!(
+      "SELECT mime, width, height, size FROM asset WHERE id = $1",
+      asset_id
+    )
+    .fetch_one(&db_pool)
+    .await
+    .unwrap();
+    assert_eq!(asset.mime, "image/png");
+    assert_eq!((asset.width, asset.height), (Some(1), Some(1)));
+    assert_eq!(asset.size, PNG.len() as i64);
+    assert_eq!(
+      store.get(&asset_id).await.unwrap().as_deref(),
+      Some(&PNG[..])
+    );
+
+    // Content an item of the user already shows is not uploaded again.
+    fixtures::space(&db_pool, 1, "alice").await;
+    fixtures::items(&db_pool, [item(1, 1)]).await;
+    sqlx::query("INSERT INTO item_assets (item_id, asset_id) VALUES (1, $1)")
+      .bind(&asset_id)
+      .execute(&db_pool)
+      .await
+      .unwrap();
+    let status = begin(&db_pool, "alice", begin_request()).await.unwrap();
+    assert_eq!(status.offset, status.size);
+    assert_eq!(status.asset_id, Some(asset_id.clone()));
+
+    // Other users upload it themselves and get the stored asset on commit.
+    let upload = begin(&db_pool, "bob", begin_request()).await.unwrap();
+    assert_eq!((upload.offset, upload.asset_id), (0, None));
+    assert!(matches!(
+      append(&db_pool, "eve", chunk(&upload.id, 0, PNG.len())).await,
+      Err(Error::UploadNotFound(_))
+    ));
+    append(&db_pool, "bob", chunk(&upload.id, 0, PNG.len()))
+      .await
+      .unwrap();
+    let commit_request = UploadCommit {
+      id: upload.id.clone(),
+      hash: hash(&PNG),
+    };
+    assert!(matches!(
+      commit(&db_pool, &store, "eve", commit_request).await,
+      Err(Error::UploadNotFound(_))
+    ));
+    let commit_request = UploadCommit {
+      id: upload.id,
+      hash: hash(&PNG),
+    };
+    let status = commit(&db_pool, &store, "bob", commit_request)
+      .await
+      .unwrap();
+    assert_eq!(status.asset_id, Some(asset_id));
+  }
+
+  #[sqlx::test(migrations = "../migrations")]
+  async fn test_upload_checksum_mismatch(db_pool: PgPool) {
+    let store = DatabaseStore::new(db_pool.clone());
+    let mut corrupted = PNG;
+    corrupted[60] ^= 0xff;
+    let upload = begin(
+      &db_pool,
+      "alice",
+      UploadBegin {
+        size: PNG.len() as i64,
+        hash: hash(&PNG),
+      },
+    )
+    .await
+    .unwrap();
+    append(
+      &db_pool,
+      "alice",
+      UploadChunk {
+        id: upload.id.clone(),
+        offset: 0,
+        data: Bytes::copy_from_slice(&corrupted),
+      },
+    )
+    .await
+    .unwrap();
+
+    let commit_request = || UploadCommit {
+      id: upload.id.clone(),
+      hash: hash(&PNG),
+    };
+    assert!(matches!(
+      commit(&db_pool, &store, "alice", commit_request()).await,
+      Err(Error::ChecksumMismatch)
+    ));
+    // The corrupted content is discarded.
+    assert!(matches!(
+      commit(&db_pool, &store, "alice", commit_request()).await,
+      Err(Error::UploadNotFound(_))
+    ));
+  }
+}
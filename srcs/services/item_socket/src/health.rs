@@ -0,0 +1,28 @@
+//! Liveness and readiness of the service. Documents live in memory, so the
+//! only dependency is the document store being readable.
+
+use axum::extract::State;
+use utils::health::{self, Check, Report};
+
+use crate::SocketState;
+
+async fn checks(state: &SocketState) -> [(&'static str, Check); 1] {
+  let documents = state.documents.clone();
+  // A shard of the store held by a stuck writer blocks readers, off the
+  // runtime so that the check can time out.
+  let documents = health::check(async move {
+    tokio::task::spawn_blocking(move || documents.len())
+      .await
+      .map(|_| ())
+  })
+  .await;
+  [("documents", documents)]
+}
+
+pub async fn healthz(State(state): State<SocketState>) -> Report {
+  Report::liveness(checks(&state).await)
+}
+
+pub async fn readyz(State(state): State<SocketState>) -> Report {
+  Report::readiness(checks(&state).await)
+}
@@ -16,6 +16,7 @@ use utils::shutdown_task;
 use yrs::sync::Awareness;
 
 mod document;
+mod health;
 mod metrics;
 mod y;
 
@@ -87,8 +88,9 @@ async fn app() -> anyhow::Result<Router> {
   let metrics = Metrics::new(&mut registry);
   let metrics = Arc::new(Mutex::new(metrics));
 
+  let socket_state = SocketState::default();
   let (io_layer, io) = SocketIo::builder()
-    .with_state(SocketState::default())
+    .with_state(socket_state.clone())
     .with_state(metrics.clone())
     .build_layer();
 
@@ -121,7 +123,13 @@ async fn app() -> anyhow::Result<Router> {
     .layer(ServiceBuilder::new().layer(io_layer))
     .route("/metrics", get(metrics_handler))
     .with_state(state)
-    .with_state(metrics);
+    .with_state(metrics)
+    .merge(
+      Router::new()
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .with_state(socket_state),
+    );
 
   Ok(router)
 }
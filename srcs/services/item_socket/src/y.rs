@@ -1,6 +1,5 @@
 use rmpv::Value;
 use std::{sync::Arc, time::Instant};
-use tracing;
 use yrs::{
   sync::{Awareness, AwarenessUpdate},
   updates::{decoder::Decode, encoder::Encode},
@@ -95,6 +94,7 @@ pub fn init_awareness_listeners(socket: &SocketRef) {
   );
 }
 
+#[allow(dead_code)]
 pub async fn init_socket_listeners(socket: &SocketRef) {
   socket.on_disconnect(
     move |socket: SocketRef,
@@ -106,7 +106,7 @@ pub async fn init_socket_listeners(socket: &SocketRef) {
       metrics.inc_disconnects("/yjs|all", format!("{reason}"));
 
       let doc_ns = socket.ns().replace("/yjs|", "");
-      if socket.broadcast().sockets().len() == 0 {
+      if socket.broadcast().sockets().is_empty() {
         documents.remove(&doc_ns);
         metrics.dec_open_documents();
       }
@@ -0,0 +1,33 @@
+-- Asset ids are UUIDs, which do not fit the original VARCHAR(16).
+ALTER TABLE item_assets ALTER COLUMN asset_id TYPE VARCHAR(36);
+ALTER TABLE asset ALTER COLUMN id TYPE VARCHAR(36);
+
+ALTER TABLE asset ADD COLUMN hash VARCHAR(64) UNIQUE;
+ALTER TABLE asset ADD COLUMN ref_count INTEGER NOT NULL DEFAULT 0;
+
+UPDATE asset SET ref_count = (
+  SELECT COUNT(*) FROM item_assets WHERE item_assets.asset_id = asset.id
+);
+
+-- Keep ref_count in sync with item_assets, including rows removed through
+-- ON DELETE CASCADE, and drop a blob once its last item is gone.
+CREATE OR REPLACE FUNCTION item_assets_inc_ref_count() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE asset SET ref_count = ref_count + 1 WHERE id = NEW.asset_id;
+  RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE OR REPLACE FUNCTION item_assets_dec_ref_count() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE asset SET ref_count = ref_count - 1 WHERE id = OLD.asset_id;
+  DELETE FROM asset WHERE id = OLD.asset_id AND ref_count <= 0;
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER item_assets_insert AFTER INSERT ON item_assets
+FOR EACH ROW EXECUTE FUNCTION item_assets_inc_ref_count();
+
+CREATE TRIGGER item_assets_delete AFTER DELETE ON item_assets
+FOR EACH ROW EXECUTE FUNCTION item_assets_dec_ref_count();
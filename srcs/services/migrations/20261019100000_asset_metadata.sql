@@ -0,0 +1,3 @@
+ALTER TABLE asset ADD COLUMN width INTEGER;
+ALTER TABLE asset ADD COLUMN height INTEGER;
+ALTER TABLE asset ADD COLUMN thumbnail BYTEA;
@@ -0,0 +1,15 @@
+-- Set when an asset loses its last reference; the garbage collector deletes it
+-- once the grace period has passed.
+ALTER TABLE asset ADD COLUMN orphaned_at TIMESTAMPTZ;
+
+UPDATE asset SET orphaned_at = now() WHERE ref_count <= 0;
+
+CREATE OR REPLACE FUNCTION item_assets_dec_ref_count() RETURNS TRIGGER AS $$
+BEGIN
+  UPDATE asset
+  SET ref_count = ref_count - 1,
+    orphaned_at = CASE WHEN ref_count <= 1 THEN now() ELSE orphaned_at END
+  WHERE id = OLD.asset_id;
+  RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
@@ -0,0 +1,23 @@
+-- Partially uploaded assets. Sessions belong to the user who began them and
+-- are keyed by the content they will produce, so a client that reconnects
+-- picks up where it left off.
+CREATE TABLE IF NOT EXISTS asset_upload (
+  id VARCHAR(36) PRIMARY KEY NOT NULL,
+  owner VARCHAR(100) NOT NULL,
+  hash VARCHAR(64) NOT NULL,
+  size BIGINT NOT NULL,
+  -- Number of bytes received, which is where the next chunk has to start.
+  received BIGINT NOT NULL DEFAULT 0,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  UNIQUE (owner, hash, size)
+);
+
+-- Chunks of an upload in progress, one row each, keyed by where they start in
+-- the content. Appending inserts a row instead of rewriting the content
+-- received so far, and the commit joins them in order.
+CREATE TABLE IF NOT EXISTS asset_upload_chunk (
+  upload_id VARCHAR(36) NOT NULL REFERENCES asset_upload (id) ON DELETE CASCADE,
+  "offset" BIGINT NOT NULL,
+  data BYTEA NOT NULL,
+  PRIMARY KEY (upload_id, "offset")
+);
@@ -0,0 +1,13 @@
+-- Asset content moves out of the metadata table. The database asset store
+-- keeps it here, other stores leave this table empty.
+CREATE TABLE IF NOT EXISTS asset_blob (
+  id VARCHAR(36) PRIMARY KEY NOT NULL,
+  data BYTEA NOT NULL
+);
+
+ALTER TABLE asset ADD COLUMN size BIGINT NOT NULL DEFAULT 0;
+
+UPDATE asset SET size = octet_length(data) WHERE data IS NOT NULL;
+INSERT INTO asset_blob (id, data) SELECT id, data FROM asset WHERE data IS NOT NULL;
+
+ALTER TABLE asset DROP COLUMN data;
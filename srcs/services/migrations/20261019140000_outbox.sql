@@ -0,0 +1,23 @@
+-- Item events waiting to be published, written in the same transaction as the
+-- change they describe.
+CREATE TABLE IF NOT EXISTS outbox (
+  id BIGSERIAL PRIMARY KEY,
+  idempotency_key VARCHAR(36) NOT NULL UNIQUE,
+  routing_key VARCHAR(255) NOT NULL,
+  payload BYTEA NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  sent_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (id) WHERE sent_at IS NULL;
+
+-- Wakes the relay once the enqueuing transaction commits.
+CREATE OR REPLACE FUNCTION outbox_notify() RETURNS TRIGGER AS $$
+BEGIN
+  PERFORM pg_notify('outbox', '');
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER outbox_insert AFTER INSERT ON outbox
+FOR EACH STATEMENT EXECUTE FUNCTION outbox_notify();
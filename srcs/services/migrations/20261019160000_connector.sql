@@ -0,0 +1,14 @@
+-- Links between two items, drawn from an anchor side of the source to one of
+-- the target. Connectors go away with either item.
+CREATE TABLE IF NOT EXISTS connector (
+  id SERIAL PRIMARY KEY,
+  source_id INTEGER NOT NULL REFERENCES item (id) ON DELETE CASCADE,
+  target_id INTEGER NOT NULL REFERENCES item (id) ON DELETE CASCADE,
+  source_anchor VARCHAR(16) NOT NULL DEFAULT 'auto',
+  target_anchor VARCHAR(16) NOT NULL DEFAULT 'auto',
+  label TEXT,
+  style TEXT
+);
+
+CREATE INDEX IF NOT EXISTS connector_source_id ON connector (source_id);
+CREATE INDEX IF NOT EXISTS connector_target_id ON connector (target_id);
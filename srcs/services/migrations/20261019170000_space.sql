@@ -0,0 +1,24 @@
+-- Boards owned by a user. Every item and connector belongs to one space and
+-- goes away with it.
+CREATE TABLE IF NOT EXISTS space (
+  id SERIAL PRIMARY KEY,
+  -- Items from before spaces existed are kept in a space without owner.
+  owner VARCHAR(100),
+  name VARCHAR(100) NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS space_owner ON space (owner);
+
+INSERT INTO space (name) SELECT 'Default' WHERE EXISTS (SELECT 1 FROM item);
+
+ALTER TABLE item ADD COLUMN space_id INTEGER REFERENCES space (id) ON DELETE CASCADE;
+UPDATE item SET space_id = (SELECT min(id) FROM space);
+ALTER TABLE item ALTER COLUMN space_id SET NOT NULL;
+
+ALTER TABLE connector ADD COLUMN space_id INTEGER REFERENCES space (id) ON DELETE CASCADE;
+UPDATE connector SET space_id = item.space_id FROM item WHERE item.id = connector.source_id;
+ALTER TABLE connector ALTER COLUMN space_id SET NOT NULL;
+
+DROP INDEX IF EXISTS item_parent_id;
+CREATE INDEX IF NOT EXISTS item_space_id ON item (space_id, parent_id, z_index);
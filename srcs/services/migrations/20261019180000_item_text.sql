@@ -0,0 +1,27 @@
+-- Plain text of the Slate schema of each item, maintained by item_producer and
+-- indexed for full-text search.
+CREATE TABLE IF NOT EXISTS item_text (
+  item_id INTEGER PRIMARY KEY REFERENCES item (id) ON DELETE CASCADE,
+  content TEXT NOT NULL,
+  document TSVECTOR NOT NULL GENERATED ALWAYS AS (to_tsvector('english', content)) STORED
+);
+
+CREATE INDEX IF NOT EXISTS item_text_document ON item_text USING GIN (document);
+
+-- Existing items are indexed from every text leaf of their schema. Schemas
+-- that are not JSON are skipped.
+DO $$
+DECLARE
+  row RECORD;
+BEGIN
+  FOR row IN SELECT id, schema FROM item WHERE schema IS NOT NULL LOOP
+    BEGIN
+      INSERT INTO item_text ( item_id, content )
+      SELECT row.id, COALESCE(string_agg(text #>> '{}', E'\n'), '')
+      FROM jsonb_path_query(row.schema::JSONB, 'strict $.**.text') AS text;
+    EXCEPTION WHEN invalid_text_representation THEN
+      CONTINUE;
+    END;
+  END LOOP;
+END
+$$;
@@ -0,0 +1,9 @@
+-- Items count their changes for optimistic concurrency. Every group of fields
+-- that can be patched on its own records the version that last changed it, so
+-- a write based on an older version only conflicts with changes to the fields
+-- it touches.
+ALTER TABLE item
+  ADD COLUMN version INTEGER NOT NULL DEFAULT 1,
+  ADD COLUMN position_version INTEGER NOT NULL DEFAULT 1,
+  ADD COLUMN size_version INTEGER NOT NULL DEFAULT 1,
+  ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 1;
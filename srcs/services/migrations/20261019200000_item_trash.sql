@@ -0,0 +1,7 @@
+-- Deleted items go to the trash until they are restored or purged. Items
+-- trashed together share the id of the item whose deletion trashed them as
+-- batch, and are restored or purged together.
+ALTER TABLE item ADD COLUMN deleted_at TIMESTAMPTZ;
+ALTER TABLE item ADD COLUMN trash_batch BIGINT;
+
+CREATE INDEX IF NOT EXISTS item_deleted_at ON item (deleted_at) WHERE deleted_at IS NOT NULL;
@@ -0,0 +1,57 @@
+-- Append-only audit trail of item changes. Every row holds who changed the
+-- item, how and the field-level diff as JSON, see history.rs. Rows outlive the
+-- item they describe.
+CREATE TABLE IF NOT EXISTS item_history (
+  id BIGSERIAL PRIMARY KEY,
+  item_id INTEGER NOT NULL,
+  actor VARCHAR(100) NOT NULL,
+  action VARCHAR(16) NOT NULL,
+  changes TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS item_history_item_id ON item_history (item_id, id);
+
+CREATE OR REPLACE FUNCTION item_history_append_only() RETURNS TRIGGER AS $$
+BEGIN
+  RAISE EXCEPTION 'item_history is append-only';
+END
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER item_history_append_only
+BEFORE UPDATE OR DELETE ON item_history
+FOR EACH ROW EXECUTE FUNCTION item_history_append_only();
+
+-- Existing items start with a creation entry holding their current state, so
+-- later revisions can be rebuilt. Schemas that are not a JSON array count as
+-- empty.
+DO $$
+DECLARE
+  row RECORD;
+  blocks JSONB;
+BEGIN
+  FOR row IN SELECT * FROM item LOOP
+    BEGIN
+      blocks := row.schema::JSONB;
+    EXCEPTION WHEN invalid_text_representation THEN
+      blocks := NULL;
+    END;
+    IF blocks IS NULL OR jsonb_typeof(blocks) <> 'array' THEN
+      blocks := '[]';
+    END IF;
+    INSERT INTO item_history ( item_id, actor, action, changes )
+    SELECT row.id, COALESCE(space.owner, ''), 'create', jsonb_build_object(
+      'x', jsonb_build_array(0, row.x),
+      'y', jsonb_build_array(0, row.y),
+      'w', jsonb_build_array(0, row.w),
+      'h', jsonb_build_array(0, row.h),
+      'schema', (
+        SELECT COALESCE(jsonb_agg(jsonb_build_object('index', index - 1, 'from', NULL, 'to', block) ORDER BY index), '[]')
+        FROM jsonb_array_elements(blocks) WITH ORDINALITY AS blocks ( block, index )
+      ),
+      'deleted_at', jsonb_build_array(NULL, extract(epoch FROM row.deleted_at)::BIGINT)
+    )::TEXT
+    FROM space WHERE space.id = row.space_id;
+  END LOOP;
+END
+$$;
@@ -0,0 +1,3 @@
+-- The editor an item is shown in, so that every client renders it the same way.
+ALTER TABLE item
+  ADD COLUMN editor VARCHAR(20) NOT NULL DEFAULT 'rich' CHECK (editor IN ('markdown', 'rich'));
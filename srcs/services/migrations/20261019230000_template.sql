@@ -0,0 +1,29 @@
+-- Items saved as a reusable layout, e.g. meeting notes. A template of a space
+-- is offered in that space only, one without a space in every space of its
+-- owner.
+CREATE TABLE IF NOT EXISTS template (
+  id SERIAL PRIMARY KEY,
+  owner VARCHAR(100) NOT NULL,
+  space_id INTEGER REFERENCES space (id) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  -- The items and the connectors between them as JSON, see templates.rs.
+  content TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS template_owner ON template (owner, space_id);
+
+-- Assets referenced by the items of a template. Each link counts as a
+-- reference like those of item_assets, so the assets outlive the items the
+-- template was saved from.
+CREATE TABLE IF NOT EXISTS template_assets (
+  template_id INTEGER REFERENCES template (id) ON DELETE CASCADE,
+  asset_id VARCHAR(36) REFERENCES asset (id) ON UPDATE CASCADE ON DELETE CASCADE,
+  PRIMARY KEY (template_id, asset_id)
+);
+
+CREATE TRIGGER template_assets_insert AFTER INSERT ON template_assets
+FOR EACH ROW EXECUTE FUNCTION item_assets_inc_ref_count();
+
+CREATE TRIGGER template_assets_delete AFTER DELETE ON template_assets
+FOR EACH ROW EXECUTE FUNCTION item_assets_dec_ref_count();
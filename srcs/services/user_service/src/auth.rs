@@ -1,18 +1,12 @@
-use std::time::{SystemTime, UNIX_EPOCH};
-
 use axum::http::StatusCode;
-use jsonwebtoken::{encode, DecodingKey, EncodingKey, Header};
-use serde::{Deserialize, Serialize};
-use utils::axum::ApiError;
+use serde::Serialize;
+use utils::{
+  axum::ApiError,
+  jwt::{self, Claims, TokenUse},
+};
 
 use crate::handlers::KEYS;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Claims {
-  pub sub: String,
-  pub exp: usize,
-}
-
 // #[async_trait]
 // impl<S> FromRequestParts<S> for Claims
 // where
@@ -26,31 +20,15 @@ pub struct Claims {
 //       .await
 //       .map_err(|_| AuthError::InvalidToken)?;
 
-//     let token_data = decode::<Claims>(bearer.token(), &KEYS.decoding, &Validation::default())
-//       .map_err(|_| AuthError::InvalidToken)?;
-
-//     Ok(token_data.claims)
+//     jwt::verify(bearer.token(), &KEYS.decoding, TokenUse::Access)
+//       .map_err(|_| AuthError::InvalidToken)
 //   }
 // }
 
-pub struct Keys {
-  pub encoding: EncodingKey,
-  pub decoding: DecodingKey,
-}
-
-impl Keys {
-  pub fn new(secret: &[u8]) -> Self {
-    Self {
-      encoding: EncodingKey::from_secret(secret),
-      decoding: DecodingKey::from_secret(secret),
-    }
-  }
-}
-
 #[derive(Debug, Serialize)]
 pub struct AuthBody {
   pub access_token: String,
-  pub expires_in: usize,
+  pub expires_in: u64,
   pub refresh_token: String,
   pub token_type: String,
 }
@@ -59,49 +37,28 @@ impl AuthBody {
   fn new(access_token: String, refresh_token: String) -> Self {
     Self {
       access_token,
-      expires_in: 3600,
+      expires_in: TokenUse::Access.lifetime(),
       refresh_token,
       token_type: "Bearer".to_string(),
     }
   }
 }
 
+/// Issues an access token and a refresh token for `sub`.
 pub fn create_jwt_response(sub: String) -> Result<AuthBody, ApiError> {
-  let expires = 3600;
-  let iat = SystemTime::now()
-    .duration_since(UNIX_EPOCH)
-    .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?
-    .as_secs() as usize;
-
-  let access_token = encode(
-    &Header::default(),
-    &Claims {
-      sub: sub.to_string(),
-      exp: iat + expires,
-    },
-    &KEYS.encoding,
-  )
-  .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?;
-
+  let sign = |token_use| {
+    jwt::sign(&Claims::new(sub.clone(), token_use), &KEYS.encoding)
+      .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))
+  };
   Ok(AuthBody::new(
-    access_token,
-    encode(
-      &Header::default(),
-      &Claims {
-        sub,
-        // Refresh token expires in a month.
-        exp: (iat + 60 * 60 * 24 * 30),
-      },
-      &KEYS.encoding,
-    )
-    .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?,
+    sign(TokenUse::Access)?,
+    sign(TokenUse::Refresh)?,
   ))
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  use jsonwebtoken::{decode, Validation};
 
   #[tokio::test]
   async fn test_create_jwt_response() {
@@ -115,24 +72,20 @@ mod tests {
 
     let auth_body = result.unwrap();
 
-    let token_data = decode::<Claims>(
-      &auth_body.access_token,
-      &KEYS.decoding,
-      &Validation::default(),
-    )
-    .expect("Failed to decode access token");
-
-    assert_eq!(token_data.claims.sub, sub);
-    assert!(token_data.claims.exp > 0);
-
-    let token_data = decode::<Claims>(
-      &auth_body.refresh_token,
-      &KEYS.decoding,
-      &Validation::default(),
-    )
-    .expect("Failed to decode refresh token");
-
-    assert_eq!(token_data.claims.sub, sub);
-    assert!(token_data.claims.exp > 0);
+    let claims = jwt::verify(&auth_body.access_token, &KEYS.decoding, TokenUse::Access)
+      .expect("Failed to decode access token");
+
+    assert_eq!(claims.sub, sub);
+    assert!(claims.exp > claims.iat);
+
+    let claims = jwt::verify(&auth_body.refresh_token, &KEYS.decoding, TokenUse::Refresh)
+      .expect("Failed to decode refresh token");
+
+    assert_eq!(claims.sub, sub);
+    assert!(claims.exp > claims.iat);
+
+    // Neither token passes for the other.
+    assert!(jwt::verify(&auth_body.access_token, &KEYS.decoding, TokenUse::Refresh).is_err());
+    assert!(jwt::verify(&auth_body.refresh_token, &KEYS.decoding, TokenUse::Access).is_err());
   }
 }
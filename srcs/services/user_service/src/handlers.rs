@@ -2,15 +2,22 @@ use argon2::{
   password_hash::{rand_core::OsRng, SaltString},
   Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
+use std::sync::Arc;
+
 use axum::{extract::State, http::StatusCode};
 use dotenvy::dotenv;
-use jsonwebtoken::{decode, Algorithm, Validation};
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use sqlx::PgPool;
-use utils::axum::{ApiError, Json};
+use utils::{
+  axum::{ApiError, Json},
+  jwt::{self, Keys, TokenUse},
+};
 
-use crate::auth::{create_jwt_response, AuthBody, Claims, Keys};
+use crate::{
+  auth::{create_jwt_response, AuthBody},
+  metrics::{Endpoint, Metrics, Operation},
+};
 
 #[allow(dead_code)]
 #[derive(sqlx::FromRow, Deserialize)]
@@ -47,14 +54,27 @@ pub struct AuthPayload {
 
 pub async fn register_email(
   State(pool): State<PgPool>,
+  State(metrics): State<Arc<Metrics>>,
   Json(payload): Json<RegisterUserPayload>,
+) -> Result<Json<AuthBody>, ApiError> {
+  let result = register(&pool, &metrics, payload).await;
+  metrics.observe(Endpoint::Register, &result);
+  result
+}
+
+async fn register(
+  pool: &PgPool,
+  metrics: &Metrics,
+  payload: RegisterUserPayload,
 ) -> Result<Json<AuthBody>, ApiError> {
   let email = payload.email.to_lowercase();
   let username = payload.username;
   let argon2 = Argon2::default();
   let salt = SaltString::generate(&mut OsRng);
-  let password_hash = argon2
-    .hash_password(payload.password.as_bytes(), &salt)
+  let password_hash = metrics
+    .time_hash(Operation::Hash, || {
+      argon2.hash_password(payload.password.as_bytes(), &salt)
+    })
     .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?
     .to_string()
     .into_bytes();
@@ -87,14 +107,25 @@ pub async fn register_email(
 
 pub async fn email_login(
   State(pool): State<PgPool>,
+  State(metrics): State<Arc<Metrics>>,
   Json(payload): Json<LoginUserPayload>,
+) -> Result<Json<AuthBody>, ApiError> {
+  let result = login(&pool, &metrics, payload).await;
+  metrics.observe(Endpoint::Login, &result);
+  result
+}
+
+async fn login(
+  pool: &PgPool,
+  metrics: &Metrics,
+  payload: LoginUserPayload,
 ) -> Result<Json<AuthBody>, ApiError> {
   let user: User = sqlx::query_as!(
     User,
     r#"SELECT * FROM "user" WHERE email = $1;"#,
     payload.email,
   )
-  .fetch_one(&pool)
+  .fetch_one(pool)
   .await
   .map_err(|_| ApiError(StatusCode::NOT_FOUND, Some("User not found.".into())))?;
 
@@ -103,24 +134,30 @@ pub async fn email_login(
   let password = std::str::from_utf8(&user.password)
     .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?;
 
-  argon2
-    .verify_password(
-      payload.password.as_bytes(),
-      &PasswordHash::new(password)
-        .map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?,
-    )
+  let password_hash =
+    PasswordHash::new(password).map_err(|_| ApiError(StatusCode::INTERNAL_SERVER_ERROR, None))?;
+  metrics
+    .time_hash(Operation::Verify, || {
+      argon2.verify_password(payload.password.as_bytes(), &password_hash)
+    })
     .map_err(|_| ApiError(StatusCode::UNAUTHORIZED, Some("Wrong credentials".into())))?;
 
   Ok(Json(create_jwt_response(user.id.to_string())?))
 }
 
-pub async fn refresh_token(Json(payload): Json<AuthPayload>) -> Result<Json<AuthBody>, ApiError> {
-  let data = decode::<Claims>(
-    &payload.refresh_token,
-    &KEYS.decoding,
-    &Validation::new(Algorithm::HS256),
-  )
-  .map_err(|_| ApiError(StatusCode::BAD_REQUEST, Some("Invalid token".into())))?;
+pub async fn refresh_token(
+  State(metrics): State<Arc<Metrics>>,
+  Json(payload): Json<AuthPayload>,
+) -> Result<Json<AuthBody>, ApiError> {
+  let result = refresh(payload);
+  metrics.observe(Endpoint::Refresh, &result);
+  result
+}
+
+fn refresh(payload: AuthPayload) -> Result<Json<AuthBody>, ApiError> {
+  // Access tokens are rejected, only refresh tokens are meant for this.
+  let claims = jwt::verify(&payload.refresh_token, &KEYS.decoding, TokenUse::Refresh)
+    .map_err(|_| ApiError(StatusCode::BAD_REQUEST, Some("Invalid token".into())))?;
 
-  Ok(Json(create_jwt_response(data.claims.sub)?))
+  Ok(Json(create_jwt_response(claims.sub)?))
 }
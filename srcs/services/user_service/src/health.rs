@@ -0,0 +1,19 @@
+//! Liveness and readiness of the service, checking the database.
+
+use axum::extract::State;
+use sqlx::PgPool;
+use utils::health::{self, Check, Report};
+
+async fn checks(db_pool: &PgPool) -> [(&'static str, Check); 1] {
+  let postgres =
+    health::check(async { sqlx::query("SELECT 1").execute(db_pool).await.map(|_| ()) }).await;
+  [("postgres", postgres)]
+}
+
+pub async fn healthz(State(db_pool): State<PgPool>) -> Report {
+  Report::liveness(checks(&db_pool).await)
+}
+
+pub async fn readyz(State(db_pool): State<PgPool>) -> Report {
+  Report::readiness(checks(&db_pool).await)
+}
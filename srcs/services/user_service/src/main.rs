@@ -1,4 +1,10 @@
-use axum::{routing::post, Router};
+use std::sync::Arc;
+
+use axum::{
+  extract::FromRef,
+  routing::{get, post},
+  Router,
+};
 use clap::Parser;
 use sqlx::PgPool;
 use tokio::net::TcpListener;
@@ -7,6 +13,10 @@ use tracing::{info, level_filters::LevelFilter};
 
 mod auth;
 mod handlers;
+mod health;
+mod metrics;
+
+use metrics::Metrics;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -22,6 +32,24 @@ struct Args {
   database_host: String,
 }
 
+#[derive(Clone)]
+struct AppState {
+  db_pool: PgPool,
+  metrics: Arc<Metrics>,
+}
+
+impl FromRef<AppState> for PgPool {
+  fn from_ref(state: &AppState) -> Self {
+    state.db_pool.clone()
+  }
+}
+
+impl FromRef<AppState> for Arc<Metrics> {
+  fn from_ref(state: &AppState) -> Self {
+    state.metrics.clone()
+  }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
   let args = Args::parse();
@@ -36,8 +64,14 @@ async fn main() -> anyhow::Result<()> {
     .route("/api/user/register", post(handlers::register_email))
     .route("/api/user/refresh", post(handlers::refresh_token))
     .route("/api/user/login", post(handlers::email_login))
+    .route("/healthz", get(health::healthz))
+    .route("/readyz", get(health::readyz))
+    .route("/metrics", get(metrics::metrics_handler))
     .layer(TraceLayer::new_for_http())
-    .with_state(db_pool);
+    .with_state(AppState {
+      db_pool,
+      metrics: Arc::new(Metrics::new()),
+    });
 
   let address = format!("{}:{}", args.host, args.port);
   info!("Server starting on http://{}", address);
@@ -0,0 +1,124 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+  body::Body,
+  extract::State,
+  http::{header::CONTENT_TYPE, StatusCode},
+  response::{IntoResponse, Response},
+};
+use prometheus_client::{
+  encoding::{text::encode, EncodeLabelSet, EncodeLabelValue},
+  metrics::{counter::Counter, family::Family, histogram::Histogram},
+  registry::Registry,
+};
+use utils::axum::ApiError;
+
+/// Upper bounds, in seconds, of the password hashing histogram. Argon2 is
+/// tuned to be slow, so the buckets start higher than request latencies.
+const HASH_BUCKETS: [f64; 9] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum Endpoint {
+  Register,
+  Login,
+  Refresh,
+}
+
+/// How a request ended: rejected requests failed on the client side (4xx),
+/// errors on ours (5xx).
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum Outcome {
+  Success,
+  Rejected,
+  Error,
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, EncodeLabelValue)]
+pub enum Operation {
+  Hash,
+  Verify,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+  endpoint: Endpoint,
+  outcome: Outcome,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct OperationLabels {
+  operation: Operation,
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+  registry: Registry,
+  requests: Family<RequestLabels, Counter>,
+  password_hash: Family<OperationLabels, Histogram>,
+}
+
+impl Metrics {
+  pub fn new() -> Self {
+    let mut registry = Registry::with_prefix("user_service");
+
+    let requests = Family::<RequestLabels, Counter>::default();
+    registry.register(
+      "requests",
+      "Number of register, login and refresh requests by outcome",
+      requests.clone(),
+    );
+
+    let password_hash = Family::<OperationLabels, Histogram>::new_with_constructor(
+      (|| Histogram::new(HASH_BUCKETS)) as fn() -> Histogram,
+    );
+    registry.register(
+      "password_hash_seconds",
+      "Time spent hashing and verifying passwords in seconds",
+      password_hash.clone(),
+    );
+
+    Metrics {
+      registry,
+      requests,
+      password_hash,
+    }
+  }
+
+  /// Counts the request by the status it is answered with.
+  pub fn observe<T>(&self, endpoint: Endpoint, result: &Result<T, ApiError>) {
+    let outcome = match result {
+      Ok(_) => Outcome::Success,
+      Err(ApiError(status, _)) if status.is_client_error() => Outcome::Rejected,
+      Err(_) => Outcome::Error,
+    };
+    self
+      .requests
+      .get_or_create(&RequestLabels { endpoint, outcome })
+      .inc();
+  }
+
+  /// Runs `hash`, recording how long it took.
+  pub fn time_hash<T>(&self, operation: Operation, hash: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let output = hash();
+    self
+      .password_hash
+      .get_or_create(&OperationLabels { operation })
+      .observe(start.elapsed().as_secs_f64());
+    output
+  }
+}
+
+pub async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+  let mut buffer = String::new();
+  encode(&mut buffer, &metrics.registry).unwrap();
+
+  Response::builder()
+    .status(StatusCode::OK)
+    .header(
+      CONTENT_TYPE,
+      "application/openmetrics-text; version=1.0.0; charset=utf-8",
+    )
+    .body(Body::from(buffer))
+    .unwrap()
+}
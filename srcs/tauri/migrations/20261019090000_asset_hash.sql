@@ -0,0 +1,21 @@
+ALTER TABLE asset ADD COLUMN hash VARCHAR(64);
+ALTER TABLE asset ADD COLUMN ref_count INTEGER NOT NULL DEFAULT 0;
+
+CREATE UNIQUE INDEX IF NOT EXISTS asset_hash ON asset (hash);
+
+UPDATE asset SET ref_count = (
+  SELECT COUNT(*) FROM item_assets WHERE item_assets.asset_id = asset.id
+);
+
+-- Keep ref_count in sync with item_assets, including rows removed through
+-- ON DELETE CASCADE, and drop a blob once its last item is gone.
+CREATE TRIGGER IF NOT EXISTS item_assets_insert AFTER INSERT ON item_assets
+BEGIN
+  UPDATE asset SET ref_count = ref_count + 1 WHERE id = NEW.asset_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS item_assets_delete AFTER DELETE ON item_assets
+BEGIN
+  UPDATE asset SET ref_count = ref_count - 1 WHERE id = OLD.asset_id;
+  DELETE FROM asset WHERE id = OLD.asset_id AND ref_count <= 0;
+END;
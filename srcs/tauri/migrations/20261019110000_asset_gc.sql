@@ -0,0 +1,15 @@
+-- Set when an asset loses its last reference; the garbage collector deletes it
+-- once the grace period has passed.
+ALTER TABLE asset ADD COLUMN orphaned_at INTEGER;
+
+UPDATE asset SET orphaned_at = unixepoch() WHERE ref_count <= 0;
+
+DROP TRIGGER IF EXISTS item_assets_delete;
+
+CREATE TRIGGER IF NOT EXISTS item_assets_delete AFTER DELETE ON item_assets
+BEGIN
+  UPDATE asset
+  SET ref_count = ref_count - 1,
+    orphaned_at = CASE WHEN ref_count <= 1 THEN unixepoch() ELSE orphaned_at END
+  WHERE id = OLD.asset_id;
+END;
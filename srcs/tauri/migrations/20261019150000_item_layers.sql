@@ -0,0 +1,9 @@
+-- Items with the same parent are stacked by z_index, ties broken by id. An
+-- item with children is a frame, which renders below its children and moves
+-- with them.
+ALTER TABLE item ADD COLUMN z_index INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE item ADD COLUMN parent_id INTEGER REFERENCES item (id) ON DELETE SET NULL;
+
+UPDATE item SET z_index = id;
+
+CREATE INDEX IF NOT EXISTS item_parent_id ON item (parent_id, z_index);
@@ -0,0 +1,19 @@
+-- Boards of the local database. Every item and connector belongs to one space
+-- and goes away with it. SQLite cannot add a required reference to an existing
+-- table, so space_id is nullable but always set.
+CREATE TABLE IF NOT EXISTS space (
+  id INTEGER PRIMARY KEY NOT NULL,
+  name VARCHAR(100) NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO space (name) VALUES ('Default');
+
+ALTER TABLE item ADD COLUMN space_id INTEGER REFERENCES space (id) ON DELETE CASCADE;
+UPDATE item SET space_id = (SELECT min(id) FROM space);
+
+ALTER TABLE connector ADD COLUMN space_id INTEGER REFERENCES space (id) ON DELETE CASCADE;
+UPDATE connector SET space_id = (SELECT space_id FROM item WHERE item.id = connector.source_id);
+
+DROP INDEX IF EXISTS item_parent_id;
+CREATE INDEX IF NOT EXISTS item_space_id ON item (space_id, parent_id, z_index);
@@ -0,0 +1,8 @@
+-- Items count their changes for optimistic concurrency. Every group of fields
+-- that can be patched on its own records the version that last changed it, so
+-- a write based on an older version only conflicts with changes to the fields
+-- it touches. The schema group covers the editor and shared state as well.
+ALTER TABLE item ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE item ADD COLUMN position_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE item ADD COLUMN size_version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE item ADD COLUMN schema_version INTEGER NOT NULL DEFAULT 1;
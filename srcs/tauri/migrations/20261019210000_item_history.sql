@@ -0,0 +1,45 @@
+-- Append-only audit trail of item changes. Every row holds who changed the
+-- item, how and the field-level diff as JSON, see history.rs. Rows outlive the
+-- item they describe.
+CREATE TABLE IF NOT EXISTS item_history (
+  id INTEGER PRIMARY KEY NOT NULL,
+  item_id INTEGER NOT NULL,
+  actor VARCHAR(100) NOT NULL,
+  action VARCHAR(16) NOT NULL,
+  changes TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS item_history_item_id ON item_history (item_id, id);
+
+CREATE TRIGGER IF NOT EXISTS item_history_no_update
+BEFORE UPDATE ON item_history
+BEGIN
+  SELECT RAISE(ABORT, 'item_history is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS item_history_no_delete
+BEFORE DELETE ON item_history
+BEGIN
+  SELECT RAISE(ABORT, 'item_history is append-only');
+END;
+
+-- Existing items start with a creation entry holding their current state, so
+-- later revisions can be rebuilt. Schemas that are not a JSON array count as
+-- empty.
+INSERT INTO item_history ( item_id, actor, action, changes )
+SELECT id, '', 'create', json_object(
+  'x', json_array(0, x),
+  'y', json_array(0, y),
+  'w', json_array(0, w),
+  'h', json_array(0, h),
+  'schema', CASE
+    WHEN json_valid(schema) AND json_type(schema) = 'array' THEN (
+      SELECT json_group_array(json_object('index', key, 'from', NULL, 'to', json(value)))
+      FROM json_each(item.schema)
+    )
+    ELSE json_array()
+  END,
+  'deleted_at', json_array(NULL, unixepoch(deleted_at))
+)
+FROM item;
@@ -0,0 +1,36 @@
+-- Items saved as a reusable layout, e.g. meeting notes. A template of a space
+-- is offered in that space only, one without a space in every space.
+CREATE TABLE IF NOT EXISTS template (
+  id INTEGER PRIMARY KEY NOT NULL,
+  space_id INTEGER REFERENCES space (id) ON DELETE CASCADE,
+  name VARCHAR(100) NOT NULL,
+  -- The items and the connectors between them as JSON, see templates.rs.
+  content TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS template_space_id ON template (space_id);
+
+-- Assets referenced by the items of a template. Each link counts as a
+-- reference like those of item_assets, so the assets outlive the items the
+-- template was saved from.
+CREATE TABLE IF NOT EXISTS template_assets (
+  template_id INTEGER,
+  asset_id VARCHAR(36),
+  PRIMARY KEY (template_id, asset_id),
+  FOREIGN KEY (template_id) REFERENCES template (id) ON DELETE CASCADE,
+  FOREIGN KEY (asset_id) REFERENCES asset (id) ON UPDATE CASCADE ON DELETE CASCADE
+);
+
+CREATE TRIGGER IF NOT EXISTS template_assets_insert AFTER INSERT ON template_assets
+BEGIN
+  UPDATE asset SET ref_count = ref_count + 1 WHERE id = NEW.asset_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS template_assets_delete AFTER DELETE ON template_assets
+BEGIN
+  UPDATE asset
+  SET ref_count = ref_count - 1,
+    orphaned_at = CASE WHEN ref_count <= 1 THEN unixepoch() ELSE orphaned_at END
+  WHERE id = OLD.asset_id;
+END;
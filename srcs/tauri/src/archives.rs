@@ -0,0 +1,187 @@
+//! Export and import of whole spaces as [`Archive`]s, the format shared with
+//! the server so spaces move between the app and it.
+
+use std::collections::HashSet;
+
+use sqlx::SqlitePool;
+use utils::{
+  archive::{self, Archive},
+  asset_store::AssetStore,
+};
+
+use crate::{
+  connectors::{self, ConnectorData},
+  history::{self, Snapshot},
+  insert_assets,
+  item::{Asset, Connector, Descendant, Item},
+  spaces::{self, Space},
+  Error,
+};
+
+/// Writes the space, outside of the trash, to an archive.
+pub async fn export(
+  pool: &SqlitePool,
+  asset_store: &dyn AssetStore,
+  id: i64,
+) -> Result<Vec<u8>, Error> {
+  let mut transaction = pool.begin().await?;
+  let name = sqlx::query_scalar!("SELECT name FROM space WHERE id = ?1", id)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(Error::SpaceNotFound(id))?;
+  let items = sqlx::query_as!(
+    Item,
+    "SELECT * FROM item WHERE space_id = ?1 AND deleted_at IS NULL ORDER BY id",
+    id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let connectors = sqlx::query_as!(
+    Connector,
+    "SELECT * FROM connector WHERE space_id = ?1 ORDER BY id",
+    id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let item_ids = serde_json::to_string(&items.iter().map(|item| item.id).collect::<Vec<_>>())?;
+  let assets = sqlx::query!(
+    r#"
+SELECT DISTINCT asset.id AS "id!", asset.name, asset.mime FROM asset
+JOIN item_assets ON item_assets.asset_id = asset.id
+WHERE item_assets.item_id IN (SELECT value FROM json_each(?1))
+ORDER BY asset.id
+    "#,
+    item_ids,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  transaction.commit().await?;
+
+  let mut archive = Archive::from_rows(name, items, connectors);
+  archive.assets.reserve(assets.len());
+  for asset in assets {
+    let data = asset_store
+      .get(&asset.id)
+      .await?
+      .ok_or_else(|| Error::UnknownAsset(asset.id.clone()))?;
+    archive.assets.push(archive::Asset {
+      id: asset.id,
+      name: asset.name,
+      mime: asset.mime,
+      data,
+    });
+  }
+
+  Ok(
+    tokio::task::spawn_blocking(move || archive.write())
+      .await
+      .unwrap()?,
+  )
+}
+
+/// Reads an archive and the assets it contains, keyed by their id in the
+/// archive.
+fn read(data: &[u8]) -> Result<(Archive, Vec<(String, Asset)>), Error> {
+  let mut archive = Archive::read(data)?;
+  let assets = std::mem::take(&mut archive.assets)
+    .into_iter()
+    .map(|entry| {
+      let mut asset = Asset::new(entry.data);
+      asset.name = entry.name;
+      asset.inspect()?;
+      Ok((entry.id, asset))
+    })
+    .collect::<Result<Vec<_>, Error>>()?;
+  Ok((archive, assets))
+}
+
+/// Creates a space from an archive, named `name` or as in the archive. Items,
+/// connectors and assets get new ids, and assets with content already stored
+/// are shared.
+pub async fn import(
+  pool: &SqlitePool,
+  asset_store: &dyn AssetStore,
+  data: Vec<u8>,
+  name: Option<&str>,
+) -> Result<Space, Error> {
+  let (archive, assets) = tokio::task::spawn_blocking(move || read(&data))
+    .await
+    .unwrap()?;
+
+  let mut transaction = pool.begin().await?;
+  let space = spaces::insert(&mut transaction, name.unwrap_or(&archive.name)).await?;
+
+  let (asset_ids, mut assets) = assets
+    .into_iter()
+    .map(|(id, asset)| ((id, asset.id.clone()), asset))
+    .unzip::<_, _, Vec<_>, Vec<_>>();
+  // Identical content resolves to the already stored asset.
+  let existing_ids = insert_assets(&mut transaction, asset_store, &mut assets).await?;
+  let mut ids = archive::Ids::new(asset_ids, &existing_ids);
+
+  for item in archive.ordered_items()? {
+    let mut descendants =
+      serde_json::from_str::<Vec<Descendant>>(item.schema.as_deref().unwrap_or("[]"))?;
+    let mut refs = HashSet::new();
+    for descendant in &descendants {
+      descendant.asset_refs(&mut refs);
+    }
+    if let Some(unknown) = ids.unknown_asset(&refs) {
+      return Err(Error::UnknownAsset(unknown.clone()));
+    }
+    for descendant in &mut descendants {
+      descendant.replace_refs(ids.assets());
+    }
+
+    let schema = serde_json::to_string(&descendants)?;
+    let parent_id = ids.parent(item);
+    let imported = sqlx::query_as!(
+      Item,
+      r#"
+INSERT INTO item ( space_id, x, y, w, h, editor, schema, z_index, parent_id )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9 )
+RETURNING *
+      "#,
+      space.id,
+      item.x,
+      item.y,
+      item.w,
+      item.h,
+      item.editor,
+      schema,
+      item.z_index,
+      parent_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    ids.insert_item(item.id, imported.id);
+
+    for asset_id in refs.iter().map(|id| &ids.assets()[id]) {
+      sqlx::query!(
+        "INSERT INTO item_assets ( item_id, asset_id ) VALUES ( ?1, ?2 ) ON CONFLICT DO NOTHING",
+        imported.id,
+        asset_id,
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    history::record(&mut transaction, "import", &Snapshot::default(), &imported).await?;
+  }
+
+  for connector in archive.connectors {
+    let connector = ids.connector(connector);
+    let data = ConnectorData {
+      source_id: connector.source_id,
+      target_id: connector.target_id,
+      source_anchor: connector.source_anchor,
+      target_anchor: connector.target_anchor,
+      label: connector.label,
+      style: connector.style,
+    };
+    connectors::check(&data)?;
+    connectors::insert(&mut transaction, Some(space.id), &data).await?;
+  }
+
+  transaction.commit().await?;
+  Ok(space)
+}
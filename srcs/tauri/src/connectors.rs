@@ -0,0 +1,159 @@
+//! Connectors link two items, e.g. the nodes of a mind map or a flow.
+
+use serde::Deserialize;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+  item::{Connector, ANCHORS},
+  Error,
+};
+
+fn default_anchor() -> String {
+  ANCHORS[0].to_string()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConnectorData {
+  pub source_id: i64,
+  pub target_id: i64,
+  #[serde(default = "default_anchor")]
+  pub source_anchor: String,
+  #[serde(default = "default_anchor")]
+  pub target_anchor: String,
+  pub label: Option<String>,
+  pub style: Option<String>,
+}
+
+/// Checks that the endpoints differ, the anchors and the style.
+pub(crate) fn check(data: &ConnectorData) -> Result<(), Error> {
+  if data.source_id == data.target_id {
+    return Err(Error::InvalidConnector("source and target must differ"));
+  }
+  for anchor in [&data.source_anchor, &data.target_anchor] {
+    if !ANCHORS.contains(&anchor.as_str()) {
+      return Err(Error::InvalidConnector("unknown anchor"));
+    }
+  }
+  if let Some(style) = &data.style {
+    serde_json::from_str::<serde_json::Value>(style)?;
+  }
+  Ok(())
+}
+
+/// Checks the connector and that both endpoints are distinct items of the
+/// same space, returning the space.
+async fn validate(
+  connection: &mut SqliteConnection,
+  data: &ConnectorData,
+) -> Result<Option<i64>, Error> {
+  check(data)?;
+  let mut space_ids = vec![];
+  for id in [data.source_id, data.target_id] {
+    let space_id = sqlx::query_scalar!(
+      "SELECT space_id FROM item WHERE id = ?1 AND deleted_at IS NULL",
+      id
+    )
+    .fetch_optional(&mut *connection)
+    .await?
+    .ok_or(Error::ItemNotFound(id))?;
+    space_ids.push(space_id);
+  }
+  if space_ids[0] != space_ids[1] {
+    return Err(Error::InvalidConnector("endpoints are in different spaces"));
+  }
+  Ok(space_ids[0])
+}
+
+/// Connectors with at least one endpoint among the items and neither in the
+/// trash.
+pub async fn attached(pool: &SqlitePool, item_ids: &[i64]) -> Result<Vec<Connector>, Error> {
+  let item_ids = serde_json::to_string(item_ids)?;
+  Ok(
+    sqlx::query_as!(
+      Connector,
+      r#"
+SELECT * FROM connector
+WHERE (source_id IN (SELECT value FROM json_each(?1))
+    OR target_id IN (SELECT value FROM json_each(?1)))
+  AND NOT EXISTS (
+    SELECT 1 FROM item
+    WHERE item.id IN (connector.source_id, connector.target_id) AND item.deleted_at IS NOT NULL
+  )
+ORDER BY id
+      "#,
+      item_ids,
+    )
+    .fetch_all(pool)
+    .await?,
+  )
+}
+
+pub async fn create(pool: &SqlitePool, data: ConnectorData) -> Result<Connector, Error> {
+  let mut transaction = pool.begin().await?;
+  let space_id = validate(&mut transaction, &data).await?;
+  let connector = insert(&mut transaction, space_id, &data).await?;
+  transaction.commit().await?;
+  Ok(connector)
+}
+
+/// Inserts a connector between items already checked to be in `space_id`.
+pub(crate) async fn insert(
+  connection: &mut SqliteConnection,
+  space_id: Option<i64>,
+  data: &ConnectorData,
+) -> Result<Connector, Error> {
+  Ok(
+    sqlx::query_as!(
+      Connector,
+      r#"
+INSERT INTO connector ( source_id, target_id, source_anchor, target_anchor, label, style, space_id )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 ) RETURNING *
+      "#,
+      data.source_id,
+      data.target_id,
+      data.source_anchor,
+      data.target_anchor,
+      data.label,
+      data.style,
+      space_id,
+    )
+    .fetch_one(connection)
+    .await?,
+  )
+}
+
+/// Replaces a connector, including its endpoints.
+pub async fn update(pool: &SqlitePool, id: i64, data: ConnectorData) -> Result<Connector, Error> {
+  let mut transaction = pool.begin().await?;
+  let space_id = validate(&mut transaction, &data).await?;
+
+  let connector = sqlx::query_as!(
+    Connector,
+    r#"
+UPDATE connector
+SET source_id = ?2, target_id = ?3, source_anchor = ?4, target_anchor = ?5, label = ?6, style = ?7
+WHERE id = ?1 AND space_id IS ?8 RETURNING *
+    "#,
+    id,
+    data.source_id,
+    data.target_id,
+    data.source_anchor,
+    data.target_anchor,
+    data.label,
+    data.style,
+    space_id,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::ConnectorNotFound(id))?;
+
+  transaction.commit().await?;
+  Ok(connector)
+}
+
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<i64, Error> {
+  sqlx::query_scalar!("DELETE FROM connector WHERE id = ?1 RETURNING id", id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(Error::ConnectorNotFound(id))
+}
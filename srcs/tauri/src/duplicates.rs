@@ -0,0 +1,126 @@
+//! Duplication of items. The copies of a selection keep their layout, moved by
+//! an offset, and the items they contain are copied along. Copies share the
+//! assets of their originals, which stay stored while any item links them.
+
+use std::collections::{HashMap, HashSet};
+
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+  history::{self, Snapshot},
+  item::Item,
+  Error,
+};
+
+/// The items `ids` and their descendants outside of the trash, bottom first.
+pub(crate) async fn subtree(
+  connection: &mut SqliteConnection,
+  ids: &[i64],
+) -> sqlx::Result<Vec<Item>> {
+  let ids = serde_json::to_string(ids).unwrap();
+  sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT id FROM item WHERE id IN (SELECT value FROM json_each(?1)) AND deleted_at IS NULL
+  UNION
+  SELECT item.id FROM item JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.deleted_at IS NULL
+)
+SELECT item.* FROM item JOIN subtree ON item.id = subtree.id
+ORDER BY item.z_index, item.id
+    "#,
+    ids,
+  )
+  .fetch_all(connection)
+  .await
+}
+
+/// Copies an item into `parent_id`. Without a `z_index` the copy is stacked on
+/// top of its new siblings.
+async fn copy(
+  connection: &mut SqliteConnection,
+  item: &Item,
+  parent_id: Option<i64>,
+  z_index: Option<i64>,
+  (dx, dy): (i64, i64),
+) -> sqlx::Result<Item> {
+  let copy = sqlx::query_as!(
+    Item,
+    r#"
+INSERT INTO item ( space_id, x, y, w, h, editor, schema, shared, z_index, parent_id )
+SELECT space_id, x + ?2, y + ?3, w, h, editor, schema, shared,
+  COALESCE(?4, (
+    SELECT COALESCE(MAX(z_index), 0) + 1 FROM item AS sibling
+    WHERE sibling.space_id IS item.space_id AND sibling.parent_id IS ?5
+  )),
+  ?5
+FROM item WHERE id = ?1
+RETURNING *
+    "#,
+    item.id,
+    dx,
+    dy,
+    z_index,
+    parent_id,
+  )
+  .fetch_one(&mut *connection)
+  .await?;
+  sqlx::query!(
+    r#"
+INSERT INTO item_assets ( item_id, asset_id )
+SELECT ?1, asset_id FROM item_assets WHERE item_id = ?2
+    "#,
+    copy.id,
+    item.id,
+  )
+  .execute(connection)
+  .await?;
+  Ok(copy)
+}
+
+/// Copies `ids`, with the items they contain, moved by `dx` and `dy`.
+pub async fn duplicate(
+  pool: &SqlitePool,
+  ids: &[i64],
+  dx: i64,
+  dy: i64,
+) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  let mut pending = subtree(&mut transaction, ids).await?;
+  if let Some(&missing) = ids
+    .iter()
+    .find(|&&id| !pending.iter().any(|item| item.id == id))
+  {
+    return Err(Error::ItemNotFound(missing));
+  }
+  let copied = pending.iter().map(|item| item.id).collect::<HashSet<_>>();
+
+  // Parents are copied before their children. Copies of the selected items
+  // are stacked on top in their original order, the stacks they contain are
+  // copied as they are.
+  let mut copies = HashMap::<i64, i64>::new();
+  let mut items = Vec::new();
+  while !pending.is_empty() {
+    let (ready, rest) = pending
+      .into_iter()
+      .partition::<Vec<_>, _>(|item| match item.parent_id {
+        Some(parent_id) => !copied.contains(&parent_id) || copies.contains_key(&parent_id),
+        None => true,
+      });
+    for item in ready {
+      let (parent_id, z_index) = match item.parent_id.and_then(|id| copies.get(&id)) {
+        Some(&parent_id) => (Some(parent_id), Some(item.z_index)),
+        None => (item.parent_id, None),
+      };
+      let copy = copy(&mut transaction, &item, parent_id, z_index, (dx, dy)).await?;
+      copies.insert(item.id, copy.id);
+      history::record(&mut transaction, "duplicate", &Snapshot::default(), &copy).await?;
+      items.push(copy);
+    }
+    pending = rest;
+  }
+
+  transaction.commit().await?;
+  Ok(items)
+}
@@ -0,0 +1,147 @@
+use std::{
+  collections::{HashMap, HashSet},
+  time::Duration,
+};
+
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use tracing::warn;
+use utils::asset_store::AssetStore;
+
+use crate::{item::Descendant, Error};
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+  /// `item_assets` links whose item schema no longer references the asset.
+  pub stale_links: Vec<(i64, String)>,
+  /// Assets no item or template references, through `item_assets`,
+  /// `template_assets` or a schema.
+  pub unreferenced: Vec<String>,
+  /// Unreferenced assets whose grace period has passed.
+  pub expired: Vec<String>,
+}
+
+/// Finds assets that are no longer referenced by any item or template and
+/// deletes those that have been orphaned for longer than `grace_period`, along
+/// with their content. With `dry_run` the report is produced without changing
+/// anything.
+pub async fn collect(
+  pool: &SqlitePool,
+  asset_store: &dyn AssetStore,
+  grace_period: Duration,
+  dry_run: bool,
+) -> Result<Report, Error> {
+  let mut transaction = pool.begin().await?;
+
+  // Items whose schema cannot be parsed keep all of their linked assets.
+  let mut schema_refs = HashMap::new();
+  let mut items = sqlx::query!("SELECT id, schema FROM item").fetch(&mut *transaction);
+  while let Some(item) = items.try_next().await? {
+    let mut refs = HashSet::new();
+    match serde_json::from_str::<Vec<Descendant>>(item.schema.as_deref().unwrap_or("[]")) {
+      Ok(descendants) => descendants
+        .iter()
+        .for_each(|descendant| descendant.asset_refs(&mut refs)),
+      Err(e) => {
+        warn!("Skipping unparsable schema of item {}: {}", item.id, e);
+        continue;
+      }
+    }
+    schema_refs.insert(item.id, refs);
+  }
+  drop(items);
+
+  let mut report = Report::default();
+  let mut referenced = schema_refs
+    .values()
+    .flatten()
+    .cloned()
+    .collect::<HashSet<_>>();
+  let links =
+    sqlx::query!(r#"SELECT item_id AS "item_id!", asset_id AS "asset_id!" FROM item_assets"#)
+      .fetch_all(&mut *transaction)
+      .await?;
+  for link in links {
+    match schema_refs.get(&link.item_id) {
+      Some(refs) if !refs.contains(&link.asset_id) => {
+        report.stale_links.push((link.item_id, link.asset_id))
+      }
+      _ => {
+        referenced.insert(link.asset_id);
+      }
+    }
+  }
+  // Templates keep the assets of the items they were saved from.
+  referenced.extend(
+    sqlx::query_scalar!(r#"SELECT asset_id AS "asset_id!" FROM template_assets"#)
+      .fetch_all(&mut *transaction)
+      .await?,
+  );
+  let referenced = serde_json::to_string(&referenced)?;
+
+  report.unreferenced = sqlx::query_scalar!(
+    "SELECT id FROM asset WHERE id NOT IN (SELECT value FROM json_each(?1))",
+    referenced
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let grace_period = grace_period.as_secs() as i64;
+  report.expired = sqlx::query_scalar!(
+    r#"
+SELECT id FROM asset
+WHERE id NOT IN (SELECT value FROM json_each(?1)) AND orphaned_at < unixepoch() - ?2
+    "#,
+    referenced,
+    grace_period,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+
+  if dry_run {
+    transaction.rollback().await?;
+    return Ok(report);
+  }
+
+  for (item_id, asset_id) in &report.stale_links {
+    sqlx::query!(
+      "DELETE FROM item_assets WHERE item_id = ?1 AND asset_id = ?2",
+      item_id,
+      asset_id,
+    )
+    .execute(&mut *transaction)
+    .await?;
+  }
+  sqlx::query!(
+    r#"
+UPDATE asset SET orphaned_at = NULL
+WHERE id IN (SELECT value FROM json_each(?1)) AND orphaned_at IS NOT NULL
+    "#,
+    referenced,
+  )
+  .execute(&mut *transaction)
+  .await?;
+  sqlx::query!(
+    r#"
+UPDATE asset SET orphaned_at = unixepoch()
+WHERE id NOT IN (SELECT value FROM json_each(?1)) AND orphaned_at IS NULL
+    "#,
+    referenced,
+  )
+  .execute(&mut *transaction)
+  .await?;
+  let expired = serde_json::to_string(&report.expired)?;
+  sqlx::query!(
+    "DELETE FROM asset WHERE id IN (SELECT value FROM json_each(?1))",
+    expired
+  )
+  .execute(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+
+  for id in &report.expired {
+    asset_store.delete(id).await?;
+  }
+  Ok(report)
+}
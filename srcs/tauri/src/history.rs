@@ -0,0 +1,245 @@
+//! Append-only audit trail of item changes. Every write records who made it
+//! and a field-level diff, which is enough to rebuild any earlier revision of
+//! an item and revert to it.
+
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+  item::{Descendant, Item},
+  patches, Error,
+};
+
+/// Fields of an item tracked by the history.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Snapshot {
+  pub x: i64,
+  pub y: i64,
+  pub w: i64,
+  pub h: i64,
+  pub schema: Vec<Descendant>,
+  pub deleted_at: Option<i64>,
+}
+
+impl Snapshot {
+  /// Schemas that cannot be parsed count as empty.
+  pub fn of(item: &Item) -> Self {
+    Self {
+      x: item.x,
+      y: item.y,
+      w: item.w,
+      h: item.h,
+      schema: item
+        .schema
+        .as_deref()
+        .and_then(|schema| serde_json::from_str(schema).ok())
+        .unwrap_or_default(),
+      deleted_at: item.deleted_at.map(|at| at.and_utc().timestamp()),
+    }
+  }
+
+  pub fn apply(&mut self, diff: &Diff) {
+    for (field, change) in [
+      (&mut self.x, diff.x),
+      (&mut self.y, diff.y),
+      (&mut self.w, diff.w),
+      (&mut self.h, diff.h),
+    ] {
+      if let Some((_, to)) = change {
+        *field = to;
+      }
+    }
+    for change in &diff.schema {
+      match &change.to {
+        Some(block) if change.index < self.schema.len() => {
+          self.schema[change.index] = block.clone()
+        }
+        Some(block) => self.schema.push(block.clone()),
+        None => self.schema.truncate(change.index),
+      }
+    }
+    if let Some((_, to)) = diff.deleted_at {
+      self.deleted_at = to;
+    }
+  }
+}
+
+/// Replacement of the top-level block at `index`. Blocks are only added or
+/// removed at the end.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlockChange {
+  pub index: usize,
+  pub from: Option<Descendant>,
+  pub to: Option<Descendant>,
+}
+
+/// Changed fields as `[from, to]`, and the changed blocks of the schema.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diff {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub x: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub y: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub w: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub h: Option<(i64, i64)>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub schema: Vec<BlockChange>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub deleted_at: Option<(Option<i64>, Option<i64>)>,
+}
+
+fn change<T: PartialEq + Clone>(from: &T, to: &T) -> Option<(T, T)> {
+  (from != to).then(|| (from.clone(), to.clone()))
+}
+
+impl Diff {
+  pub fn between(from: &Snapshot, to: &Snapshot) -> Self {
+    let blocks = from.schema.len().max(to.schema.len());
+    Self {
+      x: change(&from.x, &to.x),
+      y: change(&from.y, &to.y),
+      w: change(&from.w, &to.w),
+      h: change(&from.h, &to.h),
+      schema: (0..blocks)
+        .filter_map(|index| {
+          let (from, to) = (from.schema.get(index), to.schema.get(index));
+          (from != to).then(|| BlockChange {
+            index,
+            from: from.cloned(),
+            to: to.cloned(),
+          })
+        })
+        .collect(),
+      deleted_at: change(&from.deleted_at, &to.deleted_at),
+    }
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Revision {
+  pub id: i64,
+  pub item_id: i64,
+  pub actor: String,
+  /// `create`, `update`, `patch`, `move`, `delete`, `restore`, `purge`,
+  /// `revert`, `duplicate`, `import` or `template`.
+  pub action: String,
+  pub changes: Diff,
+  /// Unix time of the change.
+  pub created_at: i64,
+}
+
+/// Changes are made by the user running the app.
+fn actor() -> String {
+  std::env::var("USER")
+    .or_else(|_| std::env::var("USERNAME"))
+    .unwrap_or_else(|_| "local".to_string())
+}
+
+/// Tracked fields of a stored item, e.g. before it is changed.
+pub async fn snapshot(connection: &mut SqliteConnection, id: i64) -> Result<Snapshot, Error> {
+  let item = sqlx::query_as!(Item, "SELECT * FROM item WHERE id = ?1", id)
+    .fetch_optional(connection)
+    .await?
+    .ok_or(Error::ItemNotFound(id))?;
+  Ok(Snapshot::of(&item))
+}
+
+/// Appends the change from `before` to the stored `after` to the history.
+pub async fn record(
+  connection: &mut SqliteConnection,
+  action: &str,
+  before: &Snapshot,
+  after: &Item,
+) -> Result<(), Error> {
+  let changes = serde_json::to_string(&Diff::between(before, &Snapshot::of(after)))?;
+  let actor = actor();
+  sqlx::query!(
+    "INSERT INTO item_history ( item_id, actor, action, changes ) VALUES ( ?1, ?2, ?3, ?4 )",
+    after.id,
+    actor,
+    action,
+    changes,
+  )
+  .execute(connection)
+  .await?;
+  Ok(())
+}
+
+/// Revisions of an item, oldest first.
+pub async fn list(pool: &SqlitePool, item_id: i64) -> Result<Vec<Revision>, Error> {
+  let rows = sqlx::query!(
+    "SELECT * FROM item_history WHERE item_id = ?1 ORDER BY id",
+    item_id,
+  )
+  .fetch_all(pool)
+  .await?;
+  rows
+    .into_iter()
+    .map(|row| {
+      Ok(Revision {
+        id: row.id,
+        item_id: row.item_id,
+        actor: row.actor,
+        action: row.action,
+        changes: serde_json::from_str(&row.changes)?,
+        created_at: row.created_at.and_utc().timestamp(),
+      })
+    })
+    .collect()
+}
+
+/// Replaces the position, size and content of an item by their state after
+/// revision `id`, as a new revision. The revert touches every field, so it
+/// conflicts with any change made after `version`.
+pub async fn revert(pool: &SqlitePool, item_id: i64, id: i64, version: i64) -> Result<Item, Error> {
+  let mut transaction = pool.begin().await?;
+  let before = snapshot(&mut transaction, item_id).await?;
+  if before.deleted_at.is_some() {
+    return Err(Error::ItemNotFound(item_id));
+  }
+
+  let changes = sqlx::query_scalar!(
+    "SELECT changes FROM item_history WHERE item_id = ?1 AND id <= ?2 ORDER BY id",
+    item_id,
+    id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  if changes.is_empty() {
+    return Err(Error::RevisionNotFound(id));
+  }
+  let mut target = Snapshot::default();
+  for changes in changes {
+    target.apply(&serde_json::from_str(&changes)?);
+  }
+
+  let schema = serde_json::to_string(&target.schema)?;
+  let Some(item) = sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item
+SET x = ?2, y = ?3, w = ?4, h = ?5, schema = ?6, version = version + 1,
+  position_version = version + 1, size_version = version + 1, schema_version = version + 1
+WHERE id = ?1 AND max(position_version, size_version, schema_version) <= ?7
+RETURNING *
+    "#,
+    item_id,
+    target.x,
+    target.y,
+    target.w,
+    target.h,
+    schema,
+    version,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  else {
+    return Err(patches::conflict(&mut transaction, item_id).await?);
+  };
+  record(&mut transaction, "revert", &before, &item).await?;
+
+  transaction.commit().await?;
+  Ok(item)
+}
@@ -0,0 +1,220 @@
+//! Stacking order and grouping of items. Items with the same parent are
+//! siblings, stacked by `z_index` with ties broken by id. A frame renders below
+//! its children and moving it moves them along.
+
+use std::collections::BTreeMap;
+
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+  history::{self, Snapshot},
+  item::Item,
+  Error,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+  Forward,
+  Back,
+}
+
+/// Ids of the children of `parent_id` in the space outside the trash, bottom
+/// first.
+async fn children(
+  connection: &mut SqliteConnection,
+  space_id: Option<i64>,
+  parent_id: Option<i64>,
+) -> sqlx::Result<Vec<i64>> {
+  sqlx::query_scalar!(
+    r#"
+SELECT id FROM item
+WHERE space_id IS ?1 AND parent_id IS ?2 AND deleted_at IS NULL
+ORDER BY z_index, id
+    "#,
+    space_id,
+    parent_id,
+  )
+  .fetch_all(connection)
+  .await
+}
+
+/// Numbers the stack `ids` bottom to top, returning the items that changed.
+async fn restack(connection: &mut SqliteConnection, ids: &[i64]) -> sqlx::Result<Vec<Item>> {
+  let mut changed = vec![];
+  for (z_index, id) in (1..).zip(ids) {
+    let item = sqlx::query_as!(
+      Item,
+      r#"
+UPDATE item SET z_index = ?2, version = version + 1
+WHERE id = ?1 AND z_index <> ?2
+RETURNING *
+      "#,
+      id,
+      z_index,
+    )
+    .fetch_optional(&mut *connection)
+    .await?;
+    changed.extend(item);
+  }
+  Ok(changed)
+}
+
+/// Returns the space and parent of an item outside the trash.
+async fn locate(
+  connection: &mut SqliteConnection,
+  id: i64,
+) -> Result<(Option<i64>, Option<i64>), Error> {
+  let item = sqlx::query!(
+    "SELECT space_id, parent_id FROM item WHERE id = ?1 AND deleted_at IS NULL",
+    id
+  )
+  .fetch_optional(connection)
+  .await?
+  .ok_or(Error::ItemNotFound(id))?;
+  Ok((item.space_id, item.parent_id))
+}
+
+/// Keeps the last version of every changed item.
+fn dedup(changed: impl IntoIterator<Item = Item>) -> Vec<Item> {
+  changed
+    .into_iter()
+    .map(|item| (item.id, item))
+    .collect::<BTreeMap<_, _>>()
+    .into_values()
+    .collect()
+}
+
+pub async fn step(pool: &SqlitePool, id: i64, step: Step) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  let (space_id, parent_id) = locate(&mut transaction, id).await?;
+  let mut ids = children(&mut transaction, space_id, parent_id).await?;
+
+  let index = ids.iter().position(|&sibling| sibling == id).unwrap();
+  match step {
+    Step::Forward if index + 1 < ids.len() => ids.swap(index, index + 1),
+    Step::Back if index > 0 => ids.swap(index - 1, index),
+    _ => {}
+  }
+  let changed = restack(&mut transaction, &ids).await?;
+
+  transaction.commit().await?;
+  Ok(changed)
+}
+
+/// Moves `ids` into the frame `parent_id`, on top of its other children.
+pub async fn group(pool: &SqlitePool, parent_id: i64, ids: &[i64]) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  let (space_id, _) = locate(&mut transaction, parent_id).await?;
+
+  // The frame may not end up inside one of the grouped items.
+  let ancestors = sqlx::query_scalar!(
+    r#"
+WITH RECURSIVE ancestors ( id, parent_id ) AS (
+  SELECT id, parent_id FROM item WHERE id = ?1
+  UNION
+  SELECT item.id, item.parent_id FROM item JOIN ancestors ON item.id = ancestors.parent_id
+)
+SELECT id AS "id!: i64" FROM ancestors
+    "#,
+    parent_id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  if ids.iter().any(|id| ancestors.contains(id)) {
+    return Err(Error::InvalidGroup("an item cannot contain itself"));
+  }
+
+  // Grouped items keep their relative order, above the existing children.
+  let mut grouped = vec![];
+  for &id in ids {
+    let item = sqlx::query_as!(
+      Item,
+      r#"
+UPDATE item SET parent_id = ?2, version = version + 1
+WHERE id = ?1 AND space_id IS ?3 AND deleted_at IS NULL
+RETURNING *
+      "#,
+      id,
+      parent_id,
+      space_id,
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .ok_or(Error::ItemNotFound(id))?;
+    grouped.push(item);
+  }
+  grouped.sort_by_key(|item| (item.z_index, item.id));
+  let mut stack = children(&mut transaction, space_id, Some(parent_id)).await?;
+  stack.retain(|id| !ids.contains(id));
+  stack.extend(grouped.iter().map(|item| item.id));
+  let restacked = restack(&mut transaction, &stack).await?;
+
+  transaction.commit().await?;
+  Ok(dedup(grouped.into_iter().chain(restacked)))
+}
+
+/// Moves the children of a frame out of it, directly above the frame.
+pub async fn ungroup(pool: &SqlitePool, id: i64) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  let (space_id, parent_id) = locate(&mut transaction, id).await?;
+
+  let contained = children(&mut transaction, space_id, Some(id)).await?;
+  let mut stack = children(&mut transaction, space_id, parent_id).await?;
+  let index = stack.iter().position(|&sibling| sibling == id).unwrap();
+  stack.splice(index + 1..index + 1, contained);
+
+  let ungrouped = sqlx::query_as!(
+    Item,
+    r#"
+UPDATE item SET parent_id = ?2, version = version + 1
+WHERE parent_id = ?1 AND deleted_at IS NULL
+RETURNING *
+    "#,
+    id,
+    parent_id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let restacked = restack(&mut transaction, &stack).await?;
+
+  transaction.commit().await?;
+  Ok(dedup(ungrouped.into_iter().chain(restacked)))
+}
+
+/// Moves every item `id` contains by `dx`, `dy` and records the moves,
+/// returning the moved items.
+pub async fn move_contents(
+  connection: &mut SqliteConnection,
+  id: i64,
+  (dx, dy): (i64, i64),
+) -> Result<Vec<Item>, Error> {
+  let moved = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT id FROM item WHERE parent_id = ?1 AND deleted_at IS NULL
+  UNION
+  SELECT item.id FROM item JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.deleted_at IS NULL
+)
+UPDATE item
+SET x = x + ?2, y = y + ?3, version = version + 1, position_version = version + 1
+WHERE id IN subtree
+RETURNING *
+    "#,
+    id,
+    dx,
+    dy,
+  )
+  .fetch_all(&mut *connection)
+  .await?;
+  for item in &moved {
+    let before = Snapshot {
+      x: item.x - dx,
+      y: item.y - dy,
+      ..Snapshot::of(item)
+    };
+    history::record(&mut *connection, "move", &before, item).await?;
+  }
+  Ok(moved)
+}
@@ -1,21 +1,33 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use clap::Parser;
-use item::{Asset, Descendant, Item};
-use serde::{Serialize, Serializer};
+use clap::{Parser, ValueEnum};
+use item::{Asset, Connector, Descendant, Item};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
 use sqlx::{
   sqlite::{SqliteConnectOptions, SqliteJournalMode},
-  SqlitePool,
+  SqliteConnection, SqlitePool,
 };
-use std::{env, str::FromStr, vec};
+use std::{collections::HashMap, str::FromStr, sync::Arc, time::Duration};
+use store::AssetStores;
 use tauri::{AppHandle, Runtime, State};
 use tauri_plugin_dialog::DialogExt;
 use tokio::sync::RwLock;
-use tracing::{info, level_filters::LevelFilter};
-use uuid::Uuid;
-
+use tracing::{error, info, level_filters::LevelFilter};
+use utils::asset_store::{self, AssetStore, Backend, StoreArgs};
+
+mod archives;
+mod connectors;
+mod duplicates;
+mod gc;
+mod history;
 mod item;
+mod layers;
+mod patches;
+mod spaces;
+mod store;
+mod templates;
+mod trash;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -29,6 +41,38 @@ pub enum Error {
   DatabaseNotLoaded(String),
   #[error("unsupported datatype: {0}")]
   UnsupportedDatatype(String),
+  #[error(transparent)]
+  Asset(#[from] utils::asset::Error),
+  #[error(transparent)]
+  AssetStore(#[from] asset_store::Error),
+  #[error(transparent)]
+  Archive(#[from] utils::archive::Error),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error("unknown asset {0}")]
+  UnknownAsset(String),
+  #[error("unknown asset store: {0}")]
+  UnknownAssetStore(String),
+  #[error("item {0} not found")]
+  ItemNotFound(i64),
+  #[error("invalid group: {0}")]
+  InvalidGroup(&'static str),
+  #[error("connector {0} not found")]
+  ConnectorNotFound(i64),
+  #[error("invalid connector: {0}")]
+  InvalidConnector(&'static str),
+  #[error("space {0} not found")]
+  SpaceNotFound(i64),
+  #[error("invalid space: {0}")]
+  InvalidSpace(&'static str),
+  #[error("item {} was changed concurrently", .0.id)]
+  Conflict(Box<Item>),
+  #[error("revision {0} not found")]
+  RevisionNotFound(i64),
+  #[error("template {0} not found")]
+  TemplateNotFound(i64),
+  #[error("invalid template: {0}")]
+  InvalidTemplate(&'static str),
 }
 
 impl Serialize for Error {
@@ -36,13 +80,26 @@ impl Serialize for Error {
   where
     S: Serializer,
   {
-    serializer.serialize_str(self.to_string().as_ref())
+    match self {
+      // Stale writes carry the stored item so the client can rebase.
+      Error::Conflict(item) => {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("error", &self.to_string())?;
+        map.serialize_entry("current", item)?;
+        map.end()
+      }
+      _ => serializer.serialize_str(self.to_string().as_ref()),
+    }
   }
 }
 
 struct AppState {
   // TODO: Consider parking_lot rwlock if async works
   db: RwLock<Option<SqlitePool>>,
+  asset_stores: RwLock<Option<AssetStores>>,
+  asset_store_args: StoreArgs,
+  asset_gc_grace_period: Duration,
+  trash_retention: Duration,
 }
 
 #[derive(Parser, Debug)]
@@ -50,6 +107,14 @@ struct AppState {
 struct Args {
   #[arg(long, env, default_value_t = LevelFilter::INFO)]
   log_level: LevelFilter,
+  /// Seconds an asset stays orphaned before it is deleted.
+  #[arg(long, env, default_value_t = 60 * 60 * 24)]
+  asset_gc_grace_period: u64,
+  /// Seconds an item stays in the trash before it is purged.
+  #[arg(long, env, default_value_t = 60 * 60 * 24 * 30)]
+  trash_retention: u64,
+  #[command(flatten)]
+  asset_store: StoreArgs,
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -60,14 +125,46 @@ pub fn run() -> anyhow::Result<()> {
 
   tauri::Builder::default()
     .plugin(tauri_plugin_dialog::init())
-    .manage(AppState { db: None.into() })
+    .manage(AppState {
+      db: None.into(),
+      asset_stores: None.into(),
+      asset_store_args: args.asset_store,
+      asset_gc_grace_period: Duration::from_secs(args.asset_gc_grace_period),
+      trash_retention: Duration::from_secs(args.trash_retention),
+    })
     .invoke_handler(tauri::generate_handler![
       connect,
       get_nearby_items,
       get_asset,
       create_item,
       patch_item,
+      patch_fields,
+      bring_forward,
+      send_back,
+      group_items,
+      ungroup_items,
+      duplicate_items,
       delete_item,
+      list_trash,
+      restore_item,
+      purge_item,
+      item_history,
+      revert_item,
+      create_connector,
+      update_connector,
+      delete_connector,
+      list_spaces,
+      create_space,
+      rename_space,
+      delete_space,
+      export_space,
+      import_space,
+      save_template,
+      list_templates,
+      instantiate_template,
+      delete_template,
+      collect_garbage,
+      migrate_assets,
       save
     ])
     .run(tauri::generate_context!())
@@ -89,6 +186,41 @@ async fn try_connect(file_path: String) -> Result<(SqlitePool, String), Error> {
   Ok((SqlitePool::connect_with(opts).await?, file_path))
 }
 
+/// Opens the asset stores of a freshly opened database, purges its expired
+/// trash and collects its orphaned assets in the background.
+async fn open_asset_stores(state: &AppState, pool: &SqlitePool, path: &str) -> Result<(), Error> {
+  let stores = AssetStores::open(&state.asset_store_args, pool, path)?;
+  let (pool, asset_store, grace_period, retention) = (
+    pool.clone(),
+    stores.active.clone(),
+    state.asset_gc_grace_period,
+    state.trash_retention,
+  );
+  *state.asset_stores.write().await = Some(stores);
+
+  tauri::async_runtime::spawn(async move {
+    match trash::purge_expired(&pool, retention).await {
+      Ok(purged) => info!("Purged {} expired item(s) from the trash", purged),
+      Err(e) => error!("Trash purge failed: {}", e),
+    }
+    match gc::collect(&pool, asset_store.as_ref(), grace_period, false).await {
+      Ok(report) => info!(
+        "Asset GC removed {} stale link(s) and {} asset(s), {} unreferenced",
+        report.stale_links.len(),
+        report.expired.len(),
+        report.unreferenced.len(),
+      ),
+      Err(e) => error!("Asset GC failed: {}", e),
+    }
+  });
+  Ok(())
+}
+
+async fn active_asset_store(state: &AppState) -> Arc<dyn AssetStore> {
+  let stores = state.asset_stores.read().await;
+  stores.as_ref().unwrap().active.clone()
+}
+
 #[tauri::command]
 async fn save<R: Runtime>(app: AppHandle<R>, state: State<'_, AppState>) -> Result<String, Error> {
   let mut pool = state.db.write().await;
@@ -104,6 +236,7 @@ async fn save<R: Runtime>(app: AppHandle<R>, state: State<'_, AppState>) -> Resu
   let (new_pool, connected_path) = try_connect(file_path).await?;
 
   sqlx::migrate!().run(&new_pool).await?;
+  open_asset_stores(&state, &new_pool, &connected_path).await?;
   *pool = Some(new_pool);
 
   Ok(connected_path)
@@ -115,36 +248,230 @@ async fn connect(state: State<'_, AppState>, path: String) -> Result<String, Err
   let new_pool = SqlitePool::connect(&path).await?;
 
   sqlx::migrate!().run(&new_pool).await?;
+  open_asset_stores(&state, &new_pool, &path).await?;
   *pool = Some(new_pool);
 
   Ok(path)
 }
 
+/// Items returned by get_nearby_items unless asked for fewer.
+const NEARBY_PAGE_SIZE: i64 = 500;
+
+/// Most items returned by get_nearby_items at once.
+const MAX_NEARBY_PAGE_SIZE: i64 = 2000;
+
+#[derive(Debug, Default, Deserialize)]
+struct BoundingBox {
+  xmin: i64,
+  ymin: i64,
+  xmax: i64,
+  ymax: i64,
+}
+
+impl BoundingBox {
+  /// Twice the centre, which keeps it integral.
+  fn doubled_centre(&self) -> (f64, f64) {
+    (
+      self.xmin as f64 + self.xmax as f64,
+      self.ymin as f64 + self.ymax as f64,
+    )
+  }
+}
+
+/// Where a page of nearby items ended, to continue after with the same
+/// viewport.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct NearbyCursor {
+  distance: f64,
+  id: i64,
+}
+
+/// Items in view and the connectors attached to any of them.
+#[derive(Debug, Serialize)]
+struct Nearby {
+  items: Vec<Item>,
+  connectors: Vec<Connector>,
+  /// Cursor of the next page, unless this is the last one.
+  next: Option<NearbyCursor>,
+}
+
+/// Squared distance between the centres of the item and of the viewport,
+/// quadrupled, computed as the nearby query does so a cursor matches exactly.
+fn distance(item: &Item, (cx, cy): (f64, f64)) -> f64 {
+  let dx = 2.0 * item.x as f64 + item.w as f64 - cx;
+  let dy = 2.0 * item.y as f64 + item.h as f64 - cy;
+  dx * dx + dy * dy
+}
+
+/**
+ * Returns a page of the items of a space, nearest to the centre of the
+ * viewport first, continuing `after` the cursor of the previous page. With
+ * `geometry_only` the schemas are left out, e.g. to lay out a huge board before
+ * loading the content.
+ */
 #[tauri::command]
 #[tracing::instrument(skip_all)]
-async fn get_nearby_items(state: State<'_, AppState>) -> Result<Vec<Item>, Error> {
+async fn get_nearby_items(
+  state: State<'_, AppState>,
+  space_id: i64,
+  viewport: Option<BoundingBox>,
+  after: Option<NearbyCursor>,
+  limit: Option<i64>,
+  geometry_only: Option<bool>,
+) -> Result<Nearby, Error> {
   let pool = state.db.read().await;
-  let rows: Vec<Item> = sqlx::query_as!(Item, "SELECT * FROM item;")
-    .fetch_all(&pool.clone().unwrap())
-    .await?;
-  info!("Retrieved {} record(s)", rows.len());
-  Ok(rows)
+  let pool = pool.clone().unwrap();
+  let centre = viewport.unwrap_or_default().doubled_centre();
+  let limit = limit
+    .unwrap_or(NEARBY_PAGE_SIZE)
+    .clamp(1, MAX_NEARBY_PAGE_SIZE);
+  let geometry_only = geometry_only.unwrap_or(false);
+  let after_distance = after.map(|cursor| cursor.distance);
+  let after_id = after.map_or(0, |cursor| cursor.id);
+  // One more item than the page tells whether another page follows.
+  let page_size = limit + 1;
+  let mut items: Vec<Item> = sqlx::query_as!(
+    Item,
+    r#"
+SELECT id, space_id, x, y, w, h, editor,
+  CASE WHEN ?7 THEN NULL ELSE schema END AS "schema?: String",
+  shared, z_index, parent_id, version, position_version, size_version, schema_version,
+  deleted_at, trash_batch
+FROM item
+WHERE space_id = ?1 AND deleted_at IS NULL
+  AND (?4 IS NULL OR (
+    (2 * CAST(x AS REAL) + w - ?2) * (2 * CAST(x AS REAL) + w - ?2)
+      + (2 * CAST(y AS REAL) + h - ?3) * (2 * CAST(y AS REAL) + h - ?3),
+    id
+  ) > (?4, ?5))
+ORDER BY
+  (2 * CAST(x AS REAL) + w - ?2) * (2 * CAST(x AS REAL) + w - ?2)
+    + (2 * CAST(y AS REAL) + h - ?3) * (2 * CAST(y AS REAL) + h - ?3),
+  id
+LIMIT ?6
+    "#,
+    space_id,
+    centre.0,
+    centre.1,
+    after_distance,
+    after_id,
+    page_size,
+    geometry_only,
+  )
+  .fetch_all(&pool)
+  .await?;
+  let next = match items.len() as i64 > limit {
+    true => {
+      items.truncate(limit as usize);
+      items.last().map(|item| NearbyCursor {
+        distance: distance(item, centre),
+        id: item.id,
+      })
+    }
+    false => None,
+  };
+
+  let ids = items.iter().map(|item| item.id).collect::<Vec<_>>();
+  let connectors = connectors::attached(&pool, &ids).await?;
+  info!(
+    "Retrieved {} record(s) and {} connector(s)",
+    items.len(),
+    connectors.len()
+  );
+  Ok(Nearby {
+    items,
+    connectors,
+    next,
+  })
 }
 
 #[tauri::command]
 #[tracing::instrument(skip_all)]
 async fn get_asset(state: State<'_, AppState>, id: String) -> Result<Asset, Error> {
   let pool = state.db.read().await;
-  let asset: Asset = sqlx::query_as!(Asset, "SELECT * FROM asset WHERE id = ?1;", id)
-    .fetch_one(&pool.clone().unwrap())
-    .await?;
+  let mut asset: Asset = sqlx::query_as!(
+    Asset,
+    r#"
+SELECT id, name, mime, hash, width, height, thumbnail, size, NULL AS "data?: Vec<u8>"
+FROM asset WHERE id = ?1
+    "#,
+    id
+  )
+  .fetch_one(&pool.clone().unwrap())
+  .await?;
+  asset.data = active_asset_store(&state).await.get(&asset.id).await?;
   info!("Retrieved asset: {}", id);
   Ok(asset)
 }
 
+/// Inspects uploaded assets off the async runtime and checks them against the
+/// image elements referencing them.
+async fn prepare_assets(
+  data: Vec<Vec<u8>>,
+  descendants: &[Descendant],
+) -> Result<Vec<Asset>, Error> {
+  let assets = tokio::task::spawn_blocking(move || {
+    data
+      .into_iter()
+      .map(|data| {
+        let mut asset = Asset::new(data);
+        asset.inspect()?;
+        Ok(asset)
+      })
+      .collect::<Result<Vec<Asset>, utils::asset::Error>>()
+  })
+  .await
+  .unwrap()?;
+  for descendant in descendants {
+    descendant.check_refs(&assets)?;
+  }
+  Ok(assets)
+}
+
+/// Stores the metadata and content of new assets, returning the ids of those
+/// whose content was already stored, which the assets take on. Content is put
+/// before the surrounding transaction commits, so a failed commit may leave
+/// unreferenced content behind but never metadata without content.
+pub(crate) async fn insert_assets(
+  connection: &mut SqliteConnection,
+  asset_store: &dyn AssetStore,
+  assets: &mut [Asset],
+) -> Result<HashMap<String, String>, Error> {
+  let mut existing_ids = HashMap::new();
+  for asset in assets {
+    let id = sqlx::query_scalar!(
+      r#"
+INSERT INTO asset ( id, name, mime, hash, width, height, thumbnail, size )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 )
+ON CONFLICT ( hash ) DO UPDATE SET hash = excluded.hash
+RETURNING id
+      "#,
+      asset.id,
+      asset.name,
+      asset.mime,
+      asset.hash,
+      asset.width,
+      asset.height,
+      asset.thumbnail,
+      asset.size,
+    )
+    .fetch_one(&mut *connection)
+    .await?;
+    if id != asset.id {
+      existing_ids.insert(asset.id.clone(), id.clone());
+      asset.id = id;
+    } else if let Some(data) = asset.data.take() {
+      asset_store.put(&asset.id, data).await?;
+    }
+  }
+  Ok(existing_ids)
+}
+
 #[tauri::command]
+#[allow(clippy::too_many_arguments)]
 async fn create_item(
   state: State<'_, AppState>,
+  space_id: i64,
   x: i64,
   y: i64,
   w: i64,
@@ -152,54 +479,35 @@ async fn create_item(
   editor: String,
   schema: String,
   shared: Option<String>,
-  mut assets: Vec<Vec<u8>>,
+  assets: Vec<Vec<u8>>,
 ) -> Result<Item, Error> {
+  let decendants = serde_json::from_str::<Vec<Descendant>>(&schema)?;
+  let mut assets = prepare_assets(assets, &decendants).await?;
+  let mut output_descendants = decendants
+    .into_iter()
+    .map(|descendant| descendant.process_refs(&mut assets).0)
+    .collect::<Vec<Descendant>>();
+
   let pool = state.db.read().await;
   let mut transaction = pool.clone().unwrap().begin().await?;
+  spaces::find(&mut transaction, space_id).await?;
 
-  let decendants = serde_json::from_str::<Vec<Descendant>>(&schema).unwrap();
-  let mut assets = assets
-    .iter_mut()
-    .map(|asset| Asset {
-      id: Uuid::new_v4().to_string(),
-      name: "".to_string(),
-      mime: "".to_string(),
-      data: Some(asset.to_vec()),
-    })
-    .collect::<Vec<Asset>>();
-  let mut output_descendants: Vec<Descendant> = vec![];
-  let mut prepared_assets: Vec<Asset> = vec![];
-
-  for descendant in decendants {
-    let (d, a) = descendant.process_refs(&mut assets);
-    output_descendants.push(d);
-    for a in a {
-      prepared_assets.push(a.clone());
-    }
-  }
-
-  for asset in &prepared_assets {
-    sqlx::query!(
-      r#"
-INSERT INTO asset ( id, name, mime, data )
-VALUES ( ?1, ?2, ?3, ?4 )
-      "#,
-      asset.id,
-      asset.name,
-      asset.mime,
-      asset.data,
-    )
-    .execute(&mut *transaction)
-    .await?;
+  let asset_store = active_asset_store(&state).await;
+  let existing_ids = insert_assets(&mut transaction, asset_store.as_ref(), &mut assets).await?;
+  for descendant in &mut output_descendants {
+    descendant.replace_refs(&existing_ids);
   }
 
   let schema = serde_json::to_string::<Vec<Descendant>>(&output_descendants)?;
 
+  // New items are stacked on top of the other top-level items of the space.
   let item = sqlx::query_as!(
     Item,
     r#"
-INSERT INTO item ( x, y, w, h, editor, schema, shared )
-VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 ) RETURNING *
+INSERT INTO item ( space_id, x, y, w, h, editor, schema, shared, z_index )
+SELECT ?8, ?1, ?2, ?3, ?4, ?5, ?6, ?7, COALESCE(MAX(z_index), 0) + 1
+FROM item WHERE space_id = ?8 AND parent_id IS NULL
+RETURNING *
       "#,
     x,
     y,
@@ -208,15 +516,17 @@ VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 ) RETURNING *
     editor,
     schema,
     shared,
+    space_id,
   )
   .fetch_one(&mut *transaction)
   .await?;
 
-  for asset in prepared_assets {
+  for asset in assets {
     sqlx::query!(
       r#"
   INSERT INTO item_assets ( item_id, asset_id )
   VALUES ( ?1, ?2 )
+  ON CONFLICT DO NOTHING
         "#,
       item.id,
       asset.id,
@@ -224,6 +534,13 @@ VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 ) RETURNING *
     .execute(&mut *transaction)
     .await?;
   }
+  history::record(
+    &mut transaction,
+    "create",
+    &history::Snapshot::default(),
+    &item,
+  )
+  .await?;
 
   transaction.commit().await?;
 
@@ -236,19 +553,23 @@ VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 ) RETURNING *
  * items without needing to throttle on the client, while without it can only handle
  * 1 moving item per Tauri command invocation.
  *
- * The patch operations could be refined into:
- * - Moving
- * - Resizing
- * - Schema updates
+ * Every item replaces all of its fields, so it is rejected with a conflict if
+ * the stored item changed since the `version` it carries. Use patch_fields to
+ * move, resize or edit the content on its own.
+ *
+ * Stacking and grouping are left untouched, see the layer commands below.
  */
 #[tauri::command]
-async fn patch_item(state: State<'_, AppState>, items: Vec<Item>) -> Result<(), Error> {
+async fn patch_item(state: State<'_, AppState>, items: Vec<Item>) -> Result<Vec<Item>, Error> {
   let pool = state.db.read().await;
   let mut transaction = pool.clone().unwrap().begin().await?;
 
+  let mut patched = vec![];
   for item in items {
+    let before = history::snapshot(&mut transaction, item.id).await?;
     // https://github.com/launchbadge/sqlx/issues/2542
-    sqlx::query!(
+    let stored = sqlx::query_as!(
+      Item,
       r#"
   UPDATE item
   SET x = ?2,
@@ -257,8 +578,15 @@ async fn patch_item(state: State<'_, AppState>, items: Vec<Item>) -> Result<(),
     h = ?5,
     editor = ?6,
     schema = ?7,
-    shared = ?8
+    shared = ?8,
+    version = version + 1,
+    position_version = version + 1,
+    size_version = version + 1,
+    schema_version = version + 1
   WHERE id = ?1
+    AND max(position_version, size_version, schema_version) <= ?9
+    AND deleted_at IS NULL
+  RETURNING *
       "#,
       item.id,
       item.x,
@@ -268,28 +596,338 @@ async fn patch_item(state: State<'_, AppState>, items: Vec<Item>) -> Result<(),
       item.editor,
       item.schema,
       item.shared,
+      item.version,
     )
-    .execute(&mut *transaction)
+    .fetch_optional(&mut *transaction)
     .await?;
+    match stored {
+      Some(stored) => {
+        history::record(&mut transaction, "update", &before, &stored).await?;
+        patched.push(stored);
+      }
+      None => return Err(patches::conflict(&mut transaction, item.id).await?),
+    }
   }
 
   transaction.commit().await?;
 
-  Ok(())
+  Ok(patched)
+}
+
+/**
+ * Applies moves, resizes and content edits, each based on the version of its
+ * item. Patches of different fields merge, while a patch of a field changed
+ * since its version fails the whole batch with a conflict.
+ */
+#[tauri::command]
+async fn patch_fields(
+  state: State<'_, AppState>,
+  patches: Vec<patches::ItemPatch>,
+) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  patches::patch(&pool.clone().unwrap(), &patches).await
+}
+
+#[tauri::command]
+async fn bring_forward(state: State<'_, AppState>, id: i64) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  layers::step(&pool.clone().unwrap(), id, layers::Step::Forward).await
 }
 
+#[tauri::command]
+async fn send_back(state: State<'_, AppState>, id: i64) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  layers::step(&pool.clone().unwrap(), id, layers::Step::Back).await
+}
+
+#[tauri::command]
+async fn group_items(
+  state: State<'_, AppState>,
+  parent_id: i64,
+  ids: Vec<i64>,
+) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  layers::group(&pool.clone().unwrap(), parent_id, &ids).await
+}
+
+#[tauri::command]
+async fn ungroup_items(state: State<'_, AppState>, id: i64) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  layers::ungroup(&pool.clone().unwrap(), id).await
+}
+
+/**
+ * Copies the items, with every item they contain, moved by `dx`, `dy`. The
+ * copies reference the same assets as the originals.
+ */
+#[tauri::command]
+async fn duplicate_items(
+  state: State<'_, AppState>,
+  ids: Vec<i64>,
+  dx: i64,
+  dy: i64,
+) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  duplicates::duplicate(&pool.clone().unwrap(), &ids, dx, dy).await
+}
+
+/**
+ * Moves an item, and every item it contains, to the trash. It is purged once
+ * the retention period has passed, unless it is restored before.
+ */
 #[tauri::command]
 async fn delete_item(state: State<'_, AppState>, id: i64) -> Result<i64, Error> {
   let pool = state.db.read().await;
+  trash::trash(&pool.clone().unwrap(), id).await?;
+  Ok(id)
+}
 
-  sqlx::query!(
-    r#"
-DELETE FROM item WHERE id = ?1
-    "#,
+#[tauri::command]
+async fn list_trash(state: State<'_, AppState>, space_id: i64) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  trash::list(&pool.clone().unwrap(), space_id).await
+}
+
+/**
+ * Restores an item along with the items trashed with it.
+ */
+#[tauri::command]
+async fn restore_item(state: State<'_, AppState>, id: i64) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  trash::restore(&pool.clone().unwrap(), id).await
+}
+
+/**
+ * Lists who changed an item, how and when, oldest first.
+ */
+#[tauri::command]
+async fn item_history(
+  state: State<'_, AppState>,
+  id: i64,
+) -> Result<Vec<history::Revision>, Error> {
+  let pool = state.db.read().await;
+  history::list(&pool.clone().unwrap(), id).await
+}
+
+/**
+ * Restores the position, size and content an item had after revision `id`,
+ * unless the item changed since `version`.
+ */
+#[tauri::command]
+async fn revert_item(
+  state: State<'_, AppState>,
+  item_id: i64,
+  id: i64,
+  version: i64,
+) -> Result<Item, Error> {
+  let pool = state.db.read().await;
+  history::revert(&pool.clone().unwrap(), item_id, id, version).await
+}
+
+/**
+ * Permanently deletes a trashed item along with the items trashed with it.
+ */
+#[tauri::command]
+async fn purge_item(state: State<'_, AppState>, id: i64) -> Result<Vec<i64>, Error> {
+  let pool = state.db.read().await;
+  trash::purge(&pool.clone().unwrap(), id).await
+}
+
+#[tauri::command]
+async fn create_connector(
+  state: State<'_, AppState>,
+  connector: connectors::ConnectorData,
+) -> Result<Connector, Error> {
+  let pool = state.db.read().await;
+  connectors::create(&pool.clone().unwrap(), connector).await
+}
+
+#[tauri::command]
+async fn update_connector(
+  state: State<'_, AppState>,
+  id: i64,
+  connector: connectors::ConnectorData,
+) -> Result<Connector, Error> {
+  let pool = state.db.read().await;
+  connectors::update(&pool.clone().unwrap(), id, connector).await
+}
+
+#[tauri::command]
+async fn delete_connector(state: State<'_, AppState>, id: i64) -> Result<i64, Error> {
+  let pool = state.db.read().await;
+  connectors::delete(&pool.clone().unwrap(), id).await
+}
+
+#[tauri::command]
+async fn list_spaces(state: State<'_, AppState>) -> Result<Vec<spaces::Space>, Error> {
+  let pool = state.db.read().await;
+  spaces::list(&pool.clone().unwrap()).await
+}
+
+#[tauri::command]
+async fn create_space(state: State<'_, AppState>, name: String) -> Result<spaces::Space, Error> {
+  let pool = state.db.read().await;
+  spaces::create(&pool.clone().unwrap(), &name).await
+}
+
+#[tauri::command]
+async fn rename_space(
+  state: State<'_, AppState>,
+  id: i64,
+  name: String,
+) -> Result<spaces::Space, Error> {
+  let pool = state.db.read().await;
+  spaces::rename(&pool.clone().unwrap(), id, &name).await
+}
+
+/**
+ * Deletes a space along with its items and connectors.
+ */
+#[tauri::command]
+async fn delete_space(state: State<'_, AppState>, id: i64) -> Result<i64, Error> {
+  let pool = state.db.read().await;
+  spaces::delete(&pool.clone().unwrap(), id).await
+}
+
+/**
+ * Writes the space, with the items, connectors and assets in it, to an archive
+ * at `path`.
+ */
+#[tauri::command]
+async fn export_space(state: State<'_, AppState>, id: i64, path: String) -> Result<(), Error> {
+  let pool = state.db.read().await;
+  let data = archives::export(
+    &pool.clone().unwrap(),
+    active_asset_store(&state).await.as_ref(),
     id,
   )
-  .execute(&pool.clone().unwrap())
   .await?;
+  tokio::fs::write(&path, data).await?;
+  info!("Exported space {} to {}", id, path);
+  Ok(())
+}
 
-  Ok(id)
+/**
+ * Creates a space from the archive at `path`, named `name` or as in the
+ * archive.
+ */
+#[tauri::command]
+async fn import_space(
+  state: State<'_, AppState>,
+  path: String,
+  name: Option<String>,
+) -> Result<spaces::Space, Error> {
+  let data = tokio::fs::read(&path).await?;
+  let pool = state.db.read().await;
+  archives::import(
+    &pool.clone().unwrap(),
+    active_asset_store(&state).await.as_ref(),
+    data,
+    name.as_deref(),
+  )
+  .await
+}
+
+/**
+ * Saves the items, with every item they contain, as a template named `name`.
+ * With `space_only` it is offered in the space of the items only, otherwise in
+ * every space.
+ */
+#[tauri::command]
+async fn save_template(
+  state: State<'_, AppState>,
+  ids: Vec<i64>,
+  name: String,
+  space_only: Option<bool>,
+) -> Result<templates::Template, Error> {
+  let pool = state.db.read().await;
+  templates::save(
+    &pool.clone().unwrap(),
+    &ids,
+    &name,
+    space_only.unwrap_or_default(),
+  )
+  .await
+}
+
+/**
+ * Lists the templates offered in the space, or all of them without one.
+ */
+#[tauri::command]
+async fn list_templates(
+  state: State<'_, AppState>,
+  space_id: Option<i64>,
+) -> Result<Vec<templates::Template>, Error> {
+  let pool = state.db.read().await;
+  templates::list(&pool.clone().unwrap(), space_id).await
+}
+
+/**
+ * Creates new items from a template, with its top-left corner at `x`, `y` and
+ * stacked on top of the space.
+ */
+#[tauri::command]
+async fn instantiate_template(
+  state: State<'_, AppState>,
+  id: i64,
+  space_id: i64,
+  x: i64,
+  y: i64,
+) -> Result<Vec<Item>, Error> {
+  let pool = state.db.read().await;
+  templates::instantiate(&pool.clone().unwrap(), id, space_id, (x, y)).await
+}
+
+#[tauri::command]
+async fn delete_template(state: State<'_, AppState>, id: i64) -> Result<i64, Error> {
+  let pool = state.db.read().await;
+  templates::delete(&pool.clone().unwrap(), id).await
+}
+
+/**
+ * Reports assets that no item references. Unless `dry_run` is set, stale links
+ * are removed and assets orphaned for longer than the grace period are deleted.
+ */
+#[tauri::command]
+async fn collect_garbage(state: State<'_, AppState>, dry_run: bool) -> Result<gc::Report, Error> {
+  let pool = state.db.read().await;
+  gc::collect(
+    &pool.clone().unwrap(),
+    active_asset_store(&state).await.as_ref(),
+    state.asset_gc_grace_period,
+    dry_run,
+  )
+  .await
+}
+
+/**
+ * Moves the content of all assets from one store backend to another, e.g. out
+ * of the database file. The app has to be configured with the new backend to
+ * use the moved content.
+ */
+#[tauri::command]
+async fn migrate_assets(
+  state: State<'_, AppState>,
+  from: String,
+  to: String,
+) -> Result<usize, Error> {
+  let parse = |backend: String| {
+    Backend::from_str(&backend, true).map_err(|_| Error::UnknownAssetStore(backend))
+  };
+  let (from, to) = (parse(from)?, parse(to)?);
+
+  let pool = state.db.read().await;
+  let ids = sqlx::query_scalar!("SELECT id FROM asset")
+    .fetch_all(&pool.clone().unwrap())
+    .await?;
+  let stores = state.asset_stores.read().await;
+  let stores = stores.as_ref().unwrap();
+  let moved = asset_store::migrate(
+    &ids,
+    stores.build(from)?.as_ref(),
+    stores.build(to)?.as_ref(),
+  )
+  .await?;
+  info!("Moved {} of {} asset(s)", moved, ids.len());
+  Ok(moved)
 }
@@ -0,0 +1,124 @@
+//! Fine-grained writes to items. A patch changes one group of fields and only
+//! conflicts with changes to that group made after the version it was based
+//! on, so concurrent moves, resizes and edits of the same item merge.
+
+use serde::Deserialize;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{history, item::Item, layers, Error};
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+  /// Moves the item and everything it contains by the same offset.
+  Move {
+    x: i64,
+    y: i64,
+  },
+  Resize {
+    w: i64,
+    h: i64,
+  },
+  Schema {
+    schema: String,
+  },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ItemPatch {
+  pub id: i64,
+  /// Version of the item the patch is based on.
+  pub version: i64,
+  #[serde(flatten)]
+  pub change: Change,
+}
+
+/// Builds the error rejecting a stale write from the item as stored. Items in
+/// the trash are reported as missing.
+pub async fn conflict(connection: &mut SqliteConnection, id: i64) -> Result<Error, Error> {
+  let item = sqlx::query_as!(
+    Item,
+    "SELECT * FROM item WHERE id = ?1 AND deleted_at IS NULL",
+    id
+  )
+  .fetch_optional(connection)
+  .await?
+  .ok_or(Error::ItemNotFound(id))?;
+  Ok(Error::Conflict(Box::new(item)))
+}
+
+async fn apply(connection: &mut SqliteConnection, patch: &ItemPatch) -> sqlx::Result<Option<Item>> {
+  match &patch.change {
+    Change::Move { x, y } => {
+      sqlx::query_as!(
+        Item,
+        r#"
+UPDATE item SET x = ?3, y = ?4, version = version + 1, position_version = version + 1
+WHERE id = ?1 AND position_version <= ?2 AND deleted_at IS NULL
+RETURNING *
+        "#,
+        patch.id,
+        patch.version,
+        x,
+        y,
+      )
+      .fetch_optional(connection)
+      .await
+    }
+    Change::Resize { w, h } => {
+      sqlx::query_as!(
+        Item,
+        r#"
+UPDATE item SET w = ?3, h = ?4, version = version + 1, size_version = version + 1
+WHERE id = ?1 AND size_version <= ?2 AND deleted_at IS NULL
+RETURNING *
+        "#,
+        patch.id,
+        patch.version,
+        w,
+        h,
+      )
+      .fetch_optional(connection)
+      .await
+    }
+    Change::Schema { schema } => {
+      sqlx::query_as!(
+        Item,
+        r#"
+UPDATE item SET schema = ?3, version = version + 1, schema_version = version + 1
+WHERE id = ?1 AND schema_version <= ?2 AND deleted_at IS NULL
+RETURNING *
+        "#,
+        patch.id,
+        patch.version,
+        schema,
+      )
+      .fetch_optional(connection)
+      .await
+    }
+  }
+}
+
+/// Applies the patches in order, all or none of them. Returns the patched
+/// items followed by the contents of the moved ones.
+pub async fn patch(pool: &SqlitePool, patches: &[ItemPatch]) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  let mut items = vec![];
+  let mut contents = vec![];
+  for patch in patches {
+    let before = history::snapshot(&mut transaction, patch.id).await?;
+    let Some(item) = apply(&mut transaction, patch).await? else {
+      return Err(conflict(&mut transaction, patch.id).await?);
+    };
+    history::record(&mut transaction, "patch", &before, &item).await?;
+    if let Change::Move { .. } = patch.change {
+      let offset = (item.x - before.x, item.y - before.y);
+      contents.extend(layers::move_contents(&mut transaction, item.id, offset).await?);
+    }
+    items.push(item);
+  }
+
+  transaction.commit().await?;
+  items.extend(contents);
+  Ok(items)
+}
@@ -0,0 +1,77 @@
+//! Spaces are the boards of the local database. Every item and connector
+//! belongs to one and is deleted along with it.
+
+use serde::Serialize;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::Error;
+
+#[derive(Serialize, PartialEq, Clone, Debug)]
+pub struct Space {
+  pub id: i64,
+  pub name: String,
+}
+
+fn check_name(name: &str) -> Result<&str, Error> {
+  let name = name.trim();
+  match name.chars().count() {
+    0 => Err(Error::InvalidSpace("name is empty")),
+    1..=100 => Ok(name),
+    _ => Err(Error::InvalidSpace("name is longer than 100 characters")),
+  }
+}
+
+/// Checks that the space exists.
+pub async fn find(connection: &mut SqliteConnection, id: i64) -> Result<(), Error> {
+  sqlx::query_scalar!("SELECT id FROM space WHERE id = ?1", id)
+    .fetch_optional(connection)
+    .await?
+    .map(|_| ())
+    .ok_or(Error::SpaceNotFound(id))
+}
+
+pub async fn list(pool: &SqlitePool) -> Result<Vec<Space>, Error> {
+  Ok(
+    sqlx::query_as!(Space, "SELECT id, name FROM space ORDER BY created_at, id")
+      .fetch_all(pool)
+      .await?,
+  )
+}
+
+pub async fn create(pool: &SqlitePool, name: &str) -> Result<Space, Error> {
+  insert(&mut *pool.acquire().await?, name).await
+}
+
+pub async fn insert(connection: &mut SqliteConnection, name: &str) -> Result<Space, Error> {
+  let name = check_name(name)?;
+  Ok(
+    sqlx::query_as!(
+      Space,
+      "INSERT INTO space ( name ) VALUES ( ?1 ) RETURNING id, name",
+      name,
+    )
+    .fetch_one(connection)
+    .await?,
+  )
+}
+
+pub async fn rename(pool: &SqlitePool, id: i64, name: &str) -> Result<Space, Error> {
+  let name = check_name(name)?;
+  sqlx::query_as!(
+    Space,
+    "UPDATE space SET name = ?2 WHERE id = ?1 RETURNING id, name",
+    id,
+    name,
+  )
+  .fetch_optional(pool)
+  .await?
+  .ok_or(Error::SpaceNotFound(id))
+}
+
+/// Deletes the space along with its items and connectors.
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<i64, Error> {
+  sqlx::query_scalar!("DELETE FROM space WHERE id = ?1 RETURNING id", id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(Error::SpaceNotFound(id))
+}
@@ -0,0 +1,86 @@
+use std::{path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use sqlx::SqlitePool;
+use utils::asset_store::{AssetStore, Backend, Error, StoreArgs};
+
+/// The asset stores of an opened database.
+pub struct AssetStores {
+  /// Configuration with the filesystem path resolved for the database.
+  args: StoreArgs,
+  database: Arc<dyn AssetStore>,
+  /// The configured store, used for new content.
+  pub active: Arc<dyn AssetStore>,
+}
+
+impl AssetStores {
+  /// Unless configured otherwise, the filesystem backend keeps content in a
+  /// directory next to the database file.
+  pub fn open(args: &StoreArgs, pool: &SqlitePool, path: &str) -> Result<Self, Error> {
+    let mut args = args.clone();
+    if args.asset_store_path.is_none() {
+      let file = path
+        .trim_start_matches("sqlite:")
+        .trim_start_matches("//")
+        .split('?')
+        .next()
+        .unwrap_or_default();
+      args.asset_store_path = Some(PathBuf::from(file).with_extension("assets"));
+    }
+    let database: Arc<dyn AssetStore> = Arc::new(DatabaseStore::new(pool.clone()));
+    let active = args.build(args.asset_store, database.clone())?;
+    Ok(Self {
+      args,
+      database,
+      active,
+    })
+  }
+
+  pub fn build(&self, backend: Backend) -> Result<Arc<dyn AssetStore>, Error> {
+    self.args.build(backend, self.database.clone())
+  }
+}
+
+/// Keeps asset content in the `asset_blob` table of the opened database.
+pub struct DatabaseStore {
+  pool: SqlitePool,
+}
+
+impl DatabaseStore {
+  pub fn new(pool: SqlitePool) -> Self {
+    Self { pool }
+  }
+}
+
+#[async_trait]
+impl AssetStore for DatabaseStore {
+  async fn put(&self, id: &str, data: Vec<u8>) -> Result<(), Error> {
+    sqlx::query!(
+      r#"
+INSERT INTO asset_blob ( id, data ) VALUES ( ?1, ?2 )
+ON CONFLICT ( id ) DO UPDATE SET data = excluded.data
+      "#,
+      id,
+      data,
+    )
+    .execute(&self.pool)
+    .await
+    .map_err(|e| Error::Database(e.into()))?;
+    Ok(())
+  }
+
+  async fn get(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+    sqlx::query_scalar!("SELECT data FROM asset_blob WHERE id = ?1", id)
+      .fetch_optional(&self.pool)
+      .await
+      .map_err(|e| Error::Database(e.into()))
+  }
+
+  async fn delete(&self, id: &str) -> Result<(), Error> {
+    sqlx::query!("DELETE FROM asset_blob WHERE id = ?1", id)
+      .execute(&self.pool)
+      .await
+      .map_err(|e| Error::Database(e.into()))?;
+    Ok(())
+  }
+}
@@ -0,0 +1,266 @@
+//! Templates are items saved under a name to be laid out again, like the
+//! notes of a recurring meeting. A template keeps the items with the items
+//! they contain and the connectors between them, placed relative to their
+//! top-left corner, and links their assets so they stay stored.
+//! Instantiating a template creates new items, stacked on top of the space.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utils::{archive, archive::Archive};
+
+use crate::{
+  connectors::{self, ConnectorData},
+  duplicates,
+  history::{self, Snapshot},
+  item::{Connector, Descendant, Item},
+  spaces, Error,
+};
+
+#[derive(Serialize, PartialEq, Clone, Debug)]
+pub struct Template {
+  pub id: i64,
+  pub name: String,
+  /// Space the template is offered in, every space without it.
+  pub space_id: Option<i64>,
+}
+
+/// Stored content of a template, in the format of space archives. Item ids
+/// only relate the items to each other.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Content {
+  items: Vec<archive::Item>,
+  connectors: Vec<archive::Connector>,
+}
+
+fn check_name(name: &str) -> Result<&str, Error> {
+  let name = name.trim();
+  match name.chars().count() {
+    0 => Err(Error::InvalidTemplate("name is empty")),
+    1..=100 => Ok(name),
+    _ => Err(Error::InvalidTemplate("name is longer than 100 characters")),
+  }
+}
+
+/// Saves `ids`, with the items they contain, as a template named `name`,
+/// offered in the space of the items only with `space_only`.
+pub async fn save(
+  pool: &SqlitePool,
+  ids: &[i64],
+  name: &str,
+  space_only: bool,
+) -> Result<Template, Error> {
+  let name = check_name(name)?;
+  if ids.is_empty() {
+    return Err(Error::InvalidTemplate("no items"));
+  }
+  let mut transaction = pool.begin().await?;
+  let items = duplicates::subtree(&mut transaction, ids).await?;
+  if let Some(&missing) = ids
+    .iter()
+    .find(|&&id| !items.iter().any(|item| item.id == id))
+  {
+    return Err(Error::ItemNotFound(missing));
+  }
+  let space_id = items[0].space_id;
+  if items.iter().any(|item| item.space_id != space_id) {
+    return Err(Error::InvalidTemplate("items are in different spaces"));
+  }
+
+  let saved = serde_json::to_string(&items.iter().map(|item| item.id).collect::<Vec<_>>())?;
+  let connectors = sqlx::query_as!(
+    Connector,
+    r#"
+SELECT * FROM connector
+WHERE source_id IN (SELECT value FROM json_each(?1))
+  AND target_id IN (SELECT value FROM json_each(?1))
+ORDER BY id
+    "#,
+    saved,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+
+  // Items whose frame is left out become top-level items of the template.
+  let saved_ids = items.iter().map(|item| item.id).collect::<HashSet<_>>();
+  let x = items.iter().map(|item| item.x).min().unwrap_or_default();
+  let y = items.iter().map(|item| item.y).min().unwrap_or_default();
+  let content = serde_json::to_string(&Content {
+    items: items
+      .into_iter()
+      .map(|item| archive::Item {
+        id: item.id,
+        x: item.x - x,
+        y: item.y - y,
+        w: item.w,
+        h: item.h,
+        editor: item.editor,
+        schema: item.schema,
+        z_index: item.z_index,
+        parent_id: item.parent_id.filter(|id| saved_ids.contains(id)),
+      })
+      .collect(),
+    connectors: connectors
+      .into_iter()
+      .map(|connector| archive::Connector {
+        source_id: connector.source_id,
+        target_id: connector.target_id,
+        source_anchor: connector.source_anchor,
+        target_anchor: connector.target_anchor,
+        label: connector.label,
+        style: connector.style,
+      })
+      .collect(),
+  })?;
+
+  let space_id = space_id.filter(|_| space_only);
+  let template = sqlx::query_as!(
+    Template,
+    r#"
+INSERT INTO template ( space_id, name, content ) VALUES ( ?1, ?2, ?3 )
+RETURNING id, name, space_id
+    "#,
+    space_id,
+    name,
+    content,
+  )
+  .fetch_one(&mut *transaction)
+  .await?;
+  sqlx::query!(
+    r#"
+INSERT INTO template_assets ( template_id, asset_id )
+SELECT DISTINCT ?1, asset_id FROM item_assets
+WHERE item_id IN (SELECT value FROM json_each(?2))
+    "#,
+    template.id,
+    saved,
+  )
+  .execute(&mut *transaction)
+  .await?;
+
+  transaction.commit().await?;
+  Ok(template)
+}
+
+/// Lists the templates offered in `space_id`, or all of them.
+pub async fn list(pool: &SqlitePool, space_id: Option<i64>) -> Result<Vec<Template>, Error> {
+  Ok(
+    sqlx::query_as!(
+      Template,
+      r#"
+SELECT id, name, space_id FROM template
+WHERE ?1 IS NULL OR space_id IS NULL OR space_id = ?1
+ORDER BY name, id
+      "#,
+      space_id,
+    )
+    .fetch_all(pool)
+    .await?,
+  )
+}
+
+/// Creates the items of a template in `space_id` with their top-left corner
+/// at `x` and `y`.
+pub async fn instantiate(
+  pool: &SqlitePool,
+  id: i64,
+  space_id: i64,
+  (x, y): (i64, i64),
+) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  spaces::find(&mut transaction, space_id).await?;
+  let content = sqlx::query_scalar!(
+    "SELECT content FROM template WHERE id = ?1 AND (space_id IS NULL OR space_id = ?2)",
+    id,
+    space_id,
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::TemplateNotFound(id))?;
+  let content = serde_json::from_str::<Content>(&content)?;
+  let layout = Archive {
+    items: content.items,
+    connectors: content.connectors,
+    ..Default::default()
+  };
+
+  // Top-level items are stacked on top of the space in their order, the
+  // stacks they contain are created as they are.
+  let mut item_ids = HashMap::<i64, i64>::new();
+  let mut items = Vec::with_capacity(layout.items.len());
+  for item in layout.ordered_items()? {
+    let parent_id = item.parent_id.map(|id| item_ids[&id]);
+    let z_index = parent_id.map(|_| item.z_index);
+    let (item_x, item_y) = (item.x + x, item.y + y);
+    let instance = sqlx::query_as!(
+      Item,
+      r#"
+INSERT INTO item ( space_id, x, y, w, h, editor, schema, z_index, parent_id )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, COALESCE(?8, (
+  SELECT COALESCE(MAX(z_index), 0) + 1 FROM item WHERE space_id = ?1 AND parent_id IS NULL
+)), ?9 )
+RETURNING *
+      "#,
+      space_id,
+      item_x,
+      item_y,
+      item.w,
+      item.h,
+      item.editor,
+      item.schema,
+      z_index,
+      parent_id,
+    )
+    .fetch_one(&mut *transaction)
+    .await?;
+    item_ids.insert(item.id, instance.id);
+
+    let descendants =
+      serde_json::from_str::<Vec<Descendant>>(instance.schema.as_deref().unwrap_or("[]"))?;
+    let mut refs = HashSet::new();
+    for descendant in &descendants {
+      descendant.asset_refs(&mut refs);
+    }
+    for asset_id in &refs {
+      sqlx::query!(
+        "INSERT INTO item_assets ( item_id, asset_id ) VALUES ( ?1, ?2 ) ON CONFLICT DO NOTHING",
+        instance.id,
+        asset_id,
+      )
+      .execute(&mut *transaction)
+      .await?;
+    }
+    history::record(
+      &mut transaction,
+      "template",
+      &Snapshot::default(),
+      &instance,
+    )
+    .await?;
+    items.push(instance);
+  }
+
+  for connector in &layout.connectors {
+    let connector = ConnectorData {
+      source_id: item_ids[&connector.source_id],
+      target_id: item_ids[&connector.target_id],
+      source_anchor: connector.source_anchor.clone(),
+      target_anchor: connector.target_anchor.clone(),
+      label: connector.label.clone(),
+      style: connector.style.clone(),
+    };
+    connectors::insert(&mut transaction, Some(space_id), &connector).await?;
+  }
+
+  transaction.commit().await?;
+  Ok(items)
+}
+
+/// Deletes a template, releasing its assets.
+pub async fn delete(pool: &SqlitePool, id: i64) -> Result<i64, Error> {
+  sqlx::query_scalar!("DELETE FROM template WHERE id = ?1 RETURNING id", id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(Error::TemplateNotFound(id))
+}
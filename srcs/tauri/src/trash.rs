@@ -0,0 +1,196 @@
+//! Deleted items stay in the trash until they are restored, purged or expire.
+//! Deleting a frame trashes the items it contains along with it, and they are
+//! restored or purged together.
+
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use sqlx::{SqliteConnection, SqlitePool};
+
+use crate::{
+  history::{self, Snapshot},
+  item::Item,
+  Error,
+};
+
+/// Returns when a trashed item was deleted and the batch it was trashed in.
+async fn find_trashed(
+  connection: &mut SqliteConnection,
+  id: i64,
+) -> Result<(NaiveDateTime, i64), Error> {
+  sqlx::query!(
+    r#"
+SELECT deleted_at AS "deleted_at!", trash_batch AS "trash_batch!"
+FROM item WHERE id = ?1 AND deleted_at IS NOT NULL
+    "#,
+    id,
+  )
+  .map(|row| (row.deleted_at, row.trash_batch))
+  .fetch_optional(connection)
+  .await?
+  .ok_or(Error::ItemNotFound(id))
+}
+
+/// Moves an item, and every item it contains, to the trash.
+pub async fn trash(pool: &SqlitePool, id: i64) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  sqlx::query_scalar!(
+    "SELECT id FROM item WHERE id = ?1 AND deleted_at IS NULL",
+    id
+  )
+  .fetch_optional(&mut *transaction)
+  .await?
+  .ok_or(Error::ItemNotFound(id))?;
+
+  let trashed = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT ?1
+  UNION
+  SELECT item.id FROM item
+  JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.deleted_at IS NULL
+)
+UPDATE item SET deleted_at = CURRENT_TIMESTAMP, trash_batch = ?1, version = version + 1
+WHERE id IN subtree
+RETURNING *
+    "#,
+    id,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  for item in &trashed {
+    let before = Snapshot {
+      deleted_at: None,
+      ..Snapshot::of(item)
+    };
+    history::record(&mut transaction, "delete", &before, item).await?;
+  }
+
+  transaction.commit().await?;
+  Ok(trashed)
+}
+
+pub async fn list(pool: &SqlitePool, space_id: i64) -> Result<Vec<Item>, Error> {
+  Ok(
+    sqlx::query_as!(
+      Item,
+      r#"
+SELECT * FROM item
+WHERE space_id = ?1 AND deleted_at IS NOT NULL
+ORDER BY deleted_at DESC, id
+      "#,
+      space_id,
+    )
+    .fetch_all(pool)
+    .await?,
+  )
+}
+
+/// Restores the item along with the items trashed with it. An item whose frame
+/// is still in the trash is restored to the top level.
+pub async fn restore(pool: &SqlitePool, id: i64) -> Result<Vec<Item>, Error> {
+  let mut transaction = pool.begin().await?;
+  let (deleted_at, batch) = find_trashed(&mut transaction, id).await?;
+
+  sqlx::query!(
+    r#"
+UPDATE item SET parent_id = NULL
+WHERE id = ?1 AND parent_id IN (SELECT id FROM item WHERE deleted_at IS NOT NULL)
+    "#,
+    id,
+  )
+  .execute(&mut *transaction)
+  .await?;
+  let restored = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT ?1
+  UNION
+  SELECT item.id FROM item
+  JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.trash_batch = ?2
+)
+UPDATE item SET deleted_at = NULL, trash_batch = NULL, version = version + 1
+WHERE id IN subtree
+RETURNING *
+    "#,
+    id,
+    batch,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  for item in &restored {
+    let before = Snapshot {
+      deleted_at: Some(deleted_at.and_utc().timestamp()),
+      ..Snapshot::of(item)
+    };
+    history::record(&mut transaction, "restore", &before, item).await?;
+  }
+
+  transaction.commit().await?;
+  Ok(restored)
+}
+
+/// Records a `purge` for every deleted item, returning their ids.
+async fn record_purged(
+  connection: &mut SqliteConnection,
+  purged: &[Item],
+) -> Result<Vec<i64>, Error> {
+  for item in purged {
+    history::record(&mut *connection, "purge", &Snapshot::of(item), item).await?;
+  }
+  let mut ids = purged.iter().map(|item| item.id).collect::<Vec<_>>();
+  ids.sort();
+  Ok(ids)
+}
+
+/// Permanently deletes the item along with the items trashed with it,
+/// returning their ids.
+pub async fn purge(pool: &SqlitePool, id: i64) -> Result<Vec<i64>, Error> {
+  let mut transaction = pool.begin().await?;
+  let (_, batch) = find_trashed(&mut transaction, id).await?;
+
+  let purged = sqlx::query_as!(
+    Item,
+    r#"
+WITH RECURSIVE subtree ( id ) AS (
+  SELECT ?1
+  UNION
+  SELECT item.id FROM item
+  JOIN subtree ON item.parent_id = subtree.id
+  WHERE item.trash_batch = ?2
+)
+DELETE FROM item WHERE id IN subtree
+RETURNING *
+    "#,
+    id,
+    batch,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let ids = record_purged(&mut transaction, &purged).await?;
+
+  transaction.commit().await?;
+  Ok(ids)
+}
+
+/// Permanently deletes items that have been in the trash for longer than
+/// `retention`. Their assets are left to the garbage collector.
+pub async fn purge_expired(pool: &SqlitePool, retention: Duration) -> Result<u64, Error> {
+  let retention = retention.as_secs() as i64;
+  let mut transaction = pool.begin().await?;
+  let purged = sqlx::query_as!(
+    Item,
+    "DELETE FROM item WHERE unixepoch(deleted_at) < unixepoch() - ?1 RETURNING *",
+    retention,
+  )
+  .fetch_all(&mut *transaction)
+  .await?;
+  let ids = record_purged(&mut transaction, &purged).await?;
+
+  transaction.commit().await?;
+  Ok(ids.len() as u64)
+}
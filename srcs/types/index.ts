@@ -1,7 +1,65 @@
 export type Editors = Item['editor'];
 
+export type Space = {
+  id?: number;
+  name: string;
+};
+
+/**
+ * Creates a space from an archive sent as an upload with `asset:upload_begin`
+ * and `asset:upload_append`, which `space:import` commits in place of
+ * `asset:upload_commit`. A space is exported by downloading the archive from
+ * `/api/space/{id}/export`.
+ */
+export type SpaceImport = {
+  upload: string;
+  /**
+   * Hex encoded SHA-256 of the archive.
+   */
+  hash: string;
+  /**
+   * Name of the new space, the one in the archive by default.
+   */
+  name?: string;
+};
+
+/**
+ * Items saved to be laid out again with `template:instantiate`.
+ */
+export type Template = {
+  id: number;
+  name: string;
+  /**
+   * Space the template is offered in, every space of its owner without it.
+   */
+  space_id: number | null;
+};
+
+/**
+ * Saves the items, with the items they contain, as a template.
+ */
+export type TemplateSave = {
+  ids: number[];
+  name: string;
+  /**
+   * Offers the template in the space of the items only.
+   */
+  space_only?: boolean;
+};
+
+/**
+ * Creates the items of a template with their top-left corner at `x` and `y`.
+ */
+export type TemplateInstantiate = {
+  id: number;
+  space_id: number;
+  x: number;
+  y: number;
+};
+
 export type Item = {
   id?: number;
+  space_id?: number;
   x: number;
   y: number;
   w: number;
@@ -19,6 +77,209 @@ export type Item = {
   // | 'video';
   schema?: string;
   shared?: string;
+  /**
+   * Stacking order among items with the same parent.
+   */
+  z_index?: number;
+  /**
+   * The frame containing the item.
+   */
+  parent_id?: number | null;
+  /**
+   * Number of changes to the item, sent back as the base of the next write.
+   */
+  version?: number;
+  /**
+   * Unix time the item was moved to the trash.
+   */
+  deleted_at?: number | null;
+};
+
+/**
+ * A change to one group of fields of an item. Patches of different fields
+ * merge, while a patch of a field changed since `version` is rejected.
+ */
+export type ItemPatch = { id: number; version: number } & (
+  | { op: 'move'; x: number; y: number }
+  | { op: 'resize'; w: number; h: number }
+  | { op: 'schema'; schema: string }
+);
+
+/**
+ * Response to a rejected write, with the item as stored.
+ */
+export type Conflict = {
+  error: string;
+  current: Item;
+};
+
+/**
+ * Patches sent with `item:patch_many`. Unless `partial`, either all of them
+ * are applied or none.
+ */
+export type PatchBatch = {
+  patches: ItemPatch[];
+  partial?: boolean;
+};
+
+export type PatchFailure = { id: number; error: string; current?: Item };
+
+export type BatchResult = {
+  items: Item[];
+  failures: PatchFailure[];
+};
+
+/**
+ * Items to copy with `item:duplicate`, with the items they contain, moved by
+ * `dx`, `dy`. Copies reference the same assets as the originals.
+ */
+export type Duplicate = {
+  ids: number[];
+  dx: number;
+  dy: number;
+};
+
+/**
+ * An item with the assets it references and the connectors attached to it.
+ */
+export type ItemState = Item & {
+  asset_ids: string[];
+  connectors: Connector[];
+};
+
+/**
+ * Payload of `item:event` for sockets that did not connect with
+ * `{ format: 'protobuf' }`, which receive `item.ItemEvent` messages instead.
+ */
+export type ItemEvent =
+  | { type: 'created' | 'updated' | 'deleted'; item: ItemState }
+  | { type: 'batch'; items: ItemState[] };
+
+export type Anchor = 'auto' | 'top' | 'right' | 'bottom' | 'left';
+
+export type Connector = {
+  id?: number;
+  space_id?: number;
+  source_id: number;
+  target_id: number;
+  source_anchor: Anchor;
+  target_anchor: Anchor;
+  label?: string | null;
+  /**
+   * Stroke, arrowheads and the like, as JSON.
+   */
+  style?: string | null;
+};
+
+export type BoundingBox = {
+  xmin: number;
+  ymin: number;
+  xmax: number;
+  ymax: number;
+};
+
+/**
+ * Where a page of nearby items ended. Only meaningful with the same viewport.
+ */
+export type NearbyCursor = {
+  distance: number;
+  id: number;
+};
+
+/**
+ * A page of the items of a space, nearest to the centre of the viewport first.
+ * Items come in that order rather than stacked, see `z_index`.
+ */
+export type NearbyQuery = {
+  space_id: number;
+  /**
+   * The origin is the centre when missing.
+   */
+  viewport?: BoundingBox;
+  /**
+   * `next` of the previous page.
+   */
+  after?: NearbyCursor;
+  /**
+   * 500 by default, at most 2000.
+   */
+  limit?: number;
+  /**
+   * Leaves out `schema`, to lay out a huge board before loading the content.
+   */
+  geometry_only?: boolean;
+};
+
+/**
+ * Items in view and the connectors attached to any of them. A connector
+ * between items of different pages comes with both.
+ */
+export type Nearby = {
+  items: Item[];
+  connectors: Connector[];
+  /**
+   * Cursor of the next page, `null` on the last one.
+   */
+  next: NearbyCursor | null;
+};
+
+/**
+ * An item matching an `item:search` query, with the coordinates to pan to.
+ */
+export type SearchHit = {
+  id: number;
+  x: number;
+  y: number;
+  w: number;
+  h: number;
+  rank: number;
+  /**
+   * Matching fragments as HTML: the text is escaped and the terms are wrapped
+   * in `<mark>`.
+   */
+  snippet: string;
+};
+
+/**
+ * A changed block of an item's schema, absent on the side it was added or
+ * removed on.
+ */
+export type BlockChange = {
+  index: number;
+  from?: any;
+  to?: any;
+};
+
+/**
+ * Fields changed by a revision as `[from, to]`.
+ */
+export type Diff = {
+  x?: [number, number];
+  y?: [number, number];
+  w?: [number, number];
+  h?: [number, number];
+  schema?: BlockChange[];
+  deleted_at?: [number | null, number | null];
+};
+
+export type Revision = {
+  id: number;
+  item_id: number;
+  actor: string;
+  action:
+    | 'create'
+    | 'update'
+    | 'patch'
+    | 'move'
+    | 'delete'
+    | 'restore'
+    | 'purge'
+    | 'revert'
+    | 'duplicate'
+    | 'import'
+    | 'template';
+  changes: Diff;
+  created_at: number;
 };
 
 export type Asset = {
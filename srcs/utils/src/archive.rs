@@ -0,0 +1,461 @@
+//! Portable archive of a space, to move it between the desktop app and the
+//! server and to back it up. An archive is a zip of
+//!
+//! - `manifest.json`, the format version, the name of the space and its assets,
+//! - `items.json`, the items and the connectors between them,
+//! - `assets/<id>`, the content of each asset.
+//!
+//! Ids only relate the entries of an archive to each other, importing assigns
+//! new ones.
+
+use std::{
+  collections::{HashMap, HashSet},
+  io::{Cursor, Read, Write},
+};
+
+use serde::{Deserialize, Serialize};
+use zip::{result::ZipError, write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+/// Version of the archives written. Archives of older versions are read as
+/// well: fields added since have defaults.
+pub const VERSION: u32 = 1;
+
+/// Largest entry read from an archive, in bytes.
+pub const MAX_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+const MANIFEST: &str = "manifest.json";
+const ITEMS: &str = "items.json";
+const ASSETS: &str = "assets/";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Zip(#[from] ZipError),
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  Json(#[from] serde_json::Error),
+  #[error("unsupported archive version {0}, at most {VERSION} is supported")]
+  UnsupportedVersion(u32),
+  #[error("archive entry {0} is missing")]
+  MissingEntry(String),
+  #[error("archive entry {0} is too large")]
+  EntryTooLarge(String),
+  #[error("invalid archive: {0}")]
+  Invalid(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Manifest {
+  version: u32,
+  name: String,
+  assets: Vec<AssetEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct AssetEntry {
+  id: String,
+  name: String,
+  mime: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Items {
+  items: Vec<Item>,
+  #[serde(default)]
+  connectors: Vec<Connector>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Item {
+  pub id: i64,
+  pub x: i64,
+  pub y: i64,
+  pub w: i64,
+  pub h: i64,
+  pub editor: String,
+  /// References assets of the archive by their id in `ImageElement.uuid`.
+  pub schema: Option<String>,
+  pub z_index: i64,
+  pub parent_id: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Connector {
+  pub source_id: i64,
+  pub target_id: i64,
+  pub source_anchor: String,
+  pub target_anchor: String,
+  pub label: Option<String>,
+  pub style: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Asset {
+  pub id: String,
+  pub name: String,
+  pub mime: String,
+  pub data: Vec<u8>,
+}
+
+/// The content of a space.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Archive {
+  pub name: String,
+  pub items: Vec<Item>,
+  pub connectors: Vec<Connector>,
+  pub assets: Vec<Asset>,
+}
+
+/// New ids of the entries of an archive being imported, by their id in the
+/// archive.
+#[derive(Debug, Default)]
+pub struct Ids {
+  items: HashMap<i64, i64>,
+  assets: HashMap<String, String>,
+}
+
+impl Ids {
+  /// Maps each asset to the id it was stored under, or to the id of the asset
+  /// in `existing` already stored with the same content.
+  pub fn new(
+    assets: impl IntoIterator<Item = (String, String)>,
+    existing: &HashMap<String, String>,
+  ) -> Self {
+    let assets = assets
+      .into_iter()
+      .map(|(id, new_id)| match existing.get(&new_id) {
+        Some(existing_id) => (id, existing_id.clone()),
+        None => (id, new_id),
+      })
+      .collect();
+    Self {
+      items: HashMap::new(),
+      assets,
+    }
+  }
+
+  pub fn assets(&self) -> &HashMap<String, String> {
+    &self.assets
+  }
+
+  /// Returns a reference to an asset outside of the archive, if any. Only
+  /// assets of the archive can be referenced, not whichever asset happens to
+  /// have the id where it is imported.
+  pub fn unknown_asset<'a>(&self, refs: &'a HashSet<String>) -> Option<&'a String> {
+    refs.iter().find(|id| !self.assets.contains_key(*id))
+  }
+
+  pub fn insert_item(&mut self, id: i64, new_id: i64) {
+    self.items.insert(id, new_id);
+  }
+
+  /// The new id of the parent of `item`. Parents are imported before their
+  /// children, see [`Archive::ordered_items`].
+  pub fn parent(&self, item: &Item) -> Option<i64> {
+    item.parent_id.map(|id| self.items[&id])
+  }
+
+  /// The connector between the imported items.
+  pub fn connector(&self, connector: Connector) -> Connector {
+    Connector {
+      source_id: self.items[&connector.source_id],
+      target_id: self.items[&connector.target_id],
+      ..connector
+    }
+  }
+}
+
+/// Reads an entry, failing once it exceeds [`MAX_ENTRY_SIZE`] whatever size
+/// it claims.
+fn read_entry(archive: &mut ZipArchive<Cursor<&[u8]>>, name: &str) -> Result<Vec<u8>, Error> {
+  let entry = match archive.by_name(name) {
+    Ok(entry) => entry,
+    Err(ZipError::FileNotFound) => return Err(Error::MissingEntry(name.to_owned())),
+    Err(e) => return Err(e.into()),
+  };
+  let mut data = Vec::new();
+  entry.take(MAX_ENTRY_SIZE + 1).read_to_end(&mut data)?;
+  if data.len() as u64 > MAX_ENTRY_SIZE {
+    return Err(Error::EntryTooLarge(name.to_owned()));
+  }
+  Ok(data)
+}
+
+impl Archive {
+  /// Builds the archive of a space from its items and connectors, without
+  /// assets. Items left out, e.g. with the trash, take their connectors along
+  /// and leave the items they contained at the top level.
+  pub fn from_rows(
+    name: String,
+    items: impl IntoIterator<Item = impl Into<Item>>,
+    connectors: impl IntoIterator<Item = impl Into<Connector>>,
+  ) -> Self {
+    let mut items = items.into_iter().map(Into::into).collect::<Vec<Item>>();
+    let ids = items.iter().map(|item| item.id).collect::<HashSet<_>>();
+    for item in &mut items {
+      item.parent_id = item.parent_id.filter(|id| ids.contains(id));
+    }
+    let connectors = connectors
+      .into_iter()
+      .map(Into::into)
+      .filter(|connector: &Connector| {
+        ids.contains(&connector.source_id) && ids.contains(&connector.target_id)
+      })
+      .collect();
+    Self {
+      name,
+      items,
+      connectors,
+      assets: Vec::new(),
+    }
+  }
+
+  pub fn write(&self) -> Result<Vec<u8>, Error> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let json = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    // Images are compressed already.
+    let stored = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+
+    let manifest = Manifest {
+      version: VERSION,
+      name: self.name.clone(),
+      assets: self
+        .assets
+        .iter()
+        .map(|asset| AssetEntry {
+          id: asset.id.clone(),
+          name: asset.name.clone(),
+          mime: asset.mime.clone(),
+        })
+        .collect(),
+    };
+    zip.start_file(MANIFEST, json)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest)?)?;
+
+    let items = Items {
+      items: self.items.clone(),
+      connectors: self.connectors.clone(),
+    };
+    zip.start_file(ITEMS, json)?;
+    zip.write_all(&serde_json::to_vec(&items)?)?;
+
+    for asset in &self.assets {
+      zip.start_file(format!("{}{}", ASSETS, asset.id), stored)?;
+      zip.write_all(&asset.data)?;
+    }
+    Ok(zip.finish()?.into_inner())
+  }
+
+  /// Reads and validates an archive of this or an older version.
+  pub fn read(data: &[u8]) -> Result<Self, Error> {
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+    let manifest = serde_json::from_slice::<Manifest>(&read_entry(&mut zip, MANIFEST)?)?;
+    if manifest.version > VERSION {
+      return Err(Error::UnsupportedVersion(manifest.version));
+    }
+    let items = serde_json::from_slice::<Items>(&read_entry(&mut zip, ITEMS)?)?;
+
+    let mut assets = Vec::with_capacity(manifest.assets.len());
+    for entry in manifest.assets {
+      let data = read_entry(&mut zip, &format!("{}{}", ASSETS, entry.id))?;
+      assets.push(Asset {
+        id: entry.id,
+        name: entry.name,
+        mime: entry.mime,
+        data,
+      });
+    }
+
+    let archive = Self {
+      name: manifest.name,
+      items: items.items,
+      connectors: items.connectors,
+      assets,
+    };
+    archive.validate()?;
+    Ok(archive)
+  }
+
+  fn validate(&self) -> Result<(), Error> {
+    let mut ids = HashSet::new();
+    if !self.items.iter().all(|item| ids.insert(item.id)) {
+      return Err(Error::Invalid("duplicate item id"));
+    }
+    if self
+      .items
+      .iter()
+      .any(|item| item.parent_id.is_some_and(|id| !ids.contains(&id)))
+    {
+      return Err(Error::Invalid("item parent is not in the archive"));
+    }
+    if self
+      .connectors
+      .iter()
+      .any(|connector| !ids.contains(&connector.source_id) || !ids.contains(&connector.target_id))
+    {
+      return Err(Error::Invalid("connector item is not in the archive"));
+    }
+    // Every item must descend from a top-level item.
+    self.ordered_items()?;
+    Ok(())
+  }
+
+  /// The items with parents before their children, siblings bottom first.
+  pub fn ordered_items(&self) -> Result<Vec<&Item>, Error> {
+    let mut children = HashMap::<Option<i64>, Vec<&Item>>::new();
+    for item in &self.items {
+      children.entry(item.parent_id).or_default().push(item);
+    }
+    for siblings in children.values_mut() {
+      siblings.sort_by_key(|item| (item.z_index, item.id));
+    }
+
+    let mut ordered = Vec::with_capacity(self.items.len());
+    let mut parents = vec![None];
+    while let Some(parent_id) = parents.pop() {
+      for item in children.remove(&parent_id).unwrap_or_default() {
+        ordered.push(item);
+        parents.push(Some(item.id));
+      }
+    }
+    if ordered.len() != self.items.len() {
+      return Err(Error::Invalid("items contain each other"));
+    }
+    Ok(ordered)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn item(id: i64, parent_id: Option<i64>, z_index: i64) -> Item {
+    Item {
+      id,
+      x: 0,
+      y: 0,
+      w: 1,
+      h: 1,
+      editor: "rich".into(),
+      schema: Some("[]".into()),
+      z_index,
+      parent_id,
+    }
+  }
+
+  #[test]
+  fn test_archive() {
+    let archive = Archive {
+      name: "Plans".into(),
+      items: vec![item(3, Some(1), 1), item(1, None, 2), item(2, None, 1)],
+      connectors: vec![Connector {
+        source_id: 1,
+        target_id: 2,
+        source_anchor: "auto".into(),
+        target_anchor: "auto".into(),
+        label: None,
+        style: None,
+      }],
+      assets: vec![Asset {
+        id: "a".into(),
+        name: "diagram".into(),
+        mime: "image/png".into(),
+        data: vec![1, 2, 3],
+      }],
+    };
+    let read = Archive::read(&archive.write().unwrap()).unwrap();
+    assert_eq!(read, archive);
+    let order = read
+      .ordered_items()
+      .unwrap()
+      .iter()
+      .map(|item| item.id)
+      .collect::<Vec<_>>();
+    assert_eq!(order, vec![2, 1, 3]);
+
+    let cyclic = Archive {
+      items: vec![item(1, Some(2), 1), item(2, Some(1), 1)],
+      ..Default::default()
+    };
+    assert!(matches!(
+      Archive::read(&cyclic.write().unwrap()),
+      Err(Error::Invalid(_))
+    ));
+    assert!(matches!(Archive::read(b"not a zip"), Err(Error::Zip(_))));
+  }
+
+  #[test]
+  fn test_from_rows() {
+    let connector = |source_id, target_id| Connector {
+      source_id,
+      target_id,
+      source_anchor: "auto".into(),
+      target_anchor: "auto".into(),
+      label: None,
+      style: None,
+    };
+    // Item 2 was left out.
+    let archive = Archive::from_rows(
+      "Plans".into(),
+      [item(1, None, 1), item(3, Some(2), 1), item(4, Some(1), 1)],
+      [connector(1, 3), connector(2, 4)],
+    );
+    let parents = archive
+      .items
+      .iter()
+      .map(|item| item.parent_id)
+      .collect::<Vec<_>>();
+    assert_eq!(parents, vec![None, None, Some(1)]);
+    assert_eq!(archive.connectors, vec![connector(1, 3)]);
+
+    let mut ids = Ids::new(
+      [("a".into(), "x".into()), ("b".into(), "y".into())],
+      &HashMap::from([("y".into(), "z".into())]),
+    );
+    assert_eq!(ids.assets()["a"], "x");
+    assert_eq!(ids.assets()["b"], "z");
+    let refs = HashSet::from(["a".to_string(), "c".to_string()]);
+    assert_eq!(ids.unknown_asset(&refs).unwrap(), "c");
+    ids.insert_item(1, 10);
+    ids.insert_item(3, 30);
+    assert_eq!(ids.parent(&item(4, Some(1), 1)), Some(10));
+    assert_eq!(ids.connector(connector(1, 3)), connector(10, 30));
+  }
+
+  #[test]
+  fn test_versions() {
+    let write = |manifest: &str, items: &str| {
+      let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+      zip
+        .start_file(MANIFEST, SimpleFileOptions::default())
+        .unwrap();
+      zip.write_all(manifest.as_bytes()).unwrap();
+      zip.start_file(ITEMS, SimpleFileOptions::default()).unwrap();
+      zip.write_all(items.as_bytes()).unwrap();
+      zip.finish().unwrap().into_inner()
+    };
+
+    // Fields added in later versions have defaults.
+    let archive = Archive::read(&write(
+      r#"{ "version": 1, "name": "Plans", "assets": [] }"#,
+      r#"{ "items": [] }"#,
+    ))
+    .unwrap();
+    assert!(archive.connectors.is_empty());
+    assert!(matches!(
+      Archive::read(&write(
+        r#"{ "version": 2, "name": "Plans", "assets": [] }"#,
+        r#"{ "items": [] }"#,
+      )),
+      Err(Error::UnsupportedVersion(2))
+    ));
+    assert!(matches!(
+      Archive::read(&write(
+        r#"{ "version": 1, "name": "Plans", "assets": [{ "id": "a", "name": "", "mime": "" }] }"#,
+        r#"{ "items": [] }"#,
+      )),
+      Err(Error::MissingEntry(_))
+    ));
+  }
+}
@@ -0,0 +1,123 @@
+use std::io::Cursor;
+
+use image::ImageFormat;
+
+/// Content types accepted for uploaded assets.
+pub const ALLOWED_MIME_TYPES: [&str; 4] = ["image/gif", "image/jpeg", "image/png", "image/webp"];
+
+/// Thumbnails fit within a square of this many pixels.
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error("unsupported asset type: {0}")]
+  Unsupported(String),
+  #[error("declared asset type {declared} does not match detected type {detected}")]
+  Mismatch { declared: String, detected: String },
+  #[error("no asset uploaded for reference {0}")]
+  Missing(usize),
+  #[error(transparent)]
+  Image(#[from] image::ImageError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+  pub mime: String,
+  pub width: u32,
+  pub height: u32,
+  /// PNG encoded preview of the image.
+  pub thumbnail: Vec<u8>,
+}
+
+/// Detects the type of `data` from its magic bytes, rejecting anything not in
+/// [`ALLOWED_MIME_TYPES`], and reads the image dimensions and thumbnail.
+pub fn inspect(data: &[u8]) -> Result<Metadata, Error> {
+  let mime = infer::get(data)
+    .map(|kind| kind.mime_type())
+    .unwrap_or("application/octet-stream");
+  if !ALLOWED_MIME_TYPES.contains(&mime) {
+    return Err(Error::Unsupported(mime.to_string()));
+  }
+
+  let image = image::load_from_memory(data)?;
+  let mut thumbnail = vec![];
+  image
+    .thumbnail(
+      image.width().min(THUMBNAIL_SIZE),
+      image.height().min(THUMBNAIL_SIZE),
+    )
+    .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Png)?;
+
+  Ok(Metadata {
+    mime: mime.to_string(),
+    width: image.width(),
+    height: image.height(),
+    thumbnail,
+  })
+}
+
+/// Checks the type a client declared for an asset against the detected type.
+/// An empty declaration is accepted.
+pub fn check_declared(declared: &str, detected: &str) -> Result<(), Error> {
+  let declared = declared.trim().to_ascii_lowercase();
+  let normalized = match declared.as_str() {
+    "image/jpg" => "image/jpeg",
+    declared => declared,
+  };
+  if normalized.is_empty() || normalized == detected {
+    Ok(())
+  } else {
+    Err(Error::Mismatch {
+      declared,
+      detected: detected.to_string(),
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use image::{DynamicImage, RgbImage};
+
+  use super::*;
+
+  fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut data = vec![];
+    DynamicImage::ImageRgb8(RgbImage::new(width, height))
+      .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+      .unwrap();
+    data
+  }
+
+  #[test]
+  fn test_inspect() {
+    let metadata = inspect(&png(1024, 512)).unwrap();
+
+    assert_eq!(metadata.mime, "image/png");
+    assert_eq!((metadata.width, metadata.height), (1024, 512));
+    let thumbnail = image::load_from_memory(&metadata.thumbnail).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (256, 128));
+  }
+
+  #[test]
+  fn test_inspect_rejects_unsupported() {
+    assert!(matches!(
+      inspect(b"%PDF-1.7\n"),
+      Err(Error::Unsupported(mime)) if mime == "application/pdf"
+    ));
+    assert!(matches!(
+      inspect(b"<svg></svg>"),
+      Err(Error::Unsupported(_))
+    ));
+  }
+
+  #[test]
+  fn test_check_declared() {
+    assert!(check_declared("", "image/png").is_ok());
+    assert!(check_declared("image/png", "image/png").is_ok());
+    assert!(check_declared("image/jpg", "image/jpeg").is_ok());
+    assert!(matches!(
+      check_declared("image/png", "image/gif"),
+      Err(Error::Mismatch { .. })
+    ));
+  }
+}
@@ -0,0 +1,286 @@
+use std::{ops::Range, path::PathBuf, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
+use object_store::{
+  aws::AmazonS3Builder, local::LocalFileSystem, path::Path, GetOptions, GetRange, ObjectStore,
+  PutPayload,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+  #[error(transparent)]
+  Io(#[from] std::io::Error),
+  #[error(transparent)]
+  ObjectStore(#[from] object_store::Error),
+  #[error(transparent)]
+  Database(Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// Where asset content is kept. Metadata always stays in the database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backend {
+  Database,
+  Filesystem,
+  S3,
+}
+
+/// Asset content read in chunks.
+pub type ByteStream = BoxStream<'static, Result<Bytes, Error>>;
+
+/// Storage for asset content, addressed by asset id.
+#[async_trait]
+pub trait AssetStore: Send + Sync {
+  /// Stores `data` for the asset, replacing any previous content.
+  async fn put(&self, id: &str, data: Vec<u8>) -> Result<(), Error>;
+
+  async fn get(&self, id: &str) -> Result<Option<Vec<u8>>, Error>;
+
+  async fn get_range(&self, id: &str, range: Range<u64>) -> Result<Option<Vec<u8>>, Error> {
+    Ok(self.get(id).await?.map(|data| {
+      let end = (range.end as usize).min(data.len());
+      data[(range.start as usize).min(end)..end].to_vec()
+    }))
+  }
+
+  /// Reads a range of the content as it arrives, so it can be served without
+  /// holding it in memory. Stores that cannot stream read the range at once.
+  async fn stream_range(&self, id: &str, range: Range<u64>) -> Result<Option<ByteStream>, Error> {
+    Ok(
+      self
+        .get_range(id, range)
+        .await?
+        .map(|data| futures_util::stream::once(async { Ok(Bytes::from(data)) }).boxed()),
+    )
+  }
+
+  /// Removes the content of the asset. Missing content is not an error.
+  async fn delete(&self, id: &str) -> Result<(), Error>;
+}
+
+/// Configuration shared by everything that stores assets.
+#[derive(Debug, Clone, clap::Args)]
+pub struct StoreArgs {
+  #[arg(long, env, value_enum, default_value_t = Backend::Database)]
+  pub asset_store: Backend,
+  /// Directory used by the filesystem backend.
+  #[arg(long, env)]
+  pub asset_store_path: Option<PathBuf>,
+  /// Endpoint of an S3-compatible service, e.g. MinIO. Defaults to AWS.
+  #[arg(long, env)]
+  pub s3_endpoint: Option<String>,
+  #[arg(long, env, default_value_t = String::from("spaced-assets"))]
+  pub s3_bucket: String,
+  #[arg(long, env, default_value_t = String::from("us-east-1"))]
+  pub s3_region: String,
+  #[arg(long, env)]
+  pub s3_access_key_id: Option<String>,
+  #[arg(long, env)]
+  pub s3_secret_access_key: Option<String>,
+}
+
+impl StoreArgs {
+  /// Builds the store for `backend`. The database store depends on the schema
+  /// of its caller, so it is passed in rather than built here.
+  pub fn build(
+    &self,
+    backend: Backend,
+    database: Arc<dyn AssetStore>,
+  ) -> Result<Arc<dyn AssetStore>, Error> {
+    match backend {
+      Backend::Database => Ok(database),
+      Backend::Filesystem => {
+        let path = self
+          .asset_store_path
+          .clone()
+          .unwrap_or_else(|| PathBuf::from("assets"));
+        std::fs::create_dir_all(&path)?;
+        Ok(Arc::new(ObjectAssetStore::new(
+          LocalFileSystem::new_with_prefix(path)?,
+        )))
+      }
+      Backend::S3 => {
+        let mut builder = AmazonS3Builder::from_env()
+          .with_bucket_name(&self.s3_bucket)
+          .with_region(&self.s3_region);
+        if let Some(endpoint) = &self.s3_endpoint {
+          builder = builder
+            .with_endpoint(endpoint)
+            .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(access_key_id) = &self.s3_access_key_id {
+          builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &self.s3_secret_access_key {
+          builder = builder.with_secret_access_key(secret_access_key);
+        }
+        Ok(Arc::new(ObjectAssetStore::new(builder.build()?)))
+      }
+    }
+  }
+}
+
+/// Keeps asset content in an [`ObjectStore`], e.g. a local directory or an S3
+/// bucket.
+#[derive(Debug)]
+pub struct ObjectAssetStore {
+  inner: Box<dyn ObjectStore>,
+}
+
+impl ObjectAssetStore {
+  pub fn new(inner: impl ObjectStore) -> Self {
+    Self {
+      inner: Box::new(inner),
+    }
+  }
+}
+
+#[async_trait]
+impl AssetStore for ObjectAssetStore {
+  async fn put(&self, id: &str, data: Vec<u8>) -> Result<(), Error> {
+    self
+      .inner
+      .put(&Path::from(id), PutPayload::from(data))
+      .await?;
+    Ok(())
+  }
+
+  async fn get(&self, id: &str) -> Result<Option<Vec<u8>>, Error> {
+    match self.inner.get(&Path::from(id)).await {
+      Ok(result) => Ok(Some(result.bytes().await?.to_vec())),
+      Err(object_store::Error::NotFound { .. }) => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn get_range(&self, id: &str, range: Range<u64>) -> Result<Option<Vec<u8>>, Error> {
+    let range = range.start as usize..range.end as usize;
+    match self.inner.get_range(&Path::from(id), range).await {
+      Ok(data) => Ok(Some(data.to_vec())),
+      Err(object_store::Error::NotFound { .. }) => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn stream_range(&self, id: &str, range: Range<u64>) -> Result<Option<ByteStream>, Error> {
+    let options = GetOptions {
+      range: Some(GetRange::Bounded(range.start as usize..range.end as usize)),
+      ..Default::default()
+    };
+    match self.inner.get_opts(&Path::from(id), options).await {
+      Ok(result) => Ok(Some(result.into_stream().map_err(Error::from).boxed())),
+      Err(object_store::Error::NotFound { .. }) => Ok(None),
+      Err(e) => Err(e.into()),
+    }
+  }
+
+  async fn delete(&self, id: &str) -> Result<(), Error> {
+    match self.inner.delete(&Path::from(id)).await {
+      Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+      Err(e) => Err(e.into()),
+    }
+  }
+}
+
+/// Moves the content of the given assets from one store to another. Content
+/// is removed from `from` only once `to` holds it, and assets already missing
+/// from `from` are skipped, so an interrupted migration can be rerun. Returns
+/// the number of assets moved.
+pub async fn migrate(
+  ids: &[String],
+  from: &dyn AssetStore,
+  to: &dyn AssetStore,
+) -> Result<usize, Error> {
+  let mut moved = 0;
+  for id in ids {
+    let Some(data) = from.get(id).await? else {
+      continue;
+    };
+    to.put(id, data).await?;
+    from.delete(id).await?;
+    moved += 1;
+  }
+  Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn args(name: &str) -> StoreArgs {
+    StoreArgs {
+      asset_store: Backend::Filesystem,
+      asset_store_path: Some(std::env::temp_dir().join(format!(
+        "spaced-{}-{}",
+        name,
+        std::process::id()
+      ))),
+      s3_endpoint: std::env::var("S3_ENDPOINT")
+        .ok()
+        .or(Some("http://localhost:9000".into())),
+      s3_bucket: "spaced-assets".into(),
+      s3_region: "us-east-1".into(),
+      s3_access_key_id: Some("admin".into()),
+      s3_secret_access_key: Some("password".into()),
+    }
+  }
+
+  fn filesystem(name: &str) -> Arc<dyn AssetStore> {
+    let args = args(name);
+    let _ = std::fs::remove_dir_all(args.asset_store_path.as_ref().unwrap());
+    let database = Arc::new(ObjectAssetStore::new(object_store::memory::InMemory::new()));
+    args.build(Backend::Filesystem, database).unwrap()
+  }
+
+  async fn check_round_trip(store: &dyn AssetStore) {
+    store.put("a", b"content".to_vec()).await.unwrap();
+
+    assert_eq!(store.get("a").await.unwrap().unwrap(), b"content");
+    assert_eq!(store.get_range("a", 1..4).await.unwrap().unwrap(), b"ont");
+    let chunks = store
+      .stream_range("a", 1..4)
+      .await
+      .unwrap()
+      .unwrap()
+      .try_collect::<Vec<_>>()
+      .await
+      .unwrap();
+    assert_eq!(chunks.concat(), b"ont");
+    assert!(store.stream_range("missing", 0..1).await.unwrap().is_none());
+    assert_eq!(store.get("missing").await.unwrap(), None);
+
+    store.delete("a").await.unwrap();
+    store.delete("a").await.unwrap();
+    assert_eq!(store.get("a").await.unwrap(), None);
+  }
+
+  #[tokio::test]
+  async fn test_filesystem() {
+    check_round_trip(filesystem("round-trip").as_ref()).await;
+  }
+
+  /// Runs against a local MinIO, see `docker-compose.yaml`.
+  #[ignore]
+  #[tokio::test]
+  async fn test_s3() {
+    let database = Arc::new(ObjectAssetStore::new(object_store::memory::InMemory::new()));
+    let store = args("s3").build(Backend::S3, database).unwrap();
+    check_round_trip(store.as_ref()).await;
+  }
+
+  #[tokio::test]
+  async fn test_migrate() {
+    let from = ObjectAssetStore::new(object_store::memory::InMemory::new());
+    let to = filesystem("migrate");
+    from.put("a", b"a".to_vec()).await.unwrap();
+    from.put("b", b"b".to_vec()).await.unwrap();
+    let ids = ["a", "b", "c"].map(String::from);
+
+    assert_eq!(migrate(&ids, &from, to.as_ref()).await.unwrap(), 2);
+    assert_eq!(from.get("a").await.unwrap(), None);
+    assert_eq!(to.get("b").await.unwrap().unwrap(), b"b");
+    // Nothing is left to move when rerun.
+    assert_eq!(migrate(&ids, &from, to.as_ref()).await.unwrap(), 0);
+  }
+}
@@ -0,0 +1,124 @@
+//! Liveness and readiness reports. Each dependency of a service is checked
+//! with a timeout and reported on its own, so an orchestrator can tell a
+//! service that is up from one that lost its database or broker.
+
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::Duration};
+
+use axum::{
+  http::StatusCode,
+  response::{IntoResponse, Response},
+};
+use serde::Serialize;
+use tokio::time::Instant;
+
+/// Time a dependency has to answer before it is reported as down.
+pub const TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+  Up,
+  Down,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Check {
+  pub status: Status,
+  pub latency_ms: u64,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+/// Runs `probe`, failing it once [`TIMEOUT`] elapsed.
+pub async fn check<E: Display>(probe: impl Future<Output = Result<(), E>>) -> Check {
+  let start = Instant::now();
+  let error = match tokio::time::timeout(TIMEOUT, probe).await {
+    Ok(Ok(())) => None,
+    Ok(Err(e)) => Some(e.to_string()),
+    Err(_) => Some(format!("timed out after {:?}", TIMEOUT)),
+  };
+  Check {
+    status: if error.is_none() {
+      Status::Up
+    } else {
+      Status::Down
+    },
+    latency_ms: start.elapsed().as_millis() as u64,
+    error,
+  }
+}
+
+/// Status of every dependency. The service is up only if all of them are.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+  pub status: Status,
+  pub checks: BTreeMap<&'static str, Check>,
+  /// Liveness reports are served with 200 even when a dependency is down,
+  /// restarting the service would not bring it back.
+  #[serde(skip)]
+  pub live: bool,
+}
+
+impl Report {
+  fn new(checks: impl IntoIterator<Item = (&'static str, Check)>, live: bool) -> Self {
+    let checks = checks.into_iter().collect::<BTreeMap<_, _>>();
+    let status = if checks.values().all(|check| check.status == Status::Up) {
+      Status::Up
+    } else {
+      Status::Down
+    };
+    Self {
+      status,
+      checks,
+      live,
+    }
+  }
+
+  /// Report for `/healthz`.
+  pub fn liveness(checks: impl IntoIterator<Item = (&'static str, Check)>) -> Self {
+    Self::new(checks, true)
+  }
+
+  /// Report for `/readyz`, served with 503 while a dependency is down.
+  pub fn readiness(checks: impl IntoIterator<Item = (&'static str, Check)>) -> Self {
+    Self::new(checks, false)
+  }
+}
+
+impl IntoResponse for Report {
+  fn into_response(self) -> Response {
+    let status = if self.live || self.status == Status::Up {
+      StatusCode::OK
+    } else {
+      StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, axum::Json(self)).into_response()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[tokio::test]
+  async fn test_report() {
+    let up = check(async { Ok::<_, String>(()) }).await;
+    let down = check(async { Err("refused") }).await;
+    let stuck = check(std::future::pending::<Result<(), String>>()).await;
+    assert_eq!(up.status, Status::Up);
+    assert_eq!(down.error.as_deref(), Some("refused"));
+    assert_eq!(stuck.status, Status::Down);
+
+    let ready = Report::readiness([("postgres", up.clone())]);
+    assert_eq!(ready.status, Status::Up);
+    assert_eq!(ready.into_response().status(), StatusCode::OK);
+    let ready = Report::readiness([("postgres", up.clone()), ("amqp", down.clone())]);
+    assert_eq!(ready.status, Status::Down);
+    assert_eq!(
+      ready.into_response().status(),
+      StatusCode::SERVICE_UNAVAILABLE
+    );
+    let live = Report::liveness([("postgres", up), ("amqp", down)]);
+    assert_eq!(live.into_response().status(), StatusCode::OK);
+  }
+}
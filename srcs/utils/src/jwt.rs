@@ -0,0 +1,150 @@
+//! Tokens issued by the user service. Access and refresh tokens are signed
+//! with the same key, so every token says what it is for in `token_use` and
+//! who may accept it in `aud`, and [`verify`] checks both: a refresh token is
+//! never accepted as an access token, nor the other way round.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use jsonwebtoken::{
+  decode, encode,
+  errors::{Error, ErrorKind},
+  Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{Deserialize, Serialize};
+
+/// Issuer of every token.
+pub const ISSUER: &str = "spaced/user_service";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenUse {
+  /// Authenticates requests to the services.
+  Access,
+  /// Obtains new tokens from the user service.
+  Refresh,
+}
+
+impl TokenUse {
+  /// Audience of the tokens.
+  pub fn audience(self) -> &'static str {
+    match self {
+      Self::Access => "spaced",
+      Self::Refresh => "spaced/user_service/refresh",
+    }
+  }
+
+  /// Lifetime of the tokens, in seconds.
+  pub fn lifetime(self) -> u64 {
+    match self {
+      Self::Access => 60 * 60,
+      Self::Refresh => 60 * 60 * 24 * 30,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+  pub sub: String,
+  pub token_use: TokenUse,
+  pub iss: String,
+  pub aud: String,
+  pub iat: u64,
+  pub exp: u64,
+  /// Unique id of the token.
+  pub jti: String,
+}
+
+impl Claims {
+  /// Claims of a token for `sub` issued now.
+  pub fn new(sub: String, token_use: TokenUse) -> Self {
+    let iat = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_secs();
+    Self {
+      sub,
+      token_use,
+      iss: ISSUER.to_string(),
+      aud: token_use.audience().to_string(),
+      iat,
+      exp: iat + token_use.lifetime(),
+      jti: uuid::Uuid::new_v4().to_string(),
+    }
+  }
+}
+
+pub struct Keys {
+  pub encoding: EncodingKey,
+  pub decoding: DecodingKey,
+}
+
+impl Keys {
+  pub fn new(secret: &[u8]) -> Self {
+    Self {
+      encoding: EncodingKey::from_secret(secret),
+      decoding: DecodingKey::from_secret(secret),
+    }
+  }
+}
+
+pub fn sign(claims: &Claims, key: &EncodingKey) -> Result<String, Error> {
+  encode(&Header::new(Algorithm::HS256), claims, key)
+}
+
+/// Decodes a token, checking its signature, expiry, issuer, and that it is
+/// meant for `token_use`.
+pub fn verify(token: &str, key: &DecodingKey, token_use: TokenUse) -> Result<Claims, Error> {
+  let mut validation = Validation::new(Algorithm::HS256);
+  validation.set_required_spec_claims(&["sub", "iss", "aud", "iat", "exp"]);
+  validation.set_issuer(&[ISSUER]);
+  validation.set_audience(&[token_use.audience()]);
+  let claims = decode::<Claims>(token, key, &validation)?.claims;
+  if claims.token_use != token_use {
+    return Err(ErrorKind::InvalidToken.into());
+  }
+  Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_verify() {
+    let keys = Keys::new(b"secret");
+    let access = sign(
+      &Claims::new("alice".into(), TokenUse::Access),
+      &keys.encoding,
+    )
+    .unwrap();
+    let refresh = sign(
+      &Claims::new("alice".into(), TokenUse::Refresh),
+      &keys.encoding,
+    )
+    .unwrap();
+
+    let claims = verify(&access, &keys.decoding, TokenUse::Access).unwrap();
+    assert_eq!(claims.sub, "alice");
+    assert_eq!(claims.exp - claims.iat, 60 * 60);
+    let other = verify(&refresh, &keys.decoding, TokenUse::Refresh).unwrap();
+    assert_ne!(claims.jti, other.jti);
+
+    // Tokens are only accepted for what they were issued for.
+    assert!(verify(&refresh, &keys.decoding, TokenUse::Access).is_err());
+    assert!(verify(&access, &keys.decoding, TokenUse::Refresh).is_err());
+
+    // A token of the right audience still needs the matching use.
+    let mut claims = Claims::new("alice".into(), TokenUse::Refresh);
+    claims.aud = TokenUse::Access.audience().to_string();
+    let mixed = sign(&claims, &keys.encoding).unwrap();
+    assert!(verify(&mixed, &keys.decoding, TokenUse::Access).is_err());
+
+    let mut claims = Claims::new("alice".into(), TokenUse::Access);
+    claims.iss = "elsewhere".into();
+    let foreign = sign(&claims, &keys.encoding).unwrap();
+    assert!(verify(&foreign, &keys.decoding, TokenUse::Access).is_err());
+
+    let other_keys = Keys::new(b"other");
+    assert!(verify(&access, &other_keys.decoding, TokenUse::Access).is_err());
+  }
+}
@@ -1,3 +1,14 @@
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "asset")]
+pub mod asset;
+#[cfg(feature = "asset_store")]
+pub mod asset_store;
+#[cfg(feature = "health")]
+pub mod health;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+
 #[cfg(feature = "axum")]
 pub mod axum {
   use axum::{